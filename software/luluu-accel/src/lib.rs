@@ -0,0 +1,245 @@
+#![no_std]
+
+//! What the LuLuu needs from an accelerometer, kept apart from any particular one so the logic
+//! built on it, like gesture recognition and sleeping and waking, can run on the host against a
+//! [`Mock`]. `luluu_bsp::accel` has the LIS3DH driver.
+
+/// One acceleration measurement, in milli-g.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sample {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+impl Sample {
+    pub const fn new(x: i16, y: i16, z: i16) -> Self {
+        Self { x, y, z }
+    }
+
+    /// X, Y and Z, wide enough to add and subtract without overflowing.
+    #[inline(always)]
+    pub fn axes(self) -> [i32; 3] {
+        [self.x as i32, self.y as i32, self.z as i32]
+    }
+}
+
+/// Tap detection, on any axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ClickConfig {
+    /// Also detect double taps. Single taps are always detected.
+    pub double: bool,
+    /// High-pass filtered acceleration a tap must exceed.
+    pub threshold_mg: u16,
+    /// Maximum time the acceleration may stay above the threshold for it to count as a tap.
+    pub time_limit_ms: u16,
+    /// Dead time after the first tap of a double tap.
+    pub latency_ms: u16,
+    /// Window after the latency in which the second tap of a double tap must start.
+    pub window_ms: u16,
+}
+
+impl Default for ClickConfig {
+    fn default() -> Self {
+        Self {
+            double: true,
+            threshold_mg: 1200,
+            time_limit_ms: 40,
+            latency_ms: 100,
+            window_ms: 300,
+        }
+    }
+}
+
+/// Free-fall detection: all axes close to 0g at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FreeFallConfig {
+    pub threshold_mg: u16,
+    pub duration_ms: u16,
+}
+
+impl Default for FreeFallConfig {
+    fn default() -> Self {
+        Self {
+            threshold_mg: 350,
+            duration_ms: 30,
+        }
+    }
+}
+
+/// Activity (wake-up) detection: high-pass filtered acceleration on any axis above a threshold,
+/// i.e. the sleeve moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ActivityConfig {
+    pub threshold_mg: u16,
+    pub duration_ms: u16,
+}
+
+impl Default for ActivityConfig {
+    fn default() -> Self {
+        Self {
+            threshold_mg: 100,
+            duration_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Click {
+    Single,
+    Double,
+}
+
+/// Interrupt sources that fired since the last call to [`Accelerometer::events`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Events {
+    pub click: Option<Click>,
+    pub free_fall: bool,
+    pub activity: bool,
+}
+
+impl Events {
+    pub fn any(&self) -> bool {
+        self.click.is_some() || self.free_fall || self.activity
+    }
+}
+
+/// What the firmware needs from an accelerometer.
+pub trait Accelerometer {
+    type Error;
+
+    /// Read the most recent acceleration sample.
+    fn sample(&mut self) -> Result<Sample, Self::Error>;
+
+    /// Enable tap detection with the given config, or disable it with `None`.
+    fn configure_click(&mut self, config: Option<ClickConfig>) -> Result<(), Self::Error>;
+
+    /// Enable free-fall detection with the given config, or disable it with `None`.
+    fn configure_free_fall(&mut self, config: Option<FreeFallConfig>) -> Result<(), Self::Error>;
+
+    /// Enable activity detection with the given config, or disable it with `None`.
+    fn configure_activity(&mut self, config: Option<ActivityConfig>) -> Result<(), Self::Error>;
+
+    /// Read and clear the latched interrupt sources.
+    fn events(&mut self) -> Result<Events, Self::Error>;
+}
+
+/// The mock ran out of recorded readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Exhausted;
+
+/// An accelerometer that plays back recorded readings, one `(sample, events)` pair for each call
+/// to [`Accelerometer::sample`] followed by [`Accelerometer::events`], like the firmware polls it.
+/// Events only show up where detection for them has been configured, as on the real thing.
+pub struct Mock<'a> {
+    readings: &'a [(Sample, Events)],
+    next: usize,
+    pub click: Option<ClickConfig>,
+    pub free_fall: Option<FreeFallConfig>,
+    pub activity: Option<ActivityConfig>,
+}
+
+impl<'a> Mock<'a> {
+    pub const fn new(readings: &'a [(Sample, Events)]) -> Self {
+        Self { readings, next: 0, click: None, free_fall: None, activity: None }
+    }
+
+    /// How many of the readings have been sampled.
+    pub fn sampled(&self) -> usize {
+        self.next
+    }
+}
+
+impl Accelerometer for Mock<'_> {
+    type Error = Exhausted;
+
+    fn sample(&mut self) -> Result<Sample, Self::Error> {
+        let (sample, _) = self.readings.get(self.next).ok_or(Exhausted)?;
+        self.next += 1;
+        Ok(*sample)
+    }
+
+    fn configure_click(&mut self, config: Option<ClickConfig>) -> Result<(), Self::Error> {
+        self.click = config;
+        Ok(())
+    }
+
+    fn configure_free_fall(&mut self, config: Option<FreeFallConfig>) -> Result<(), Self::Error> {
+        self.free_fall = config;
+        Ok(())
+    }
+
+    fn configure_activity(&mut self, config: Option<ActivityConfig>) -> Result<(), Self::Error> {
+        self.activity = config;
+        Ok(())
+    }
+
+    fn events(&mut self) -> Result<Events, Self::Error> {
+        let (_, events) = self.readings.get(self.next.wrapping_sub(1)).ok_or(Exhausted)?;
+        let click = match (events.click, self.click) {
+            (Some(Click::Double), Some(ClickConfig { double: false, .. })) => Some(Click::Single),
+            (click, Some(_)) => click,
+            (_, None) => None,
+        };
+        Ok(Events {
+            click,
+            free_fall: events.free_fall && self.free_fall.is_some(),
+            activity: events.activity && self.activity.is_some(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STILL: Sample = Sample::new(0, 0, 1000);
+
+    #[test]
+    fn mock_plays_back_readings_in_order() {
+        let moved = Events { activity: true, ..Events::default() };
+        let readings = [(STILL, Events::default()), (Sample::new(300, -50, 980), moved)];
+        let mut accel = Mock::new(&readings);
+        accel.configure_activity(Some(ActivityConfig::default())).unwrap();
+
+        assert_eq!(accel.sample(), Ok(STILL));
+        assert_eq!(accel.events(), Ok(Events::default()));
+        assert_eq!(accel.sample(), Ok(Sample::new(300, -50, 980)));
+        assert_eq!(accel.events(), Ok(moved));
+        assert_eq!(accel.sampled(), 2);
+        assert_eq!(accel.sample(), Err(Exhausted));
+    }
+
+    #[test]
+    fn mock_only_reports_configured_events() {
+        let everything = Events { click: Some(Click::Double), free_fall: true, activity: true };
+        let readings = [(STILL, everything); 3];
+        let mut accel = Mock::new(&readings);
+
+        accel.sample().unwrap();
+        assert!(!accel.events().unwrap().any());
+
+        accel.configure_click(Some(ClickConfig { double: false, ..ClickConfig::default() })).unwrap();
+        accel.sample().unwrap();
+        assert_eq!(accel.events().unwrap(), Events { click: Some(Click::Single), ..Events::default() });
+
+        accel.configure_click(Some(ClickConfig::default())).unwrap();
+        accel.configure_free_fall(Some(FreeFallConfig::default())).unwrap();
+        accel.configure_activity(Some(ActivityConfig::default())).unwrap();
+        accel.sample().unwrap();
+        assert_eq!(accel.events().unwrap(), everything);
+    }
+
+    #[test]
+    fn mock_events_before_a_sample() {
+        let mut accel = Mock::new(&[]);
+        assert_eq!(accel.events(), Err(Exhausted));
+    }
+}
@@ -0,0 +1,414 @@
+//! Driver for a LIS3DH 3-axis accelerometer on the board's I2C bus.
+//!
+//! Rev 1.1 of the board doesn't have one of its own, so it goes on the STEMMA QT connector, J3,
+//! on a breakout like Adafruit's. That connector only has the I2C bus, on GPIO2 and GPIO3, so
+//! nothing is wired to the LIS3DH's INT1 pin and its interrupts are polled instead: they're latched
+//! until [`Accelerometer::events`] is called. Rev 2.0 has one on the board, with INT1 on GPIO24,
+//! but its I2C bus is on GPIO18 and GPIO19, which [`Pins`](crate::Pins) uses for the display.
+//!
+//! The driver is generic over any [`embedded_hal::i2c::I2c`] bus, and implements the
+//! [`Accelerometer`] trait from `luluu-accel`, which has a mock of it for running logic on the
+//! host.
+
+use embedded_hal::i2c::I2c;
+
+pub use luluu_accel::{Accelerometer, ActivityConfig, Click, ClickConfig, Events, FreeFallConfig, Sample};
+
+use crate::hal::fugit::HertzU32;
+use crate::{hal, pac, I2cClock, I2cData};
+
+/// Value of the `WHO_AM_I` register of a LIS3DH.
+pub const WHO_AM_I_VALUE: u8 = 0x33;
+
+mod reg {
+    pub const WHO_AM_I: u8 = 0x0F;
+    pub const CTRL_REG1: u8 = 0x20;
+    pub const CTRL_REG2: u8 = 0x21;
+    pub const CTRL_REG3: u8 = 0x22;
+    pub const CTRL_REG4: u8 = 0x23;
+    pub const CTRL_REG5: u8 = 0x24;
+    pub const OUT_X_L: u8 = 0x28;
+    pub const INT1_CFG: u8 = 0x30;
+    pub const INT1_SRC: u8 = 0x31;
+    pub const INT1_THS: u8 = 0x32;
+    pub const INT1_DURATION: u8 = 0x33;
+    pub const INT2_CFG: u8 = 0x34;
+    pub const INT2_SRC: u8 = 0x35;
+    pub const INT2_THS: u8 = 0x36;
+    pub const INT2_DURATION: u8 = 0x37;
+    pub const CLICK_CFG: u8 = 0x38;
+    pub const CLICK_SRC: u8 = 0x39;
+    pub const CLICK_THS: u8 = 0x3A;
+    pub const TIME_LIMIT: u8 = 0x3B;
+    pub const TIME_LATENCY: u8 = 0x3C;
+    pub const TIME_WINDOW: u8 = 0x3D;
+
+    /// Set on a register address to auto-increment it during multi-byte reads.
+    pub const AUTO_INCREMENT: u8 = 0x80;
+}
+
+// CTRL_REG2
+const HP_CLICK: u8 = 1 << 2;
+const HP_IA2: u8 = 1 << 1;
+// CTRL_REG3
+const I1_CLICK: u8 = 1 << 7;
+const I1_IA1: u8 = 1 << 6;
+const I1_IA2: u8 = 1 << 5;
+// CTRL_REG4
+const BDU: u8 = 1 << 7;
+const HR: u8 = 1 << 3;
+// CTRL_REG5
+const LIR_INT1: u8 = 1 << 3;
+const LIR_INT2: u8 = 1 << 1;
+// INTx_CFG / INTx_SRC
+const AOI: u8 = 1 << 7;
+const IA: u8 = 1 << 6;
+const ALL_HIGH: u8 = 0b0010_1010;
+const ALL_LOW: u8 = 0b0001_0101;
+// CLICK_CFG / CLICK_SRC / CLICK_THS
+const ALL_SINGLE: u8 = 0b0001_0101;
+const ALL_DOUBLE: u8 = 0b0010_1010;
+const D_CLICK: u8 = 1 << 5;
+const S_CLICK: u8 = 1 << 4;
+const LIR_CLICK: u8 = 1 << 7;
+
+/// State of the jumper that sets the LSB of the accelerometer's I2C address: "Accel Addr LSB" on
+/// the back of the board, or the `SDO` one on a breakout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AddrJumper {
+    Open,
+    Bridged,
+}
+
+impl AddrJumper {
+    /// The 7-bit I2C address the LIS3DH answers on with the jumper in this state.
+    pub const fn address(self) -> u8 {
+        match self {
+            AddrJumper::Open => 0x18,
+            AddrJumper::Bridged => 0x19,
+        }
+    }
+}
+
+/// The jumper state this crate was built for. Enable the `accel-addr-bridged` feature if you
+/// bridged the jumper on your board.
+pub const ADDR_JUMPER: AddrJumper = if cfg!(feature = "accel-addr-bridged") {
+    AddrJumper::Bridged
+} else {
+    AddrJumper::Open
+};
+
+/// Frequency the accelerometer's I2C bus is run at.
+pub const I2C_FREQ: HertzU32 = HertzU32::kHz(400);
+
+/// The board's I2C bus, on the STEMMA QT connector.
+pub type BoardI2c = hal::I2C<pac::I2C1, (I2cData, I2cClock)>;
+
+/// The accelerometer as it is connected on the board.
+pub type BoardAccelerometer = Lis3dh<BoardI2c>;
+
+/// Bring up the board's I2C bus at [`I2C_FREQ`].
+pub fn init_i2c(
+    i2c1: pac::I2C1,
+    i2c_data: I2cData,
+    i2c_clock: I2cClock,
+    resets: &mut pac::RESETS,
+    system_clock: HertzU32,
+) -> BoardI2c {
+    hal::I2C::i2c1(i2c1, i2c_data, i2c_clock, I2C_FREQ, resets, system_clock)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DataRate {
+    Hz1,
+    Hz10,
+    Hz25,
+    Hz50,
+    Hz100,
+    Hz200,
+    Hz400,
+}
+
+impl DataRate {
+    pub const fn hz(self) -> u32 {
+        match self {
+            DataRate::Hz1 => 1,
+            DataRate::Hz10 => 10,
+            DataRate::Hz25 => 25,
+            DataRate::Hz50 => 50,
+            DataRate::Hz100 => 100,
+            DataRate::Hz200 => 200,
+            DataRate::Hz400 => 400,
+        }
+    }
+
+    const fn odr_bits(self) -> u8 {
+        match self {
+            DataRate::Hz1 => 0b0001,
+            DataRate::Hz10 => 0b0010,
+            DataRate::Hz25 => 0b0011,
+            DataRate::Hz50 => 0b0100,
+            DataRate::Hz100 => 0b0101,
+            DataRate::Hz200 => 0b0110,
+            DataRate::Hz400 => 0b0111,
+        }
+    }
+
+    /// Convert a duration to a number of output data periods, as used by the duration and
+    /// click timing registers. Saturates at `max`.
+    fn ticks(self, millis: u16, max: u8) -> u8 {
+        let ticks = (millis as u32 * self.hz()).div_ceil(1000);
+        ticks.min(max as u32) as u8
+    }
+}
+
+/// Full-scale measurement range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Range {
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+impl Range {
+    const fn fs_bits(self) -> u8 {
+        match self {
+            Range::G2 => 0b00,
+            Range::G4 => 0b01,
+            Range::G8 => 0b10,
+            Range::G16 => 0b11,
+        }
+    }
+
+    /// milli-g per digit of a 12-bit high resolution sample.
+    const fn sample_mg_per_digit(self) -> i16 {
+        match self {
+            Range::G2 => 1,
+            Range::G4 => 2,
+            Range::G8 => 4,
+            Range::G16 => 12,
+        }
+    }
+
+    /// milli-g per digit of the interrupt and click threshold registers.
+    const fn threshold_mg_per_digit(self) -> u16 {
+        match self {
+            Range::G2 => 16,
+            Range::G4 => 32,
+            Range::G8 => 62,
+            Range::G16 => 186,
+        }
+    }
+
+    /// Convert a threshold to register units. Saturates at the 7-bit register maximum.
+    fn threshold(self, mg: u16) -> u8 {
+        (mg / self.threshold_mg_per_digit()).clamp(1, 0x7F) as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    pub data_rate: DataRate,
+    pub range: Range,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_rate: DataRate::Hz100,
+            range: Range::G4,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    /// Error from the underlying I2C bus.
+    Bus(E),
+    /// Something answered on the address, but it isn't a LIS3DH. Contains the `WHO_AM_I` value
+    /// that was read.
+    WrongDevice(u8),
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Bus(e)
+    }
+}
+
+pub struct Lis3dh<I2C> {
+    i2c: I2C,
+    address: u8,
+    config: Config,
+    ctrl_reg2: u8,
+    ctrl_reg3: u8,
+}
+
+impl<I2C: I2c> Lis3dh<I2C> {
+    /// Check that a LIS3DH is present at the address selected by `jumper` and configure it for
+    /// continuous 12-bit measurements with block data update. All interrupts start disabled.
+    pub fn new(i2c: I2C, jumper: AddrJumper, config: Config) -> Result<Self, Error<I2C::Error>> {
+        let mut lis3dh = Self {
+            i2c,
+            address: jumper.address(),
+            config,
+            ctrl_reg2: 0,
+            ctrl_reg3: 0,
+        };
+
+        let who_am_i = lis3dh.read_reg(reg::WHO_AM_I)?;
+        if who_am_i != WHO_AM_I_VALUE {
+            return Err(Error::WrongDevice(who_am_i));
+        }
+
+        lis3dh.write_reg(reg::CTRL_REG1, config.data_rate.odr_bits() << 4 | 0b111)?;
+        lis3dh.write_reg(reg::CTRL_REG2, 0)?;
+        lis3dh.write_reg(reg::CTRL_REG3, 0)?;
+        lis3dh.write_reg(reg::CTRL_REG4, BDU | config.range.fs_bits() << 4 | HR)?;
+        lis3dh.write_reg(reg::CTRL_REG5, LIR_INT1 | LIR_INT2)?;
+        lis3dh.write_reg(reg::INT1_CFG, 0)?;
+        lis3dh.write_reg(reg::INT2_CFG, 0)?;
+        lis3dh.write_reg(reg::CLICK_CFG, 0)?;
+
+        Ok(lis3dh)
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Stop measuring and put the LIS3DH in its power-down mode. Call [`Lis3dh::new`] again on the
+    /// bus to bring it back.
+    pub fn power_down(&mut self) -> Result<(), Error<I2C::Error>> {
+        self.write_reg(reg::CTRL_REG1, 0)?;
+        Ok(())
+    }
+
+    /// Give back the underlying bus.
+    pub fn free(self) -> I2C {
+        self.i2c
+    }
+
+    fn read_reg(&mut self, reg: u8) -> Result<u8, I2C::Error> {
+        let mut value = [0u8];
+        self.i2c.write_read(self.address, &[reg], &mut value)?;
+        Ok(value[0])
+    }
+
+    fn write_reg(&mut self, reg: u8, value: u8) -> Result<(), I2C::Error> {
+        self.i2c.write(self.address, &[reg, value])
+    }
+
+    fn update_ctrl_reg2(&mut self, set: u8, clear: u8) -> Result<(), I2C::Error> {
+        self.ctrl_reg2 = (self.ctrl_reg2 & !clear) | set;
+        self.write_reg(reg::CTRL_REG2, self.ctrl_reg2)
+    }
+
+    fn update_ctrl_reg3(&mut self, set: u8, clear: u8) -> Result<(), I2C::Error> {
+        self.ctrl_reg3 = (self.ctrl_reg3 & !clear) | set;
+        self.write_reg(reg::CTRL_REG3, self.ctrl_reg3)
+    }
+}
+
+impl<I2C: I2c> Accelerometer for Lis3dh<I2C> {
+    type Error = Error<I2C::Error>;
+
+    fn sample(&mut self) -> Result<Sample, Self::Error> {
+        let mut raw = [0u8; 6];
+        self.i2c.write_read(self.address, &[reg::OUT_X_L | reg::AUTO_INCREMENT], &mut raw)?;
+
+        // samples are left-justified 12 bit values in high resolution mode
+        let scale = self.config.range.sample_mg_per_digit();
+        let axis = |lo: u8, hi: u8| (i16::from_le_bytes([lo, hi]) >> 4) * scale;
+        Ok(Sample {
+            x: axis(raw[0], raw[1]),
+            y: axis(raw[2], raw[3]),
+            z: axis(raw[4], raw[5]),
+        })
+    }
+
+    fn configure_click(&mut self, config: Option<ClickConfig>) -> Result<(), Self::Error> {
+        let Some(config) = config else {
+            self.write_reg(reg::CLICK_CFG, 0)?;
+            self.update_ctrl_reg2(0, HP_CLICK)?;
+            self.update_ctrl_reg3(0, I1_CLICK)?;
+            return Ok(());
+        };
+
+        let rate = self.config.data_rate;
+        let ths = self.config.range.threshold(config.threshold_mg);
+        self.write_reg(reg::CLICK_THS, LIR_CLICK | ths)?;
+        self.write_reg(reg::TIME_LIMIT, rate.ticks(config.time_limit_ms, 0x7F))?;
+        // latency and window are full 8 bit registers, unlike the rest
+        self.write_reg(reg::TIME_LATENCY, rate.ticks(config.latency_ms, 0xFF))?;
+        self.write_reg(reg::TIME_WINDOW, rate.ticks(config.window_ms, 0xFF))?;
+
+        let cfg = if config.double { ALL_SINGLE | ALL_DOUBLE } else { ALL_SINGLE };
+        self.write_reg(reg::CLICK_CFG, cfg)?;
+        self.update_ctrl_reg2(HP_CLICK, 0)?;
+        self.update_ctrl_reg3(I1_CLICK, 0)?;
+        Ok(())
+    }
+
+    fn configure_free_fall(&mut self, config: Option<FreeFallConfig>) -> Result<(), Self::Error> {
+        let Some(config) = config else {
+            self.write_reg(reg::INT1_CFG, 0)?;
+            self.update_ctrl_reg3(0, I1_IA1)?;
+            return Ok(());
+        };
+
+        self.write_reg(reg::INT1_THS, self.config.range.threshold(config.threshold_mg))?;
+        self.write_reg(reg::INT1_DURATION, self.config.data_rate.ticks(config.duration_ms, 0x7F))?;
+        // AND of all the low events: every axis is near 0g
+        self.write_reg(reg::INT1_CFG, AOI | ALL_LOW)?;
+        self.update_ctrl_reg3(I1_IA1, 0)?;
+        Ok(())
+    }
+
+    fn configure_activity(&mut self, config: Option<ActivityConfig>) -> Result<(), Self::Error> {
+        let Some(config) = config else {
+            self.write_reg(reg::INT2_CFG, 0)?;
+            self.update_ctrl_reg2(0, HP_IA2)?;
+            self.update_ctrl_reg3(0, I1_IA2)?;
+            return Ok(());
+        };
+
+        // interrupt generator 2 runs on high-pass filtered data so gravity doesn't count as
+        // activity, and is routed to the INT1 pin alongside the others.
+        self.update_ctrl_reg2(HP_IA2, 0)?;
+        self.write_reg(reg::INT2_THS, self.config.range.threshold(config.threshold_mg))?;
+        self.write_reg(reg::INT2_DURATION, self.config.data_rate.ticks(config.duration_ms, 0x7F))?;
+        // OR of all the high events: any axis moved
+        self.write_reg(reg::INT2_CFG, ALL_HIGH)?;
+        self.update_ctrl_reg3(I1_IA2, 0)?;
+        Ok(())
+    }
+
+    fn events(&mut self) -> Result<Events, Self::Error> {
+        let click_src = self.read_reg(reg::CLICK_SRC)?;
+        let int1_src = self.read_reg(reg::INT1_SRC)?;
+        let int2_src = self.read_reg(reg::INT2_SRC)?;
+
+        let click = if click_src & IA == 0 {
+            None
+        } else if click_src & D_CLICK != 0 {
+            Some(Click::Double)
+        } else if click_src & S_CLICK != 0 {
+            Some(Click::Single)
+        } else {
+            None
+        };
+
+        Ok(Events {
+            click,
+            free_fall: int1_src & IA != 0,
+            activity: int2_src & IA != 0,
+        })
+    }
+}
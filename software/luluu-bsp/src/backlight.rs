@@ -0,0 +1,137 @@
+//! Display backlight brightness control.
+//!
+//! The backlight is driven from [`DispBacklightPwm`] (GPIO22), which is channel A of PWM slice 3.
+//! Brightness is given in percent of perceived brightness and gamma corrected into a duty cycle,
+//! so that 50% looks about half as bright as 100%.
+
+use embedded_hal_0_2::PwmPin;
+
+use crate::hal::pwm::{FreeRunning, Pwm3, Slice};
+use crate::{DispBacklightPwm, DispBacklightToggle};
+
+pub type BacklightSlice = Slice<Pwm3, FreeRunning>;
+
+/// PWM counter wrap value. At the default 125MHz system clock and no clock divider this gives a
+/// 25kHz PWM frequency, which is well above anything visible or audible.
+pub const PWM_TOP: u16 = 4999;
+
+/// Brightness is tracked internally in hundredths of a percent so that slow fades are smooth.
+const SCALE: u32 = 100;
+
+/// Perceived brightness to duty cycle in parts per 10000, for every 10%. This is `x^2.2`.
+const GAMMA_CURVE: [u16; 11] = [0, 63, 290, 707, 1332, 2176, 3250, 4563, 6121, 7931, 10000];
+
+/// Duty cycle, out of [`PWM_TOP`] + 1, for a brightness in hundredths of a percent.
+fn duty_for(level: u32) -> u16 {
+    let level = level.min(100 * SCALE);
+    let step = 10 * SCALE;
+    let i = (level / step) as usize;
+    let frac = level % step;
+
+    let lo = GAMMA_CURVE[i] as u32;
+    let hi = GAMMA_CURVE[(i + 1).min(GAMMA_CURVE.len() - 1)] as u32;
+    let per_10000 = lo + (hi - lo) * frac / step;
+
+    (per_10000 * (PWM_TOP as u32 + 1) / 10000) as u16
+}
+
+struct Fade {
+    from: u32,
+    start_millis: u32,
+    duration_millis: u32,
+}
+
+/// Gamma corrected, fading backlight.
+///
+/// Fades are driven by calling [`Backlight::update`] regularly, e.g. once per frame or more.
+pub struct Backlight {
+    slice: BacklightSlice,
+    _pin: DispBacklightPwm,
+    level: u32,
+    target: u32,
+    fade: Option<Fade>,
+}
+
+impl Backlight {
+    /// Take over the backlight pin and PWM slice 3. The backlight starts off.
+    pub fn new(mut slice: BacklightSlice, pin: DispBacklightToggle) -> Self {
+        slice.default_config();
+        slice.set_top(PWM_TOP);
+        slice.channel_a.set_duty(0);
+        slice.enable();
+        Self {
+            slice,
+            _pin: pin.reconfigure(),
+            level: 0,
+            target: 0,
+            fade: None,
+        }
+    }
+
+    /// Current brightness in percent, partway through a fade if one is running.
+    pub fn brightness(&self) -> u8 {
+        (self.level / SCALE) as u8
+    }
+
+    /// The brightness in percent that the backlight is at or fading towards.
+    pub fn target(&self) -> u8 {
+        (self.target / SCALE) as u8
+    }
+
+    #[inline(always)]
+    pub fn is_fading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    /// Set the brightness in percent right away, cancelling any fade.
+    pub fn set_brightness(&mut self, percent: u8) {
+        self.fade = None;
+        self.target = percent.min(100) as u32 * SCALE;
+        self.apply(self.target);
+    }
+
+    /// Start fading from the current brightness to `percent` over `duration_millis`. A fade that's
+    /// already running is picked up from wherever it got to.
+    pub fn fade_to(&mut self, percent: u8, duration_millis: u32, now_millis: u32) {
+        let target = percent.min(100) as u32 * SCALE;
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        if duration_millis == 0 {
+            self.set_brightness(percent);
+            return;
+        }
+        self.fade = Some(Fade {
+            from: self.level,
+            start_millis: now_millis,
+            duration_millis,
+        });
+    }
+
+    /// Advance the running fade, if any.
+    pub fn update(&mut self, now_millis: u32) {
+        let Some(fade) = &self.fade else {
+            return;
+        };
+
+        let elapsed = now_millis.wrapping_sub(fade.start_millis);
+        if elapsed >= fade.duration_millis {
+            self.fade = None;
+            self.apply(self.target);
+            return;
+        }
+
+        let level = if self.target >= fade.from {
+            fade.from + (self.target - fade.from) * elapsed / fade.duration_millis
+        } else {
+            fade.from - (fade.from - self.target) * elapsed / fade.duration_millis
+        };
+        self.apply(level);
+    }
+
+    fn apply(&mut self, level: u32) {
+        self.level = level;
+        self.slice.channel_a.set_duty(duty_for(level));
+    }
+}
@@ -0,0 +1,165 @@
+//! Battery voltage and charge status sensing.
+//!
+//! The `+BATT` rail is measured on [`BattSense`](crate::BattSense) (GPIO29 / ADC3) through a
+//! divider of two equal resistors, and the TP4054's open-drain `~CHRG` output is read on
+//! [`ChargeStat`](crate::ChargeStat). Neither is routed on any board revision so far, where
+//! `~CHRG` only drives the charge LED. An unconnected ADC pin floats and can read anything,
+//! including a believable battery voltage, so only use this on a board that has them wired up.
+//! Without the `~CHRG` connection the pull-up makes the battery always read as not charging.
+//!
+//! This module and both pins are only there with the `battery-sense` feature.
+
+use embedded_hal::digital::InputPin;
+use embedded_hal_0_2::adc::OneShot;
+
+use crate::hal::adc::{Adc, AdcPin};
+use crate::{pac, BattSense, ChargeStat};
+
+/// Ratio of the `+BATT` voltage to the voltage at the ADC pin.
+pub const BATT_SENSE_DIVIDER: u32 = 2;
+
+/// The ADC's reference voltage, which is the 3.3V rail.
+pub const ADC_VREF_MILLIVOLTS: u32 = 3300;
+
+/// Anything below this can't be the battery: a LiPo this flat would have cut out the 3.3V
+/// regulator long before we got here.
+pub const MIN_PLAUSIBLE_MILLIVOLTS: u16 = 2500;
+
+/// How many ADC conversions are averaged for each reading.
+const OVERSAMPLE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChargeStatus {
+    /// Connected to USB power and charging.
+    Charging,
+    /// Running from the battery, or charging has finished.
+    NotCharging,
+}
+
+pub struct Battery {
+    adc: Adc,
+    sense: AdcPin<BattSense>,
+    charge_stat: ChargeStat,
+}
+
+impl Battery {
+    pub fn new(adc: pac::ADC, batt_sense: BattSense, charge_stat: ChargeStat, resets: &mut pac::RESETS) -> Self {
+        Self {
+            adc: Adc::new(adc, resets),
+            sense: AdcPin::new(batt_sense),
+            charge_stat,
+        }
+    }
+
+    /// Battery voltage in millivolts, or `None` if the reading is implausible.
+    pub fn millivolts(&mut self) -> Option<u16> {
+        let mut total: u32 = 0;
+        for _ in 0..OVERSAMPLE {
+            let raw: u16 = OneShot::read(&mut self.adc, &mut self.sense).ok()?;
+            total += raw as u32;
+        }
+        let raw = total / OVERSAMPLE;
+
+        // 12 bit conversion
+        let millivolts = (raw * ADC_VREF_MILLIVOLTS * BATT_SENSE_DIVIDER / 4096) as u16;
+        if millivolts < MIN_PLAUSIBLE_MILLIVOLTS {
+            return None;
+        }
+        Some(millivolts)
+    }
+
+    pub fn charge_status(&mut self) -> ChargeStatus {
+        // ~CHRG is pulled low by the charger while charging
+        match self.charge_stat.is_low() {
+            Ok(true) => ChargeStatus::Charging,
+            _ => ChargeStatus::NotCharging,
+        }
+    }
+}
+
+/// Rough state of charge of a single-cell LiPo from its resting voltage, in percent.
+pub fn lipo_percent(millivolts: u16) -> u8 {
+    // (millivolts, percent), from a typical 1C discharge curve
+    const CURVE: [(u16, u8); 11] = [
+        (3300, 0),
+        (3500, 5),
+        (3600, 10),
+        (3680, 20),
+        (3740, 30),
+        (3780, 40),
+        (3820, 50),
+        (3870, 60),
+        (3950, 70),
+        (4060, 85),
+        (4180, 100),
+    ];
+
+    if millivolts <= CURVE[0].0 {
+        return 0;
+    }
+    for window in CURVE.windows(2) {
+        let (lo_mv, lo_pct) = window[0];
+        let (hi_mv, hi_pct) = window[1];
+        if millivolts <= hi_mv {
+            let t = (millivolts - lo_mv) as u32 * (hi_pct - lo_pct) as u32 / (hi_mv - lo_mv) as u32;
+            return lo_pct + t as u8;
+        }
+    }
+    100
+}
+
+/// GPIO number of [`ChargeStat`], used to set up the dormant wake-up.
+const CHARGE_STAT_GPIO: usize = 23;
+
+/// Value written to `XOSC.DORMANT` to stop the crystal oscillator ("coma").
+const XOSC_DORMANT_VALUE: u32 = 0x636f_6d61;
+
+/// Power down bits of the `PLL.PWR` register: `PD`, `DSMPD`, `POSTDIVPD` and `VCOPD`.
+const PLL_PWR_ALL_DOWN: u32 = 0b10_1101;
+
+/// Put the RP2040 in DORMANT mode, its lowest power state, until the charger starts charging,
+/// then reset.
+///
+/// This is meant to be the very last thing the firmware does when the battery runs flat, after it
+/// has turned off everything else it can. It runs the clocks from the crystal, turns off the PLLs
+/// and ring oscillator, and stops the crystal. The falling edge of `~CHRG` restarts the crystal, at
+/// which point we reset rather than try to restore the previous clock setup. If `~CHRG` isn't
+/// connected, only the power switch or the reset button will bring the board back.
+pub fn shutdown_until_charging() -> ! {
+    cortex_m::interrupt::disable();
+
+    // SAFETY: we never return, and nothing else runs with interrupts disabled, so no one else
+    // can observe the peripherals we take over here.
+    let p = unsafe { pac::Peripherals::steal() };
+
+    // run clk_ref from the crystal and clk_sys from clk_ref, so nothing depends on the PLLs
+    p.CLOCKS.clk_ref_ctrl.modify(|_, w| w.src().xosc_clksrc());
+    while p.CLOCKS.clk_ref_selected.read().bits() != 1 << 2 {}
+    p.CLOCKS.clk_sys_ctrl.modify(|_, w| w.src().clk_ref());
+    while p.CLOCKS.clk_sys_selected.read().bits() != 1 {}
+
+    p.CLOCKS.clk_usb_ctrl.modify(|_, w| w.enable().clear_bit());
+    p.CLOCKS.clk_adc_ctrl.modify(|_, w| w.enable().clear_bit());
+    p.CLOCKS.clk_peri_ctrl.modify(|_, w| w.enable().clear_bit());
+
+    p.PLL_SYS.pwr.write(|w| unsafe { w.bits(PLL_PWR_ALL_DOWN) });
+    p.PLL_USB.pwr.write(|w| unsafe { w.bits(PLL_PWR_ALL_DOWN) });
+    p.ROSC.ctrl.modify(|_, w| w.enable().disable());
+
+    // wake on the falling edge of ~CHRG. each DORMANT_WAKE_INTE register covers 8 gpios with 4
+    // event bits each: level low, level high, edge low, edge high.
+    const EDGE_LOW: usize = 2;
+    let bit = (CHARGE_STAT_GPIO % 8) * 4 + EDGE_LOW;
+    match CHARGE_STAT_GPIO / 8 {
+        0 => p.IO_BANK0.dormant_wake_inte0.write(|w| unsafe { w.bits(1 << bit) }),
+        1 => p.IO_BANK0.dormant_wake_inte1.write(|w| unsafe { w.bits(1 << bit) }),
+        2 => p.IO_BANK0.dormant_wake_inte2.write(|w| unsafe { w.bits(1 << bit) }),
+        _ => p.IO_BANK0.dormant_wake_inte3.write(|w| unsafe { w.bits(1 << bit) }),
+    }
+
+    p.XOSC.dormant.write(|w| unsafe { w.bits(XOSC_DORMANT_VALUE) });
+    while p.XOSC.status.read().stable().bit_is_clear() {}
+
+    cortex_m::peripheral::SCB::sys_reset();
+}
@@ -0,0 +1,222 @@
+//! Statically allocated buffers, handed out safely.
+//!
+//! Big buffers like framebuffers can't live on the stack, so they're `static`s. Rather than
+//! `static mut`, they go in a [`BufferCell`] or [`FramebufferPool`], which give out a [`Buffer`]
+//! token for each buffer at most once. The token is the only way to get at the buffer, so whoever
+//! holds it owns the buffer, and it can be passed around (or to the other core) to hand it over.
+//!
+//! Statics can be placed in a particular SRAM bank with the `.sram4` and `.sram5` sections from
+//! `memory.x`, for example to keep a buffer used by one core off the banks the other core is
+//! hammering. Those sections aren't initialised at startup, so a [`BufferCell`]'s taken flag would
+//! start out as whatever was in the RAM, and stay set through a reset. Instead the data goes there
+//! on its own in a [`BufferStorage`], and a [`PlacedBufferCell`] in the main RAM hands it out:
+//!
+//! ```ignore
+//! #[link_section = ".sram4"]
+//! static FILE_BUFFER_MEM: BufferStorage<[u8; 4096]> = BufferStorage::new([0; 4096]);
+//! static FILE_BUFFER: PlacedBufferCell<[u8; 4096]> = PlacedBufferCell::new(&FILE_BUFFER_MEM);
+//! ```
+//!
+//! Those banks are 4KiB each, so framebuffers go in the main, striped, RAM.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{Framebuffer, Rgb565BE, FULL_FRAMEBUFFER_SIZE, HALF_FRAMEBUFFER_SIZE};
+
+pub type FullFramebuffer = Framebuffer<Rgb565BE, FULL_FRAMEBUFFER_SIZE>;
+pub type HalfFramebuffer = Framebuffer<Rgb565BE, HALF_FRAMEBUFFER_SIZE>;
+
+/// Ownership of a statically allocated buffer. There's only ever one for each buffer.
+pub struct Buffer<T: 'static> {
+    data: &'static mut T,
+}
+
+impl<T> Buffer<T> {
+    /// Give up the token for a plain reference, which lasts forever.
+    #[inline(always)]
+    pub fn leak(self) -> &'static mut T {
+        self.data
+    }
+
+    /// Turn the token into a pointer, for sending it somewhere a `Buffer` can't go, like the
+    /// SIO FIFO to the other core.
+    #[inline(always)]
+    pub fn into_raw(self) -> *mut T {
+        self.data
+    }
+
+    /// Turn a pointer from [`Buffer::into_raw`] back into a token.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`Buffer::into_raw`], and not been turned back into a token
+    /// already.
+    #[inline(always)]
+    pub unsafe fn from_raw(ptr: *mut T) -> Self {
+        Self { data: &mut *ptr }
+    }
+}
+
+impl<T> Deref for Buffer<T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<T> DerefMut for Buffer<T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        self.data
+    }
+}
+
+/// One buffer in a `static` in the main RAM, which is initialised at startup.
+pub struct BufferCell<T> {
+    taken: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: the data is only reachable through the one `Buffer` handed out for it
+unsafe impl<T: Send> Sync for BufferCell<T> {}
+
+impl<T> BufferCell<T> {
+    pub const fn new(initial: T) -> Self {
+        Self {
+            taken: AtomicBool::new(false),
+            data: UnsafeCell::new(initial),
+        }
+    }
+
+    /// Take the buffer. Only the first call, from either core, gets it.
+    pub fn take(&'static self) -> Option<Buffer<T>> {
+        if !take_flag(&self.taken) {
+            return None;
+        }
+        // SAFETY: we just took the flag, so this is the only reference there will ever be
+        Some(Buffer { data: unsafe { &mut *self.data.get() } })
+    }
+}
+
+/// The data for a [`PlacedBufferCell`], kept apart from its taken flag so it can go in a section
+/// that isn't initialised at startup, like `.sram4` and `.sram5`.
+#[repr(transparent)]
+pub struct BufferStorage<T>(UnsafeCell<T>);
+
+// SAFETY: the data is only reachable through the one `Buffer` its `PlacedBufferCell` hands out
+unsafe impl<T: Send> Sync for BufferStorage<T> {}
+
+impl<T> BufferStorage<T> {
+    /// Nothing sets this up in sections that aren't initialised, so there `initial` is only for
+    /// the type's sake and the data starts out as whatever was in the RAM.
+    pub const fn new(initial: T) -> Self {
+        Self(UnsafeCell::new(initial))
+    }
+}
+
+/// One buffer in a [`BufferStorage`] `static`, with its taken flag in this one, which goes in the
+/// main RAM so it's cleared at startup, resets included.
+pub struct PlacedBufferCell<T: 'static> {
+    taken: AtomicBool,
+    data: &'static BufferStorage<T>,
+}
+
+impl<T> PlacedBufferCell<T> {
+    pub const fn new(data: &'static BufferStorage<T>) -> Self {
+        Self {
+            taken: AtomicBool::new(false),
+            data,
+        }
+    }
+
+    /// Take the buffer. Only the first call, from either core, gets it.
+    ///
+    /// There must only be one `PlacedBufferCell` for each [`BufferStorage`], or they could each
+    /// hand out the same buffer.
+    pub fn take(&'static self) -> Option<Buffer<T>> {
+        if !take_flag(&self.taken) {
+            return None;
+        }
+        // SAFETY: we just took the flag, so this is the only reference there will ever be
+        Some(Buffer { data: unsafe { &mut *self.data.0.get() } })
+    }
+}
+
+/// Front and back framebuffers, for drawing or decoding into one while the other is sent to the
+/// display, plus `SCRATCH` half-size scratch buffers.
+///
+/// Two full framebuffers take up 225KiB of the 264KiB of SRAM, so there's not much room for scratch
+/// buffers alongside them.
+pub struct FramebufferPool<const SCRATCH: usize> {
+    taken: AtomicBool,
+    front: UnsafeCell<FullFramebuffer>,
+    back: UnsafeCell<FullFramebuffer>,
+    scratch: [UnsafeCell<HalfFramebuffer>; SCRATCH],
+}
+
+// SAFETY: the buffers are only reachable through the one `Buffer` handed out for each
+unsafe impl<const SCRATCH: usize> Sync for FramebufferPool<SCRATCH> {}
+
+/// Everything in a [`FramebufferPool`].
+pub struct Framebuffers<const SCRATCH: usize> {
+    pub front: Buffer<FullFramebuffer>,
+    pub back: Buffer<FullFramebuffer>,
+    pub scratch: [Buffer<HalfFramebuffer>; SCRATCH],
+}
+
+impl<const SCRATCH: usize> Framebuffers<SCRATCH> {
+    /// Swap the front and back buffers, e.g. once the back one is ready to be shown.
+    #[inline(always)]
+    pub fn swap(&mut self) {
+        core::mem::swap(&mut self.front, &mut self.back);
+    }
+}
+
+impl<const SCRATCH: usize> FramebufferPool<SCRATCH> {
+    /// All buffers start out black.
+    pub const fn new() -> Self {
+        Self {
+            taken: AtomicBool::new(false),
+            front: UnsafeCell::new(Framebuffer::const_new(Rgb565BE::ZERO)),
+            back: UnsafeCell::new(Framebuffer::const_new(Rgb565BE::ZERO)),
+            scratch: [const { UnsafeCell::new(Framebuffer::const_new(Rgb565BE::ZERO)) }; SCRATCH],
+        }
+    }
+
+    /// Take all of the buffers. Only the first call, from either core, gets them.
+    pub fn take(&'static self) -> Option<Framebuffers<SCRATCH>> {
+        if !take_flag(&self.taken) {
+            return None;
+        }
+        // SAFETY: we just took the flag, so these are the only references there will ever be
+        unsafe {
+            Some(Framebuffers {
+                front: Buffer { data: &mut *self.front.get() },
+                back: Buffer { data: &mut *self.back.get() },
+                scratch: core::array::from_fn(|i| Buffer { data: &mut *self.scratch[i].get() }),
+            })
+        }
+    }
+}
+
+impl<const SCRATCH: usize> Default for FramebufferPool<SCRATCH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Set `flag`, returning whether it wasn't set already. The M0+ has no compare and swap, so this
+/// uses a critical section instead, which on the RP2040 also excludes the other core.
+fn take_flag(flag: &AtomicBool) -> bool {
+    critical_section::with(|_| {
+        if flag.load(Ordering::Relaxed) {
+            return false;
+        }
+        flag.store(true, Ordering::Relaxed);
+        true
+    })
+}
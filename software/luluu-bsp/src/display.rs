@@ -0,0 +1,417 @@
+//! The display's side of the shared SPI bus.
+//!
+//! [`DisplayBus`] owns the display's chip select and data/command pins. It hands out a
+//! [`DisplayInterface`] for `mipidsi` to initialize and configure the display with, and can also
+//! write pixels itself with [`DisplayBus::write_pixels_with`], which uses DMA so the CPU can work on
+//! something else while they're sent, or [`DisplayBus::write_lines`], which has the CPU generate
+//! each line while the one before it is sent.
+//!
+//! Every write holds the [`SharedSpi`] for its duration, so the other core can't use the bus (or
+//! take a critical section) until it's done.
+
+use core::cell::{Cell, RefCell};
+
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+use crate::hal::fugit::HertzU32;
+use crate::spi_dma::{DmaSpiBus, SharedSpi};
+use crate::{DispCsMain, DispDataCmd};
+
+/// MIPI DCS commands we send ourselves.
+pub(crate) mod dcs {
+    pub const EXIT_INVERT_MODE: u8 = 0x20;
+    pub const ENTER_INVERT_MODE: u8 = 0x21;
+    pub const SET_COLUMN_ADDRESS: u8 = 0x2a;
+    pub const SET_PAGE_ADDRESS: u8 = 0x2b;
+    pub const WRITE_MEMORY_START: u8 = 0x2c;
+    pub const SET_ADDRESS_MODE: u8 = 0x36;
+}
+
+/// MADCTL bits.
+mod madctl {
+    pub const MY: u8 = 0x80;
+    pub const MX: u8 = 0x40;
+    pub const MV: u8 = 0x20;
+}
+
+/// The controller has memory for 320 rows, and the panel shows the first 240. Anything that
+/// reverses the row order has to skip the rest.
+const HIDDEN_ROWS: u16 = 80;
+
+/// The longest line [`DisplayBus::write_lines`] can send, a whole row of the panel.
+pub const MAX_LINE_PIXELS: usize = 240;
+
+/// Iterator data is converted to bytes in chunks of this many bytes before being sent.
+const ITER_CHUNK_SIZE: usize = 64;
+
+/// Turning the image on the panel, clockwise in quarter turns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Rotation {
+    #[default]
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+impl Rotation {
+    pub const fn from_quarter_turns(turns: u8) -> Self {
+        match turns % 4 {
+            0 => Self::Deg0,
+            1 => Self::Deg90,
+            2 => Self::Deg180,
+            _ => Self::Deg270,
+        }
+    }
+
+    pub const fn quarter_turns(self) -> u8 {
+        self as u8
+    }
+
+    pub const fn from_degrees(degrees: u16) -> Option<Self> {
+        match degrees {
+            0 => Some(Self::Deg0),
+            90 => Some(Self::Deg90),
+            180 => Some(Self::Deg180),
+            270 => Some(Self::Deg270),
+            _ => None,
+        }
+    }
+
+    pub const fn degrees(self) -> u16 {
+        self as u16 * 90
+    }
+
+    /// This rotation followed by `other`.
+    pub const fn then(self, other: Self) -> Self {
+        Self::from_quarter_turns(self.quarter_turns() + other.quarter_turns())
+    }
+}
+
+/// Which way round the image goes on the panel. The controller applies it as it takes pixels in,
+/// so it costs nothing per pixel, and everything is still drawn in the same coordinates.
+///
+/// Mirroring is of the panel as it's mounted, before the rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Orientation {
+    pub rotation: Rotation,
+    /// Flip left and right.
+    pub mirror_x: bool,
+    /// Flip top and bottom.
+    pub mirror_y: bool,
+}
+
+impl Orientation {
+    /// How the display comes up, the way `mipidsi` initializes it.
+    pub const DEFAULT: Self = Self {
+        rotation: Rotation::Deg0,
+        mirror_x: false,
+        mirror_y: false,
+    };
+
+    /// The same, turned further by `rotation`.
+    pub const fn rotated(self, rotation: Rotation) -> Self {
+        Self {
+            rotation: self.rotation.then(rotation),
+            ..self
+        }
+    }
+
+    /// The address order bits of the MADCTL (memory data access control) register.
+    pub const fn madctl(self) -> u8 {
+        let mut bits = match self.rotation {
+            Rotation::Deg0 => 0,
+            Rotation::Deg90 => madctl::MX | madctl::MV,
+            Rotation::Deg180 => madctl::MX | madctl::MY,
+            Rotation::Deg270 => madctl::MY | madctl::MV,
+        };
+        if self.mirror_x {
+            bits ^= madctl::MX;
+        }
+        if self.mirror_y {
+            bits ^= madctl::MY;
+        }
+        bits
+    }
+
+    /// What to add to window coordinates to land on the part of the controller's memory that the
+    /// panel shows.
+    pub const fn window_offset(self) -> (u16, u16) {
+        let bits = self.madctl();
+        match (bits & madctl::MY != 0, bits & madctl::MV != 0) {
+            (false, _) => (0, 0),
+            (true, false) => (0, HIDDEN_ROWS),
+            // rows and columns are swapped, so rows run along x
+            (true, true) => (HIDDEN_ROWS, 0),
+        }
+    }
+}
+
+struct Pins {
+    cs: DispCsMain,
+    dc: DispDataCmd,
+}
+
+pub struct DisplayBus<'a> {
+    spi: &'a SharedSpi,
+    pins: RefCell<Pins>,
+    baudrate: Cell<HertzU32>,
+    orientation: Cell<Orientation>,
+}
+
+impl<'a> DisplayBus<'a> {
+    pub fn new(spi: &'a SharedSpi, mut cs: DispCsMain, dc: DispDataCmd, baudrate: HertzU32) -> Self {
+        let _ = cs.set_high();
+        Self {
+            spi,
+            pins: RefCell::new(Pins { cs, dc }),
+            baudrate: Cell::new(baudrate),
+            orientation: Cell::new(Orientation::DEFAULT),
+        }
+    }
+
+    /// Takes effect from the next write.
+    pub fn set_baudrate(&self, baudrate: HertzU32) {
+        self.baudrate.set(baudrate);
+    }
+
+    /// Only changes how pixels written from now on are placed, what's on the display already stays
+    /// where it is until it's written again.
+    pub fn set_orientation(&self, orientation: Orientation) -> Result<(), DisplayError> {
+        self.command(dcs::SET_ADDRESS_MODE, &[orientation.madctl()])?;
+        self.orientation.set(orientation);
+        Ok(())
+    }
+
+    /// For panels that need their colours inverted to show them the right way round, or don't
+    /// after all. Applies to what's on the display already too.
+    pub fn set_invert_colors(&self, invert: bool) -> Result<(), DisplayError> {
+        self.command(if invert { dcs::ENTER_INVERT_MODE } else { dcs::EXIT_INVERT_MODE }, &[])
+    }
+
+    /// An interface for display drivers to send commands and data through.
+    pub fn interface(&self) -> DisplayInterface<'_, 'a> {
+        DisplayInterface { bus: self }
+    }
+
+    /// Write RGB565 big-endian `pixels` to the rectangle from `(sx, sy)` to `(ex, ey)` inclusive,
+    /// running `during` while they're sent.
+    ///
+    /// `during` runs inside the critical section holding the bus, so it can't use the bus and
+    /// interrupts are off until it returns.
+    pub fn write_pixels_with<R>(
+        &self,
+        sx: u16,
+        sy: u16,
+        ex: u16,
+        ey: u16,
+        pixels: &[u8],
+        during: impl FnOnce() -> R,
+    ) -> Result<R, DisplayError> {
+        self.set_window(sx, sy, ex, ey)?;
+        self.with_pixel_data(|spi| spi.write_with(pixels, during))
+    }
+
+    /// Write the rectangle from `(sx, sy)` to `(ex, ey)` inclusive out of `fb`, a whole framebuffer
+    /// of RGB565 big-endian pixels that's `width` pixels wide. Used to update only the part of the
+    /// display that's changed.
+    pub fn write_region(
+        &self,
+        sx: u16,
+        sy: u16,
+        ex: u16,
+        ey: u16,
+        fb: &[u8],
+        width: usize,
+    ) -> Result<(), DisplayError> {
+        let row_bytes = width * 2;
+        let rows = &fb[sy as usize * row_bytes..(ey as usize + 1) * row_bytes];
+
+        // full rows are already one contiguous run of pixels
+        if sx == 0 && ex as usize == width - 1 {
+            return self.write_pixels_with(sx, sy, ex, ey, rows, || ());
+        }
+
+        self.set_window(sx, sy, ex, ey)?;
+        self.with_pixel_data(|spi| {
+            for row in rows.chunks_exact(row_bytes) {
+                let _ = spi.write(&row[sx as usize * 2..(ex as usize + 1) * 2]);
+            }
+            let _ = spi.flush();
+        })
+    }
+
+    /// Write the rectangle from `(sx, sy)` to `(ex, ey)` inclusive one line at a time, for pixels
+    /// that are generated as they're sent. `fill` is given each line's y and a buffer to put its
+    /// RGB565 big-endian pixels in, and is called for the next line while the one before is sent.
+    ///
+    /// Lines can be up to [`MAX_LINE_PIXELS`] long. Like `during` in
+    /// [`DisplayBus::write_pixels_with`], `fill` runs with the bus held.
+    pub fn write_lines(
+        &self,
+        sx: u16,
+        sy: u16,
+        ex: u16,
+        ey: u16,
+        mut fill: impl FnMut(u16, &mut [u8]),
+    ) -> Result<(), DisplayError> {
+        let line_bytes = (ex - sx + 1) as usize * 2;
+        let mut lines = [[0u8; MAX_LINE_PIXELS * 2]; 2];
+        let [first, second] = &mut lines;
+        let mut sending = &mut first[..line_bytes];
+        let mut filling = &mut second[..line_bytes];
+
+        self.set_window(sx, sy, ex, ey)?;
+        self.with_pixel_data(|spi| {
+            fill(sy, &mut *sending);
+            for y in sy..=ey {
+                spi.write_with(sending, || {
+                    if y < ey {
+                        fill(y + 1, &mut *filling);
+                    }
+                });
+                core::mem::swap(&mut sending, &mut filling);
+            }
+        })
+    }
+
+    fn set_window(&self, sx: u16, sy: u16, ex: u16, ey: u16) -> Result<(), DisplayError> {
+        let orientation = self.orientation.get();
+        set_window(sx, sy, ex, ey, orientation, |command, params| self.command(command, params))
+    }
+
+    /// Run `f` with the bus held and the display taking pixel data. The display keeps filling the
+    /// window for as long as chip select stays low, so `f` can send the pixels in as many writes
+    /// as it likes.
+    fn with_pixel_data<R>(&self, f: impl FnOnce(&mut DmaSpiBus) -> R) -> Result<R, DisplayError> {
+        critical_section::with(|cs| {
+            let mut spi = self.spi.borrow_ref_mut(cs);
+            spi.use_baudrate(self.baudrate.get());
+            let mut pins = self.pins.borrow_mut();
+            pins.dc.set_high().map_err(|_| DisplayError::DCError)?;
+            pins.cs.set_low().map_err(|_| DisplayError::CSError)?;
+            let result = f(&mut spi);
+            pins.cs.set_high().map_err(|_| DisplayError::CSError)?;
+
+            Ok(result)
+        })
+    }
+
+    fn command(&self, command: u8, params: &[u8]) -> Result<(), DisplayError> {
+        self.send(false, &[command])?;
+        if !params.is_empty() {
+            self.send(true, params)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, data: bool, bytes: &[u8]) -> Result<(), DisplayError> {
+        critical_section::with(|cs| {
+            let mut spi = self.spi.borrow_ref_mut(cs);
+            spi.use_baudrate(self.baudrate.get());
+            let mut pins = self.pins.borrow_mut();
+
+            if data {
+                pins.dc.set_high().map_err(|_| DisplayError::DCError)?;
+            } else {
+                pins.dc.set_low().map_err(|_| DisplayError::DCError)?;
+            }
+            pins.cs.set_low().map_err(|_| DisplayError::CSError)?;
+            let result = spi.write(bytes).and_then(|_| spi.flush());
+            pins.cs.set_high().map_err(|_| DisplayError::CSError)?;
+
+            result.map_err(|_| DisplayError::BusWriteError)
+        })
+    }
+}
+
+/// Send the commands that make the rectangle from `(sx, sy)` to `(ex, ey)` inclusive the display's
+/// window, ready for pixel data, with `command`. The display is in `orientation`.
+pub(crate) fn set_window(
+    sx: u16,
+    sy: u16,
+    ex: u16,
+    ey: u16,
+    orientation: Orientation,
+    mut command: impl FnMut(u8, &[u8]) -> Result<(), DisplayError>,
+) -> Result<(), DisplayError> {
+    let (x_offset, y_offset) = orientation.window_offset();
+    let (sx, ex) = (sx + x_offset, ex + x_offset);
+    let (sy, ey) = (sy + y_offset, ey + y_offset);
+    let [sx_hi, sx_lo] = sx.to_be_bytes();
+    let [ex_hi, ex_lo] = ex.to_be_bytes();
+    let [sy_hi, sy_lo] = sy.to_be_bytes();
+    let [ey_hi, ey_lo] = ey.to_be_bytes();
+
+    command(dcs::SET_COLUMN_ADDRESS, &[sx_hi, sx_lo, ex_hi, ex_lo])?;
+    command(dcs::SET_PAGE_ADDRESS, &[sy_hi, sy_lo, ey_hi, ey_lo])?;
+    command(dcs::WRITE_MEMORY_START, &[])
+}
+
+/// Turn `format` into bytes and pass them to `send`, in as many pieces as it takes.
+pub(crate) fn send_format(
+    format: DataFormat<'_>,
+    mut send: impl FnMut(&[u8]) -> Result<(), DisplayError>,
+) -> Result<(), DisplayError> {
+    match format {
+        DataFormat::U8(bytes) => send(bytes),
+        DataFormat::U16(words) => send(bytemuck::cast_slice(words)),
+        DataFormat::U16BE(words) => {
+            for word in words.iter_mut() {
+                *word = word.to_be();
+            }
+            send(bytemuck::cast_slice(&*words))
+        }
+        DataFormat::U16LE(words) => {
+            for word in words.iter_mut() {
+                *word = word.to_le();
+            }
+            send(bytemuck::cast_slice(&*words))
+        }
+        DataFormat::U8Iter(iter) => send_iter(iter.map(|b| [b, 0]), 1, send),
+        DataFormat::U16BEIter(iter) => send_iter(iter.map(u16::to_be_bytes), 2, send),
+        DataFormat::U16LEIter(iter) => send_iter(iter.map(u16::to_le_bytes), 2, send),
+        _ => Err(DisplayError::DataFormatNotImplemented),
+    }
+}
+
+/// Send the first `width` bytes of each item.
+fn send_iter(
+    iter: impl Iterator<Item = [u8; 2]>,
+    width: usize,
+    mut send: impl FnMut(&[u8]) -> Result<(), DisplayError>,
+) -> Result<(), DisplayError> {
+    let mut chunk = [0u8; ITER_CHUNK_SIZE];
+    let mut len = 0;
+    for item in iter {
+        chunk[len..len + width].copy_from_slice(&item[..width]);
+        len += width;
+        if len == ITER_CHUNK_SIZE {
+            send(&chunk)?;
+            len = 0;
+        }
+    }
+    if len > 0 {
+        send(&chunk[..len])?;
+    }
+    Ok(())
+}
+
+/// A [`DisplayBus`] as a [`WriteOnlyDataCommand`].
+pub struct DisplayInterface<'b, 'a> {
+    bus: &'b DisplayBus<'a>,
+}
+
+impl WriteOnlyDataCommand for DisplayInterface<'_, '_> {
+    fn send_commands(&mut self, cmd: DataFormat<'_>) -> Result<(), DisplayError> {
+        send_format(cmd, |bytes| self.bus.send(false, bytes))
+    }
+
+    fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), DisplayError> {
+        send_format(buf, |bytes| self.bus.send(true, bytes))
+    }
+}
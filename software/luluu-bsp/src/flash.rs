@@ -0,0 +1,213 @@
+//! The part of the W25Q128's 16MiB of flash past the 2MiB the firmware lives in, the `NVM` region
+//! in `memory.x`, for keeping things in that aren't part of the firmware.
+//!
+//! It's read like any other memory, through XIP, see [`storage`]. Writing to it means taking the
+//! flash out of XIP mode while it's [`erase`]d or [`program`]med, so nothing else can run from
+//! flash or read it meanwhile: interrupts are turned off, and the code doing it runs from RAM, but
+//! core 1 has to be kept out of the way by the caller.
+//!
+//! New firmware is put in the storage first, then [`install`]ed over the old.
+
+use crate::hal::rom_data;
+use crate::pac;
+
+/// The flash the firmware's in, from the start.
+pub const FIRMWARE_LEN: usize = 2 * 1024 * 1024;
+
+/// Where the storage starts in the flash, after the firmware.
+pub const STORAGE_OFFSET: u32 = FIRMWARE_LEN as u32;
+
+pub const STORAGE_LEN: usize = 14 * 1024 * 1024;
+
+/// The smallest part of the flash that can be erased.
+pub const SECTOR_SIZE: usize = 4096;
+
+/// The most that can be programmed at once, and what programming has to be lined up with.
+pub const PAGE_SIZE: usize = 256;
+
+/// Where the flash shows up through XIP.
+const XIP_BASE: usize = 0x1000_0000;
+
+const SECTOR_ERASE: u8 = 0x20;
+
+/// The storage, as it's read through XIP.
+pub fn storage() -> &'static [u8] {
+    // SAFETY: the whole flash is mapped through XIP, and this part is nothing else's
+    unsafe { core::slice::from_raw_parts((XIP_BASE + STORAGE_OFFSET as usize) as *const u8, STORAGE_LEN) }
+}
+
+/// Erase the sectors from `offset` into the storage on, for `len` bytes, leaving them all `0xff`.
+/// `offset` and `len` are rounded out to whole sectors.
+///
+/// # Safety
+///
+/// Core 1 can't be running, or it could be running from flash when it's taken away.
+pub unsafe fn erase(offset: usize, len: usize) {
+    let start = offset / SECTOR_SIZE * SECTOR_SIZE;
+    let end = (offset + len).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+    assert!(end <= STORAGE_LEN);
+    run(Operation::Erase { offset: STORAGE_OFFSET + start as u32, len: end - start });
+}
+
+/// Program `bytes`, which are whole pages, into the storage from `offset` on, which has to be the
+/// start of a page. The flash can only clear bits, so the pages have to have been [`erase`]d
+/// first.
+///
+/// # Safety
+///
+/// Core 1 can't be running, like for [`erase`]. `bytes` can't be in flash either, since it's out
+/// of reach while they're programmed.
+pub unsafe fn program(offset: usize, bytes: &[u8]) {
+    assert!(offset.is_multiple_of(PAGE_SIZE) && bytes.len().is_multiple_of(PAGE_SIZE));
+    assert!(offset + bytes.len() <= STORAGE_LEN);
+    run(Operation::Program { offset: STORAGE_OFFSET + offset as u32, bytes: bytes.as_ptr(), len: bytes.len() });
+}
+
+/// Erase the sectors from `offset` on, which has to be the start of one, for `bytes`, then program
+/// `bytes` into them. What's left of the last page is `0xff`.
+///
+/// # Safety
+///
+/// Like for [`program`].
+pub unsafe fn write(offset: usize, bytes: &[u8]) {
+    assert!(offset.is_multiple_of(SECTOR_SIZE));
+    erase(offset, bytes.len());
+    let whole_pages = bytes.len() / PAGE_SIZE * PAGE_SIZE;
+    if whole_pages > 0 {
+        program(offset, &bytes[..whole_pages]);
+    }
+    let rest = &bytes[whole_pages..];
+    if !rest.is_empty() {
+        let mut page = [0xff; PAGE_SIZE];
+        page[..rest.len()].copy_from_slice(rest);
+        program(offset + whole_pages, &page);
+    }
+}
+
+/// Copy the `len` bytes from `offset` into the storage over the firmware, then reset the chip into
+/// it. The watchdog's stopped, since it can't be fed meanwhile.
+///
+/// The first sector, with the second stage bootloader in, is erased first and written last. If the
+/// power goes part way through, the boot ROM finds no bootloader and shows up as a USB drive for
+/// firmware to be copied onto, like with BOOTSEL held down, rather than starting half of it.
+///
+/// # Safety
+///
+/// Core 1 can't be running, like for [`erase`], and what's copied has to be firmware.
+pub unsafe fn install(offset: usize, len: usize) -> ! {
+    assert!(offset.is_multiple_of(SECTOR_SIZE) && len > 0 && len <= FIRMWARE_LEN && offset + len <= STORAGE_LEN);
+    let rom = Rom::lookup();
+    let mut boot2 = [0u32; 64];
+    core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), boot2.len());
+    (*pac::WATCHDOG::ptr()).ctrl.modify(|_, w| w.enable().clear_bit());
+    let mut sector = [0u32; SECTOR_SIZE / 4];
+    cortex_m::interrupt::disable();
+    install_from_ram(
+        &rom,
+        boot2.as_ptr(),
+        STORAGE_OFFSET + offset as u32,
+        len.div_ceil(SECTOR_SIZE) as u32,
+        sector.as_mut_ptr(),
+    )
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Erase { offset: u32, len: usize },
+    Program { offset: u32, bytes: *const u8, len: usize },
+}
+
+/// The boot ROM's flash functions, see section 2.8.3.1.3 of the RP2040 datasheet.
+struct Rom {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+impl Rom {
+    /// The hal's lookup runs from the flash, so this has to be done before it's taken out of XIP.
+    fn lookup() -> Self {
+        Self {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        }
+    }
+}
+
+unsafe fn run(operation: Operation) {
+    let rom = Rom::lookup();
+    // the second stage bootloader sets XIP up again afterwards, much faster than the ROM's
+    // `flash_enter_cmd_xip` would. it's the first 256 bytes of the flash, and has to be copied out
+    // of it first.
+    let mut boot2 = [0u32; 64];
+    core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), boot2.len());
+    cortex_m::interrupt::free(|_| run_from_ram(&rom, operation, boot2.as_ptr()));
+}
+
+/// Everything this calls is in the ROM or RAM, since the flash isn't there while it runs.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn run_from_ram(rom: &Rom, operation: Operation, boot2: *const u32) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    match operation {
+        Operation::Erase { offset, len } => (rom.flash_range_erase)(offset, len, SECTOR_SIZE as u32, SECTOR_ERASE),
+        Operation::Program { offset, bytes, len } => (rom.flash_range_program)(offset, bytes, len),
+    }
+    (rom.flash_flush_cache)();
+    // it's Thumb code
+    let enter_xip: unsafe extern "C" fn() = core::mem::transmute(boot2 as usize + 1);
+    enter_xip();
+}
+
+/// Copy `n_sectors` sectors from `from` in the flash to its start, a sector at a time through
+/// `sector` in RAM, then reset. The firmware's gone as soon as this starts, so like
+/// [`run_from_ram`] it can only call the ROM, and it can't panic either: the arithmetic wraps.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn install_from_ram(rom: &Rom, boot2: *const u32, from: u32, n_sectors: u32, sector: *mut u32) -> ! {
+    let enter_xip: unsafe extern "C" fn() = core::mem::transmute(boot2 as usize + 1);
+
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(0, SECTOR_SIZE, SECTOR_SIZE as u32, SECTOR_ERASE);
+    (rom.flash_flush_cache)();
+    enter_xip();
+
+    let mut idx: u32 = 1;
+    loop {
+        // the first sector goes last
+        let target = if idx >= n_sectors { 0 } else { idx };
+        let offset = target.wrapping_mul(SECTOR_SIZE as u32);
+        // read through XIP, before it's taken away
+        let source = (XIP_BASE as u32).wrapping_add(from).wrapping_add(offset) as *const u32;
+        let mut word = 0;
+        while word < SECTOR_SIZE / 4 {
+            sector.wrapping_add(word).write_volatile(source.wrapping_add(word).read_volatile());
+            word = word.wrapping_add(1);
+        }
+
+        (rom.connect_internal_flash)();
+        (rom.flash_exit_xip)();
+        (rom.flash_range_erase)(offset, SECTOR_SIZE, SECTOR_SIZE as u32, SECTOR_ERASE);
+        (rom.flash_range_program)(offset, sector as *const u8, SECTOR_SIZE);
+        (rom.flash_flush_cache)();
+        enter_xip();
+
+        if target == 0 {
+            break;
+        }
+        idx = idx.wrapping_add(1);
+    }
+
+    // SYSRESETREQ in the AIRCR, like `SCB::sys_reset`, which is in flash
+    (0xe000_ed0c as *mut u32).write_volatile(0x05fa_0004);
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -1,8 +1,12 @@
 #![no_std]
 
+use core::convert::Infallible;
 use core::mem::MaybeUninit;
 
-use bytemuck::{NoUninit, Zeroable};
+use bytemuck::{AnyBitPattern, NoUninit, Zeroable};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::{Dimensions, DrawTarget, OriginDimensions, Pixel, Size};
+use embedded_graphics::primitives::Rectangle;
 use embedded_sdmmc::{TimeSource, Timestamp};
 use hal::rosc::{RingOscillator, Enabled};
 use rand_core::RngCore;
@@ -17,6 +21,19 @@ pub use luluu_enc;
 
 pub use luluu_enc::{Rgb565BE, Rgb565NE, Rgb888};
 
+pub mod accel;
+pub mod backlight;
+pub mod buffers;
+#[cfg(feature = "battery-sense")]
+pub mod battery;
+pub mod display;
+pub mod flash;
+pub mod pio_display;
+pub mod rtc;
+pub mod spi_dma;
+pub mod uart;
+pub mod usb;
+
 /// The linker will place this boot block at the start of our program image. We
 /// need this to help the ROM bootloader get our code up and running.
 #[cfg(feature = "boot2")]
@@ -58,6 +75,16 @@ pub type DispBacklightToggle = Pin<Gpio22, FunctionSioOutput, PullUp>;
 
 pub type DispBacklightPwm = Pin<Gpio22, FunctionPwm, PullUp>;
 
+#[cfg(feature = "battery-sense")]
+pub type ChargeStat = Pin<Gpio23, FunctionSioInput, PullUp>;
+
+#[cfg(feature = "battery-sense")]
+pub type BattSense = Pin<Gpio29, hal::gpio::FunctionNull, PullNone>;
+
+/// The pins as they're connected on Rev 1.1 of the board.
+///
+/// Rev 1.1 has no push buttons. Rev 2.0 has SW_A and SW_B, but on GPIO1 and GPIO5, which Rev 1.1
+/// uses for UART Rx and the card's chip select, so they aren't here.
 pub struct Pins {
     /// UART Tx pin
     pub uart_tx: UartTx,
@@ -102,7 +129,17 @@ pub struct Pins {
     /// ```ignore
     /// let bl_pin: DispBacklightPwm = pins.disp_backlight.reconfigure();
     /// ```
+    ///
+    /// or handed to [`backlight::Backlight`] for gamma corrected brightness control and fades.
     pub disp_backlight: DispBacklightToggle,
+
+    /// Battery charger status (active low) pin. See [`battery`] for wiring.
+    #[cfg(feature = "battery-sense")]
+    pub charge_stat: ChargeStat,
+
+    /// Battery voltage sense (ADC3) pin. See [`battery`] for wiring.
+    #[cfg(feature = "battery-sense")]
+    pub batt_sense: BattSense,
 }
 
 impl Pins {
@@ -127,6 +164,10 @@ impl Pins {
             disp_data_cmd: pins.gpio18.reconfigure(),
             disp_cs_main: pins.gpio19.reconfigure(),
             disp_backlight: pins.gpio22.reconfigure(),
+            #[cfg(feature = "battery-sense")]
+            charge_stat: pins.gpio23.reconfigure(),
+            #[cfg(feature = "battery-sense")]
+            batt_sense: pins.gpio29.reconfigure(),
         }
     }
 }
@@ -134,8 +175,8 @@ impl Pins {
 /// Layout of Spi pins.
 pub type SpiPinLayout = (SpiMosi, SpiMiso, SpiClock);
 
-/// A dummy timesource, which is mostly important for creating files. Since we have no real-time
-/// clock, just dummy it to the beginning of 2023.
+/// A dummy timesource, which is mostly important for creating files. It's always the beginning of
+/// 2023. [`rtc::RtcTimeSource`] falls back to it until the real-time clock has been set.
 #[derive(Default)]
 pub struct DummyTimesource;
 
@@ -152,42 +193,6 @@ impl TimeSource for DummyTimesource {
     }
 }
 
-#[macro_export]
-macro_rules! singleton {
-    {
-        $name:ident {
-            $(#[$staticattr:meta])*
-            static mut $staticname:ident: $t:ty = $initializer:expr;
-        }
-    } => {
-        $(#[$staticattr])*
-        static mut $staticname: $t = $initializer;
-        struct $name(&'static mut $t);
-
-        impl Deref for $name {
-            type Target = $t;
-
-            #[inline(always)]
-            fn deref(&self) -> &Self::Target {
-                self.0
-            }
-        }
-
-        impl DerefMut for $name {
-            #[inline(always)]
-            fn deref_mut(&mut self) -> &mut Self::Target {
-                self.0
-            }
-        }
-
-        impl $name {
-            pub unsafe fn acquire() -> Self {
-                Self(unsafe { &mut $staticname })
-            }
-        }
-    };
-}
-
 /// Generate a random u32
 pub fn gen_rand_u32(rosc: &mut RingOscillator<Enabled>) -> u32 {
     RngCore::next_u32(rosc)
@@ -206,8 +211,9 @@ pub const FULL_FRAMEBUFFER_SIZE: usize = 240 * 240;
 /// The number of pixels in a half-size framebuffer
 pub const HALF_FRAMEBUFFER_SIZE: usize = 120 * 120;
 
+/// Word aligned, so DMA can read pixels out of it more than a byte at a time.
 #[derive(Clone, Copy)]
-#[repr(transparent)]
+#[repr(C, align(4))]
 pub struct Framebuffer<P, const N: usize = FULL_FRAMEBUFFER_SIZE> {
     data: [P; N],
 }
@@ -237,6 +243,18 @@ impl<P: NoUninit, const N: usize> Framebuffer<P, N> {
     }
 }
 
+impl<P: NoUninit + AnyBitPattern, const N: usize> Framebuffer<P, N> {
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: P has no uninit, any bytes are a valid P, and it's tightly packed
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.data.as_mut_ptr().cast(),
+                self.data.len() * core::mem::size_of::<P>()
+            )
+        }
+    }
+}
+
 impl<P, const N: usize> Framebuffer<P, N> {
     #[inline(always)]
     pub const fn pixels(&self) -> &[P; N] {
@@ -248,3 +266,65 @@ impl<P, const N: usize> Framebuffer<P, N> {
         &mut self.data
     }
 }
+
+impl<P, const N: usize> Framebuffer<P, N> {
+    /// Width and height of the square of pixels the framebuffer holds.
+    pub const SIDE: usize = square_side(N);
+}
+
+/// The side of the largest square with at most `n` pixels.
+const fn square_side(n: usize) -> usize {
+    let mut side = 0;
+    while (side + 1) * (side + 1) <= n {
+        side += 1;
+    }
+    side
+}
+
+/// Draws into the framebuffer as a [`Framebuffer::SIDE`] pixel square. To draw over a frame smaller
+/// than that, draw it at full size first.
+impl<const N: usize> DrawTarget for Framebuffer<Rgb565BE, N> {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let side = Self::SIDE;
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+                continue;
+            };
+            if x < side && y < side {
+                self.data[y * side + x] = color.into();
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        if area.is_zero_sized() {
+            return Ok(());
+        }
+        let side = Self::SIDE;
+        let (x, width) = (area.top_left.x as usize, area.size.width as usize);
+        for y in area.rows() {
+            let start = y as usize * side + x;
+            self.data[start..start + width].fill(color.into());
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.data[..Self::SIDE * Self::SIDE].fill(color.into());
+        Ok(())
+    }
+}
+
+impl<const N: usize> OriginDimensions for Framebuffer<Rgb565BE, N> {
+    fn size(&self) -> Size {
+        Size::new_equal(Self::SIDE as u32)
+    }
+}
@@ -0,0 +1,466 @@
+//! The display's side of the shared SPI bus, driven by PIO instead of the SPI peripheral.
+//!
+//! [`PioDisplayBus`] does the same job as [`DisplayBus`](crate::display::DisplayBus), but clocks the
+//! bits out with a PIO state machine. The program it runs can send each 16 bit pixel it's given
+//! 2 or 4 times in a row, so a frame that's a half or quarter of the display's width can be
+//! upscaled horizontally on the wire with [`PioDisplayBus::write_region_scaled`], straight out of
+//! the framebuffer. Only a half or quarter of the pixels are read out of RAM, and the CPU doesn't
+//! touch them at all.
+//!
+//! The display and SD card share their clock and data pins, which belong to the SPI peripheral.
+//! While a write holds the [`SharedSpi`], the pins are switched over to PIO0 so the state machine
+//! can drive them, and switched back before it's let go, so the SD card never notices.
+
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+use embedded_hal::digital::OutputPin;
+
+use crate::display::{dcs, send_format, set_window, Orientation};
+use crate::hal::dma::{Channel, SingleChannel, CH2};
+use crate::hal::fugit::HertzU32;
+use crate::hal::pio::{
+    Buffers, PinDir, Running, ShiftDirection, StateMachine, Tx, UninitStateMachine, PIO, SM0,
+};
+use crate::spi_dma::SharedSpi;
+use crate::{pac, DispCsMain, DispDataCmd};
+
+/// The shared bus's pins, which the state machine borrows.
+const CLOCK_PIN: u8 = 14;
+const DATA_PIN: u8 = 15;
+
+/// GPIO function select values for the shared bus's pins.
+const FUNCSEL_SPI: u8 = 1;
+const FUNCSEL_PIO0: u8 = 6;
+
+/// DMA request signal paced by PIO0 SM0's TX FIFO.
+const DREQ_PIO0_TX0: u8 = 0;
+
+/// How many times each pixel is repeated on the wire, for upscaling frames smaller than the
+/// display as they're sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Replicate {
+    X1,
+    X2,
+    X4,
+}
+
+impl Replicate {
+    /// For a frame `scale` times smaller than the display, if it's a scale we can do.
+    pub fn from_scale(scale: usize) -> Option<Self> {
+        match scale {
+            1 => Some(Self::X1),
+            2 => Some(Self::X2),
+            4 => Some(Self::X4),
+            _ => None,
+        }
+    }
+
+    pub fn scale(self) -> usize {
+        match self {
+            Self::X1 => 1,
+            Self::X2 => 2,
+            Self::X4 => 4,
+        }
+    }
+}
+
+/// What the state machine is set up to send.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// One byte per FIFO word, sent once. Commands, parameters and unscaled pixels.
+    Bytes,
+    /// One pixel per FIFO word, sent the given number of times.
+    Pixels(Replicate),
+}
+
+struct Inner {
+    _sm: StateMachine<(pac::PIO0, SM0), Running>,
+    _tx: Tx<(pac::PIO0, SM0)>,
+    dma: Channel<CH2>,
+    cs: DispCsMain,
+    dc: DispDataCmd,
+    /// Where the program starts, which is where it waits for the next word.
+    offset: u8,
+    mode: Mode,
+    baudrate: HertzU32,
+}
+
+pub struct PioDisplayBus<'a> {
+    spi: &'a SharedSpi,
+    inner: RefCell<Inner>,
+    sys_frequency: HertzU32,
+    baudrate: Cell<HertzU32>,
+    orientation: Cell<Orientation>,
+}
+
+impl<'a> PioDisplayBus<'a> {
+    /// Install the program in `pio` and run it on `sm`. `sys_frequency` is what PIO0 is clocked at.
+    pub fn new(
+        spi: &'a SharedSpi,
+        pio: &mut PIO<pac::PIO0>,
+        sm: UninitStateMachine<(pac::PIO0, SM0)>,
+        dma: Channel<CH2>,
+        mut cs: DispCsMain,
+        dc: DispDataCmd,
+        sys_frequency: HertzU32,
+        baudrate: HertzU32,
+    ) -> Self {
+        let _ = cs.set_high();
+
+        // each word is sent `ISR + 1` times, for as many bits as the pull threshold. the clock
+        // (side set) rises halfway through each bit, with the data already stable, which is SPI
+        // mode 0
+        let program = pio_proc::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+            "    pull block      side 0",
+            "    mov x, osr      side 0",
+            "    mov y, isr      side 0",
+            "word:",
+            "    mov osr, x      side 0",
+            "bit:",
+            "    out pins, 1     side 0",
+            "    jmp !osre bit   side 1",
+            "    jmp y-- word    side 0",
+            ".wrap",
+        );
+        let installed = pio.install(&program.program).unwrap();
+        let offset = installed.offset();
+
+        let (int, frac) = clock_divisor(sys_frequency, baudrate);
+        let (mut sm, _rx, tx) = crate::hal::pio::PIOBuilder::from_program(installed)
+            .out_pins(DATA_PIN, 1)
+            .side_set_pin_base(CLOCK_PIN)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(false)
+            .pull_threshold(8)
+            .buffers(Buffers::OnlyTx)
+            .clock_divisor_fixed_point(int, frac)
+            .build(sm);
+        sm.set_pindirs([(CLOCK_PIN, PinDir::Output), (DATA_PIN, PinDir::Output)]);
+        let sm = sm.start();
+
+        Self {
+            spi,
+            inner: RefCell::new(Inner {
+                _sm: sm,
+                _tx: tx,
+                dma,
+                cs,
+                dc,
+                offset,
+                mode: Mode::Bytes,
+                baudrate,
+            }),
+            sys_frequency,
+            baudrate: Cell::new(baudrate),
+            orientation: Cell::new(Orientation::DEFAULT),
+        }
+    }
+
+    /// Takes effect from the next write.
+    pub fn set_baudrate(&self, baudrate: HertzU32) {
+        self.baudrate.set(baudrate);
+    }
+
+    /// Only changes how pixels written from now on are placed, what's on the display already stays
+    /// where it is until it's written again.
+    pub fn set_orientation(&self, orientation: Orientation) -> Result<(), DisplayError> {
+        self.command(dcs::SET_ADDRESS_MODE, &[orientation.madctl()])?;
+        self.orientation.set(orientation);
+        Ok(())
+    }
+
+    /// For panels that need their colours inverted to show them the right way round, or don't
+    /// after all. Applies to what's on the display already too.
+    pub fn set_invert_colors(&self, invert: bool) -> Result<(), DisplayError> {
+        self.command(if invert { dcs::ENTER_INVERT_MODE } else { dcs::EXIT_INVERT_MODE }, &[])
+    }
+
+    /// An interface for display drivers to send commands and data through.
+    pub fn interface(&self) -> PioDisplayInterface<'_, 'a> {
+        PioDisplayInterface { bus: self }
+    }
+
+    /// Write RGB565 big-endian `pixels` to the rectangle from `(sx, sy)` to `(ex, ey)` inclusive,
+    /// running `during` while they're sent.
+    ///
+    /// `during` runs inside the critical section holding the bus, so it can't use the bus and
+    /// interrupts are off until it returns.
+    pub fn write_pixels_with<R>(
+        &self,
+        sx: u16,
+        sy: u16,
+        ex: u16,
+        ey: u16,
+        pixels: &[u8],
+        during: impl FnOnce() -> R,
+    ) -> Result<R, DisplayError> {
+        self.set_window(sx, sy, ex, ey)?;
+        self.with_pixel_data(Mode::Bytes, |inner| {
+            inner.start_dma(pixels, Mode::Bytes);
+            let result = during();
+            inner.wait_dma();
+            result
+        })
+    }
+
+    /// Write the rectangle from `(sx, sy)` to `(ex, ey)` inclusive out of `fb`, a whole framebuffer
+    /// of RGB565 big-endian pixels that's `width` pixels wide. Used to update only the part of the
+    /// display that's changed.
+    pub fn write_region(
+        &self,
+        sx: u16,
+        sy: u16,
+        ex: u16,
+        ey: u16,
+        fb: &[u8],
+        width: usize,
+    ) -> Result<(), DisplayError> {
+        self.write_region_scaled(sx, sy, ex, ey, fb, width, Replicate::X1)
+    }
+
+    /// Like [`PioDisplayBus::write_region`], but for a framebuffer holding a frame `replicate`
+    /// times smaller than the display, which is upscaled as it's sent. Each pixel is repeated
+    /// across by the state machine, and each row is sent again for the rows below it.
+    ///
+    /// The rectangle is in the display's pixels, so its edges need to be on multiples of the
+    /// scale, and `width` is the frame's width.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_region_scaled(
+        &self,
+        sx: u16,
+        sy: u16,
+        ex: u16,
+        ey: u16,
+        fb: &[u8],
+        width: usize,
+        replicate: Replicate,
+    ) -> Result<(), DisplayError> {
+        let scale = replicate.scale();
+        let row_bytes = width * 2;
+        let (src_sx, src_ex) = (sx as usize / scale, ex as usize / scale);
+        let (src_sy, src_ey) = (sy as usize / scale, ey as usize / scale);
+        let rows = &fb[src_sy * row_bytes..(src_ey + 1) * row_bytes];
+
+        let mode = match replicate {
+            Replicate::X1 => Mode::Bytes,
+            replicate => Mode::Pixels(replicate),
+        };
+
+        self.set_window(sx, sy, ex, ey)?;
+        self.with_pixel_data(mode, |inner| {
+            // full rows of an unscaled frame are already one contiguous run of pixels
+            if scale == 1 && src_sx == 0 && src_ex == width - 1 {
+                inner.start_dma(rows, mode);
+                inner.wait_dma();
+                return;
+            }
+
+            for y in sy..=ey {
+                let row = &rows[(y as usize / scale - src_sy) * row_bytes..][..row_bytes];
+                // the FIFO keeps the state machine busy while the next row's transfer is set up
+                inner.start_dma(&row[src_sx * 2..(src_ex + 1) * 2], mode);
+                inner.wait_dma();
+            }
+        })
+    }
+
+    fn set_window(&self, sx: u16, sy: u16, ex: u16, ey: u16) -> Result<(), DisplayError> {
+        let orientation = self.orientation.get();
+        set_window(sx, sy, ex, ey, orientation, |command, params| self.command(command, params))
+    }
+
+    /// Run `f` with the bus held, the state machine set up for `mode`, and the display taking pixel
+    /// data. Returns once the last bit is out.
+    fn with_pixel_data<R>(&self, mode: Mode, f: impl FnOnce(&mut Inner) -> R) -> Result<R, DisplayError> {
+        self.with_bus(|inner| {
+            inner.use_mode(mode);
+            inner.dc.set_high().map_err(|_| DisplayError::DCError)?;
+            inner.cs.set_low().map_err(|_| DisplayError::CSError)?;
+            let result = f(inner);
+            inner.wait_idle();
+            inner.cs.set_high().map_err(|_| DisplayError::CSError)?;
+
+            Ok(result)
+        })
+    }
+
+    /// Run `f` with the bus held and its pins switched over to the state machine.
+    fn with_bus<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        critical_section::with(|cs| {
+            // nobody else can use the bus while we hold it, which is what makes borrowing its
+            // pins okay
+            let _spi = self.spi.borrow_ref_mut(cs);
+            let mut inner = self.inner.borrow_mut();
+            inner.use_baudrate(self.sys_frequency, self.baudrate.get());
+
+            select_function(FUNCSEL_PIO0);
+            let result = f(&mut inner);
+            select_function(FUNCSEL_SPI);
+
+            result
+        })
+    }
+
+    fn command(&self, command: u8, params: &[u8]) -> Result<(), DisplayError> {
+        self.send(false, &[command])?;
+        if !params.is_empty() {
+            self.send(true, params)?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, data: bool, bytes: &[u8]) -> Result<(), DisplayError> {
+        self.with_bus(|inner| {
+            inner.use_mode(Mode::Bytes);
+            if data {
+                inner.dc.set_high().map_err(|_| DisplayError::DCError)?;
+            } else {
+                inner.dc.set_low().map_err(|_| DisplayError::DCError)?;
+            }
+            inner.cs.set_low().map_err(|_| DisplayError::CSError)?;
+            inner.start_dma(bytes, Mode::Bytes);
+            inner.wait_dma();
+            inner.wait_idle();
+            inner.cs.set_high().map_err(|_| DisplayError::CSError)
+        })
+    }
+}
+
+impl Inner {
+    /// Set the state machine up for `mode`. Only call it while the state machine is idle.
+    fn use_mode(&mut self, mode: Mode) {
+        if mode == self.mode {
+            return;
+        }
+        let (bits, repeat) = match mode {
+            Mode::Bytes => (8, 1),
+            Mode::Pixels(replicate) => (16, replicate.scale() as u8),
+        };
+
+        let sm = sm_regs();
+        sm.sm_shiftctrl.modify(|_, w| unsafe { w.pull_thresh().bits(bits) });
+        // the program reloads its repeat count from the ISR, which it never shifts into
+        let set_x = pio::InstructionOperands::SET {
+            destination: pio::SetDestination::X,
+            data: repeat - 1,
+        };
+        let mov_isr = pio::InstructionOperands::MOV {
+            destination: pio::MovDestination::ISR,
+            op: pio::MovOperation::None,
+            source: pio::MovSource::X,
+        };
+        // the side set bits are left at 0, keeping the clock low
+        for instr in [set_x.encode(), mov_isr.encode()] {
+            sm.sm_instr.write(|w| unsafe { w.sm0_instr().bits(instr) });
+        }
+
+        self.mode = mode;
+    }
+
+    fn use_baudrate(&mut self, sys_frequency: HertzU32, baudrate: HertzU32) {
+        if baudrate != self.baudrate {
+            let (int, frac) = clock_divisor(sys_frequency, baudrate);
+            sm_regs().sm_clkdiv.write(|w| unsafe {
+                w.int().bits(int);
+                w.frac().bits(frac);
+                w
+            });
+            self.baudrate = baudrate;
+        }
+    }
+
+    /// Start feeding `data` to the state machine with DMA, as bytes in [`Mode::Bytes`] or big
+    /// endian pixels otherwise.
+    fn start_dma(&mut self, data: &[u8], mode: Mode) {
+        // make sure everything written to `data` so far is visible to the DMA
+        compiler_fence(Ordering::SeqCst);
+
+        // narrow writes to the FIFO are copied across the whole word, so the byte or pixel ends
+        // up at the top where the state machine shifts out from. pixels are read as little endian
+        // halfwords, so they're byte swapped on the way to put their high byte first.
+        let (size, len, bswap) = match mode {
+            Mode::Bytes => (0, data.len(), false),
+            Mode::Pixels(_) => {
+                debug_assert!(data.as_ptr() as usize % 2 == 0);
+                (1, data.len() / 2, true)
+            }
+        };
+
+        let ch = self.dma.ch();
+        ch.ch_al1_ctrl.write(|w| unsafe {
+            w.data_size().bits(size);
+            w.incr_read().set_bit();
+            w.incr_write().clear_bit();
+            w.bswap().bit(bswap);
+            w.treq_sel().bits(DREQ_PIO0_TX0);
+            w.chain_to().bits(self.dma.id());
+            w.en().set_bit();
+            w
+        });
+        ch.ch_read_addr.write(|w| unsafe { w.bits(data.as_ptr() as u32) });
+        ch.ch_trans_count.write(|w| unsafe { w.bits(len as u32) });
+        ch.ch_al2_write_addr_trig.write(|w| unsafe { w.bits(txf_addr()) });
+    }
+
+    /// Wait for the DMA to have put everything in the FIFO.
+    fn wait_dma(&self) {
+        while self.dma.ch().ch_ctrl_trig.read().busy().bit_is_set() {}
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    /// Wait for the state machine to have sent everything, and be waiting for more.
+    fn wait_idle(&self) {
+        let pio = unsafe { &*pac::PIO0::ptr() };
+        let sm = sm_regs();
+        loop {
+            let fifo_empty = pio.fstat.read().txempty().bits() & 1 != 0;
+            let waiting = sm.sm_addr.read().bits() == self.offset as u32
+                && sm.sm_execctrl.read().exec_stalled().bit_is_set();
+            if fifo_empty && waiting {
+                break;
+            }
+        }
+    }
+}
+
+/// A [`PioDisplayBus`] as a [`WriteOnlyDataCommand`].
+pub struct PioDisplayInterface<'b, 'a> {
+    bus: &'b PioDisplayBus<'a>,
+}
+
+impl WriteOnlyDataCommand for PioDisplayInterface<'_, '_> {
+    fn send_commands(&mut self, cmd: DataFormat<'_>) -> Result<(), DisplayError> {
+        send_format(cmd, |bytes| self.bus.send(false, bytes))
+    }
+
+    fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), DisplayError> {
+        send_format(buf, |bytes| self.bus.send(true, bytes))
+    }
+}
+
+/// Each bit takes two PIO cycles, as a 16.8 fixed point divisor. It can't go faster than 1.
+fn clock_divisor(sys_frequency: HertzU32, baudrate: HertzU32) -> (u16, u8) {
+    let div = (sys_frequency.to_Hz() as u64 * 256 / (2 * baudrate.to_Hz() as u64)).clamp(256, 0xff_ffff);
+    ((div >> 8) as u16, div as u8)
+}
+
+/// Hand the shared bus's pins to the SPI peripheral or PIO0.
+fn select_function(funcsel: u8) {
+    let io = unsafe { &*pac::IO_BANK0::ptr() };
+    for pin in [CLOCK_PIN, DATA_PIN] {
+        io.gpio[pin as usize].gpio_ctrl.modify(|_, w| unsafe { w.funcsel().bits(funcsel) });
+    }
+}
+
+fn sm_regs() -> &'static pac::pio0::SM {
+    unsafe { &(*pac::PIO0::ptr()).sm[0] }
+}
+
+fn txf_addr() -> u32 {
+    unsafe { &(*pac::PIO0::ptr()).txf[0] as *const _ as u32 }
+}
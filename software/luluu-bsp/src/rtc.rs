@@ -0,0 +1,91 @@
+//! Wall-clock time from the RP2040's real-time clock.
+//!
+//! [`Rtc`] owns the clock, for setting it and reading it on core 0. [`RtcTimeSource`] reads it
+//! without needing the [`Rtc`], so the SD card can timestamp files from whichever core owns it.
+//!
+//! Nothing keeps the clock running without power, so it starts again from [`START`] every time the
+//! LuLuu is turned on, until it's set.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embedded_sdmmc::{TimeSource, Timestamp};
+
+use crate::hal::clocks::RtcClock;
+use crate::hal::rtc::RealTimeClock;
+use crate::pac;
+use crate::DummyTimesource;
+
+pub use crate::hal::rtc::{DateTime, DayOfWeek, RtcError};
+
+/// Where the clock starts from at power on, the same time [`DummyTimesource`] gives.
+pub const START: DateTime = DateTime {
+    year: 2023,
+    month: 1,
+    day: 1,
+    day_of_week: DayOfWeek::Sunday,
+    hour: 0,
+    minute: 0,
+    second: 0,
+};
+
+/// Whether the clock has been set since power on. Shared with [`RtcTimeSource`].
+static IS_SET: AtomicBool = AtomicBool::new(false);
+
+pub struct Rtc {
+    rtc: RealTimeClock,
+}
+
+impl Rtc {
+    /// Start the clock from [`START`].
+    pub fn new(rtc: pac::RTC, clock: RtcClock, resets: &mut pac::RESETS) -> Self {
+        let rtc = RealTimeClock::new(rtc, clock, resets, START).ok().unwrap();
+        Self { rtc }
+    }
+
+    /// The RTC doesn't work out the day of the week itself, `time` has to have the right one.
+    pub fn set(&mut self, time: DateTime) -> Result<(), RtcError> {
+        self.rtc.set_datetime(time)?;
+        IS_SET.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether the clock has been set since power on. Until it has, it's counting from [`START`].
+    pub fn is_set(&self) -> bool {
+        IS_SET.load(Ordering::Relaxed)
+    }
+
+    /// The current time, if the clock has been set.
+    pub fn now(&self) -> Option<DateTime> {
+        if !self.is_set() {
+            return None;
+        }
+        self.rtc.now().ok()
+    }
+}
+
+/// Timestamps files with the time from the RTC, or [`DummyTimesource`]'s until it's been set.
+#[derive(Default)]
+pub struct RtcTimeSource;
+
+impl TimeSource for RtcTimeSource {
+    fn get_timestamp(&self) -> Timestamp {
+        if !IS_SET.load(Ordering::Relaxed) {
+            return DummyTimesource.get_timestamp();
+        }
+
+        // SAFETY: reading the time doesn't change anything, and it's only ever written by the
+        // `Rtc`, which owns the RTC
+        let rtc = unsafe { &*pac::RTC::ptr() };
+        // reading RTC_0 latches RTC_1, so they're from the same moment
+        let rtc_0 = rtc.rtc_0.read();
+        let rtc_1 = rtc.rtc_1.read();
+        Timestamp {
+            year_since_1970: (rtc_1.year().bits() - 1970) as u8,
+            zero_indexed_month: rtc_1.month().bits() - 1,
+            zero_indexed_day: rtc_1.day().bits() - 1,
+            hours: rtc_0.hour().bits(),
+            minutes: rtc_0.min().bits(),
+            seconds: rtc_0.sec().bits(),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! DMA driven transfers on the SPI bus shared by the display and SD card.
+//!
+//! [`DmaSpiBus`] implements [`SpiBus`] so it can be shared with `embedded-hal-bus` devices like the
+//! HAL's own [`Spi`](hal::Spi), but moves anything longer than [`DMA_MIN_LEN`] with DMA instead of
+//! the CPU. That keeps the bus saturated, which the CPU can't quite do at the speeds we run at, and
+//! with [`DmaSpiBus::write_with`] lets the CPU get on with something else while a write is sent.
+//!
+//! The bus is shared between the cores as a [`SharedSpi`]. Whoever is using it holds a critical
+//! section, which on the RP2040 also holds off the other core, and sets the baud rate it wants with
+//! [`DmaSpiBus::use_baudrate`]. [`SharedSpiDevice`] does that for devices like the SD card.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use critical_section::Mutex;
+use embedded_hal::delay::DelayUs;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::{ErrorType, Operation, SpiBus, SpiDevice};
+
+use crate::hal::dma::{Channel, SingleChannel, CH0, CH1};
+use crate::hal::fugit::HertzU32;
+use crate::{hal, pac, SpiPinLayout};
+
+pub type BoardSpi = hal::Spi<hal::spi::Enabled, pac::SPI1, SpiPinLayout, 8>;
+
+/// Transfers shorter than this are done by the CPU, since setting up DMA for them isn't worth it.
+pub const DMA_MIN_LEN: usize = 32;
+
+/// DMA request signals paced by the SPI1 FIFOs.
+const DREQ_SPI1_TX: u8 = 18;
+const DREQ_SPI1_RX: u8 = 19;
+
+/// Sent while reading, same as the HAL does.
+static READ_FILL_WORD: u8 = 0;
+
+/// The SPI bus, shared between the cores.
+pub type SharedSpi = Mutex<RefCell<DmaSpiBus>>;
+
+/// The board's SPI bus, with two DMA channels for transmitting and receiving.
+pub struct DmaSpiBus {
+    spi: BoardSpi,
+    tx: Channel<CH0>,
+    rx: Channel<CH1>,
+    peri_frequency: HertzU32,
+    baudrate: HertzU32,
+}
+
+impl DmaSpiBus {
+    /// `baudrate` is what `spi` was initialized with.
+    pub fn new(
+        spi: BoardSpi,
+        tx: Channel<CH0>,
+        rx: Channel<CH1>,
+        peri_frequency: HertzU32,
+        baudrate: HertzU32,
+    ) -> Self {
+        Self { spi, tx, rx, peri_frequency, baudrate }
+    }
+
+    /// Switch to `baudrate`, if the bus isn't running at it already.
+    pub fn use_baudrate(&mut self, baudrate: HertzU32) {
+        if baudrate != self.baudrate {
+            self.spi.set_baudrate(self.peri_frequency, baudrate);
+            self.baudrate = baudrate;
+        }
+    }
+
+    /// Send `data` using DMA, running `during` while it's being sent. Returns once both are done.
+    ///
+    /// `during` can't use the bus, since it's borrowed for the duration of the write, and `data`
+    /// can't be modified while it's being sent, but anything else is fair game.
+    pub fn write_with<R>(&mut self, data: &[u8], during: impl FnOnce() -> R) -> R {
+        self.drain_rx();
+
+        // make sure everything written to `data` so far is visible to the DMA
+        compiler_fence(Ordering::SeqCst);
+
+        let ch = self.tx.ch();
+        ch.ch_al1_ctrl.write(|w| unsafe {
+            w.data_size().bits(0);
+            w.incr_read().set_bit();
+            w.incr_write().clear_bit();
+            w.treq_sel().bits(DREQ_SPI1_TX);
+            w.chain_to().bits(self.tx.id());
+            w.en().set_bit();
+            w
+        });
+        ch.ch_read_addr.write(|w| unsafe { w.bits(data.as_ptr() as u32) });
+        ch.ch_trans_count.write(|w| unsafe { w.bits(data.len() as u32) });
+        ch.ch_al2_write_addr_trig.write(|w| unsafe { w.bits(spi_dr_addr()) });
+
+        let result = during();
+
+        while self.tx.ch().ch_ctrl_trig.read().busy().bit_is_set() {}
+        compiler_fence(Ordering::SeqCst);
+
+        // the last few bytes are still in the FIFO. we ignored everything received while sending
+        // so the RX FIFO overflowed, throw away what's in it so it doesn't end up in the next read
+        self.wait_idle();
+        self.drain_rx();
+
+        result
+    }
+
+    /// Full duplex transfer with DMA. Reads into `rx` while transmitting from `tx`, which may point
+    /// to the same buffer. If `tx_incr` is false the same byte is sent `len` times.
+    ///
+    /// # Safety
+    ///
+    /// `tx` must be valid for reading `len` bytes (or 1 if `!tx_incr`) and `rx` valid for writing
+    /// `len` bytes.
+    unsafe fn transfer_dma(&mut self, tx: *const u8, tx_incr: bool, rx: *mut u8, len: usize) {
+        self.drain_rx();
+        compiler_fence(Ordering::SeqCst);
+
+        // start receiving first so it's ready by the time the first byte comes back
+        let ch = self.rx.ch();
+        ch.ch_al1_ctrl.write(|w| {
+            w.data_size().bits(0);
+            w.incr_read().clear_bit();
+            w.incr_write().set_bit();
+            w.treq_sel().bits(DREQ_SPI1_RX);
+            w.chain_to().bits(self.rx.id());
+            w.en().set_bit();
+            w
+        });
+        ch.ch_read_addr.write(|w| w.bits(spi_dr_addr()));
+        ch.ch_trans_count.write(|w| w.bits(len as u32));
+        ch.ch_al2_write_addr_trig.write(|w| w.bits(rx as u32));
+
+        let ch = self.tx.ch();
+        ch.ch_al1_ctrl.write(|w| {
+            w.data_size().bits(0);
+            w.incr_read().bit(tx_incr);
+            w.incr_write().clear_bit();
+            w.treq_sel().bits(DREQ_SPI1_TX);
+            w.chain_to().bits(self.tx.id());
+            w.en().set_bit();
+            w
+        });
+        ch.ch_read_addr.write(|w| w.bits(tx as u32));
+        ch.ch_trans_count.write(|w| w.bits(len as u32));
+        ch.ch_al2_write_addr_trig.write(|w| w.bits(spi_dr_addr()));
+
+        // receiving always finishes last
+        while self.rx.ch().ch_ctrl_trig.read().busy().bit_is_set() {}
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    fn wait_idle(&self) {
+        let regs = unsafe { &*pac::SPI1::ptr() };
+        while regs.sspsr.read().bsy().bit_is_set() {}
+    }
+
+    fn drain_rx(&mut self) {
+        let regs = unsafe { &*pac::SPI1::ptr() };
+        while regs.sspsr.read().rne().bit_is_set() {
+            let _ = regs.sspdr.read();
+        }
+        regs.sspicr.write(|w| w.roric().set_bit());
+    }
+}
+
+impl ErrorType for DmaSpiBus {
+    type Error = Infallible;
+}
+
+impl SpiBus for DmaSpiBus {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        if words.len() < DMA_MIN_LEN {
+            return self.spi.read(words);
+        }
+        // SAFETY: both pointers are valid for the whole transfer, which finishes before we return
+        unsafe { self.transfer_dma(&READ_FILL_WORD, false, words.as_mut_ptr(), words.len()) };
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        if words.len() < DMA_MIN_LEN {
+            return self.spi.write(words);
+        }
+        self.write_with(words, || ());
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        if read.len() != write.len() || read.len() < DMA_MIN_LEN {
+            return self.spi.transfer(read, write);
+        }
+        // SAFETY: both buffers are valid for the whole transfer, which finishes before we return
+        unsafe { self.transfer_dma(write.as_ptr(), true, read.as_mut_ptr(), read.len()) };
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        if words.len() < DMA_MIN_LEN {
+            return self.spi.transfer_in_place(words);
+        }
+        // SAFETY: the buffer is valid for the whole transfer, which finishes before we return.
+        // each byte is sent before the byte received in its place is written back.
+        let ptr = words.as_mut_ptr();
+        unsafe { self.transfer_dma(ptr, true, ptr, words.len()) };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.wait_idle();
+        Ok(())
+    }
+}
+
+/// A device on the [`SharedSpi`] with its own chip select and baud rate, usable from either core.
+///
+/// Like `embedded-hal-bus`'s `CriticalSectionDevice`, but switches the bus to this device's baud
+/// rate at the start of each transaction.
+pub struct SharedSpiDevice<'a, CS, D> {
+    bus: &'a SharedSpi,
+    cs: CS,
+    delay: D,
+    baudrate: HertzU32,
+}
+
+impl<'a, CS: OutputPin<Error = Infallible>, D: DelayUs> SharedSpiDevice<'a, CS, D> {
+    pub fn new(bus: &'a SharedSpi, mut cs: CS, delay: D, baudrate: HertzU32) -> Self {
+        let _ = cs.set_high();
+        Self { bus, cs, delay, baudrate }
+    }
+
+    /// Takes effect from the next transaction.
+    pub fn set_baudrate(&mut self, baudrate: HertzU32) {
+        self.baudrate = baudrate;
+    }
+}
+
+impl<CS, D> ErrorType for SharedSpiDevice<'_, CS, D> {
+    type Error = Infallible;
+}
+
+impl<CS: OutputPin<Error = Infallible>, D: DelayUs> SpiDevice for SharedSpiDevice<'_, CS, D> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        critical_section::with(|cs| {
+            let mut bus = self.bus.borrow_ref_mut(cs);
+            bus.use_baudrate(self.baudrate);
+
+            self.cs.set_low()?;
+            for operation in operations.iter_mut() {
+                match operation {
+                    Operation::Read(words) => bus.read(words)?,
+                    Operation::Write(words) => bus.write(words)?,
+                    Operation::Transfer(read, write) => bus.transfer(read, write)?,
+                    Operation::TransferInPlace(words) => bus.transfer_in_place(words)?,
+                    Operation::DelayUs(us) => {
+                        bus.flush()?;
+                        self.delay.delay_us(*us);
+                    }
+                }
+            }
+            bus.flush()?;
+            self.cs.set_high()
+        })
+    }
+}
+
+fn spi_dr_addr() -> u32 {
+    unsafe { &(*pac::SPI1::ptr()).sspdr as *const _ as u32 }
+}
@@ -0,0 +1,40 @@
+//! The UART on the debug connector, [`UartTx`] on GPIO0 and [`UartRx`] on GPIO1, at 3.3V.
+//!
+//! [`init`] sets it up at [`BAUD_RATE`], 8N1, which is what USB serial adapters and terminal
+//! programs expect by default. It's usually split into a [`UartReader`] and a [`UartWriter`].
+
+use embedded_hal_0_2::serial::Read;
+use hal::uart::{DataBits, Enabled, Reader, StopBits, UartConfig, UartPeripheral, Writer};
+
+use crate::hal::fugit::{HertzU32, RateExtU32};
+use crate::{hal, pac, UartRx, UartTx};
+
+pub type UartPins = (UartTx, UartRx);
+
+pub type Uart = UartPeripheral<Enabled, pac::UART0, UartPins>;
+
+pub type UartReader = Reader<pac::UART0, UartPins>;
+
+pub type UartWriter = Writer<pac::UART0, UartPins>;
+
+pub const BAUD_RATE: u32 = 115_200;
+
+pub fn init(
+    uart0: pac::UART0,
+    uart_tx: UartTx,
+    uart_rx: UartRx,
+    resets: &mut pac::RESETS,
+    peripheral_clock: HertzU32,
+) -> Uart {
+    let config = UartConfig::new(BAUD_RATE.Hz(), DataBits::Eight, None, StopBits::One);
+    UartPeripheral::new(uart0, (uart_tx, uart_rx), resets)
+        .enable(config, peripheral_clock)
+        // only fails for baud rates the clock can't make
+        .unwrap()
+}
+
+/// The next byte that's arrived, if there is one. A byte that arrived garbled is dropped, and
+/// looks like nothing's arrived yet.
+pub fn read_byte(reader: &mut UartReader) -> Option<u8> {
+    reader.read().ok()
+}
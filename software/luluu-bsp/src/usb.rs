@@ -0,0 +1,35 @@
+//! The USB-C port's data lines, for showing up on a computer as a USB device.
+//!
+//! [`init_bus`] sets up the controller, and the classes the firmware wants are built on the
+//! [`UsbBusAllocator`] it returns, followed by a `UsbDevice` with [`VID_PID`] and the strings here.
+//! Nothing happens on the bus until that device is polled.
+
+pub use usb_device;
+
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::UsbVidPid;
+
+use crate::{hal, pac};
+
+pub type UsbBus = hal::usb::UsbBus;
+
+/// pid.codes' test IDs, until the LuLuu has some of its own.
+pub const VID_PID: UsbVidPid = UsbVidPid(0x1209, 0x0001);
+
+pub const MANUFACTURER: &str = "LuLuu";
+
+pub const PRODUCT: &str = "LuLuu!";
+
+/// Largest packet on a full speed bulk endpoint.
+pub const MAX_PACKET_SIZE: u16 = 64;
+
+pub fn init_bus(
+    regs: pac::USBCTRL_REGS,
+    dpram: pac::USBCTRL_DPRAM,
+    usb_clock: hal::clocks::UsbClock,
+    resets: &mut pac::RESETS,
+) -> UsbBusAllocator<UsbBus> {
+    // VBUS detection isn't used, it's always taken to be there. The host not talking to us is
+    // enough to tell there's no computer plugged in.
+    UsbBusAllocator::new(UsbBus::new(regs, dpram, usb_clock, true, resets))
+}
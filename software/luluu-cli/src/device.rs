@@ -0,0 +1,153 @@
+//! Talking to a LuLuu plugged in over USB, through the serial port it shows up as.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use eyre::WrapErr;
+use luluu_enc::{DirtyRect, Rgb565BE, Size};
+use luluu_proto::{Client, ClientError, Link, Request, Response};
+
+/// The serial port, opened as a file.
+pub struct Serial {
+    file: File,
+}
+
+impl Serial {
+    pub fn open(path: &Path) -> Result<Self, eyre::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .wrap_err_with(|| format!("Could not open serial port {}, is the device plugged in?", path.display()))?;
+        #[cfg(unix)]
+        make_raw(&file).wrap_err_with(|| format!("Could not set up serial port {}", path.display()))?;
+        Ok(Self { file })
+    }
+}
+
+/// Stop the terminal driver from changing the bytes going through, and give up on reads after a
+/// few seconds instead of waiting forever.
+#[cfg(unix)]
+fn make_raw(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    // SAFETY: the termios is only used once tcgetattr has filled it in, and fd stays open
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        // in tenths of a second, long enough for the device to write a batch of an upload to the
+        // card
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 50;
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // anything still waiting to be read is from someone else
+        libc::tcflush(fd, libc::TCIOFLUSH);
+    }
+    Ok(())
+}
+
+impl Link for Serial {
+    type Error = io::Error;
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.file.flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+pub struct Device {
+    client: Client<Serial>,
+}
+
+impl Device {
+    pub fn open(path: &Path) -> Result<Self, eyre::Error> {
+        Ok(Self { client: Client::new(Serial::open(path)?) })
+    }
+
+    /// Send `request` and wait for the answer, which is an error if the device couldn't do it.
+    pub fn request(&mut self, request: &Request<'_>) -> Result<Response<'_>, eyre::Error> {
+        answer(self.client.request(request))
+    }
+
+    /// Send `request`, which is just answered with [`Response::Ok`].
+    pub fn command(&mut self, request: &Request<'_>) -> Result<(), eyre::Error> {
+        match self.request(request)? {
+            Response::Ok => Ok(()),
+            response => eyre::bail!("Unexpected answer from the device: {:?}", response),
+        }
+    }
+
+    /// Write `bytes` to the file called `name` on the card, calling `progress` with how many bytes
+    /// have been sent so far after each part.
+    pub fn upload(&mut self, name: &str, bytes: &[u8], mut progress: impl FnMut(usize)) -> Result<(), eyre::Error> {
+        let len = u32::try_from(bytes.len()).map_err(|_| eyre::eyre!("File is too big for the card."))?;
+        self.command(&Request::UploadStart { name, len })?;
+        let mut sent = 0;
+        for chunk in bytes.chunks(luluu_proto::MAX_CHUNK_LEN) {
+            self.command(&Request::UploadData { bytes: chunk })?;
+            sent += chunk.len();
+            progress(sent);
+        }
+        Ok(())
+    }
+
+    /// Show a `size` by `size` frame on the display, of which `pixels` are the ones in `rect`, row
+    /// by row. The rest is left as it was in the last frame.
+    pub fn stream_frame(&mut self, size: Size, rect: DirtyRect, pixels: &[Rgb565BE], packed: bool) -> Result<(), eyre::Error> {
+        let bytes = match packed {
+            true => {
+                let mut bytes = vec![0; luluu_proto::stream::max_packed_len(pixels.len())];
+                let len = luluu_proto::stream::pack(pixels, &mut bytes).unwrap();
+                bytes.truncate(len);
+                bytes
+            }
+            false => Rgb565BE::slice_as_bytes(pixels).to_vec(),
+        };
+        let link_error = |error: ClientError<io::Error>| eyre::eyre!("Talking to the device failed: {}.", error);
+        let len = bytes.len() as u32;
+        self.client.send(&Request::StreamFrame { size, rect, packed, len }).map_err(link_error)?;
+        // the data isn't answered, only the whole frame once it's shown
+        for chunk in bytes.chunks(luluu_proto::MAX_CHUNK_LEN) {
+            self.client.send(&Request::StreamData { bytes: chunk }).map_err(link_error)?;
+        }
+        match answer(self.client.receive())? {
+            Response::Ok => Ok(()),
+            response => eyre::bail!("Unexpected answer from the device: {:?}", response),
+        }
+    }
+}
+
+/// The answer to a request, which is an error if the device couldn't do it.
+fn answer(result: Result<Response<'_>, ClientError<io::Error>>) -> Result<Response<'_>, eyre::Error> {
+    match result {
+        Ok(Response::Failed(failure)) => eyre::bail!("The device said no: {}.", failure),
+        Ok(response) => Ok(response),
+        Err(error) => eyre::bail!("Talking to the device failed: {}.", error),
+    }
+}
+
+/// The name a file at `path` gets on the card, which only has 8.3 upper case names.
+pub fn card_name(path: &Path) -> Result<String, eyre::Error> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| eyre::eyre!("{} isn't a file name the card can have.", path.display()))?
+        .to_ascii_uppercase();
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((&name, ""));
+    let allowed = |part: &str| part.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"_-~!#$%&'()@^{}".contains(&byte));
+    if stem.is_empty() || stem.len() > 8 || extension.len() > 3 || !allowed(stem) || !allowed(extension) {
+        eyre::bail!("{} has to be renamed to an 8.3 name like FIREWORK.LU to go on the card.", name);
+    }
+    Ok(name)
+}
@@ -0,0 +1,177 @@
+//! Packaging firmware into images the device updates itself from, see [`luluu_update`].
+
+use luluu_update::{Header, Version, MAX_IMAGE_LEN};
+
+/// Where the flash starts in the RP2040's address space.
+const FLASH_BASE: u32 = 0x1000_0000;
+
+const UF2_BLOCK_LEN: usize = 512;
+const UF2_MAGIC_START0: u32 = 0x0a32_4655;
+const UF2_MAGIC_START1: u32 = 0x9e5d_5157;
+const UF2_MAGIC_END: u32 = 0x0ab1_6f30;
+/// The block isn't for the main flash.
+const UF2_FLAG_NOT_MAIN_FLASH: u32 = 0x1;
+
+/// The bytes of the flash in a UF2 file, like the ones `elf2uf2-rs` makes, from its start. Gaps
+/// between the blocks are left erased.
+pub fn from_uf2(uf2: &[u8]) -> Result<Vec<u8>, eyre::Error> {
+    if uf2.is_empty() || !uf2.len().is_multiple_of(UF2_BLOCK_LEN) {
+        eyre::bail!("Not a UF2 file, it isn't made of 512 byte blocks.");
+    }
+    let mut flash = Vec::new();
+    for (idx, block) in uf2.as_chunks::<UF2_BLOCK_LEN>().0.iter().enumerate() {
+        let word = |at: usize| u32::from_le_bytes(block[at..at + 4].try_into().unwrap());
+        if word(0) != UF2_MAGIC_START0 || word(4) != UF2_MAGIC_START1 || word(UF2_BLOCK_LEN - 4) != UF2_MAGIC_END {
+            eyre::bail!("Not a UF2 file, block {} doesn't start and end right.", idx);
+        }
+        if word(8) & UF2_FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+        let (address, len) = (word(12), word(16) as usize);
+        if len > 476 {
+            eyre::bail!("Block {} of the UF2 file has more in it than fits.", idx);
+        }
+        let offset = address
+            .checked_sub(FLASH_BASE)
+            .filter(|offset| offset + len as u32 <= MAX_IMAGE_LEN)
+            .ok_or_else(|| eyre::eyre!("Block {} of the UF2 file is for {:#010x}, outside the flash the firmware goes in.", idx, address))?
+            as usize;
+        if flash.len() < offset + len {
+            flash.resize(offset + len, 0xff);
+        }
+        flash[offset..offset + len].copy_from_slice(&block[32..32 + len]);
+    }
+    if flash.is_empty() {
+        eyre::bail!("The UF2 file has nothing in it for the flash.");
+    }
+    Ok(flash)
+}
+
+/// An image of `flash`, the bytes of the flash from its start, for firmware `version`.
+pub fn pack(version: Version, flash: &[u8]) -> Result<Vec<u8>, eyre::Error> {
+    // the boot ROM only starts firmware with a second stage bootloader, which is the first thing
+    if flash.len() <= 256 {
+        eyre::bail!("That's too small to be the firmware, it needs the second stage bootloader at the start.");
+    }
+    let header = Header::for_bytes(version, flash).map_err(|error| eyre::eyre!("Can't package that: {}.", error))?;
+    let mut image = header.encode().to_vec();
+    image.extend_from_slice(flash);
+    Ok(image)
+}
+
+/// The header of `image`, once it's been checked.
+pub fn check(image: &[u8]) -> Result<Header, luluu_update::Error> {
+    let (header, flash) = image
+        .split_first_chunk::<{ luluu_update::HEADER_LEN }>()
+        .ok_or(luluu_update::Error::NotAnImage)?;
+    let header = Header::decode(header)?;
+    header.check(flash)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A UF2 block putting `data` at `address`.
+    fn block(address: u32, data: &[u8], flags: u32) -> [u8; UF2_BLOCK_LEN] {
+        let mut block = [0; UF2_BLOCK_LEN];
+        let mut put = |at: usize, word: u32| block[at..at + 4].copy_from_slice(&word.to_le_bytes());
+        put(0, UF2_MAGIC_START0);
+        put(4, UF2_MAGIC_START1);
+        put(8, flags);
+        put(12, address);
+        put(16, data.len() as u32);
+        put(UF2_BLOCK_LEN - 4, UF2_MAGIC_END);
+        block[32..32 + data.len()].copy_from_slice(data);
+        block
+    }
+
+    fn uf2(blocks: &[[u8; UF2_BLOCK_LEN]]) -> Vec<u8> {
+        blocks.concat()
+    }
+
+    #[test]
+    fn blocks_with_a_gap() {
+        let uf2 = uf2(&[block(FLASH_BASE, &[1; 256], 0), block(FLASH_BASE + 512, &[2; 256], 0)]);
+        let flash = from_uf2(&uf2).unwrap();
+        assert_eq!(flash.len(), 768);
+        assert!(flash[..256].iter().all(|&b| b == 1));
+        assert!(flash[256..512].iter().all(|&b| b == 0xff));
+        assert!(flash[512..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn blocks_out_of_order() {
+        let uf2 = uf2(&[block(FLASH_BASE + 256, &[2; 256], 0), block(FLASH_BASE, &[1; 256], 0)]);
+        let flash = from_uf2(&uf2).unwrap();
+        assert_eq!(flash.len(), 512);
+        assert!(flash[..256].iter().all(|&b| b == 1));
+        assert!(flash[256..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn skips_blocks_not_for_the_main_flash() {
+        let uf2 = uf2(&[
+            block(FLASH_BASE, &[1; 256], 0),
+            block(0x2000_0000, &[2; 256], UF2_FLAG_NOT_MAIN_FLASH),
+        ]);
+        assert_eq!(from_uf2(&uf2).unwrap(), [1; 256]);
+
+        let uf2 = self::uf2(&[block(FLASH_BASE, &[2; 256], UF2_FLAG_NOT_MAIN_FLASH)]);
+        assert!(from_uf2(&uf2).is_err());
+    }
+
+    #[test]
+    fn rejects_addresses_outside_the_flash() {
+        let below = uf2(&[block(FLASH_BASE - 256, &[1; 256], 0)]);
+        assert!(from_uf2(&below).is_err());
+
+        let past = uf2(&[block(FLASH_BASE + MAX_IMAGE_LEN - 128, &[1; 256], 0)]);
+        assert!(from_uf2(&past).is_err());
+        let up_to_the_end = uf2(&[block(FLASH_BASE + MAX_IMAGE_LEN - 256, &[1; 256], 0)]);
+        assert_eq!(from_uf2(&up_to_the_end).unwrap().len(), MAX_IMAGE_LEN as usize);
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        let mut bad_magic = uf2(&[block(FLASH_BASE, &[1; 256], 0)]);
+        bad_magic[0] ^= 1;
+        assert!(from_uf2(&bad_magic).is_err());
+
+        let mut bad_end = uf2(&[block(FLASH_BASE, &[1; 256], 0)]);
+        bad_end[UF2_BLOCK_LEN - 1] ^= 1;
+        assert!(from_uf2(&bad_end).is_err());
+
+        let short = uf2(&[block(FLASH_BASE, &[1; 256], 0)]);
+        assert!(from_uf2(&short[..UF2_BLOCK_LEN - 1]).is_err());
+        assert!(from_uf2(&[]).is_err());
+
+        let mut too_full = uf2(&[block(FLASH_BASE, &[1; 256], 0)]);
+        too_full[16..20].copy_from_slice(&477u32.to_le_bytes());
+        assert!(from_uf2(&too_full).is_err());
+    }
+
+    #[test]
+    fn pack_then_check() {
+        let version = Version { major: 0, minor: 3, patch: 1 };
+        let flash = from_uf2(&uf2(&[block(FLASH_BASE, &[1; 256], 0), block(FLASH_BASE + 256, &[2; 256], 0)])).unwrap();
+        let image = pack(version, &flash).unwrap();
+        let header = check(&image).unwrap();
+        assert_eq!(header.version, version);
+        assert_eq!(header.len, 512);
+        assert_eq!(&image[luluu_update::HEADER_LEN..], flash);
+
+        let mut garbled = image.clone();
+        *garbled.last_mut().unwrap() ^= 1;
+        assert_eq!(check(&garbled), Err(luluu_update::Error::Corrupted));
+        assert_eq!(check(&image[..image.len() - 1]), Err(luluu_update::Error::Corrupted));
+        assert_eq!(check(&image[..10]), Err(luluu_update::Error::NotAnImage));
+    }
+
+    #[test]
+    fn pack_needs_the_bootloader() {
+        let version = Version { major: 0, minor: 3, patch: 1 };
+        assert!(pack(version, &[0; 256]).is_err());
+    }
+}
@@ -1,9 +1,14 @@
-use std::{path::PathBuf, fs::File, io::Write};
+use std::{path::{Path, PathBuf}, fs::File, io::Write};
 
 use clap::{Parser, Subcommand};
 
 use eyre::WrapErr;
-use luluu_enc::{Rgb565BE, Rgba8888, Rgb565NE, MagicBytes};
+use luluu_enc::{DirtyRect, Rgb565BE, Rgba8888, Rgb565NE, MagicBytes};
+use luluu_proto::{Request, Response};
+
+mod device;
+mod firmware;
+mod stream;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -22,98 +27,326 @@ enum Commands {
         /// Override the output's frame rate. Derived from the GIF if not provided.
         #[arg(short, long, value_name = "FRAMERATE")]
         frame_rate: Option<u8>,
+    },
+    /// Make or check the device's settings file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Set the device's clock
+    Clock {
+        #[command(subcommand)]
+        command: ClockCommands,
+    },
+    /// Control a device plugged in over USB
+    Device {
+        /// The serial port it shows up as, like /dev/ttyACM0 or COM3
+        #[arg(short, long, value_name = "PORT")]
+        port: PathBuf,
+
+        #[command(subcommand)]
+        command: DeviceCommands,
+    },
+    /// Show frames on the display of a device plugged in over USB, straight from this computer
+    Stream {
+        /// The serial port it shows up as, like /dev/ttyACM0 or COM3
+        #[arg(short, long, value_name = "PORT")]
+        port: PathBuf,
+
+        /// Send every pixel as it is, instead of packing them so there are fewer to send
+        #[arg(long)]
+        raw: bool,
+
+        #[command(subcommand)]
+        source: StreamSource,
+    },
+    /// Package firmware for the device to update itself from the SD card
+    Firmware {
+        #[command(subcommand)]
+        command: FirmwareCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Write a settings file with every setting at its default, to edit and copy to the SD card
+    Init {
+        /// Where to write it
+        #[arg(value_name = "FILEPATH", default_value = luluu_config::FILE_NAME)]
+        file_path: PathBuf,
+
+        /// Replace the file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check a settings file for mistakes, and show the settings the device would end up with
+    Check {
+        /// The file to check
+        #[arg(value_name = "FILEPATH", default_value = luluu_config::FILE_NAME)]
+        file_path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum FirmwareCommands {
+    /// Make an update file from firmware built for the device, to copy onto the SD card
+    Pack {
+        /// The firmware, as a .uf2 file or a .bin of the flash from its start
+        #[arg(value_name = "FILEPATH")]
+        file_path: PathBuf,
+
+        /// Its version, like 0.2.0. The device only installs firmware newer than its own.
+        #[arg(short, long, value_name = "VERSION")]
+        version: String,
+
+        /// Where to write the update file
+        #[arg(short, long, value_name = "FILEPATH", default_value = luluu_update::FILE_NAME)]
+        output: PathBuf,
+    },
+    /// Check an update file, and show the version of the firmware in it
+    Check {
+        /// The file to check
+        #[arg(value_name = "FILEPATH", default_value = luluu_update::FILE_NAME)]
+        file_path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum ClockCommands {
+    /// Write a file that sets the device's clock when it starts up with it on the SD card
+    Set {
+        /// Where to write it
+        #[arg(value_name = "FILEPATH", default_value = luluu_config::time::FILE_NAME)]
+        file_path: PathBuf,
+
+        /// The time to set, like "2024-06-01 14:30:00". The current local time if not provided.
+        #[arg(short, long, value_name = "TIME")]
+        time: Option<String>,
+
+        /// Add this many seconds, to make up for the time it takes to move the card over and
+        /// start the device.
+        #[arg(short, long, value_name = "SECONDS", default_value_t = 0)]
+        ahead: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum DeviceCommands {
+    /// Show the firmware's version
+    Version,
+    /// Show the battery's voltage and whether it's charging
+    Battery,
+    /// List the animations on the SD card
+    List,
+    /// Switch to an animation on the SD card
+    Play {
+        /// Its file name, like FIREWORK.LU
+        #[arg(value_name = "NAME")]
+        name: String,
+    },
+    /// Set the backlight brightness, until the device is next turned on
+    Brightness {
+        #[arg(value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+        percent: u8,
+    },
+    /// Copy an animation onto the SD card and switch to it
+    Upload {
+        /// The .LU file to copy, with an 8.3 name
+        #[arg(value_name = "FILEPATH")]
+        file_path: PathBuf,
+    },
+    /// Check a settings file and copy it onto the SD card. It's used from the next time the
+    /// device starts up.
+    Settings {
+        /// The file to copy
+        #[arg(value_name = "FILEPATH", default_value = luluu_config::FILE_NAME)]
+        file_path: PathBuf,
+
+        /// Restart the device afterwards so they're used straight away
+        #[arg(long)]
+        reboot: bool,
+    },
+    /// Restart the device
+    Reboot,
+}
+
+/// A GIF's frames, in the device's pixels.
+struct Gif {
+    /// The width and height of the frames.
+    size: u8,
+    /// How long to show each frame for, in hundredths of a second.
+    delays: Vec<u16>,
+    /// All of the frames' pixels, one after the other.
+    pixels: Vec<Rgb565BE>,
+}
+
+fn read_gif(file_path: &Path) -> Result<Gif, eyre::Error> {
+    let file = File::open(file_path)
+        .wrap_err_with(|| format!("Failed to read GIF from {}", file_path.display()))?;
+
+    let mut decode_opts = gif::DecodeOptions::new();
+    decode_opts.set_color_output(gif::ColorOutput::RGBA);
+
+    let mut decoder = decode_opts.read_info(file)
+        .wrap_err_with(|| "Found the file, but failed to read it as a GIF.")?;
+
+    let mut delays = Vec::new();
+    let mut size = None;
+
+    let mut data_buf: Vec<Rgb565BE> = Vec::new();
+
+    let mut frame_number: u16 = 0;
+    while let Some(frame) = decoder.read_next_frame()
+        .wrap_err_with(|| format!("Failed to read frame {} of provided GIF", frame_number))?
+    {
+        let frame_size: u8  = match (frame.width, frame.height) {
+            (60, 60) => 60,
+            (120, 120) => 120,
+            (240, 240) => 240,
+            _ => eyre::bail!("Provided GIF is not the right size. It must be either 60x60, 120x120, or 240x240 pixels!"),
+        };
+
+        if *size.get_or_insert(frame_size) != frame_size {
+            eyre::bail!("Provided GIF has frames with different sizes. All frames must be 60x60, 120x120, or 240x240 pixels!");
+        }
+
+        delays.push(frame.delay);
+
+        let frame_data_len = frame_size as usize * frame_size as usize;
+        let data_start_idx = data_buf.len();
+        data_buf.resize(data_buf.len() + frame_data_len, Rgb565BE::ZERO);
+
+        let src_pixels = Rgba8888::cast_bytes(&*frame.buffer);
+        let dst_pixels = &mut data_buf[data_start_idx..(data_start_idx + frame_data_len)];
+
+        for (src_pixel, dst_pixel) in src_pixels.iter().zip(dst_pixels) {
+            let [r, g, b] = src_pixel.rgb();
+            let col_dre_srgb = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+            // let col_near = decode_srgb(col_dre_srgb);
+            let r_5 = ((col_dre_srgb.0 * (luluu_enc::MAX_5 as f32)) as u8).max(1u8);
+            let g_6 = ((col_dre_srgb.1 * (luluu_enc::MAX_6 as f32)) as u8).max(1u8);
+            let b_5 = ((col_dre_srgb.2 * (luluu_enc::MAX_5 as f32)) as u8).max(1u8);
+            let pixel_565 = Rgb565NE::pack_565(r_5, g_6, b_5);
+            *dst_pixel = pixel_565.to_be();
+        }
+
+        frame_number = frame_number
+            .checked_add(1)
+            .ok_or(eyre::eyre!("Too many frames in provided GIF!"))?;
     }
+
+    if frame_number == 0 {
+        eyre::bail!("Found a GIF but it had zero frames.");
+    }
+
+    Ok(Gif { size: size.unwrap(), delays, pixels: data_buf })
 }
 
-fn main() -> Result<(), eyre::Error> {
-    pretty_env_logger::init_custom_env("debug,luluu_enc=warn");
+#[derive(Subcommand)]
+enum StreamSource {
+    /// Stream a GIF, over and over
+    Gif {
+        /// The GIF to stream, 60x60, 120x120, or 240x240 pixels
+        #[arg(value_name = "FILEPATH")]
+        file_path: PathBuf,
 
-    let cli = Cli::parse();
-    match &cli.command {
-        Commands::Convert { file_path, frame_rate } => {
-            let file = File::open(file_path)
-                .wrap_err_with(|| format!("Failed to read file to convert from {}", file_path.display()))?;
+        /// Override the frame rate. Taken from the GIF if not provided.
+        #[arg(short, long, value_name = "FRAMERATE")]
+        frame_rate: Option<u8>,
+
+        /// Stream it once, then let the device go back to its card
+        #[arg(long)]
+        once: bool,
+    },
+    /// Stream a .LU file, over and over
+    Lu {
+        /// The file to stream
+        #[arg(value_name = "FILEPATH")]
+        file_path: PathBuf,
 
-            let mut decode_opts = gif::DecodeOptions::new();
-            decode_opts.set_color_output(gif::ColorOutput::RGBA);
+        /// Stream it once, then let the device go back to its card
+        #[arg(long)]
+        once: bool,
+    },
+    /// Stream part of the screen. Needs ffmpeg installed to capture it.
+    Screen {
+        /// The left edge of the part to stream, in the screen's pixels
+        #[arg(short, value_name = "X", default_value_t = 0)]
+        x: u32,
 
-            let mut decoder = decode_opts.read_info(file)
-                .wrap_err_with(|| "Found the file, but failed to read it as a GIF.")?;
+        /// The top edge of the part to stream
+        #[arg(short, value_name = "Y", default_value_t = 0)]
+        y: u32,
 
-            let mut delay = None;
-            let mut size = None;
+        /// How wide the part to stream is
+        #[arg(long, value_name = "WIDTH", default_value_t = 240)]
+        width: u32,
 
-            let mut data_buf: Vec<Rgb565BE> = Vec::new();
+        /// How tall the part to stream is
+        #[arg(long, value_name = "HEIGHT", default_value_t = 240)]
+        height: u32,
 
-            let mut frame_number: u16 = 0;
-            while let Some(frame) = decoder.read_next_frame()
-                .wrap_err_with(|| format!("Failed to read frame {} of provided GIF", frame_number))?
-            {
-                let frame_size: u8  = match (frame.width, frame.height) {
-                    (60, 60) => 60,
-                    (120, 120) => 120,
-                    (240, 240) => 240,
-                    _ => eyre::bail!("Provided GIF is not the right size. It must be either 60x60, 120x120, or 240x240 pixels!"),
-                };
+        /// What to scale it to for the device, 60, 120, or 240. Smaller is quicker to send.
+        #[arg(short, long, value_name = "SIZE", default_value_t = 120)]
+        size: u8,
 
-                if *size.get_or_insert(frame_size) != frame_size {
-                    eyre::bail!("Provided GIF has frames with different sizes. All frames must be 60x60, 120x120, or 240x240 pixels!");
-                }
+        /// Frames to capture a second
+        #[arg(short, long, value_name = "FRAMERATE", default_value_t = 10)]
+        frame_rate: u8,
+    },
+}
 
-                match &mut delay {
-                    delay @ None => *delay = Some(frame.delay),
-                    Some(delay) => if *delay != frame.delay {
-                        log::warn!("Detected uneven delay between frames. This is unsupported; computing frame rate based on first frame delay.")
-                    }
-                }
+/// Read and parse a settings file, logging the problems in it. Returns its bytes, the settings the
+/// device would end up with, and how many problems there were.
+fn read_settings(file_path: &Path) -> Result<(Vec<u8>, luluu_config::Config, usize), eyre::Error> {
+    let bytes = std::fs::read(file_path)
+        .wrap_err_with(|| format!("Failed to read settings file from {}", file_path.display()))?;
 
-                let frame_data_len = frame_size as usize * frame_size as usize;
-                let data_start_idx = data_buf.len();
-                data_buf.resize(data_buf.len() + frame_data_len, Rgb565BE::ZERO);
-
-                let src_pixels = Rgba8888::cast_bytes(&*frame.buffer);
-                let dst_pixels = &mut data_buf[data_start_idx..(data_start_idx + frame_data_len)];
-
-                for (src_pixel, dst_pixel) in src_pixels.iter().zip(dst_pixels) {
-                    let [r, g, b] = src_pixel.rgb();
-                    let col_dre_srgb = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
-                    // let col_near = decode_srgb(col_dre_srgb);
-                    let r_5 = ((col_dre_srgb.0 * (luluu_enc::MAX_5 as f32)) as u8).max(1u8);
-                    let g_6 = ((col_dre_srgb.1 * (luluu_enc::MAX_6 as f32)) as u8).max(1u8);
-                    let b_5 = ((col_dre_srgb.2 * (luluu_enc::MAX_5 as f32)) as u8).max(1u8);
-                    let pixel_565 = Rgb565NE::pack_565(r_5, g_6, b_5);
-                    *dst_pixel = pixel_565.to_be();
-                }
+    if bytes.len() > luluu_config::MAX_FILE_SIZE {
+        log::warn!(
+            "File is {} bytes, the device only reads the first {}.",
+            bytes.len(),
+            luluu_config::MAX_FILE_SIZE,
+        );
+    }
 
-                frame_number = frame_number
-                    .checked_add(1)
-                    .ok_or(eyre::eyre!("Too many frames in provided GIF!"))?;
-            }
+    let mut n_errors = 0;
+    let config = luluu_config::Config::parse(&bytes[..bytes.len().min(luluu_config::MAX_FILE_SIZE)], |error| {
+        log::error!("{}", error);
+        n_errors += 1;
+    });
+    Ok((bytes, config, n_errors))
+}
+
+fn main() -> Result<(), eyre::Error> {
+    pretty_env_logger::init_custom_env("debug,luluu_enc=warn");
 
-            if frame_number == 0 {
-                eyre::bail!("Found a GIF but it had zero frames.");
+    let cli = Cli::parse();
+    match &cli.command {
+        Commands::Convert { file_path, frame_rate } => {
+            let gif = read_gif(file_path)?;
+            if gif.delays.iter().any(|&delay| delay != gif.delays[0]) {
+                log::warn!("Detected uneven delay between frames. This is unsupported; computing frame rate based on first frame delay.")
             }
 
-            let size = luluu_enc::Size(size.unwrap());
+            let size = luluu_enc::Size(gif.size);
 
             let mut frame_rate = luluu_enc::FrameRate(frame_rate.unwrap_or_else(|| {
-                let delay = delay.unwrap().max(1);
+                let delay = gif.delays[0].max(1);
                 (100 / delay) as u8
             }));
 
             frame_rate.make_nearest_supported(size).unwrap();
 
-            // supported frame rates: 1, 2, 3, 4, 5, 6, 8, 10, 12, 15
-
-            drop(decoder);
+            // supported frame rates: 1, 2, 3, 4, 5, 6, 8, 10, 12, and 15, 20, 24, 30 below 240x240
 
             let header = luluu_enc::Header {
                 magic: MagicBytes::CORRECT,
-                version: luluu_enc::Version::ZERO,
+                version: luluu_enc::Version::ONE,
                 encoding: luluu_enc::Encoding::RGB565BE,
                 size,
                 frame_rate,
-                n_frames: luluu_enc::NumFrames::from_u16(frame_number),
+                n_frames: luluu_enc::NumFrames::from_u16(gif.delays.len() as u16),
             };
 
             let mut out_file_path = file_path.clone();
@@ -125,8 +358,221 @@ fn main() -> Result<(), eyre::Error> {
             out_file.write_all(header.as_bytes())
                 .wrap_err_with(|| "Failed to write output file.")?;
 
-            out_file.write_all(Rgb565BE::slice_as_bytes(&data_buf))
-                .wrap_err_with(|| "Failed to write output file.")?;
+            let frame_pixels = size.0 as usize * size.0 as usize;
+            let frames: Vec<&[Rgb565BE]> = gif.pixels.chunks_exact(frame_pixels).collect();
+            let mut dirty_pixels = 0;
+            for (i, frame) in frames.iter().enumerate() {
+                // the first frame comes after the last one when looping
+                let prev = frames[(i + frames.len() - 1) % frames.len()];
+                let dirty_rect = DirtyRect::between(prev, frame, size);
+                dirty_pixels += dirty_rect.width as usize * dirty_rect.height as usize;
+
+                out_file.write_all(&dirty_rect.to_bytes())
+                    .wrap_err_with(|| "Failed to write output file.")?;
+                out_file.write_all(Rgb565BE::slice_as_bytes(frame))
+                    .wrap_err_with(|| "Failed to write output file.")?;
+            }
+
+            log::info!(
+                "Wrote {} frames, {:.0}% of pixels change between frames.",
+                frames.len(),
+                100.0 * dirty_pixels as f32 / gif.pixels.len() as f32,
+            );
+        }
+        Commands::Config { command: ConfigCommands::Init { file_path, force } } => {
+            if file_path.exists() && !force {
+                eyre::bail!("{} already exists, pass --force to replace it.", file_path.display());
+            }
+
+            let mut ini = String::new();
+            luluu_config::Config::DEFAULT.write_ini(&mut ini)?;
+            std::fs::write(file_path, ini)
+                .wrap_err_with(|| format!("Could not write settings file: {}", file_path.display()))?;
+
+            log::info!("Wrote default settings to {}, copy it to the root of the SD card once you've edited it.", file_path.display());
+        }
+        Commands::Config { command: ConfigCommands::Check { file_path } } => {
+            if !file_path.file_name().is_some_and(|name| name.eq_ignore_ascii_case(luluu_config::FILE_NAME)) {
+                log::warn!("The device only reads settings from a file called {}.", luluu_config::FILE_NAME);
+            }
+            let (_, config, n_errors) = read_settings(file_path)?;
+
+            let mut ini = String::new();
+            config.write_ini(&mut ini)?;
+            println!("{}", ini);
+
+            if n_errors > 0 {
+                eyre::bail!("Found {} problems, those settings will be left at their defaults.", n_errors);
+            }
+            log::info!("No problems found.");
+        }
+        Commands::Clock { command: ClockCommands::Set { file_path, time, ahead } } => {
+            let time = match time {
+                Some(time) => chrono::NaiveDateTime::parse_from_str(time.trim(), "%Y-%m-%d %H:%M:%S")
+                    .or_else(|_| chrono::NaiveDateTime::parse_from_str(time.trim(), "%Y-%m-%d %H:%M"))
+                    .wrap_err_with(|| format!("Expected a time like \"2024-06-01 14:30:00\", got \"{}\"", time))?,
+                None => chrono::Local::now().naive_local(),
+            };
+            let time = time + chrono::Duration::seconds(*ahead as i64);
+
+            let text = time.format("%Y-%m-%d %H:%M:%S").to_string();
+            if luluu_config::time::DateTime::parse(&text).is_none() {
+                eyre::bail!(
+                    "The device can only be set to times from {} to {}.",
+                    luluu_config::time::DateTime::MIN_YEAR,
+                    luluu_config::time::DateTime::MAX_YEAR,
+                );
+            }
+            std::fs::write(file_path, format!("{}\n", text))
+                .wrap_err_with(|| format!("Could not write time file: {}", file_path.display()))?;
+
+            log::info!(
+                "Wrote {} to {}, copy it to the root of the SD card. The device sets its clock to it when it starts up.",
+                text,
+                file_path.display(),
+            );
+        }
+        Commands::Device { port, command } => {
+            let mut device = device::Device::open(port)?;
+            match command {
+                DeviceCommands::Version => match device.request(&Request::Version)? {
+                    Response::Version { version } => println!("{}", version),
+                    response => eyre::bail!("Unexpected answer from the device: {:?}", response),
+                },
+                DeviceCommands::Battery => match device.request(&Request::Battery)? {
+                    Response::Battery { millivolts, charging } => {
+                        match millivolts {
+                            Some(millivolts) => println!("{:.2} V", millivolts as f32 / 1000.0),
+                            None => println!("Couldn't measure the battery"),
+                        }
+                        if charging {
+                            println!("Charging");
+                        }
+                    }
+                    response => eyre::bail!("Unexpected answer from the device: {:?}", response),
+                },
+                DeviceCommands::List => match device.request(&Request::ListFiles)? {
+                    Response::Files { names } => {
+                        for name in names.lines() {
+                            println!("{}", name);
+                        }
+                    }
+                    response => eyre::bail!("Unexpected answer from the device: {:?}", response),
+                },
+                DeviceCommands::Play { name } => {
+                    device.command(&Request::Play { name: &name.to_ascii_uppercase() })?;
+                }
+                DeviceCommands::Brightness { percent } => {
+                    device.command(&Request::SetBrightness { percent: *percent })?;
+                }
+                DeviceCommands::Upload { file_path } => {
+                    let name = device::card_name(file_path)?;
+                    let bytes = std::fs::read(file_path)
+                        .wrap_err_with(|| format!("Failed to read file to upload from {}", file_path.display()))?;
+
+                    let header = bytes.get(..luluu_enc::HEADER_SIZE)
+                        .and_then(|header| luluu_enc::Header::decode(header.try_into().unwrap()).ok());
+                    if header.is_none() {
+                        eyre::bail!("{} isn't an animation, make one with the convert command.", file_path.display());
+                    }
+                    if !name.ends_with(".LU") {
+                        log::warn!("The device only plays files ending in .LU.");
+                    }
+
+                    let mut last_percent = None;
+                    device.upload(&name, &bytes, |sent| {
+                        let percent = sent * 100 / bytes.len();
+                        if last_percent.replace(percent / 10) != Some(percent / 10) {
+                            log::info!("Uploaded {}%", percent);
+                        }
+                    })?;
+                    log::info!("Uploaded {} to the card.", name);
+                }
+                DeviceCommands::Settings { file_path, reboot } => {
+                    let (bytes, _, n_errors) = read_settings(file_path)?;
+                    if n_errors > 0 {
+                        eyre::bail!("Found {} problems, fix them before copying the settings to the device.", n_errors);
+                    }
+
+                    device.upload(luluu_config::FILE_NAME, &bytes, |_| ())?;
+                    if *reboot {
+                        device.command(&Request::Reboot)?;
+                        log::info!("Copied the settings to the card, the device is restarting to use them.");
+                    } else {
+                        log::info!("Copied the settings to the card, they're used from the next time the device starts up.");
+                    }
+                }
+                DeviceCommands::Reboot => device.command(&Request::Reboot)?,
+            }
+        }
+        Commands::Stream { port, raw, source } => {
+            let mut streamer = stream::Streamer::new(device::Device::open(port)?, !raw);
+            let once = match source {
+                StreamSource::Gif { file_path, frame_rate, once } => {
+                    let gif = read_gif(file_path)?;
+                    let size = luluu_enc::Size(gif.size);
+                    let frame_pixels = gif.size as usize * gif.size as usize;
+                    loop {
+                        for (frame, &delay) in gif.pixels.chunks_exact(frame_pixels).zip(&gif.delays) {
+                            let duration = match frame_rate {
+                                Some(frame_rate) => std::time::Duration::from_secs(1) / (*frame_rate).max(1) as u32,
+                                None => std::time::Duration::from_millis(delay.max(1) as u64 * 10),
+                            };
+                            streamer.show(size, frame, duration)?;
+                        }
+                        if *once {
+                            break true;
+                        }
+                    }
+                }
+                StreamSource::Lu { file_path, once } => {
+                    stream::stream_lu(&mut streamer, file_path, *once)?;
+                    true
+                }
+                StreamSource::Screen { x, y, width, height, size, frame_rate } => {
+                    if !luluu_enc::Size(*size).is_supported() {
+                        eyre::bail!("The size has to be 60, 120, or 240.");
+                    }
+                    let region = stream::Region { x: *x, y: *y, width: *width, height: *height };
+                    stream::stream_screen(&mut streamer, &region, luluu_enc::Size(*size), (*frame_rate).max(1))?;
+                    true
+                }
+            };
+            if once {
+                streamer.end()?;
+            }
+        }
+        Commands::Firmware { command: FirmwareCommands::Pack { file_path, version, output } } => {
+            let version = luluu_update::Version::parse(version)
+                .ok_or_else(|| eyre::eyre!("Expected a version like 0.2.0, got \"{}\"", version))?;
+            let bytes = std::fs::read(file_path)
+                .wrap_err_with(|| format!("Failed to read firmware from {}", file_path.display()))?;
+            let is_uf2 = file_path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("uf2"));
+            let flash = match is_uf2 {
+                true => firmware::from_uf2(&bytes)?,
+                false => bytes,
+            };
+
+            let image = firmware::pack(version, &flash)?;
+            std::fs::write(output, &image)
+                .wrap_err_with(|| format!("Could not write update file: {}", output.display()))?;
+
+            if !output.file_name().is_some_and(|name| name.eq_ignore_ascii_case(luluu_update::FILE_NAME)) {
+                log::warn!("The device only updates from a file called {}.", luluu_update::FILE_NAME);
+            }
+            log::info!(
+                "Wrote firmware {} ({} bytes) to {}, copy it to the root of the SD card. The device installs it when it next starts up.",
+                version,
+                flash.len(),
+                output.display(),
+            );
+        }
+        Commands::Firmware { command: FirmwareCommands::Check { file_path } } => {
+            let image = std::fs::read(file_path)
+                .wrap_err_with(|| format!("Failed to read update file from {}", file_path.display()))?;
+            let header = firmware::check(&image)
+                .map_err(|error| eyre::eyre!("{} can't be installed: {}.", file_path.display(), error))?;
+            log::info!("{} is firmware {}, {} bytes, and isn't damaged.", file_path.display(), header.version, header.len);
         }
     }
 
@@ -0,0 +1,164 @@
+//! Streaming frames straight to the display of a device plugged in over USB, from a GIF, a .LU
+//! file, or the screen.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use eyre::WrapErr;
+use luluu_enc::{DirtyRect, Rgb565BE, Size};
+
+use crate::device::Device;
+
+/// Sends frames to the device, only the part of each that's changed since the last, at the pace
+/// they're meant to be shown.
+pub struct Streamer {
+    device: Device,
+    packed: bool,
+    /// The last frame sent, and its size.
+    last: Option<(Size, Vec<Rgb565BE>)>,
+    /// When the next frame's due on the display.
+    due: Option<Instant>,
+    n_frames: u32,
+    n_bytes: usize,
+}
+
+impl Streamer {
+    pub fn new(device: Device, packed: bool) -> Self {
+        Self { device, packed, last: None, due: None, n_frames: 0, n_bytes: 0 }
+    }
+
+    /// Show `frame`, which is `size` by `size`, for `duration`. Waits until the last one's been
+    /// shown for as long as it should be first.
+    pub fn show(&mut self, size: Size, frame: &[Rgb565BE], duration: Duration) -> Result<(), eyre::Error> {
+        let rect = match &self.last {
+            Some((last_size, last)) if *last_size == size => DirtyRect::between(last, frame, size),
+            _ => DirtyRect::full(size),
+        };
+        let pixels = rect_pixels(frame, size, rect);
+
+        if let Some(due) = self.due {
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        // frames that take too long to send push the rest back, rather than being hurried after
+        let sent_at = Instant::now();
+        self.device.stream_frame(size, rect, &pixels, self.packed)?;
+        self.due = Some(sent_at + duration);
+
+        self.last = Some((size, frame.to_vec()));
+        self.n_frames += 1;
+        self.n_bytes += pixels.len() * 2;
+        if self.n_frames.is_multiple_of(100) {
+            log::info!("Streamed {} frames, {} KiB of pixels.", self.n_frames, self.n_bytes / 1024);
+        }
+        Ok(())
+    }
+
+    /// Let the device go back to the animations on its card.
+    pub fn end(mut self) -> Result<(), eyre::Error> {
+        self.device.command(&luluu_proto::Request::StreamEnd)
+    }
+}
+
+/// The pixels of `frame` in `rect`, row by row.
+fn rect_pixels(frame: &[Rgb565BE], size: Size, rect: DirtyRect) -> Vec<Rgb565BE> {
+    let (x, width) = (rect.x as usize, rect.width as usize);
+    frame
+        .chunks_exact(size.0 as usize)
+        .skip(rect.y as usize)
+        .take(rect.height as usize)
+        .flat_map(|row| &row[x..x + width])
+        .copied()
+        .collect()
+}
+
+/// Stream a .LU file, once or over and over.
+pub fn stream_lu(streamer: &mut Streamer, file_path: &Path, once: bool) -> Result<(), eyre::Error> {
+    let bytes = std::fs::read(file_path)
+        .wrap_err_with(|| format!("Failed to read .LU file from {}", file_path.display()))?;
+    let header = bytes
+        .get(..luluu_enc::HEADER_SIZE)
+        .and_then(|header| luluu_enc::Header::decode(header.try_into().unwrap()).ok())
+        .ok_or_else(|| eyre::eyre!("{} isn't an animation, make one with the convert command.", file_path.display()))?;
+    if header.encoding != luluu_enc::Encoding::RGB565BE {
+        eyre::bail!("Only RGB565 animations can be streamed.");
+    }
+
+    let n_frames = header.n_frames.as_u16() as u32;
+    let duration = Duration::from_secs(1) / header.frame_rate.0 as u32;
+    // the pixels come after each frame's dirty rect, if it has one
+    let pixels_offset = header.frame_bytes() - header.pixel_bytes();
+    loop {
+        for frame in 0..n_frames {
+            let start = (header.frame_offset(frame) + pixels_offset) as usize;
+            let pixels = bytes
+                .get(start..start + header.pixel_bytes() as usize)
+                .ok_or_else(|| eyre::eyre!("{} is cut short, it ends part way through frame {}.", file_path.display(), frame))?;
+            streamer.show(header.size, Rgb565BE::cast_bytes(pixels), duration)?;
+        }
+        if once {
+            return Ok(());
+        }
+    }
+}
+
+/// Where on the screen to stream from, in its own pixels.
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Stream `region` of the screen, scaled to `size`, until ffmpeg stops. ffmpeg does the capturing
+/// and scaling, since it knows how to on every platform, and keeps to `frame_rate` too.
+pub fn stream_screen(streamer: &mut Streamer, region: &Region, size: Size, frame_rate: u8) -> Result<(), eyre::Error> {
+    let Region { x, y, width, height } = *region;
+    let frame_rate = frame_rate.to_string();
+    let video_size = format!("{}x{}", width, height);
+    let mut scale = format!("scale={0}:{0}:flags=area", size.0);
+
+    let mut ffmpeg = Command::new("ffmpeg");
+    ffmpeg.args(["-loglevel", "error"]);
+    if cfg!(target_os = "windows") {
+        ffmpeg.args(["-f", "gdigrab", "-framerate", &frame_rate]);
+        ffmpeg.args(["-offset_x", &x.to_string(), "-offset_y", &y.to_string(), "-video_size", &video_size]);
+        ffmpeg.args(["-i", "desktop"]);
+    } else if cfg!(target_os = "macos") {
+        ffmpeg.args(["-f", "avfoundation", "-framerate", &frame_rate, "-i", "1:none"]);
+        scale = format!("crop={}:{}:{}:{},{}", width, height, x, y, scale);
+    } else {
+        let display = std::env::var("DISPLAY").unwrap_or_else(|_| ":0".to_string());
+        ffmpeg.args(["-f", "x11grab", "-framerate", &frame_rate, "-video_size", &video_size]);
+        ffmpeg.args(["-i", &format!("{}+{},{}", display, x, y)]);
+    }
+    ffmpeg.args(["-vf", &scale, "-pix_fmt", "rgb565be", "-f", "rawvideo", "-"]);
+
+    let mut child = ffmpeg
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .wrap_err("Could not run ffmpeg, which is needed to capture the screen. Is it installed?")?;
+    let mut stdout = child.stdout.take().unwrap();
+
+    let mut frame = vec![0; size.0 as usize * size.0 as usize * 2];
+    let result = loop {
+        if let Err(error) = stdout.read_exact(&mut frame) {
+            break match error.kind() {
+                std::io::ErrorKind::UnexpectedEof => Ok(()),
+                _ => Err(error).wrap_err("Failed to read frames from ffmpeg"),
+            };
+        }
+        // ffmpeg keeps the pace, so they're shown as soon as they arrive
+        if let Err(error) = streamer.show(size, Rgb565BE::cast_bytes(&frame), Duration::ZERO) {
+            break Err(error);
+        }
+    };
+    let _ = child.kill();
+    let status = child.wait()?;
+    if result.is_ok() && !status.success() {
+        eyre::bail!("ffmpeg stopped with {}, see above for why.", status);
+    }
+    result
+}
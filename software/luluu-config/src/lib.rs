@@ -0,0 +1,800 @@
+#![no_std]
+
+//! The LuLuu's settings file, `LULUU.INI` in the root of the SD card.
+//!
+//! It's INI style: `key = value` lines in `[section]`s, with comments starting with `;` or `#`.
+//! Anything left out, or that isn't valid, keeps its default, so a file only needs the settings
+//! that are being changed. For example:
+//!
+//! ```ini
+//! [display]
+//! brightness = 60
+//! rotation = 180
+//!
+//! [playback]
+//! order = sequential
+//! change_every = 300
+//! ```
+//!
+//! The `[schedule]` section is different, its lines are rules for changing animations and
+//! brightness by the time of day. See [`schedule`].
+//!
+//! `[gestures]` picks what tapping, shaking and flipping the sleeve do, one of `next`,
+//! `previous`, `shuffle`, `pause` or `none`:
+//!
+//! ```ini
+//! [gestures]
+//! tap = none
+//! double_tap = next
+//! shake = shuffle
+//! wrist_flip = pause
+//! ```
+//!
+//! `[cache] files` picks animations to copy into the LuLuu's own flash memory, where they play
+//! from without a card, and faster than from the card:
+//!
+//! ```ini
+//! [cache]
+//! files = FIREWORK.LU HEART*
+//! ```
+//!
+//! This crate has no hardware dependencies, so the same parser checks files on the host in
+//! `luluu-cli` as reads them on the device.
+
+use core::fmt;
+
+use crate::schedule::{FileName, Schedule};
+
+pub mod schedule;
+pub mod time;
+
+/// The name of the settings file in the root of the SD card.
+pub const FILE_NAME: &str = "LULUU.INI";
+
+/// The biggest settings file that's read, anything past this is ignored.
+pub const MAX_FILE_SIZE: usize = 2048;
+
+/// The most file names in a list of them, like `[cache] files`.
+pub const MAX_LISTED_FILES: usize = 8;
+
+/// Turning the display towards gravity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AutoRotate {
+    Off,
+    /// Only turn it upside down, like when the sleeve's moved to the other arm.
+    Flip,
+    /// Keep the image the right way up whichever edge of the display is up.
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PlayOrder {
+    /// Start with a random animation, and pick another at random each time.
+    Shuffle,
+    /// Go through the animations in the order they're on the card.
+    Sequential,
+}
+
+/// Showing the time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClockFace {
+    Off,
+    /// In small digits over the bottom of the animation.
+    Over,
+    /// In big digits, instead of the animation.
+    Instead,
+}
+
+/// Something the wearer can ask the player to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    /// Switch to the next animation on the card.
+    Next,
+    /// Switch to the previous animation on the card.
+    Previous,
+    /// Switch to a random other animation on the card.
+    Shuffle,
+    /// Freeze on the current frame, or resume if already paused.
+    TogglePause,
+}
+
+/// Which action each gesture triggers, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GestureActions {
+    pub tap: Option<Action>,
+    pub double_tap: Option<Action>,
+    pub shake: Option<Action>,
+    pub wrist_flip: Option<Action>,
+}
+
+impl GestureActions {
+    /// Single taps are too easy to trigger by accident, so they do nothing by default.
+    pub const DEFAULT: Self = Self {
+        tap: None,
+        double_tap: Some(Action::Next),
+        shake: Some(Action::Shuffle),
+        wrist_flip: Some(Action::TogglePause),
+    };
+}
+
+impl Default for GestureActions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Backlight brightness at power on, in percent.
+    pub brightness_percent: u8,
+    /// Clockwise, 0, 90, 180 or 270.
+    pub rotation_degrees: u16,
+    pub mirror_x: bool,
+    pub mirror_y: bool,
+    pub auto_rotate: AutoRotate,
+    /// Invert the display's colours. The panel on the LuLuu needs this on.
+    pub invert_colors: bool,
+    pub order: PlayOrder,
+    /// Move on to the next animation after this long. 0 keeps playing the same one.
+    pub change_every_seconds: u32,
+    /// Dim the backlight after this long without motion or other activity. 0 never dims.
+    pub dim_after_seconds: u32,
+    /// Go to sleep after this long without motion or other activity. 0 never sleeps.
+    pub sleep_after_seconds: u32,
+    /// Change in acceleration between two samples, summed over all axes, that counts as moving.
+    pub motion_threshold_mg: u16,
+    /// Gravity into the display above which it's facing the wearer, for a wrist raise to wake it.
+    pub raise_min_z_mg: u16,
+    /// Most gravity along the display's other axes for it to still be facing the wearer.
+    pub raise_max_tilt_mg: u16,
+    /// How long the display has to be held facing the wearer to wake it.
+    pub raise_hold_millis: u32,
+    /// Wake up on a tap as well as a wrist raise.
+    pub wake_on_tap: bool,
+    /// How the time is shown.
+    pub clock_face: ClockFace,
+    /// Show the date under the time.
+    pub clock_date: bool,
+    /// Show the time as 1:30pm instead of 13:30.
+    pub clock_twelve_hour: bool,
+    pub gestures: GestureActions,
+    /// Animations and brightness by the time of day.
+    pub schedule: Schedule,
+    /// Let a computer plugged in over USB use the card as a drive, pausing playback meanwhile.
+    pub usb_drive: bool,
+    /// The animations on the card to keep copies of in flash.
+    pub cache_files: FileNames,
+    /// SPI clock for reading the SD card, once it's initialized.
+    pub sd_clock_khz: u32,
+    /// SPI clock for the display.
+    pub display_clock_khz: u32,
+}
+
+impl Config {
+    pub const DEFAULT: Self = Self {
+        brightness_percent: 100,
+        rotation_degrees: 0,
+        mirror_x: false,
+        mirror_y: false,
+        auto_rotate: AutoRotate::Off,
+        invert_colors: true,
+        order: PlayOrder::Shuffle,
+        change_every_seconds: 0,
+        dim_after_seconds: 20,
+        sleep_after_seconds: 30,
+        motion_threshold_mg: 120,
+        raise_min_z_mg: 750,
+        raise_max_tilt_mg: 450,
+        raise_hold_millis: 150,
+        wake_on_tap: true,
+        clock_face: ClockFace::Off,
+        clock_date: false,
+        clock_twelve_hour: false,
+        gestures: GestureActions::DEFAULT,
+        schedule: Schedule::EMPTY,
+        usb_drive: true,
+        cache_files: FileNames::EMPTY,
+        sd_clock_khz: 31_250,
+        display_clock_khz: 62_500,
+    };
+
+    /// Read a settings file. Each problem with it is passed to `on_error` and the setting it was
+    /// for keeps its default, so this always gives a usable config.
+    pub fn parse(bytes: &[u8], mut on_error: impl FnMut(Error)) -> Self {
+        let mut config = Self::DEFAULT;
+
+        let Ok(text) = core::str::from_utf8(bytes) else {
+            on_error(Error { line: 0, kind: ErrorKind::NotText });
+            return config;
+        };
+
+        let mut section = None;
+        let mut seen_section = false;
+        for (idx, line) in text.lines().enumerate() {
+            let line_number = idx as u32 + 1;
+            let mut error = |kind| on_error(Error { line: line_number, kind });
+
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Section::from_name(name.trim());
+                seen_section = true;
+                if section.is_none() {
+                    error(ErrorKind::UnknownSection);
+                }
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                error(ErrorKind::NotKeyValue);
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+
+            // keys in an unknown section were already reported with it
+            let Some(section) = section else {
+                if !seen_section {
+                    error(ErrorKind::NoSection);
+                }
+                continue;
+            };
+
+            // its lines are rules rather than settings
+            if section == Section::Schedule {
+                if let Err(kind) = config.schedule.push(key, value) {
+                    error(kind);
+                }
+                continue;
+            }
+
+            let Some(key) = KEYS.iter().find(|k| k.section == section && k.name.eq_ignore_ascii_case(key)) else {
+                error(ErrorKind::UnknownKey);
+                continue;
+            };
+            if let Err(kind) = config.set(section, key.name, value) {
+                error(kind);
+            }
+        }
+
+        config
+    }
+
+    /// Write out a settings file with every setting in it, at the values in `self`, and comments
+    /// explaining them.
+    pub fn write_ini(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(out, "; LuLuu settings. Anything left out keeps its default.")?;
+        let mut last_section = None;
+        for key in KEYS {
+            if last_section != Some(key.section) {
+                writeln!(out)?;
+                writeln!(out, "[{}]", key.section.name())?;
+                last_section = Some(key.section);
+            }
+            writeln!(out, "; {}", key.help)?;
+            writeln!(out, "{} = {}", key.name, self.get(key.section, key.name).unwrap())?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "[{}]", Section::Schedule.name())?;
+        writeln!(out, "; rules for the time of day, the first that applies wins: [days] [HH:MM-HH:MM] = [files] [brightness%]")?;
+        writeln!(out, "; files can have * and ? wildcards in, and every file plays if none are given")?;
+        writeln!(out, "; for example: 22:00-07:00 = CALM* 20%")?;
+        writeln!(out, ";          or: mon-fri 08:00-08:10 = MORNING")?;
+        for rule in self.schedule.rules() {
+            writeln!(out, "{}", rule)?;
+        }
+        Ok(())
+    }
+
+    /// `key` is one of the names in [`KEYS`].
+    fn set(&mut self, section: Section, key: &str, value: &str) -> Result<(), ErrorKind> {
+        match (section, key) {
+            (Section::Display, "brightness") => self.brightness_percent = parse_number(value, 1, 100)? as u8,
+            (Section::Display, "rotation") => {
+                self.rotation_degrees = match value {
+                    "0" => 0,
+                    "90" => 90,
+                    "180" => 180,
+                    "270" => 270,
+                    _ => return Err(ErrorKind::InvalidValue { expected: "0, 90, 180 or 270" }),
+                }
+            }
+            (Section::Display, "mirror_x") => self.mirror_x = parse_bool(value)?,
+            (Section::Display, "mirror_y") => self.mirror_y = parse_bool(value)?,
+            (Section::Display, "auto_rotate") => {
+                self.auto_rotate = match_word(value, &[("off", AutoRotate::Off), ("flip", AutoRotate::Flip), ("any", AutoRotate::Any)])
+                    .ok_or(ErrorKind::InvalidValue { expected: "off, flip or any" })?
+            }
+            (Section::Display, "invert_colors") => self.invert_colors = parse_bool(value)?,
+            (Section::Playback, "order") => {
+                self.order = match_word(value, &[("shuffle", PlayOrder::Shuffle), ("sequential", PlayOrder::Sequential)])
+                    .ok_or(ErrorKind::InvalidValue { expected: "shuffle or sequential" })?
+            }
+            (Section::Playback, "change_every") => self.change_every_seconds = parse_number(value, 0, MAX_SECONDS)?,
+            (Section::Power, "dim_after") => self.dim_after_seconds = parse_number(value, 0, MAX_SECONDS)?,
+            (Section::Power, "sleep_after") => self.sleep_after_seconds = parse_number(value, 0, MAX_SECONDS)?,
+            (Section::Power, "motion_threshold") => self.motion_threshold_mg = parse_number(value, 1, 4000)? as u16,
+            (Section::Power, "raise_min_z") => self.raise_min_z_mg = parse_number(value, 0, 1000)? as u16,
+            (Section::Power, "raise_max_tilt") => self.raise_max_tilt_mg = parse_number(value, 0, 1000)? as u16,
+            (Section::Power, "raise_hold") => self.raise_hold_millis = parse_number(value, 0, 5000)?,
+            (Section::Power, "wake_on_tap") => self.wake_on_tap = parse_bool(value)?,
+            (Section::Clock, "face") => {
+                self.clock_face = match_word(value, &[
+                    ("off", ClockFace::Off),
+                    ("over", ClockFace::Over),
+                    ("instead", ClockFace::Instead),
+                ])
+                .ok_or(ErrorKind::InvalidValue { expected: "off, over or instead" })?
+            }
+            (Section::Clock, "date") => self.clock_date = parse_bool(value)?,
+            (Section::Clock, "twelve_hour") => self.clock_twelve_hour = parse_bool(value)?,
+            (Section::Gestures, "tap") => self.gestures.tap = parse_action(value)?,
+            (Section::Gestures, "double_tap") => self.gestures.double_tap = parse_action(value)?,
+            (Section::Gestures, "shake") => self.gestures.shake = parse_action(value)?,
+            (Section::Gestures, "wrist_flip") => self.gestures.wrist_flip = parse_action(value)?,
+            (Section::Usb, "drive") => self.usb_drive = parse_bool(value)?,
+            (Section::Cache, "files") => self.cache_files = FileNames::parse(value)?,
+            (Section::Spi, "sd_clock") => self.sd_clock_khz = parse_number(value, 400, 31_250)?,
+            (Section::Spi, "display_clock") => self.display_clock_khz = parse_number(value, 1_000, 62_500)?,
+            _ => return Err(ErrorKind::UnknownKey),
+        }
+        Ok(())
+    }
+
+    fn get(&self, section: Section, key: &str) -> Option<Value> {
+        Some(match (section, key) {
+            (Section::Display, "brightness") => Value::Number(self.brightness_percent as u32),
+            (Section::Display, "rotation") => Value::Number(self.rotation_degrees as u32),
+            (Section::Display, "mirror_x") => Value::Bool(self.mirror_x),
+            (Section::Display, "mirror_y") => Value::Bool(self.mirror_y),
+            (Section::Display, "auto_rotate") => Value::Word(match self.auto_rotate {
+                AutoRotate::Off => "off",
+                AutoRotate::Flip => "flip",
+                AutoRotate::Any => "any",
+            }),
+            (Section::Display, "invert_colors") => Value::Bool(self.invert_colors),
+            (Section::Playback, "order") => Value::Word(match self.order {
+                PlayOrder::Shuffle => "shuffle",
+                PlayOrder::Sequential => "sequential",
+            }),
+            (Section::Playback, "change_every") => Value::Number(self.change_every_seconds),
+            (Section::Power, "dim_after") => Value::Number(self.dim_after_seconds),
+            (Section::Power, "sleep_after") => Value::Number(self.sleep_after_seconds),
+            (Section::Power, "motion_threshold") => Value::Number(self.motion_threshold_mg as u32),
+            (Section::Power, "raise_min_z") => Value::Number(self.raise_min_z_mg as u32),
+            (Section::Power, "raise_max_tilt") => Value::Number(self.raise_max_tilt_mg as u32),
+            (Section::Power, "raise_hold") => Value::Number(self.raise_hold_millis),
+            (Section::Power, "wake_on_tap") => Value::Bool(self.wake_on_tap),
+            (Section::Clock, "face") => Value::Word(match self.clock_face {
+                ClockFace::Off => "off",
+                ClockFace::Over => "over",
+                ClockFace::Instead => "instead",
+            }),
+            (Section::Clock, "date") => Value::Bool(self.clock_date),
+            (Section::Clock, "twelve_hour") => Value::Bool(self.clock_twelve_hour),
+            (Section::Gestures, "tap") => Value::Word(action_name(self.gestures.tap)),
+            (Section::Gestures, "double_tap") => Value::Word(action_name(self.gestures.double_tap)),
+            (Section::Gestures, "shake") => Value::Word(action_name(self.gestures.shake)),
+            (Section::Gestures, "wrist_flip") => Value::Word(action_name(self.gestures.wrist_flip)),
+            (Section::Usb, "drive") => Value::Bool(self.usb_drive),
+            (Section::Cache, "files") => Value::Files(self.cache_files),
+            (Section::Spi, "sd_clock") => Value::Number(self.sd_clock_khz),
+            (Section::Spi, "display_clock") => Value::Number(self.display_clock_khz),
+            _ => return None,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// File names, separated by spaces or commas, which can have `*` and `?` wildcards in like in the
+/// schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileNames {
+    names: [FileName; MAX_LISTED_FILES],
+    len: u8,
+}
+
+impl FileNames {
+    pub const EMPTY: Self = Self { names: [FileName::EMPTY; MAX_LISTED_FILES], len: 0 };
+
+    fn parse(value: &str) -> Result<Self, ErrorKind> {
+        let mut names = Self::EMPTY;
+        for word in value.split([' ', '\t', ',']).filter(|word| !word.is_empty()) {
+            let slot = names.names.get_mut(names.len as usize).ok_or(ErrorKind::InvalidValue {
+                expected: "at most 8 file names",
+            })?;
+            *slot = FileName::new(word).ok_or(ErrorKind::InvalidValue {
+                expected: "file names of up to 12 characters",
+            })?;
+            names.len += 1;
+        }
+        Ok(names)
+    }
+
+    pub fn names(&self) -> &[FileName] {
+        &self.names[..self.len as usize]
+    }
+
+    /// Whether `file_name` matches any of the names. None do if there aren't any.
+    pub fn matches(&self, file_name: &str) -> bool {
+        self.names().iter().any(|name| name.matches(file_name))
+    }
+}
+
+impl Default for FileNames {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for FileNames {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", self.names())
+    }
+}
+
+/// A problem with a settings file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Error {
+    /// Counting from 1, or 0 for the file as a whole.
+    pub line: u32,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ErrorKind {
+    /// The file isn't UTF-8 text.
+    NotText,
+    /// A line that isn't a section, a `key = value` or a comment.
+    NotKeyValue,
+    UnknownSection,
+    /// A setting before the first section.
+    NoSection,
+    UnknownKey,
+    InvalidValue { expected: &'static str },
+    OutOfRange { min: u32, max: u32 },
+    /// More rules in the schedule than it has room for.
+    TooManyRules { max: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "line {}: ", self.line)?;
+        }
+        match self.kind {
+            ErrorKind::NotText => write!(f, "not a text file"),
+            ErrorKind::NotKeyValue => write!(f, "expected `[section]` or `key = value`"),
+            ErrorKind::UnknownSection => {
+                write!(f, "unknown section, expected one of display, playback, power, clock, gestures, schedule, usb, cache or spi")
+            }
+            ErrorKind::NoSection => write!(f, "settings need to be in a `[section]`"),
+            ErrorKind::UnknownKey => write!(f, "unknown setting for this section"),
+            ErrorKind::InvalidValue { expected } => write!(f, "invalid value, expected {}", expected),
+            ErrorKind::OutOfRange { min, max } => write!(f, "out of range, expected {} to {}", min, max),
+            ErrorKind::TooManyRules { max } => write!(f, "too many rules, only the first {} are used", max),
+        }
+    }
+}
+
+/// A day, which is plenty for any of the timeouts.
+const MAX_SECONDS: u32 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Display,
+    Playback,
+    Power,
+    Clock,
+    Gestures,
+    Schedule,
+    Usb,
+    Cache,
+    Spi,
+}
+
+impl Section {
+    fn from_name(name: &str) -> Option<Self> {
+        match_word(name, &[
+            ("display", Self::Display),
+            ("playback", Self::Playback),
+            ("power", Self::Power),
+            ("clock", Self::Clock),
+            ("gestures", Self::Gestures),
+            ("schedule", Self::Schedule),
+            ("usb", Self::Usb),
+            ("cache", Self::Cache),
+            ("spi", Self::Spi),
+        ])
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Display => "display",
+            Self::Playback => "playback",
+            Self::Power => "power",
+            Self::Clock => "clock",
+            Self::Gestures => "gestures",
+            Self::Schedule => "schedule",
+            Self::Usb => "usb",
+            Self::Cache => "cache",
+            Self::Spi => "spi",
+        }
+    }
+}
+
+struct Key {
+    section: Section,
+    name: &'static str,
+    help: &'static str,
+}
+
+/// Every setting, in the order they're written out.
+const KEYS: &[Key] = &[
+    Key { section: Section::Display, name: "brightness", help: "backlight brightness at power on, 1 to 100 percent" },
+    Key { section: Section::Display, name: "rotation", help: "turn the image clockwise by 0, 90, 180 or 270 degrees" },
+    Key { section: Section::Display, name: "mirror_x", help: "flip the image left to right, true or false" },
+    Key { section: Section::Display, name: "mirror_y", help: "flip the image top to bottom, true or false" },
+    Key { section: Section::Display, name: "auto_rotate", help: "follow gravity: off, flip (upside down only) or any" },
+    Key { section: Section::Display, name: "invert_colors", help: "invert the panel's colours, the LuLuu's panel needs true" },
+    Key { section: Section::Playback, name: "order", help: "shuffle or sequential" },
+    Key { section: Section::Playback, name: "change_every", help: "seconds before moving on to the next animation, 0 for never" },
+    Key { section: Section::Power, name: "dim_after", help: "seconds without movement before dimming, 0 for never" },
+    Key { section: Section::Power, name: "sleep_after", help: "seconds without movement before sleeping, 0 for never" },
+    Key { section: Section::Power, name: "motion_threshold", help: "change in acceleration that counts as movement, 1 to 4000 mg" },
+    Key { section: Section::Power, name: "raise_min_z", help: "gravity into the display for it to face you when raised, 0 to 1000 mg" },
+    Key { section: Section::Power, name: "raise_max_tilt", help: "most sideways gravity for the display to still face you, 0 to 1000 mg" },
+    Key { section: Section::Power, name: "raise_hold", help: "milliseconds to hold the display facing you to wake it, 0 to 5000" },
+    Key { section: Section::Power, name: "wake_on_tap", help: "wake up on a tap too, true or false" },
+    Key { section: Section::Clock, name: "face", help: "show the time: off, over the animation or instead of it" },
+    Key { section: Section::Clock, name: "date", help: "show the date under the time, true or false" },
+    Key { section: Section::Clock, name: "twelve_hour", help: "show the time as 1:30pm instead of 13:30, true or false" },
+    Key { section: Section::Gestures, name: "tap", help: "what a tap does: next, previous, shuffle, pause or none" },
+    Key { section: Section::Gestures, name: "double_tap", help: "what a double tap does: next, previous, shuffle, pause or none" },
+    Key { section: Section::Gestures, name: "shake", help: "what a shake does: next, previous, shuffle, pause or none" },
+    Key { section: Section::Gestures, name: "wrist_flip", help: "what flipping the display over does: next, previous, shuffle, pause or none" },
+    Key { section: Section::Usb, name: "drive", help: "show the card as a drive on a computer, pausing playback, true or false" },
+    Key { section: Section::Cache, name: "files", help: "animations to copy into flash, to play without a card, with * and ? wildcards" },
+    Key { section: Section::Spi, name: "sd_clock", help: "SD card clock in kHz, 400 to 31250" },
+    Key { section: Section::Spi, name: "display_clock", help: "display clock in kHz, 1000 to 62500" },
+];
+
+enum Value {
+    Number(u32),
+    Bool(bool),
+    Word(&'static str),
+    Files(FileNames),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Word(w) => f.write_str(w),
+            Value::Files(names) => {
+                for (idx, name) in names.names().iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(" ")?;
+                    }
+                    f.write_str(name.as_str())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find([';', '#']) {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+fn match_word<T: Copy>(value: &str, words: &[(&str, T)]) -> Option<T> {
+    words.iter().find(|(word, _)| value.eq_ignore_ascii_case(word)).map(|(_, t)| *t)
+}
+
+fn parse_bool(value: &str) -> Result<bool, ErrorKind> {
+    match_word(value, &[
+        ("true", true),
+        ("yes", true),
+        ("on", true),
+        ("1", true),
+        ("false", false),
+        ("no", false),
+        ("off", false),
+        ("0", false),
+    ])
+    .ok_or(ErrorKind::InvalidValue { expected: "true or false" })
+}
+
+/// `none` is no action at all.
+fn parse_action(value: &str) -> Result<Option<Action>, ErrorKind> {
+    match_word(value, &[
+        ("next", Some(Action::Next)),
+        ("previous", Some(Action::Previous)),
+        ("shuffle", Some(Action::Shuffle)),
+        ("pause", Some(Action::TogglePause)),
+        ("none", None),
+    ])
+    .ok_or(ErrorKind::InvalidValue { expected: "next, previous, shuffle, pause or none" })
+}
+
+fn action_name(action: Option<Action>) -> &'static str {
+    match action {
+        Some(Action::Next) => "next",
+        Some(Action::Previous) => "previous",
+        Some(Action::Shuffle) => "shuffle",
+        Some(Action::TogglePause) => "pause",
+        None => "none",
+    }
+}
+
+pub(crate) fn parse_number(value: &str, min: u32, max: u32) -> Result<u32, ErrorKind> {
+    let n: u32 = value.parse().map_err(|_| ErrorKind::InvalidValue { expected: "a whole number" })?;
+    if n < min || n > max {
+        return Err(ErrorKind::OutOfRange { min, max });
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Parse `text`, collecting the errors.
+    fn parse(text: &str) -> (Config, Vec<Error>) {
+        let mut errors = Vec::new();
+        let config = Config::parse(text.as_bytes(), |error| errors.push(error));
+        (config, errors)
+    }
+
+    fn ini(config: &Config) -> String {
+        let mut out = String::new();
+        config.write_ini(&mut out).unwrap();
+        out
+    }
+
+    fn error(line: u32, kind: ErrorKind) -> Error {
+        Error { line, kind }
+    }
+
+    #[test]
+    fn default_round_trips() {
+        assert_eq!(parse(&ini(&Config::DEFAULT)), (Config::DEFAULT, Vec::new()));
+    }
+
+    #[test]
+    fn changed_settings_round_trip() {
+        let (config, errors) = parse(concat!(
+            "[display]\nbrightness = 40\nrotation = 270\nauto_rotate = any\n",
+            "[playback]\norder = sequential\n",
+            "[power]\nwake_on_tap = no\nraise_hold = 300\n",
+            "[gestures]\ntap = previous\nshake = none\n",
+            "[cache]\nfiles = CAT*.LU DOG.LU\n",
+            "[schedule]\n22:00-07:00 = CALM* 20%\n",
+        ));
+        assert_eq!(errors, []);
+        assert_eq!(config.brightness_percent, 40);
+        assert_eq!(config.gestures.tap, Some(Action::Previous));
+        assert_eq!(config.gestures.shake, None);
+        assert_eq!(config.cache_files.names().len(), 2);
+        assert_eq!(config.schedule.rules().len(), 1);
+
+        assert_eq!(parse(&ini(&config)), (config, Vec::new()));
+    }
+
+    #[test]
+    fn comments_and_case() {
+        let (config, errors) = parse(concat!(
+            "; a comment\n",
+            "# another\n",
+            "\n",
+            "[ DISPLAY ] ; trailing\n",
+            "Brightness = 50 # trailing\n",
+            "MIRROR_X=ON\n",
+            "[Clock]\n",
+            "face = Instead\n",
+        ));
+        assert_eq!(errors, []);
+        assert_eq!(config.brightness_percent, 50);
+        assert!(config.mirror_x);
+        assert_eq!(config.clock_face, ClockFace::Instead);
+    }
+
+    #[test]
+    fn setting_before_any_section() {
+        let (config, errors) = parse("brightness = 50\n[display]\nmirror_y = true\n");
+        assert_eq!(errors, [error(1, ErrorKind::NoSection)]);
+        assert_eq!(config.brightness_percent, Config::DEFAULT.brightness_percent);
+        assert!(config.mirror_y);
+    }
+
+    #[test]
+    fn unknown_section_is_reported_once() {
+        let (config, errors) = parse("[display]\nbrightness = 50\n[sound]\nvolume = 11\nbrightness = 20\n[usb]\ndrive = off\n");
+        assert_eq!(errors, [error(3, ErrorKind::UnknownSection)]);
+        assert_eq!(config.brightness_percent, 50);
+        assert!(!config.usb_drive);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let (config, errors) = parse("[display]\nbrightness = 50\n[playback]\nbrightness = 20\n");
+        assert_eq!(errors, [error(4, ErrorKind::UnknownKey)]);
+        assert_eq!(config.brightness_percent, 50);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let (config, errors) = parse("[display]\nbrightness = 0\n[spi]\nsd_clock = 100000\n");
+        assert_eq!(errors, [
+            error(2, ErrorKind::OutOfRange { min: 1, max: 100 }),
+            error(4, ErrorKind::OutOfRange { min: 400, max: 31_250 }),
+        ]);
+        assert_eq!(config, Config::DEFAULT);
+    }
+
+    #[test]
+    fn invalid_value() {
+        let (config, errors) = parse("[display]\nrotation = 45\nmirror_x = maybe\nbrightness = lots\n[gestures]\ntap = dance\n");
+        assert_eq!(errors, [
+            error(2, ErrorKind::InvalidValue { expected: "0, 90, 180 or 270" }),
+            error(3, ErrorKind::InvalidValue { expected: "true or false" }),
+            error(4, ErrorKind::InvalidValue { expected: "a whole number" }),
+            error(6, ErrorKind::InvalidValue { expected: "next, previous, shuffle, pause or none" }),
+        ]);
+        assert_eq!(config, Config::DEFAULT);
+    }
+
+    #[test]
+    fn not_key_value() {
+        let (config, errors) = parse("[display]\nbrightness\nmirror_x = true\n");
+        assert_eq!(errors, [error(2, ErrorKind::NotKeyValue)]);
+        assert!(config.mirror_x);
+    }
+
+    #[test]
+    fn not_text() {
+        let mut errors = Vec::new();
+        let config = Config::parse(b"[display]\nbrightness = \xff\n", |error| errors.push(error));
+        assert_eq!(errors, [error(0, ErrorKind::NotText)]);
+        assert_eq!(config, Config::DEFAULT);
+    }
+
+    #[test]
+    fn too_many_cache_files() {
+        let (config, errors) = parse("[cache]\nfiles = A B C D E F G H I\n");
+        assert_eq!(errors, [error(2, ErrorKind::InvalidValue { expected: "at most 8 file names" })]);
+        assert_eq!(config.cache_files, FileNames::EMPTY);
+
+        let (config, errors) = parse("[cache]\nfiles = A,B,C,D,E,F,G,H\n");
+        assert_eq!(errors, []);
+        assert_eq!(config.cache_files.names().len(), MAX_LISTED_FILES);
+        assert!(config.cache_files.matches("H"));
+    }
+}
@@ -0,0 +1,572 @@
+//! Changing which animations play, and how bright, by the time of day.
+//!
+//! Each line of the `[schedule]` section is a rule: when it applies, then which files to play and
+//! at what brightness. For example:
+//!
+//! ```ini
+//! [schedule]
+//! 22:00-07:00 = CALM* 20%
+//! mon-fri 08:00-08:10 = MORNING
+//! sat,sun = WEEKEND*
+//! 19:00-22:00 = 50%
+//! ```
+//!
+//! The first rule that applies wins, and with none applying every file is played at the
+//! brightness from `[display]`. A time range that goes past midnight belongs to the day it starts
+//! on. File names can have `*` and `?` wildcards in, and without a `.` they're matched against the
+//! name without its extension.
+
+use core::fmt;
+
+use crate::time::DateTime;
+use crate::ErrorKind;
+
+/// The most rules a schedule holds, any more are left out.
+pub const MAX_RULES: usize = 8;
+
+/// The most file names in a rule.
+pub const MAX_FILE_NAMES: usize = 4;
+
+/// Long enough for an 8.3 name.
+const MAX_FILE_NAME_LEN: usize = 12;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    rules: [Rule; MAX_RULES],
+    len: u8,
+}
+
+impl Schedule {
+    pub const EMPTY: Self = Self { rules: [Rule::EMPTY; MAX_RULES], len: 0 };
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules[..self.len as usize]
+    }
+
+    /// The index into [`rules`](Self::rules) of the first rule that applies at `now`, if any do.
+    pub fn active(&self, now: &DateTime) -> Option<usize> {
+        self.rules().iter().position(|rule| rule.applies_at(now))
+    }
+
+    /// Read a rule from its line in the settings file, `when = what`, and add it to the end.
+    pub(crate) fn push(&mut self, when: &str, what: &str) -> Result<(), ErrorKind> {
+        let rule = Rule::parse(when, what)?;
+        let slot = self.rules.get_mut(self.len as usize).ok_or(ErrorKind::TooManyRules { max: MAX_RULES as u32 })?;
+        *slot = rule;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Schedule {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", self.rules())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rule {
+    days: Days,
+    /// Minutes since midnight. The same start and end is the whole day.
+    start_minute: u16,
+    end_minute: u16,
+    file_names: [FileName; MAX_FILE_NAMES],
+    n_file_names: u8,
+    brightness_percent: Option<u8>,
+}
+
+impl Rule {
+    const EMPTY: Self = Self {
+        days: Days::EVERY,
+        start_minute: 0,
+        end_minute: 0,
+        file_names: [FileName::EMPTY; MAX_FILE_NAMES],
+        n_file_names: 0,
+        brightness_percent: None,
+    };
+
+    /// `when` is the days and time range, `what` the file names and brightness.
+    fn parse(when: &str, what: &str) -> Result<Self, ErrorKind> {
+        const WHEN: ErrorKind = ErrorKind::InvalidValue {
+            expected: "days like mon-fri and/or a time range like 22:00-07:00, before the =",
+        };
+        const WHAT: ErrorKind = ErrorKind::InvalidValue {
+            expected: "file names and/or a brightness like 30%, after the =",
+        };
+
+        let mut rule = Self::EMPTY;
+
+        let mut when_parts = when.split_whitespace();
+        let (days, range) = match (when_parts.next(), when_parts.next(), when_parts.next()) {
+            (Some(days), Some(range), None) => (Some(days), Some(range)),
+            (Some(one), None, None) if one.contains(':') => (None, Some(one)),
+            (Some(one), None, None) => (Some(one), None),
+            _ => return Err(WHEN),
+        };
+        if let Some(days) = days {
+            rule.days = Days::parse(days).ok_or(WHEN)?;
+        }
+        if let Some(range) = range {
+            let (start, end) = range.split_once('-').ok_or(WHEN)?;
+            rule.start_minute = parse_minute(start).filter(|&minute| minute < MINUTES_PER_DAY).ok_or(WHEN)?;
+            rule.end_minute = parse_minute(end).ok_or(WHEN)? % MINUTES_PER_DAY;
+        }
+
+        for word in what.split_whitespace() {
+            if let Some(percent) = word.strip_suffix('%') {
+                let percent = crate::parse_number(percent, 1, 100)?;
+                rule.brightness_percent = Some(percent as u8);
+            } else {
+                let slot = rule.file_names.get_mut(rule.n_file_names as usize).ok_or(ErrorKind::InvalidValue {
+                    expected: "at most 4 file names",
+                })?;
+                *slot = FileName::new(word).ok_or(ErrorKind::InvalidValue {
+                    expected: "file names of up to 12 characters",
+                })?;
+                rule.n_file_names += 1;
+            }
+        }
+        if rule.n_file_names == 0 && rule.brightness_percent.is_none() {
+            return Err(WHAT);
+        }
+
+        Ok(rule)
+    }
+
+    pub fn applies_at(&self, now: &DateTime) -> bool {
+        let minute = now.hour as u16 * 60 + now.minute as u16;
+        let today = now.day_of_week();
+        if self.start_minute == self.end_minute {
+            return self.days.contains(today);
+        }
+        if self.start_minute < self.end_minute {
+            return self.days.contains(today) && (self.start_minute..self.end_minute).contains(&minute);
+        }
+        // past midnight, so the early hours belong to the day before
+        let yesterday = (today + 6) % 7;
+        (self.days.contains(today) && minute >= self.start_minute)
+            || (self.days.contains(yesterday) && minute < self.end_minute)
+    }
+
+    /// Whether `file_name` is one of the files this rule plays. A rule without any file names
+    /// plays them all.
+    pub fn plays(&self, file_name: &str) -> bool {
+        self.file_names().is_empty() || self.file_names().iter().any(|pattern| pattern.matches(file_name))
+    }
+
+    pub fn file_names(&self) -> &[FileName] {
+        &self.file_names[..self.n_file_names as usize]
+    }
+
+    /// The brightness to change to while this rule applies, or `None` to leave it as it is.
+    pub fn brightness_percent(&self) -> Option<u8> {
+        self.brightness_percent
+    }
+}
+
+/// Written out the same way [`Schedule`] reads them, `when = what`.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let whole_day = self.start_minute == self.end_minute;
+        if self.days != Days::EVERY || whole_day {
+            write!(f, "{}", self.days)?;
+        }
+        if !whole_day {
+            if self.days != Days::EVERY {
+                f.write_str(" ")?;
+            }
+            // ending at midnight reads better as the end of the day than the start of it
+            let end_minute = match self.end_minute {
+                0 => MINUTES_PER_DAY,
+                end_minute => end_minute,
+            };
+            write!(
+                f,
+                "{:02}:{:02}-{:02}:{:02}",
+                self.start_minute / 60,
+                self.start_minute % 60,
+                end_minute / 60,
+                end_minute % 60,
+            )?;
+        }
+
+        f.write_str(" =")?;
+        for file_name in self.file_names() {
+            write!(f, " {}", file_name.as_str())?;
+        }
+        if let Some(percent) = self.brightness_percent {
+            write!(f, " {}%", percent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Days of the week, bit 0 for Sunday up to bit 6 for Saturday, as in [`DateTime::day_of_week`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Days(u8);
+
+impl Days {
+    pub const EVERY: Self = Self(0x7f);
+
+    const NAMES: [&'static str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+    /// Like `mon-fri`, `sat,sun`, `mon,wed-fri` or `daily`. Ranges can go round the end of the
+    /// week, like `fri-mon`.
+    fn parse(text: &str) -> Option<Self> {
+        if text.eq_ignore_ascii_case("daily") {
+            return Some(Self::EVERY);
+        }
+        let mut days = 0u8;
+        for part in text.split(',') {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let (first, last) = (Self::day(first)?, Self::day(last)?);
+            let mut day = first;
+            loop {
+                days |= 1 << day;
+                if day == last {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+        }
+        Some(Self(days))
+    }
+
+    fn day(name: &str) -> Option<u8> {
+        Self::NAMES.iter().position(|day| name.trim().eq_ignore_ascii_case(day)).map(|day| day as u8)
+    }
+
+    pub fn contains(self, day_of_week: u8) -> bool {
+        self.0 & (1 << day_of_week) != 0
+    }
+}
+
+/// Runs of days are written as ranges, starting from Monday so weekends stay together.
+impl fmt::Display for Days {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::EVERY {
+            return f.write_str("daily");
+        }
+        let monday_first = |i: u8| (i + 1) % 7;
+        let mut first_run = true;
+        let mut i = 0;
+        while i < 7 {
+            if !self.contains(monday_first(i)) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i + 1 < 7 && self.contains(monday_first(i + 1)) {
+                i += 1;
+            }
+            if !first_run {
+                f.write_str(",")?;
+            }
+            first_run = false;
+            f.write_str(Self::NAMES[monday_first(start) as usize])?;
+            if i > start {
+                write!(f, "-{}", Self::NAMES[monday_first(i) as usize])?;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+/// A file name to play, which can have `*` and `?` wildcards in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FileName {
+    bytes: [u8; MAX_FILE_NAME_LEN],
+    len: u8,
+}
+
+impl FileName {
+    pub(crate) const EMPTY: Self = Self { bytes: [0; MAX_FILE_NAME_LEN], len: 0 };
+
+    pub(crate) fn new(pattern: &str) -> Option<Self> {
+        if pattern.len() > MAX_FILE_NAME_LEN || !pattern.is_ascii() {
+            return None;
+        }
+        let mut name = Self::EMPTY;
+        name.bytes[..pattern.len()].copy_from_slice(pattern.as_bytes());
+        name.len = pattern.len() as u8;
+        Some(name)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever made from ASCII
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+
+    /// Ignoring case, like the card does.
+    pub fn matches(&self, file_name: &str) -> bool {
+        let pattern = self.as_str();
+        let file_name = match pattern.contains('.') {
+            true => file_name,
+            false => file_name.split_once('.').map_or(file_name, |(stem, _)| stem),
+        };
+        wildcard_match(pattern.as_bytes(), file_name.as_bytes())
+    }
+}
+
+impl fmt::Debug for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for FileName {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}", self.as_str())
+    }
+}
+
+/// `*` matches any run of characters, including none, and `?` any one character.
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was, and where in the text it's matched up to so far
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // let the `*` take one more character and try again from there
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// `HH:MM`, up to `24:00` for the end of the day.
+fn parse_minute(text: &str) -> Option<u16> {
+    let (hour, minute) = text.trim().split_once(':')?;
+    let hour: u16 = hour.parse().ok()?;
+    let minute: u16 = minute.parse().ok()?;
+    if minute >= 60 || hour > 24 || (hour == 24 && minute > 0) {
+        return None;
+    }
+    Some(hour * 60 + minute)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn rule(when: &str, what: &str) -> Rule {
+        Rule::parse(when, what).unwrap()
+    }
+
+    /// A time in June 2024, where the 3rd is a Monday.
+    fn at(day: u8, hour: u8, minute: u8) -> DateTime {
+        DateTime { year: 2024, month: 6, day, hour, minute, second: 0 }
+    }
+
+    const MON: u8 = 3;
+    const FRI: u8 = 7;
+    const SAT: u8 = 8;
+    const SUN: u8 = 9;
+
+    #[test]
+    fn days() {
+        let days = |text| Days::parse(text).map(|days| days.0);
+        assert_eq!(days("daily"), Some(0x7f));
+        assert_eq!(days("DAILY"), Some(0x7f));
+        assert_eq!(days("mon-fri"), Some(0b0111110));
+        assert_eq!(days("sat,sun"), Some(0b1000001));
+        assert_eq!(days("Mon,wed-fri"), Some(0b0111010));
+        // round the end of the week
+        assert_eq!(days("fri-mon"), Some(0b1100011));
+        assert_eq!(days("wed"), Some(0b0001000));
+        assert_eq!(days("sun-sat"), Some(0x7f));
+        assert_eq!(days("monday"), None);
+        assert_eq!(days("mon-"), None);
+        assert_eq!(days("mon,,tue"), None);
+        assert_eq!(days(""), None);
+    }
+
+    #[test]
+    fn days_display() {
+        let display = |text| Days::parse(text).unwrap().to_string();
+        assert_eq!(display("sun-sat"), "daily");
+        assert_eq!(display("mon-fri"), "mon-fri");
+        assert_eq!(display("sun,sat"), "sat-sun");
+        assert_eq!(display("fri-mon"), "mon,fri-sun");
+        assert_eq!(display("wed,mon,thu"), "mon,wed-thu");
+    }
+
+    #[test]
+    fn minutes() {
+        assert_eq!(parse_minute("00:00"), Some(0));
+        assert_eq!(parse_minute(" 7:05 "), Some(7 * 60 + 5));
+        assert_eq!(parse_minute("23:59"), Some(MINUTES_PER_DAY - 1));
+        assert_eq!(parse_minute("24:00"), Some(MINUTES_PER_DAY));
+        assert_eq!(parse_minute("24:01"), None);
+        assert_eq!(parse_minute("12:60"), None);
+        assert_eq!(parse_minute("12"), None);
+        assert_eq!(parse_minute("noon"), None);
+    }
+
+    #[test]
+    fn parse_rules() {
+        let night = rule("22:00-07:00", "CALM* 20%");
+        assert_eq!((night.days, night.start_minute, night.end_minute), (Days::EVERY, 22 * 60, 7 * 60));
+        assert_eq!(night.file_names().len(), 1);
+        assert_eq!(night.file_names()[0].as_str(), "CALM*");
+        assert_eq!(night.brightness_percent(), Some(20));
+
+        let weekend = rule("sat,sun", "WEEKEND* PARTY.LU");
+        assert_eq!((weekend.start_minute, weekend.end_minute), (0, 0));
+        assert_eq!(weekend.file_names().len(), 2);
+        assert_eq!(weekend.brightness_percent(), None);
+
+        // up to the end of the day wraps round to midnight
+        let evening = rule("mon-fri 19:00-24:00", "50%");
+        assert_eq!((evening.start_minute, evening.end_minute), (19 * 60, 0));
+        assert!(evening.file_names().is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_bad_rules() {
+        for (when, what) in [
+            ("", "CALM"),
+            ("mon-fri 08:00-09:00 extra", "CALM"),
+            ("someday", "CALM"),
+            ("08:00", "CALM"),
+            ("24:00-07:00", "CALM"),
+            ("08:00-25:00", "CALM"),
+            ("mon-fri", ""),
+            ("mon-fri", "0%"),
+            ("mon-fri", "101%"),
+            ("mon-fri", "A B C D E"),
+            ("mon-fri", "MUCHTOOLONG.LU"),
+        ] {
+            assert!(Rule::parse(when, what).is_err(), "{when:?} = {what:?}");
+        }
+    }
+
+    #[test]
+    fn applies_within_the_day() {
+        let morning = rule("mon-fri 08:00-08:10", "MORNING");
+        assert!(!morning.applies_at(&at(MON, 7, 59)));
+        assert!(morning.applies_at(&at(MON, 8, 0)));
+        assert!(morning.applies_at(&at(FRI, 8, 9)));
+        assert!(!morning.applies_at(&at(FRI, 8, 10)));
+        assert!(!morning.applies_at(&at(SAT, 8, 5)));
+    }
+
+    #[test]
+    fn applies_past_midnight_on_the_day_it_started() {
+        let late = rule("fri 22:00-02:00", "LATE");
+        assert!(!late.applies_at(&at(FRI, 21, 59)));
+        assert!(late.applies_at(&at(FRI, 22, 0)));
+        assert!(late.applies_at(&at(FRI, 23, 59)));
+        // the early hours of Saturday are still Friday night
+        assert!(late.applies_at(&at(SAT, 0, 0)));
+        assert!(late.applies_at(&at(SAT, 1, 59)));
+        assert!(!late.applies_at(&at(SAT, 2, 0)));
+        assert!(!late.applies_at(&at(SAT, 22, 0)));
+        // and Friday's early hours belong to Thursday
+        assert!(!late.applies_at(&at(FRI, 1, 0)));
+
+        // Sunday night runs into Monday, across the end of the week
+        let sunday = rule("sun 23:00-01:00", "SUNDAY");
+        assert!(sunday.applies_at(&at(SUN, 23, 30)));
+        assert!(sunday.applies_at(&at(MON + 7, 0, 30)));
+        assert!(!sunday.applies_at(&at(MON + 7, 1, 0)));
+        assert!(!sunday.applies_at(&at(MON + 8, 0, 30)));
+    }
+
+    #[test]
+    fn applies_all_day() {
+        let weekend = rule("sat,sun", "WEEKEND*");
+        assert!(weekend.applies_at(&at(SAT, 0, 0)));
+        assert!(weekend.applies_at(&at(SUN, 23, 59)));
+        assert!(!weekend.applies_at(&at(FRI, 23, 59)));
+        assert!(!weekend.applies_at(&at(MON + 7, 0, 0)));
+    }
+
+    #[test]
+    fn first_active_rule_wins() {
+        let mut schedule = Schedule::EMPTY;
+        schedule.push("22:00-07:00", "CALM* 20%").unwrap();
+        schedule.push("mon-fri 08:00-08:10", "MORNING").unwrap();
+        schedule.push("sat,sun", "WEEKEND*").unwrap();
+        assert_eq!(schedule.active(&at(SAT, 23, 0)), Some(0));
+        assert_eq!(schedule.active(&at(MON, 8, 5)), Some(1));
+        assert_eq!(schedule.active(&at(SAT, 12, 0)), Some(2));
+        assert_eq!(schedule.active(&at(MON, 12, 0)), None);
+    }
+
+    #[test]
+    fn too_many_rules() {
+        let mut schedule = Schedule::EMPTY;
+        for _ in 0..MAX_RULES {
+            schedule.push("daily", "50%").unwrap();
+        }
+        assert_eq!(schedule.push("daily", "60%"), Err(ErrorKind::TooManyRules { max: MAX_RULES as u32 }));
+        assert_eq!(schedule.rules().len(), MAX_RULES);
+    }
+
+    #[test]
+    fn rules_display_as_they_are_read() {
+        for line in [
+            "22:00-07:00 = CALM* 20%",
+            "mon-fri 08:00-08:10 = MORNING",
+            "sat-sun = WEEKEND* PARTY.LU",
+            "19:00-24:00 = 50%",
+            "daily = ALL",
+        ] {
+            let (when, what) = line.split_once('=').unwrap();
+            assert_eq!(rule(when, what).to_string(), line);
+        }
+    }
+
+    #[test]
+    fn file_name_wildcards() {
+        let matches = |pattern, file_name| FileName::new(pattern).unwrap().matches(file_name);
+        assert!(matches("CALM*", "CALM.LU"));
+        assert!(matches("CALM*", "calmsea.lu"));
+        assert!(matches("CALM", "CALM.LU"));
+        assert!(!matches("CALM", "CALMSEA.LU"));
+        assert!(matches("C?LM", "CALM.LU"));
+        assert!(!matches("C?LM", "CLM.LU"));
+        assert!(matches("*.LU", "HEART.LU"));
+        assert!(!matches("*.LU", "HEART.TXT"));
+        assert!(matches("H*T*", "HEART.LU"));
+        assert!(!matches("HEART.LU", "HEART"));
+
+        let names: Vec<_> = ["CALM.LU", "HEART.LU"].into_iter().filter(|name| rule("daily", "HE*").plays(name)).collect();
+        assert_eq!(names, ["HEART.LU"]);
+        assert!(rule("daily", "50%").plays("ANYTHING.LU"));
+    }
+}
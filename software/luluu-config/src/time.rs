@@ -0,0 +1,214 @@
+//! Wall-clock dates and times, for setting the LuLuu's clock.
+//!
+//! The clock can be set by putting a `SETTIME.TXT` file in the root of the SD card with the time
+//! in it, like `2024-06-01 14:30:00`. It's read when the LuLuu starts up, then deleted so the same
+//! time isn't set again next time.
+
+use core::fmt;
+
+/// The name of the file the time is set from, in the root of the SD card.
+pub const FILE_NAME: &str = "SETTIME.TXT";
+
+/// The biggest time file that's read, anything past this is ignored.
+pub const MAX_FILE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31, depending on the month.
+    pub day: u8,
+    /// 0 to 23.
+    pub hour: u8,
+    /// 0 to 59.
+    pub minute: u8,
+    /// 0 to 59.
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The earliest and latest years that can be set. FAT timestamps can hold them, and the clock's
+    /// leap years are right for all of them.
+    pub const MIN_YEAR: u16 = 2000;
+    pub const MAX_YEAR: u16 = 2099;
+
+    /// Read a time like `2024-06-01 14:30:00` or `2024-06-01T14:30`, with or without the seconds.
+    /// `None` if it isn't one, or isn't a real date and time.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (date, time) = text.split_once([' ', 'T'])?;
+
+        let mut date = date.split('-');
+        let year = date.next()?.parse().ok()?;
+        let month = date.next()?.parse().ok()?;
+        let day = date.next()?.parse().ok()?;
+        if date.next().is_some() {
+            return None;
+        }
+
+        let mut time = time.trim().split(':');
+        let hour = time.next()?.parse().ok()?;
+        let minute = time.next()?.parse().ok()?;
+        let second = match time.next() {
+            Some(second) => second.parse().ok()?,
+            None => 0,
+        };
+        if time.next().is_some() {
+            return None;
+        }
+
+        let date_time = Self { year, month, day, hour, minute, second };
+        date_time.is_valid().then_some(date_time)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (Self::MIN_YEAR..=Self::MAX_YEAR).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// 0 for Sunday up to 6 for Saturday.
+    pub fn day_of_week(&self) -> u8 {
+        // Sakamoto's method
+        const MONTH_OFFSETS: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = if self.month < 3 { self.year - 1 } else { self.year };
+        let days = year + year / 4 - year / 100 + year / 400 + MONTH_OFFSETS[self.month as usize - 1] + self.day as u16;
+        (days % 7) as u8
+    }
+
+    /// The name of the day of the week, like `"Sat"`.
+    pub fn day_name(&self) -> &'static str {
+        ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"][self.day_of_week() as usize]
+    }
+
+    /// The name of the month, like `"Jun"`.
+    pub fn month_name(&self) -> &'static str {
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"][self.month as usize - 1]
+    }
+}
+
+/// The same format [`DateTime::parse`] reads.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+
+    use super::*;
+
+    fn date_time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime { year, month, day, hour, minute, second }
+    }
+
+    #[test]
+    fn parse_formats() {
+        let expected = date_time(2024, 6, 1, 14, 30, 5);
+        assert_eq!(DateTime::parse("2024-06-01 14:30:05"), Some(expected));
+        assert_eq!(DateTime::parse("2024-06-01T14:30:05"), Some(expected));
+        assert_eq!(DateTime::parse("  2024-06-01 14:30:05\r\n"), Some(expected));
+        assert_eq!(DateTime::parse("2024-6-1 14:30"), Some(date_time(2024, 6, 1, 14, 30, 0)));
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        for text in [
+            "",
+            "2024-06-01",
+            "14:30:00",
+            "2024-06 14:30",
+            "2024-06-01-02 14:30",
+            "2024-06-01 14",
+            "2024-06-01 14:30:00:00",
+            "2024/06/01 14:30",
+            "2024-06-01 2pm",
+            "-2024-06-01 14:30",
+        ] {
+            assert_eq!(DateTime::parse(text), None, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn parse_rejects_impossible_times() {
+        for text in [
+            "2024-06-01 24:00",
+            "2024-06-01 23:60",
+            "2024-06-01 23:59:60",
+            "2024-00-01 12:00",
+            "2024-13-01 12:00",
+            "2024-06-00 12:00",
+            "2024-06-31 12:00",
+            "2024-01-32 12:00",
+        ] {
+            assert_eq!(DateTime::parse(text), None, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn parse_only_supported_years() {
+        assert!(DateTime::parse("2000-01-01 00:00").is_some());
+        assert!(DateTime::parse("2099-12-31 23:59:59").is_some());
+        assert_eq!(DateTime::parse("1999-12-31 23:59:59"), None);
+        assert_eq!(DateTime::parse("2100-01-01 00:00"), None);
+    }
+
+    #[test]
+    fn leap_years() {
+        assert!(DateTime::parse("2024-02-29 12:00").is_some());
+        assert_eq!(DateTime::parse("2023-02-29 12:00"), None);
+        assert!(DateTime::parse("2023-02-28 12:00").is_some());
+        // divisible by 400, so a leap year after all
+        assert!(DateTime::parse("2000-02-29 12:00").is_some());
+        assert_eq!(DateTime::parse("2024-02-30 12:00"), None);
+
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2023, 2), 28);
+        assert_eq!(days_in_month(2000, 2), 29);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2024, 4), 30);
+        assert_eq!(days_in_month(2024, 12), 31);
+    }
+
+    #[test]
+    fn day_of_week() {
+        let day = |year, month, day| date_time(year, month, day, 12, 0, 0).day_name();
+        assert_eq!(day(2000, 1, 1), "Sat");
+        assert_eq!(day(2000, 2, 29), "Tue");
+        assert_eq!(day(2024, 2, 29), "Thu");
+        assert_eq!(day(2024, 3, 1), "Fri");
+        assert_eq!(day(2024, 6, 1), "Sat");
+        assert_eq!(day(2024, 6, 3), "Mon");
+        assert_eq!(day(2099, 12, 31), "Thu");
+    }
+
+    #[test]
+    fn display_parses_back() {
+        let date_time = date_time(2024, 2, 29, 7, 5, 9);
+        assert_eq!(date_time.to_string(), "2024-02-29 07:05:09");
+        assert_eq!(DateTime::parse(&date_time.to_string()), Some(date_time));
+        assert_eq!(date_time.month_name(), "Feb");
+    }
+}
@@ -32,6 +32,8 @@ pub struct Version(pub u8);
 
 impl Version {
     pub const ZERO: Self = Self(0);
+    /// Each frame is preceded by its [`DirtyRect`].
+    pub const ONE: Self = Self(1);
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, AnyBitPattern, NoUninit, TransparentWrapper)]
@@ -68,9 +70,8 @@ impl FrameRate {
     #[inline(always)]
     pub fn is_supported(self, size: u8) -> bool {
         match self.0 {
-            1 | 2 | 3 | 4 | 5 | 6 | 8 => true,
-            10 | 12 => size == 60 || size == 120,
-            15 | 20 | 24 => size == 60,
+            1 | 2 | 3 | 4 | 5 | 6 | 8 | 10 | 12 => true,
+            15 | 20 | 24 | 30 => size == 60 || size == 120,
             _ => false,
         }
     }
@@ -85,23 +86,20 @@ impl FrameRate {
             7..=8 => 8,
             9..=11 => 10,
             12..=13 => 12,
-            14..=16 => 15,
+            14..=18 => 15,
             19..=21 => 20,
             22..=26 => 24,
+            27..=33 => 30,
             _ => {
-                warn!("higher framerate than supported detected. Setting to 15.");
-                15
+                warn!("higher framerate than supported detected. Setting to 30.");
+                30
             }
         };
         match size.0 {
-            60 => (),
-            120 => if frame_rate > 12 {
-                warn!("Frame rates higher than 12 are not supported at 120x120. Setting to 12.");
+            60 | 120 => (),
+            240 => if frame_rate > 12 {
+                warn!("Frame rates higher than 12 are not supported at 240x240. Setting to 12.");
                 frame_rate = 12;
-            },
-            240 => if frame_rate > 4 {
-                warn!("Frame rates higher than 4 are not supported at 240x240. Setting to 4.");
-                frame_rate = 4;
             }
             _ => return Err(Error::UnsupportedSize(size)),
         };
@@ -124,6 +122,8 @@ impl Size {
     }
 }
 
+/// The start of every file. The frames follow it, each one a [`DirtyRect`] (only from
+/// [`Version::ONE`] on) and then its pixels, row by row.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, AnyBitPattern, NoUninit)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C, align(1))]
@@ -146,7 +146,7 @@ impl Header {
         }
 
         match header.version {
-            Version::ZERO => (),
+            Version::ZERO | Version::ONE => (),
             version => return Err(Error::UnknownVersion(version))
         }
 
@@ -169,10 +169,132 @@ impl Header {
     pub fn as_bytes(&self) -> &[u8; HEADER_SIZE] {
         bytemuck::cast_ref(self)
     }
+
+    #[inline(always)]
+    pub fn has_dirty_rects(&self) -> bool {
+        self.version == Version::ONE
+    }
+
+    /// Size of one frame's pixels in bytes.
+    #[inline]
+    pub fn pixel_bytes(&self) -> u32 {
+        let bytes_per_pixel = match self.encoding {
+            Encoding::RGB888 => 3,
+            _ => 2,
+        };
+        self.size.0 as u32 * self.size.0 as u32 * bytes_per_pixel
+    }
+
+    /// Size of one frame in bytes, including its dirty rect if it has one.
+    #[inline]
+    pub fn frame_bytes(&self) -> u32 {
+        match self.has_dirty_rects() {
+            true => DIRTY_RECT_SIZE as u32 + self.pixel_bytes(),
+            false => self.pixel_bytes(),
+        }
+    }
+
+    /// Where `frame` starts in the file.
+    #[inline]
+    pub fn frame_offset(&self, frame: u32) -> u32 {
+        HEADER_SIZE as u32 + frame * self.frame_bytes()
+    }
 }
 
 pub const HEADER_SIZE: usize = core::mem::size_of::<Header>();
 
+/// The part of a frame that's different from the frame before it, in the file's pixels. The first
+/// frame's is compared to the last frame, since animations loop.
+///
+/// A frame the same as the one before has an empty rect, with zero width and height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AnyBitPattern, NoUninit)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C, align(1))]
+pub struct DirtyRect {
+    pub x: u8,
+    pub y: u8,
+    pub width: u8,
+    pub height: u8,
+}
+
+pub const DIRTY_RECT_SIZE: usize = core::mem::size_of::<DirtyRect>();
+
+impl DirtyRect {
+    pub const EMPTY: Self = Self { x: 0, y: 0, width: 0, height: 0 };
+
+    /// The whole of a frame of `size`.
+    #[inline]
+    pub const fn full(size: Size) -> Self {
+        Self { x: 0, y: 0, width: size.0, height: size.0 }
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Whether the rect is inside a frame of `size`. Rects read from a file or sent over USB have
+    /// to be checked with this before they're used.
+    #[inline]
+    pub fn fits(&self, size: Size) -> bool {
+        let fits = |start: u8, len: u8| start as usize + len as usize <= size.0 as usize;
+        fits(self.x, self.width) && fits(self.y, self.height)
+    }
+
+    /// The smallest rect covering every pixel that's different between two `size` by `size`
+    /// frames.
+    pub fn between(prev: &[Rgb565BE], next: &[Rgb565BE], size: Size) -> Self {
+        let size = size.0 as usize;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (size, size, 0, 0);
+
+        for (y, (prev_row, next_row)) in prev.chunks_exact(size).zip(next.chunks_exact(size)).enumerate() {
+            for (x, (prev, next)) in prev_row.iter().zip(next_row).enumerate() {
+                if prev.0 != next.0 {
+                    min_x = min_x.min(x);
+                    min_y = min_y.min(y);
+                    max_x = max_x.max(x);
+                    max_y = max_y.max(y);
+                }
+            }
+        }
+
+        if min_x > max_x {
+            return Self::EMPTY;
+        }
+        Self {
+            x: min_x as u8,
+            y: min_y as u8,
+            width: (max_x - min_x + 1) as u8,
+            height: (max_y - min_y + 1) as u8,
+        }
+    }
+
+    /// The same rect in a frame upscaled by `scale`. Anything past 255 once scaled is cut off
+    /// there, which can't happen to a rect that [`fits`](Self::fits) a frame that's upscaled to
+    /// the display's size.
+    #[inline]
+    pub fn scaled(self, scale: u8) -> Self {
+        let x = self.x.saturating_mul(scale);
+        let y = self.y.saturating_mul(scale);
+        Self {
+            x,
+            y,
+            width: self.width.saturating_mul(scale).min(u8::MAX - x),
+            height: self.height.saturating_mul(scale).min(u8::MAX - y),
+        }
+    }
+
+    #[inline(always)]
+    pub fn from_bytes(bytes: [u8; DIRTY_RECT_SIZE]) -> Self {
+        bytemuck::cast(bytes)
+    }
+
+    #[inline(always)]
+    pub fn to_bytes(self) -> [u8; DIRTY_RECT_SIZE] {
+        bytemuck::cast(self)
+    }
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
@@ -328,3 +450,112 @@ impl Rgb565BE {
     }
 }
 
+#[cfg(feature = "embedded-graphics")]
+mod graphics {
+    use embedded_graphics::pixelcolor::raw::{RawData, RawU16};
+    use embedded_graphics::pixelcolor::Rgb565;
+
+    use super::{Rgb565BE, Rgb565NE};
+
+    impl From<Rgb565NE> for Rgb565 {
+        #[inline(always)]
+        fn from(color: Rgb565NE) -> Self {
+            RawU16::new(color.0).into()
+        }
+    }
+
+    impl From<Rgb565> for Rgb565NE {
+        #[inline(always)]
+        fn from(color: Rgb565) -> Self {
+            Self(RawU16::from(color).into_inner())
+        }
+    }
+
+    impl From<Rgb565BE> for Rgb565 {
+        #[inline(always)]
+        fn from(color: Rgb565BE) -> Self {
+            color.to_ne().into()
+        }
+    }
+
+    impl From<Rgb565> for Rgb565BE {
+        #[inline(always)]
+        fn from(color: Rgb565) -> Self {
+            Rgb565NE::from(color).to_be()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+
+    use super::*;
+
+    const WHITE: Rgb565BE = Rgb565BE::from_raw([0xff; 2]);
+
+    #[test]
+    fn between_identical_frames_is_empty() {
+        let frame = vec![WHITE; 60 * 60];
+        let rect = DirtyRect::between(&frame, &frame, Size(60));
+        assert_eq!(rect, DirtyRect::EMPTY);
+        assert!(rect.is_empty());
+    }
+
+    #[test]
+    fn between_frames_one_pixel_apart() {
+        let prev = vec![Rgb565BE::ZERO; 60 * 60];
+        let mut next = prev.clone();
+        next[17 * 60 + 42] = WHITE;
+        let rect = DirtyRect::between(&prev, &next, Size(60));
+        assert_eq!(rect, DirtyRect { x: 42, y: 17, width: 1, height: 1 });
+    }
+
+    #[test]
+    fn between_frames_changed_in_opposite_corners() {
+        let prev = vec![Rgb565BE::ZERO; 120 * 120];
+        let mut next = prev.clone();
+        next[10 * 120 + 100] = WHITE;
+        next[90 * 120 + 5] = WHITE;
+        let rect = DirtyRect::between(&prev, &next, Size(120));
+        assert_eq!(rect, DirtyRect { x: 5, y: 10, width: 96, height: 81 });
+    }
+
+    #[test]
+    fn between_completely_different_frames_is_full() {
+        let prev = vec![Rgb565BE::ZERO; 240 * 240];
+        let next = vec![WHITE; 240 * 240];
+        let rect = DirtyRect::between(&prev, &next, Size(240));
+        assert_eq!(rect, DirtyRect::full(Size(240)));
+    }
+
+    #[test]
+    fn fits() {
+        assert!(DirtyRect::full(Size(240)).fits(Size(240)));
+        assert!(DirtyRect::EMPTY.fits(Size(60)));
+        assert!(DirtyRect { x: 20, y: 100, width: 100, height: 20 }.fits(Size(120)));
+        assert!(!DirtyRect::full(Size(240)).fits(Size(120)));
+        assert!(!DirtyRect { x: 0, y: 200, width: 10, height: 100 }.fits(Size(240)));
+        assert!(!DirtyRect { x: 255, y: 0, width: 255, height: 1 }.fits(Size(240)));
+    }
+
+    #[test]
+    fn scaled_to_the_display() {
+        let rect = DirtyRect { x: 10, y: 59, width: 3, height: 1 };
+        assert_eq!(rect.scaled(4), DirtyRect { x: 40, y: 236, width: 12, height: 4 });
+        assert_eq!(DirtyRect::full(Size(60)).scaled(4), DirtyRect::full(Size(240)));
+        assert_eq!(DirtyRect::full(Size(120)).scaled(2), DirtyRect::full(Size(240)));
+        assert_eq!(rect.scaled(1), rect);
+        assert_eq!(DirtyRect::EMPTY.scaled(4), DirtyRect::EMPTY);
+    }
+
+    #[test]
+    fn scaled_past_255_is_cut_off() {
+        let rect = DirtyRect { x: 100, y: 50, width: 100, height: 20 };
+        assert_eq!(rect.scaled(2), DirtyRect { x: 200, y: 100, width: 55, height: 40 });
+        assert_eq!(rect.scaled(4), DirtyRect { x: 255, y: 200, width: 0, height: 55 });
+    }
+}
@@ -0,0 +1,372 @@
+#![no_std]
+
+//! Accelerometer gesture recognition for the LuLuu.
+//!
+//! This crate has no hardware dependencies, so the recogniser can be run on the host by feeding it
+//! recorded samples, for example to tune the thresholds in [`Config`]. The samples come from an
+//! [`luluu_accel::Accelerometer`].
+
+use luluu_accel::Sample;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Gesture {
+    /// A single sharp knock on the sleeve.
+    Tap,
+    /// Two taps in quick succession.
+    DoubleTap,
+    /// Vigorous back-and-forth movement.
+    Shake,
+    /// The display turned from facing up to facing down, or the other way around.
+    WristFlip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Change in acceleration between two consecutive samples, summed over all axes, that counts
+    /// as the start of a tap. Set to 0 to disable detecting taps from samples, for example if they
+    /// are reported by the accelerometer hardware through [`Recognizer::tap`] instead.
+    pub tap_jerk_threshold_mg: u16,
+    /// Longest a tap's spike may last. Anything longer is just movement.
+    pub tap_max_millis: u32,
+    /// Longest time between two taps for them to count as a double tap. A single tap is only
+    /// reported once this has passed without a second one.
+    pub double_tap_window_millis: u32,
+    /// Acceleration (with gravity removed) on any axis that counts as a shake stroke.
+    pub shake_threshold_mg: u16,
+    /// Number of changes of direction needed for a shake.
+    pub shake_min_reversals: u8,
+    /// Time in which all of the changes of direction of a shake have to happen.
+    pub shake_window_millis: u32,
+    /// Gravity along the display's Z axis that counts as facing up or down.
+    pub flip_threshold_mg: u16,
+    /// Longest time a wrist flip may take from facing one way to facing the other.
+    pub flip_max_millis: u32,
+    /// Time after recognising a gesture during which no other gesture is reported. Gestures that
+    /// complete in that time are dropped, not reported late, including a single tap whose double
+    /// tap window runs out then.
+    pub cooldown_millis: u32,
+}
+
+impl Config {
+    pub const DEFAULT: Self = Self {
+        tap_jerk_threshold_mg: 1500,
+        tap_max_millis: 100,
+        double_tap_window_millis: 400,
+        shake_threshold_mg: 900,
+        shake_min_reversals: 4,
+        shake_window_millis: 1000,
+        flip_threshold_mg: 650,
+        flip_max_millis: 1200,
+        cooldown_millis: 600,
+    };
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Gravity is tracked as an exponential moving average, in fixed point with this many fractional
+/// bits.
+const GRAVITY_FRAC_BITS: u32 = 4;
+
+/// Each new sample moves the gravity estimate `1 / 2^GRAVITY_SMOOTHING_SHIFT` of the way.
+const GRAVITY_SMOOTHING_SHIFT: u32 = 3;
+
+/// Turns a stream of accelerometer samples into [`Gesture`]s.
+///
+/// Feed it samples at a steady rate with [`Recognizer::feed`]. The defaults in [`Config`] are tuned
+/// for 25 to 100 samples per second. Timestamps are free-running milliseconds and may wrap.
+pub struct Recognizer {
+    config: Config,
+    last_sample: Option<Sample>,
+    gravity: [i32; 3],
+    spike_start: Option<u32>,
+    pending_tap: Option<u32>,
+    shake_signs: [i8; 3],
+    shake_reversals: u8,
+    shake_start: u32,
+    facing_up_at: Option<u32>,
+    facing_down_at: Option<u32>,
+    last_gesture_at: Option<u32>,
+}
+
+impl Recognizer {
+    pub const fn new(config: Config) -> Self {
+        Self {
+            config,
+            last_sample: None,
+            gravity: [0; 3],
+            spike_start: None,
+            pending_tap: None,
+            shake_signs: [0; 3],
+            shake_reversals: 0,
+            shake_start: 0,
+            facing_up_at: None,
+            facing_down_at: None,
+            last_gesture_at: None,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Forget everything seen so far, e.g. after samples stopped arriving for a while.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// Feed the next sample, returning a gesture if one was completed.
+    pub fn feed(&mut self, now_millis: u32, sample: Sample) -> Option<Gesture> {
+        let axes = sample.axes();
+
+        let Some(last_sample) = self.last_sample.replace(sample) else {
+            self.gravity = axes.map(|a| a << GRAVITY_FRAC_BITS);
+            return None;
+        };
+
+        let mut dynamic = [0i32; 3];
+        for i in 0..3 {
+            let scaled = axes[i] << GRAVITY_FRAC_BITS;
+            self.gravity[i] += (scaled - self.gravity[i]) >> GRAVITY_SMOOTHING_SHIFT;
+            dynamic[i] = axes[i] - (self.gravity[i] >> GRAVITY_FRAC_BITS);
+        }
+
+        let shake = self.update_shake(now_millis, dynamic);
+        let flip = self.update_flip(now_millis);
+        let tap = self.update_tap(now_millis, last_sample, sample);
+
+        if shake {
+            self.pending_tap = None;
+            return self.emit(now_millis, Gesture::Shake);
+        }
+        if flip {
+            return self.emit(now_millis, Gesture::WristFlip);
+        }
+        if let Some(gesture) = tap {
+            return self.emit(now_millis, gesture);
+        }
+
+        None
+    }
+
+    /// Report a tap detected by something other than the samples, e.g. the accelerometer's own
+    /// click detection. It goes through the same double tap logic as taps found in samples.
+    pub fn tap(&mut self, now_millis: u32) -> Option<Gesture> {
+        let gesture = self.register_tap(now_millis)?;
+        self.emit(now_millis, gesture)
+    }
+
+    /// Report `gesture`, unless it's in the cooldown of the last one, when it's dropped.
+    fn emit(&mut self, now_millis: u32, gesture: Gesture) -> Option<Gesture> {
+        if let Some(last) = self.last_gesture_at {
+            if now_millis.wrapping_sub(last) < self.config.cooldown_millis {
+                return None;
+            }
+        }
+        self.last_gesture_at = Some(now_millis);
+        Some(gesture)
+    }
+
+    fn update_shake(&mut self, now_millis: u32, dynamic: [i32; 3]) -> bool {
+        if self.shake_reversals > 0
+            && now_millis.wrapping_sub(self.shake_start) > self.config.shake_window_millis
+        {
+            self.shake_reversals = 0;
+        }
+
+        let threshold = self.config.shake_threshold_mg as i32;
+        for (sign, &value) in self.shake_signs.iter_mut().zip(dynamic.iter()) {
+            if value.abs() < threshold {
+                continue;
+            }
+            let new_sign = value.signum() as i8;
+            if *sign != 0 && *sign != new_sign {
+                if self.shake_reversals == 0 {
+                    self.shake_start = now_millis;
+                }
+                self.shake_reversals = self.shake_reversals.saturating_add(1);
+            }
+            *sign = new_sign;
+        }
+
+        if self.shake_reversals >= self.config.shake_min_reversals {
+            self.shake_reversals = 0;
+            self.shake_signs = [0; 3];
+            return true;
+        }
+        false
+    }
+
+    fn update_flip(&mut self, now_millis: u32) -> bool {
+        let gravity_z = self.gravity[2] >> GRAVITY_FRAC_BITS;
+        let threshold = self.config.flip_threshold_mg as i32;
+
+        let (this_way, other_way) = if gravity_z >= threshold {
+            (&mut self.facing_up_at, &mut self.facing_down_at)
+        } else if gravity_z <= -threshold {
+            (&mut self.facing_down_at, &mut self.facing_up_at)
+        } else {
+            return false;
+        };
+
+        let was_other_way = match other_way.take() {
+            Some(at) => now_millis.wrapping_sub(at) <= self.config.flip_max_millis,
+            None => false,
+        };
+        let already_this_way = this_way.is_some();
+        *this_way = Some(now_millis);
+
+        was_other_way && !already_this_way
+    }
+
+    fn update_tap(&mut self, now_millis: u32, last_sample: Sample, sample: Sample) -> Option<Gesture> {
+        let threshold = self.config.tap_jerk_threshold_mg as i32;
+        if threshold > 0 {
+            let jerk: i32 = sample
+                .axes()
+                .iter()
+                .zip(last_sample.axes().iter())
+                .map(|(a, b)| (a - b).abs())
+                .sum();
+
+            match self.spike_start {
+                None if jerk >= threshold => self.spike_start = Some(now_millis),
+                Some(start) if jerk < threshold / 2 => {
+                    self.spike_start = None;
+                    let short = now_millis.wrapping_sub(start) <= self.config.tap_max_millis;
+                    // the strokes of a shake look a lot like taps
+                    if short && self.shake_reversals == 0 {
+                        return self.register_tap(now_millis);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        match self.pending_tap {
+            Some(at) if now_millis.wrapping_sub(at) > self.config.double_tap_window_millis => {
+                self.pending_tap = None;
+                Some(Gesture::Tap)
+            }
+            _ => None,
+        }
+    }
+
+    fn register_tap(&mut self, now_millis: u32) -> Option<Gesture> {
+        match self.pending_tap.replace(now_millis) {
+            Some(at) if now_millis.wrapping_sub(at) <= self.config.double_tap_window_millis => {
+                self.pending_tap = None;
+                Some(Gesture::DoubleTap)
+            }
+            // the previous tap's window ran out before we got to report it
+            Some(_) => Some(Gesture::Tap),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// A trace in `fixtures/`: a sample a line as `millis,x,y,z`, with `#` comments.
+    fn samples(trace: &'static str) -> impl Iterator<Item = (u32, Sample)> {
+        trace.lines().filter(|line| !line.starts_with('#') && !line.is_empty()).map(|line| {
+            let mut fields = line.split(',').map(|field| field.trim().parse::<i32>().unwrap());
+            let mut next = || fields.next().unwrap();
+            (next() as u32, Sample::new(next() as i16, next() as i16, next() as i16))
+        })
+    }
+
+    /// What the recogniser makes of `trace`, and when.
+    fn recognise(config: Config, trace: &'static str) -> Vec<(u32, Gesture)> {
+        let mut recognizer = Recognizer::new(config);
+        samples(trace)
+            .filter_map(|(millis, sample)| Some((millis, recognizer.feed(millis, sample)?)))
+            .collect()
+    }
+
+    fn gestures(trace: &'static str) -> Vec<Gesture> {
+        recognise(Config::DEFAULT, trace).into_iter().map(|(_, gesture)| gesture).collect()
+    }
+
+    const TAP: &str = include_str!("../fixtures/tap.csv");
+    const DOUBLE_TAP: &str = include_str!("../fixtures/double_tap.csv");
+    const SHAKE: &str = include_str!("../fixtures/shake.csv");
+    const FLIP: &str = include_str!("../fixtures/flip.csv");
+    const WALKING: &str = include_str!("../fixtures/walking.csv");
+    const ARM_SWING: &str = include_str!("../fixtures/arm_swing.csv");
+
+    #[test]
+    fn tap() {
+        let recognised = recognise(Config::DEFAULT, TAP);
+        assert_eq!(recognised.iter().map(|&(_, gesture)| gesture).collect::<Vec<_>>(), [Gesture::Tap]);
+        // only once it's too late for it to be a double tap
+        let (millis, _) = recognised[0];
+        assert!(millis > 400 + Config::DEFAULT.double_tap_window_millis, "reported at {}ms", millis);
+    }
+
+    #[test]
+    fn double_tap() {
+        assert_eq!(gestures(DOUBLE_TAP), [Gesture::DoubleTap]);
+    }
+
+    #[test]
+    fn shake() {
+        assert_eq!(gestures(SHAKE), [Gesture::Shake]);
+    }
+
+    #[test]
+    fn wrist_flip() {
+        assert_eq!(gestures(FLIP), [Gesture::WristFlip]);
+    }
+
+    #[test]
+    fn walking_is_not_a_gesture() {
+        assert_eq!(gestures(WALKING), []);
+    }
+
+    #[test]
+    fn arm_swing_is_not_a_gesture() {
+        assert_eq!(gestures(ARM_SWING), []);
+    }
+
+    #[test]
+    fn taps_from_hardware() {
+        // like the firmware, which leaves taps to the accelerometer's click detection
+        let config = Config { tap_jerk_threshold_mg: 0, ..Config::DEFAULT };
+        assert_eq!(recognise(config, TAP), []);
+
+        let mut recognizer = Recognizer::new(config);
+        let mut recognised = Vec::new();
+        for (millis, sample) in samples(TAP) {
+            recognised.extend(recognizer.feed(millis, sample));
+            if millis == 400 {
+                recognised.extend(recognizer.tap(millis));
+            }
+        }
+        assert_eq!(recognised, [Gesture::Tap]);
+    }
+
+    #[test]
+    fn tap_in_cooldown_is_dropped() {
+        let still = Sample::new(0, 0, 1000);
+        let mut recognizer = Recognizer::new(Config::DEFAULT);
+        assert_eq!(recognizer.tap(0), None);
+        assert_eq!(recognizer.tap(100), Some(Gesture::DoubleTap));
+        // its window runs out at 600ms, before the cooldown does at 700ms
+        assert_eq!(recognizer.tap(200), None);
+        for millis in (200..2000).step_by(10) {
+            assert_eq!(recognizer.feed(millis, still), None, "at {}ms", millis);
+        }
+    }
+}
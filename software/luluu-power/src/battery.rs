@@ -0,0 +1,246 @@
+//! How much charge the battery has left, and what the player should do about it.
+//!
+//! Measuring the voltage is the firmware's business; this only turns readings into a
+//! [`BatteryLevel`].
+
+/// Tunables for [`BatteryPolicy`]. Thresholds are on the smoothed battery voltage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatteryConfig {
+    /// Below this, show the low battery icon and dim the backlight.
+    pub low_millivolts: u16,
+    /// Below this, also halve the frame rate.
+    pub critical_millivolts: u16,
+    /// Below this, turn everything off before the cell is damaged.
+    pub shutdown_millivolts: u16,
+    /// How far the voltage has to recover above a threshold to go back up a level, so that the
+    /// sag and recovery around a bright frame don't make us flip back and forth.
+    pub hysteresis_millivolts: u16,
+}
+
+impl BatteryConfig {
+    pub const DEFAULT: Self = Self {
+        low_millivolts: 3550,
+        critical_millivolts: 3420,
+        shutdown_millivolts: 3300,
+        hysteresis_millivolts: 60,
+    };
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// How much charge is left, from the player's point of view. Ordered from most to least charge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BatteryLevel {
+    Normal,
+    Low,
+    Critical,
+    Shutdown,
+}
+
+impl BatteryLevel {
+    /// Show every `frame_step`th frame of the animation, each for `frame_step` times as long.
+    pub fn frame_step(self) -> u32 {
+        match self {
+            BatteryLevel::Normal | BatteryLevel::Low => 1,
+            BatteryLevel::Critical | BatteryLevel::Shutdown => 2,
+        }
+    }
+
+    /// Brightest the backlight is allowed to be, in percent.
+    pub fn max_brightness_percent(self) -> u8 {
+        match self {
+            BatteryLevel::Normal => 100,
+            BatteryLevel::Low => 60,
+            BatteryLevel::Critical => 30,
+            BatteryLevel::Shutdown => 0,
+        }
+    }
+
+    pub fn show_warning(self) -> bool {
+        self >= BatteryLevel::Low
+    }
+}
+
+/// Each new reading moves the smoothed voltage `1 / 2^SMOOTHING_SHIFT` of the way.
+const SMOOTHING_SHIFT: u32 = 2;
+
+/// Turns battery voltage readings into a [`BatteryLevel`].
+///
+/// Readings are smoothed, since the voltage dips while the backlight is on and the SD card is busy.
+/// The level only gets worse until the battery is charging, or has recovered by the configured
+/// hysteresis. Once at [`BatteryLevel::Shutdown`] it stays there.
+pub struct BatteryPolicy {
+    config: BatteryConfig,
+    smoothed_millivolts: Option<u16>,
+    level: BatteryLevel,
+}
+
+impl BatteryPolicy {
+    pub fn new(config: BatteryConfig) -> Self {
+        Self {
+            config,
+            smoothed_millivolts: None,
+            level: BatteryLevel::Normal,
+        }
+    }
+
+    #[inline(always)]
+    pub fn level(&self) -> BatteryLevel {
+        self.level
+    }
+
+    /// Feed the latest reading, returning the new level if it changed. A missing reading, which
+    /// means the board can't measure its battery, leaves the level alone.
+    pub fn update(&mut self, millivolts: Option<u16>, charging: bool) -> Option<BatteryLevel> {
+        if let Some(millivolts) = millivolts {
+            let smoothed = match self.smoothed_millivolts {
+                Some(smoothed) => {
+                    let delta = (millivolts as i32 - smoothed as i32) >> SMOOTHING_SHIFT;
+                    (smoothed as i32 + delta) as u16
+                }
+                None => millivolts,
+            };
+            self.smoothed_millivolts = Some(smoothed);
+        }
+
+        let new_level = if self.level == BatteryLevel::Shutdown {
+            BatteryLevel::Shutdown
+        } else if charging {
+            // the voltage reads high while charging, but we're not going to run out either
+            BatteryLevel::Normal
+        } else if let Some(smoothed) = self.smoothed_millivolts {
+            let level = self.level_for(smoothed);
+            if level >= self.level {
+                level
+            } else {
+                self.level_for(smoothed.saturating_sub(self.config.hysteresis_millivolts))
+                    .min(self.level)
+            }
+        } else {
+            self.level
+        };
+
+        if new_level == self.level {
+            return None;
+        }
+        self.level = new_level;
+        Some(new_level)
+    }
+
+    fn level_for(&self, millivolts: u16) -> BatteryLevel {
+        if millivolts < self.config.shutdown_millivolts {
+            BatteryLevel::Shutdown
+        } else if millivolts < self.config.critical_millivolts {
+            BatteryLevel::Critical
+        } else if millivolts < self.config.low_millivolts {
+            BatteryLevel::Low
+        } else {
+            BatteryLevel::Normal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn policy(first_millivolts: u16) -> BatteryPolicy {
+        let mut policy = BatteryPolicy::new(BatteryConfig::DEFAULT);
+        policy.update(Some(first_millivolts), false);
+        policy
+    }
+
+    /// Feed the same reading until the smoothed voltage has caught up, returning each change.
+    fn settle(policy: &mut BatteryPolicy, millivolts: u16) -> Vec<BatteryLevel> {
+        (0..32).filter_map(|_| policy.update(Some(millivolts), false)).collect()
+    }
+
+    #[test]
+    fn starts_normal_and_ignores_missing_readings() {
+        let mut policy = BatteryPolicy::new(BatteryConfig::DEFAULT);
+        assert_eq!(policy.level(), BatteryLevel::Normal);
+        assert_eq!(policy.update(None, false), None);
+        assert_eq!(policy.level(), BatteryLevel::Normal);
+
+        let mut policy = self::policy(3500);
+        assert_eq!(policy.update(None, false), None);
+        assert_eq!(policy.level(), BatteryLevel::Low);
+    }
+
+    #[test]
+    fn first_reading_is_taken_as_is() {
+        let mut policy = BatteryPolicy::new(BatteryConfig::DEFAULT);
+        assert_eq!(policy.update(Some(3400), false), Some(BatteryLevel::Critical));
+    }
+
+    #[test]
+    fn crosses_each_threshold_on_the_way_down() {
+        let mut policy = policy(4000);
+        assert_eq!(settle(&mut policy, 3200), [BatteryLevel::Low, BatteryLevel::Critical, BatteryLevel::Shutdown]);
+    }
+
+    #[test]
+    fn smooths_a_single_dip() {
+        let mut policy = policy(3600);
+        // a quarter of the way to 3400 is 3550, which is still enough
+        assert_eq!(policy.update(Some(3400), false), None);
+        assert_eq!(policy.level(), BatteryLevel::Normal);
+        assert_eq!(policy.update(Some(3400), false), Some(BatteryLevel::Low));
+    }
+
+    #[test]
+    fn recovering_needs_the_hysteresis() {
+        let config = BatteryConfig::DEFAULT;
+        let mut policy = policy(3500);
+        assert_eq!(policy.level(), BatteryLevel::Low);
+
+        // back above the threshold, but not by enough
+        assert_eq!(settle(&mut policy, config.low_millivolts + config.hysteresis_millivolts - 30), []);
+        assert_eq!(policy.level(), BatteryLevel::Low);
+
+        assert_eq!(settle(&mut policy, config.low_millivolts + config.hysteresis_millivolts + 10), [BatteryLevel::Normal]);
+    }
+
+    #[test]
+    fn recovers_one_level_at_a_time() {
+        let config = BatteryConfig::DEFAULT;
+        let mut policy = policy(3400);
+        assert_eq!(policy.level(), BatteryLevel::Critical);
+
+        // enough above critical, not enough above low
+        assert_eq!(settle(&mut policy, config.critical_millivolts + config.hysteresis_millivolts + 10), [BatteryLevel::Low]);
+    }
+
+    #[test]
+    fn charging_is_normal() {
+        let mut policy = policy(3400);
+        assert_eq!(policy.update(Some(3400), true), Some(BatteryLevel::Normal));
+        assert_eq!(policy.update(Some(3400), false), Some(BatteryLevel::Critical));
+    }
+
+    #[test]
+    fn shutdown_is_final() {
+        let mut policy = policy(3250);
+        assert_eq!(policy.level(), BatteryLevel::Shutdown);
+        assert_eq!(policy.update(Some(4200), true), None);
+        assert_eq!(settle(&mut policy, 4200), []);
+        assert_eq!(policy.level(), BatteryLevel::Shutdown);
+    }
+
+    #[test]
+    fn levels_get_stricter() {
+        let levels = [BatteryLevel::Normal, BatteryLevel::Low, BatteryLevel::Critical, BatteryLevel::Shutdown];
+        assert_eq!(levels.map(BatteryLevel::frame_step), [1, 1, 2, 2]);
+        assert_eq!(levels.map(BatteryLevel::max_brightness_percent), [100, 60, 30, 0]);
+        assert_eq!(levels.map(BatteryLevel::show_warning), [false, true, true, true]);
+    }
+}
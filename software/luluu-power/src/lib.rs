@@ -0,0 +1,402 @@
+#![no_std]
+
+//! When the LuLuu's display sleeps and wakes, and in [`battery`], how it copes with a flat battery.
+//!
+//! It's kept out of the firmware, with no hardware dependencies, so it can be tested on the host
+//! against a [`luluu_accel::Mock`] accelerometer and made-up battery readings.
+
+use luluu_accel::{Click, Events, Sample};
+
+pub mod battery;
+
+/// Tunables for [`PowerPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PowerConfig {
+    /// Dim the backlight after this long without motion or other activity.
+    pub dim_after_millis: u32,
+    /// Brightness in percent to dim the backlight to.
+    pub dim_brightness_percent: u8,
+    /// Go to sleep after this long without motion or other activity.
+    pub sleep_after_millis: u32,
+    /// Change in acceleration between two consecutive samples, summed over all axes, that counts
+    /// as the sleeve moving.
+    pub motion_threshold_mg: u16,
+    /// Acceleration along the display's Z axis above which the display is considered to be facing
+    /// the wearer. Negate it if the board is mounted the other way up.
+    pub raise_min_z_mg: i16,
+    /// Maximum acceleration along X and Y for the display to still count as facing the wearer.
+    pub raise_max_tilt_mg: i16,
+    /// How long the display must be held facing the wearer after a raise to wake up.
+    pub raise_hold_millis: u32,
+    /// Wake up on a single or double tap.
+    pub wake_on_tap: bool,
+}
+
+impl PowerConfig {
+    pub const DEFAULT: Self = Self {
+        dim_after_millis: 20_000,
+        dim_brightness_percent: 15,
+        sleep_after_millis: 30_000,
+        motion_threshold_mg: 120,
+        raise_min_z_mg: 750,
+        raise_max_tilt_mg: 450,
+        raise_hold_millis: 150,
+        wake_on_tap: true,
+    };
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerTransition {
+    /// Dim the backlight, but keep playing.
+    Dim,
+    /// Turn off the backlight and put the display to sleep.
+    Sleep,
+    /// Wake the display back up if it was asleep, and restore the backlight.
+    Wake,
+}
+
+/// Decides when to put the display to sleep and when to wake it, based on accelerometer samples,
+/// accelerometer interrupts and other activity.
+///
+/// While awake, any motion or other activity resets the idle timer, and the backlight is dimmed and
+/// then the display put to sleep as it runs out. While asleep, only a wrist raise (the display
+/// being turned to face the wearer and held there) or a tap wakes it up.
+pub struct PowerPolicy {
+    config: PowerConfig,
+    asleep: bool,
+    dimmed: bool,
+    last_activity: u32,
+    last_sample: Option<Sample>,
+    /// Whether we've seen the display facing away since going to sleep, so that a sleeve resting
+    /// face up doesn't immediately wake itself.
+    armed: bool,
+    facing_since: Option<u32>,
+}
+
+impl PowerPolicy {
+    pub fn new(config: PowerConfig, now_millis: u32) -> Self {
+        Self {
+            config,
+            asleep: false,
+            dimmed: false,
+            last_activity: now_millis,
+            last_sample: None,
+            armed: false,
+            facing_since: None,
+        }
+    }
+
+    pub fn config(&self) -> &PowerConfig {
+        &self.config
+    }
+
+    #[inline(always)]
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
+    }
+
+    /// Whether the backlight should be dimmed. Never true while asleep.
+    #[inline(always)]
+    pub fn is_dimmed(&self) -> bool {
+        self.dimmed
+    }
+
+    /// Something other than motion happened that should count as the wearer interacting with the
+    /// sleeve, like plugging it into a computer.
+    pub fn note_activity(&mut self, now_millis: u32) -> Option<PowerTransition> {
+        self.last_activity = now_millis;
+        self.wake()
+    }
+
+    /// Feed the latest accelerometer state. Either may be missing if reading it failed.
+    pub fn update(&mut self, now_millis: u32, sample: Option<Sample>, events: Option<Events>) -> Option<PowerTransition> {
+        let mut moved = events.map(|e| e.activity).unwrap_or(false);
+        let tapped = matches!(events.and_then(|e| e.click), Some(Click::Single | Click::Double));
+
+        if let Some(sample) = sample {
+            if let Some(last) = self.last_sample {
+                let delta = (sample.x as i32 - last.x as i32).unsigned_abs()
+                    + (sample.y as i32 - last.y as i32).unsigned_abs()
+                    + (sample.z as i32 - last.z as i32).unsigned_abs();
+                moved |= delta >= self.config.motion_threshold_mg as u32;
+            }
+            self.last_sample = Some(sample);
+        }
+
+        if !self.asleep {
+            if moved || tapped {
+                self.last_activity = now_millis;
+                return self.wake();
+            }
+
+            let idle_millis = now_millis.wrapping_sub(self.last_activity);
+            if idle_millis >= self.config.sleep_after_millis {
+                self.asleep = true;
+                self.dimmed = false;
+                self.armed = false;
+                self.facing_since = None;
+                return Some(PowerTransition::Sleep);
+            }
+            if !self.dimmed && idle_millis >= self.config.dim_after_millis {
+                self.dimmed = true;
+                return Some(PowerTransition::Dim);
+            }
+            return None;
+        }
+
+        if tapped && self.config.wake_on_tap {
+            self.last_activity = now_millis;
+            return self.wake();
+        }
+
+        let sample = sample?;
+
+        if !self.is_facing_wearer(sample) {
+            self.armed = true;
+            self.facing_since = None;
+            return None;
+        }
+
+        if !self.armed {
+            return None;
+        }
+
+        let facing_since = *self.facing_since.get_or_insert(now_millis);
+        if now_millis.wrapping_sub(facing_since) >= self.config.raise_hold_millis {
+            self.last_activity = now_millis;
+            return self.wake();
+        }
+
+        None
+    }
+
+    fn is_facing_wearer(&self, sample: Sample) -> bool {
+        let z_ok = if self.config.raise_min_z_mg >= 0 {
+            sample.z >= self.config.raise_min_z_mg
+        } else {
+            sample.z <= self.config.raise_min_z_mg
+        };
+        z_ok
+            && sample.x.unsigned_abs() <= self.config.raise_max_tilt_mg.unsigned_abs()
+            && sample.y.unsigned_abs() <= self.config.raise_max_tilt_mg.unsigned_abs()
+    }
+
+    fn wake(&mut self) -> Option<PowerTransition> {
+        if self.asleep || self.dimmed {
+            self.asleep = false;
+            self.dimmed = false;
+            Some(PowerTransition::Wake)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use luluu_accel::{Accelerometer, ClickConfig, Mock};
+
+    use super::*;
+
+    /// How often the firmware polls the accelerometer.
+    const POLL_MILLIS: u32 = 40;
+
+    const FACING: Sample = Sample::new(0, 0, 1000);
+    const AWAY: Sample = Sample::new(1000, 0, 0);
+    const TILTED: Sample = Sample::new(600, 0, 800);
+
+    const NOTHING: Events = Events { click: None, free_fall: false, activity: false };
+    const TAP: Events = Events { click: Some(Click::Single), free_fall: false, activity: false };
+
+    fn held(sample: Sample, millis: u32) -> impl Iterator<Item = (Sample, Events)> {
+        core::iter::repeat_n((sample, NOTHING), (millis / POLL_MILLIS) as usize)
+    }
+
+    /// Poll `readings` like the firmware does, every [`POLL_MILLIS`] from `start`, returning the
+    /// time after the last one and each transition with when it happened.
+    fn run(policy: &mut PowerPolicy, start: u32, readings: &[(Sample, Events)], click: bool) -> (u32, Vec<(u32, PowerTransition)>) {
+        let mut accel = Mock::new(readings);
+        if click {
+            accel.configure_click(Some(ClickConfig::default())).unwrap();
+        }
+        let mut now = start;
+        let mut transitions = Vec::new();
+        while let Ok(sample) = accel.sample() {
+            let events = accel.events().unwrap();
+            if let Some(transition) = policy.update(now, Some(sample), Some(events)) {
+                transitions.push((now, transition));
+            }
+            now += POLL_MILLIS;
+        }
+        assert_eq!(accel.sampled(), readings.len());
+        (now, transitions)
+    }
+
+    /// A policy that's gone to sleep with the display held at `sample`, and been polled once since,
+    /// and the time it's got to.
+    fn asleep(config: PowerConfig, sample: Sample) -> (PowerPolicy, u32) {
+        let mut policy = PowerPolicy::new(config, 0);
+        let readings: Vec<_> = held(sample, config.sleep_after_millis + 2 * POLL_MILLIS).collect();
+        let (now, transitions) = run(&mut policy, 0, &readings, false);
+        assert_eq!(transitions.last(), Some(&(config.sleep_after_millis, PowerTransition::Sleep)));
+        assert!(policy.is_asleep());
+        (policy, now)
+    }
+
+    #[test]
+    fn dims_then_sleeps() {
+        let mut policy = PowerPolicy::new(PowerConfig::DEFAULT, 0);
+        let readings: Vec<_> = held(FACING, 40_000).collect();
+        let (_, transitions) = run(&mut policy, 0, &readings, false);
+        assert_eq!(transitions, [(20_000, PowerTransition::Dim), (30_000, PowerTransition::Sleep)]);
+        assert!(policy.is_asleep());
+        assert!(!policy.is_dimmed());
+    }
+
+    #[test]
+    fn motion_resets_the_idle_timer() {
+        let mut policy = PowerPolicy::new(PowerConfig::DEFAULT, 0);
+        let readings: Vec<_> = held(FACING, 15_000).chain(held(TILTED, 31_000)).collect();
+        let (_, transitions) = run(&mut policy, 0, &readings, false);
+        assert_eq!(transitions, [(35_000, PowerTransition::Dim), (45_000, PowerTransition::Sleep)]);
+    }
+
+    #[test]
+    fn motion_while_dimmed_wakes() {
+        let mut policy = PowerPolicy::new(PowerConfig::DEFAULT, 0);
+        let readings: Vec<_> = held(FACING, 25_000).chain(held(TILTED, 1_000)).collect();
+        let (_, transitions) = run(&mut policy, 0, &readings, false);
+        assert_eq!(transitions, [(20_000, PowerTransition::Dim), (25_000, PowerTransition::Wake)]);
+        assert!(!policy.is_dimmed());
+    }
+
+    #[test]
+    fn jitter_and_activity_interrupts() {
+        // wobbling by less than the threshold isn't moving
+        let wobble = Sample::new(40, -30, 1040);
+        let mut policy = PowerPolicy::new(PowerConfig::DEFAULT, 0);
+        let readings: Vec<_> = held(FACING, 12_000).flat_map(|reading| [reading, (wobble, NOTHING)]).collect();
+        let (_, transitions) = run(&mut policy, 0, &readings, false);
+        assert_eq!(transitions, [(20_000, PowerTransition::Dim)]);
+
+        // but the accelerometer's own activity detection is
+        let mut policy = PowerPolicy::new(PowerConfig::DEFAULT, 0);
+        assert_eq!(policy.update(15_000, None, Some(Events { activity: true, ..NOTHING })), None);
+        assert_eq!(policy.update(34_960, Some(FACING), Some(NOTHING)), None);
+        assert_eq!(policy.update(35_000, Some(FACING), Some(NOTHING)), Some(PowerTransition::Dim));
+    }
+
+    #[test]
+    fn motion_while_asleep_doesnt_wake() {
+        let (mut policy, now) = asleep(PowerConfig::DEFAULT, AWAY);
+        let readings: Vec<_> = held(TILTED, 1_000).chain(held(AWAY, 1_000)).collect();
+        let (_, transitions) = run(&mut policy, now, &readings, false);
+        assert_eq!(transitions, []);
+        assert!(policy.is_asleep());
+    }
+
+    #[test]
+    fn wakes_on_a_raise_held_long_enough() {
+        let (mut policy, now) = asleep(PowerConfig::DEFAULT, AWAY);
+        let raised_at = now;
+        let readings: Vec<_> = held(FACING, 1_000).collect();
+        let (_, transitions) = run(&mut policy, now, &readings, false);
+        // facing from the first sample, and 150ms later is the fifth
+        assert_eq!(transitions, [(raised_at + 160, PowerTransition::Wake)]);
+        assert!(!policy.is_asleep());
+    }
+
+    #[test]
+    fn a_raise_thats_too_short_doesnt_wake() {
+        let (mut policy, now) = asleep(PowerConfig::DEFAULT, AWAY);
+        let readings: Vec<_> = held(FACING, 120).chain(held(AWAY, 200)).chain(held(FACING, 120)).collect();
+        let (now, transitions) = run(&mut policy, now, &readings, false);
+        assert_eq!(transitions, []);
+
+        // the hold starts again from the next raise
+        let readings: Vec<_> = held(FACING, 400).collect();
+        let (_, transitions) = run(&mut policy, now, &readings, false);
+        assert_eq!(transitions, [(now + 40, PowerTransition::Wake)]);
+    }
+
+    #[test]
+    fn only_wakes_once_its_faced_away() {
+        // gone to sleep facing up, like resting on a table
+        let (mut policy, now) = asleep(PowerConfig::DEFAULT, FACING);
+        let readings: Vec<_> = held(FACING, 5_000).collect();
+        let (now, transitions) = run(&mut policy, now, &readings, false);
+        assert_eq!(transitions, []);
+
+        let readings: Vec<_> = held(AWAY, 200).chain(held(FACING, 1_000)).collect();
+        let (_, transitions) = run(&mut policy, now, &readings, false);
+        assert_eq!(transitions, [(now + 200 + 160, PowerTransition::Wake)]);
+    }
+
+    #[test]
+    fn tilted_isnt_facing() {
+        let (mut policy, now) = asleep(PowerConfig::DEFAULT, AWAY);
+        let readings: Vec<_> = held(TILTED, 2_000).collect();
+        let (_, transitions) = run(&mut policy, now, &readings, false);
+        assert_eq!(transitions, []);
+    }
+
+    #[test]
+    fn mounted_the_other_way_up() {
+        let config = PowerConfig { raise_min_z_mg: -750, ..PowerConfig::DEFAULT };
+        let (mut policy, now) = asleep(config, AWAY);
+        let readings: Vec<_> = held(FACING, 1_000).chain(held(Sample::new(0, 0, -1000), 1_000)).collect();
+        let (_, transitions) = run(&mut policy, now, &readings, false);
+        assert_eq!(transitions, [(now + 1_000 + 160, PowerTransition::Wake)]);
+    }
+
+    #[test]
+    fn wake_on_tap() {
+        let (mut policy, now) = asleep(PowerConfig::DEFAULT, AWAY);
+        let readings = [(AWAY, NOTHING), (AWAY, TAP), (AWAY, NOTHING)];
+        let (_, transitions) = run(&mut policy, now, &readings, true);
+        assert_eq!(transitions, [(now + 40, PowerTransition::Wake)]);
+
+        let config = PowerConfig { wake_on_tap: false, ..PowerConfig::DEFAULT };
+        let (mut policy, now) = asleep(config, AWAY);
+        let (_, transitions) = run(&mut policy, now, &readings, true);
+        assert_eq!(transitions, []);
+    }
+
+    #[test]
+    fn a_tap_while_awake_is_activity() {
+        let mut policy = PowerPolicy::new(PowerConfig::DEFAULT, 0);
+        let readings: Vec<_> = held(FACING, 10_000).chain([(FACING, TAP)]).chain(held(FACING, 25_000)).collect();
+        let (_, transitions) = run(&mut policy, 0, &readings, true);
+        assert_eq!(transitions, [(30_000, PowerTransition::Dim)]);
+    }
+
+    #[test]
+    fn other_activity() {
+        let (mut policy, now) = asleep(PowerConfig::DEFAULT, FACING);
+        assert_eq!(policy.note_activity(now), Some(PowerTransition::Wake));
+        assert_eq!(policy.note_activity(now + 1_000), None);
+        assert_eq!(policy.update(now + 20_960, Some(FACING), None), None);
+        assert_eq!(policy.update(now + 21_000, Some(FACING), None), Some(PowerTransition::Dim));
+    }
+
+    #[test]
+    fn never() {
+        let config = PowerConfig { dim_after_millis: u32::MAX, sleep_after_millis: u32::MAX, ..PowerConfig::DEFAULT };
+        let mut policy = PowerPolicy::new(config, 0);
+        let readings: Vec<_> = held(FACING, 100_000).collect();
+        let (_, transitions) = run(&mut policy, 0, &readings, false);
+        assert_eq!(transitions, []);
+    }
+}
@@ -0,0 +1,271 @@
+//! Framing messages for the wire.
+//!
+//! A frame is the message followed by its CRC-16, [COBS] encoded so there are no zero bytes in it,
+//! then a zero byte to end it. A reader that starts part way through a frame, or sees one get
+//! garbled, only loses that frame.
+//!
+//! [COBS]: https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing
+
+use crate::{Error, MAX_MESSAGE_LEN};
+
+const CHECKSUM_LEN: usize = 2;
+
+/// The most bytes a frame of a [`MAX_MESSAGE_LEN`] message takes on the wire.
+pub const MAX_FRAME_LEN: usize = cobs_len(MAX_MESSAGE_LEN + CHECKSUM_LEN) + 1;
+
+/// Bytes taken by `len` bytes once they're COBS encoded: one more for every 254, or part of.
+const fn cobs_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Frame `message` into `out`, returning how many bytes of it the frame takes.
+pub fn encode(message: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+    if message.len() > MAX_MESSAGE_LEN || out.len() < cobs_len(message.len() + CHECKSUM_LEN) + 1 {
+        return Err(Error::TooLong);
+    }
+    let checksum = crc16(message).to_le_bytes();
+
+    // the byte before each run of non-zero bytes says how far it is to the next zero, which is
+    // filled in once the run's over
+    let mut code_idx = 0;
+    let mut len = 1;
+    for &byte in message.iter().chain(&checksum) {
+        if byte != 0 {
+            out[len] = byte;
+            len += 1;
+        }
+        if byte == 0 || len - code_idx == 0xff {
+            out[code_idx] = (len - code_idx) as u8;
+            code_idx = len;
+            len += 1;
+        }
+    }
+    out[code_idx] = (len - code_idx) as u8;
+    out[len] = 0;
+    Ok(len + 1)
+}
+
+/// Collects frames from bytes as they arrive.
+pub struct FrameReader {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    /// Where the message is in `buf` after a frame ends, until the next byte.
+    message_len: usize,
+    /// The frame being read is too long, so it's skipped up to its end.
+    overflowed: bool,
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        Self { buf: [0; MAX_FRAME_LEN], len: 0, message_len: 0, overflowed: false }
+    }
+
+    /// Take the next byte. `Some(Ok(()))` if it ended a frame, and the message in it is ready to
+    /// read with [`FrameReader::message`]. `Some(Err(_))` if it ended a frame that's no good.
+    pub fn push(&mut self, byte: u8) -> Option<Result<(), Error>> {
+        self.message_len = 0;
+        if byte != 0 {
+            if self.len == self.buf.len() {
+                self.overflowed = true;
+            } else {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+            return None;
+        }
+
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflowed) {
+            return Some(Err(Error::TooLong));
+        }
+        // two zeros in a row are just a gap between frames
+        if len == 0 {
+            return None;
+        }
+        let decoded_len = match decode_in_place(&mut self.buf[..len]) {
+            Some(decoded_len) if decoded_len >= CHECKSUM_LEN => decoded_len,
+            _ => return Some(Err(Error::Malformed)),
+        };
+        let message_len = decoded_len - CHECKSUM_LEN;
+        let checksum = u16::from_le_bytes([self.buf[message_len], self.buf[message_len + 1]]);
+        if checksum != crc16(&self.buf[..message_len]) {
+            return Some(Err(Error::BadChecksum));
+        }
+        self.message_len = message_len;
+        Some(Ok(()))
+    }
+
+    /// The message in the frame the last byte pushed ended, or nothing if it didn't end one.
+    pub fn message(&self) -> &[u8] {
+        &self.buf[..self.message_len]
+    }
+
+    /// Forget about any frame part way through.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.message_len = 0;
+        self.overflowed = false;
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Undo the COBS encoding of `bytes`, without the zero at the end. The decoded bytes are never any
+/// longer so they're put back in `bytes`. Returns how many there are, or `None` if it's not valid.
+fn decode_in_place(bytes: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < bytes.len() {
+        let code = bytes[read] as usize;
+        if code == 0 || read + code > bytes.len() {
+            return None;
+        }
+        bytes.copy_within(read + 1..read + code, write);
+        write += code - 1;
+        read += code;
+        // runs of the longest length don't end with a zero
+        if code != 0xff && read < bytes.len() {
+            bytes[write] = 0;
+            write += 1;
+        }
+    }
+    Some(write)
+}
+
+/// CRC-16/CCITT-FALSE.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn encoded(message: &[u8]) -> Vec<u8> {
+        let mut out = [0; MAX_FRAME_LEN];
+        let len = encode(message, &mut out).unwrap();
+        out[..len].to_vec()
+    }
+
+    /// Push all of `bytes`, returning what each frame in them came to.
+    fn read(reader: &mut FrameReader, bytes: &[u8]) -> Vec<Result<Vec<u8>, Error>> {
+        let mut frames = Vec::new();
+        for &byte in bytes {
+            match reader.push(byte) {
+                Some(Ok(())) => frames.push(Ok(reader.message().to_vec())),
+                Some(Err(error)) => frames.push(Err(error)),
+                None => assert!(reader.message().is_empty()),
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn crc() {
+        // the standard check value
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(&[]), 0xffff);
+    }
+
+    #[test]
+    fn round_trip() {
+        let long: Vec<u8> = (0..MAX_MESSAGE_LEN).map(|i| (i % 251) as u8 + 1).collect();
+        let messages: [&[u8]; 9] = [
+            &[],
+            &[0],
+            &[0, 0, 0],
+            b"hello",
+            &[1, 0, 2, 0, 0, 3],
+            &[0xff; 253],
+            &[0xff; 254],
+            &[0xff; 255],
+            &long,
+        ];
+        let mut reader = FrameReader::new();
+        for message in messages {
+            let frame = encoded(message);
+            assert!(frame.len() <= MAX_FRAME_LEN);
+            assert_eq!(frame.iter().position(|&byte| byte == 0), Some(frame.len() - 1));
+            assert_eq!(read(&mut reader, &frame), [Ok(message.to_vec())]);
+        }
+    }
+
+    #[test]
+    fn gaps_between_frames() {
+        let mut bytes = std::vec![0, 0];
+        bytes.extend(encoded(b"one"));
+        bytes.extend([0, 0, 0]);
+        bytes.extend(encoded(b"two"));
+        assert_eq!(read(&mut FrameReader::new(), &bytes), [Ok(b"one".to_vec()), Ok(b"two".to_vec())]);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let frame = encoded(b"hello, luluu");
+        for cut in 1..frame.len() - 1 {
+            let mut bytes = frame[cut..].to_vec();
+            bytes.extend(encoded(b"next"));
+            let frames = read(&mut FrameReader::new(), &bytes);
+            // starting part way through only loses that frame
+            assert_eq!(frames.len(), 2, "cut at {cut}");
+            assert!(frames[0].is_err(), "cut at {cut}");
+            assert_eq!(frames[1], Ok(b"next".to_vec()));
+        }
+
+        let mut bytes = frame[..frame.len() - 3].to_vec();
+        bytes.push(0);
+        assert!(read(&mut FrameReader::new(), &bytes)[0].is_err());
+        // too short for the checksum
+        assert_eq!(read(&mut FrameReader::new(), &[2, 1, 0]), [Err(Error::Malformed)]);
+        // a run going past the end
+        assert_eq!(read(&mut FrameReader::new(), &[9, 1, 2, 0]), [Err(Error::Malformed)]);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut frame = encoded(b"hello");
+        frame[2] ^= 0x01;
+        frame.extend(encoded(b"next"));
+        assert_eq!(read(&mut FrameReader::new(), &frame), [Err(Error::BadChecksum), Ok(b"next".to_vec())]);
+    }
+
+    #[test]
+    fn oversized() {
+        let mut out = [0; MAX_FRAME_LEN];
+        assert_eq!(encode(&[1; MAX_MESSAGE_LEN + 1], &mut out), Err(Error::TooLong));
+        assert_eq!(encode(b"hello", &mut out[..8]), Err(Error::TooLong));
+        assert_eq!(encode(b"hello", &mut out[..9]), Ok(9));
+
+        let mut bytes = std::vec![1; MAX_FRAME_LEN + 10];
+        bytes.push(0);
+        bytes.extend(encoded(b"next"));
+        assert_eq!(read(&mut FrameReader::new(), &bytes), [Err(Error::TooLong), Ok(b"next".to_vec())]);
+    }
+
+    #[test]
+    fn reset_forgets_half_a_frame() {
+        let frame = encoded(b"hello");
+        let mut reader = FrameReader::new();
+        assert!(read(&mut reader, &frame[..3]).is_empty());
+        reader.reset();
+        assert_eq!(read(&mut reader, &encoded(b"next")), [Ok(b"next".to_vec())]);
+    }
+}
@@ -0,0 +1,701 @@
+#![no_std]
+
+//! The protocol for controlling a LuLuu from a computer over its USB serial port, spoken by the
+//! firmware and by `luluu-cli device`.
+//!
+//! The computer sends a [`Request`] and waits for the LuLuu to answer it with one [`Response`]
+//! before it sends anything else. Each message goes over the wire in a [`frame`], which adds a
+//! checksum and marks where it ends.
+//!
+//! Frames can be streamed to the display too, see [`stream`].
+//!
+//! This crate has no hardware dependencies. [`Client`] works over anything that implements
+//! [`Link`], so both ends can be run on the host against a stand-in link that hands the bytes from
+//! one straight to the other.
+
+use core::fmt;
+
+pub mod frame;
+pub mod stream;
+
+use frame::FrameReader;
+use luluu_enc::{DirtyRect, Size, DIRTY_RECT_SIZE};
+
+/// The longest message, after it's been encoded but before it's framed.
+pub const MAX_MESSAGE_LEN: usize = 8 + MAX_CHUNK_LEN;
+
+/// The most bytes of a file sent in one [`Request::UploadData`], or of a frame in one
+/// [`Request::StreamData`].
+pub const MAX_CHUNK_LEN: usize = 256;
+
+/// File names on the card are 8.3, like `FIREWORK.LU`.
+pub const MAX_NAME_LEN: usize = 12;
+
+/// Something in the bytes of a message or frame that isn't right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Too long to fit in the buffer it's going in, or longer than [`MAX_MESSAGE_LEN`].
+    TooLong,
+    /// Cut short, or not a message this version of the protocol knows about.
+    Malformed,
+    /// The frame was garbled on the way.
+    BadChecksum,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooLong => write!(f, "message too long"),
+            Error::Malformed => write!(f, "malformed message"),
+            Error::BadChecksum => write!(f, "message garbled on the way"),
+        }
+    }
+}
+
+/// Sent from the computer to the LuLuu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Request<'a> {
+    /// Answered with [`Response::Version`].
+    Version,
+    /// Answered with [`Response::Battery`].
+    Battery,
+    /// Answered with [`Response::Files`].
+    ListFiles,
+    /// Switch to the animation in the file called `name`.
+    Play { name: &'a str },
+    /// Set the backlight brightness until the next power on, in percent.
+    SetBrightness { percent: u8 },
+    /// Start writing the file called `name` on the card, replacing it if it's already there.
+    /// `len` bytes of it follow in [`Request::UploadData`]s, and nothing else is answered until
+    /// they have, or until there's been nothing for a few seconds. Playback stops meanwhile.
+    UploadStart { name: &'a str, len: u32 },
+    /// The next part of the file being uploaded. Answered once it's on the card.
+    UploadData { bytes: &'a [u8] },
+    /// Restart the LuLuu, like turning it off and on again, after answering.
+    Reboot,
+    /// Show a `size` by `size` frame instead of the card's animations. The pixels in `rect` follow
+    /// in `len` bytes of [`Request::StreamData`], row by row, [packed](stream) if `packed` is set.
+    /// The rest of the frame is left as it was in the last one. Answered once it's on the display.
+    /// The card's animations come back after [`Request::StreamEnd`], or once frames stop arriving
+    /// for a few seconds.
+    StreamFrame { size: Size, rect: DirtyRect, packed: bool, len: u32 },
+    /// The next part of the frame being streamed. Not answered, so they can be sent one after the
+    /// other without waiting.
+    StreamData { bytes: &'a [u8] },
+    /// Go back to the card's animations.
+    StreamEnd,
+}
+
+/// Sent from the LuLuu to the computer, answering a [`Request`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Response<'a> {
+    /// Done what was asked.
+    Ok,
+    Failed(Failure),
+    /// The firmware's version.
+    Version { version: &'a str },
+    /// The battery's voltage, if it could be measured, and whether it's charging.
+    Battery { millivolts: Option<u16>, charging: bool },
+    /// The names of the animations on the card, one per line.
+    Files { names: &'a str },
+}
+
+/// Why the LuLuu couldn't do what was asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    /// It's not something that can be asked now, like sending data without starting an upload.
+    BadRequest,
+    /// There's no file with that name.
+    NotFound,
+    /// The name isn't one the card can have.
+    BadName,
+    /// Reading or writing the card failed.
+    CardError,
+    /// The LuLuu's busy with something else, like a computer using the card as a drive.
+    Busy,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::BadRequest => write!(f, "the device can't do that right now"),
+            Failure::NotFound => write!(f, "no file with that name on the card"),
+            Failure::BadName => write!(f, "not a name the card can have, it has to be 8.3 like FIREWORK.LU"),
+            Failure::CardError => write!(f, "reading or writing the SD card failed"),
+            Failure::Busy => write!(f, "the device is busy, is it being used as a USB drive?"),
+        }
+    }
+}
+
+mod kind {
+    pub const VERSION: u8 = 0x01;
+    pub const BATTERY: u8 = 0x02;
+    pub const LIST_FILES: u8 = 0x03;
+    pub const PLAY: u8 = 0x04;
+    pub const SET_BRIGHTNESS: u8 = 0x05;
+    pub const UPLOAD_START: u8 = 0x06;
+    pub const UPLOAD_DATA: u8 = 0x07;
+    pub const REBOOT: u8 = 0x08;
+    pub const STREAM_FRAME: u8 = 0x09;
+    pub const STREAM_DATA: u8 = 0x0a;
+    pub const STREAM_END: u8 = 0x0b;
+
+    pub const OK: u8 = 0x80;
+    pub const FAILED: u8 = 0x81;
+    pub const VERSION_IS: u8 = 0x82;
+    pub const BATTERY_IS: u8 = 0x83;
+    pub const FILES: u8 = 0x84;
+}
+
+impl<'a> Request<'a> {
+    /// Encode the request into `out`, returning how many bytes it takes.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(out);
+        match *self {
+            Request::Version => writer.u8(kind::VERSION)?,
+            Request::Battery => writer.u8(kind::BATTERY)?,
+            Request::ListFiles => writer.u8(kind::LIST_FILES)?,
+            Request::Play { name } => {
+                writer.u8(kind::PLAY)?;
+                writer.name(name)?;
+            }
+            Request::SetBrightness { percent } => {
+                writer.u8(kind::SET_BRIGHTNESS)?;
+                writer.u8(percent)?;
+            }
+            Request::UploadStart { name, len } => {
+                writer.u8(kind::UPLOAD_START)?;
+                writer.name(name)?;
+                writer.u32(len)?;
+            }
+            Request::UploadData { bytes } => {
+                if bytes.len() > MAX_CHUNK_LEN {
+                    return Err(Error::TooLong);
+                }
+                writer.u8(kind::UPLOAD_DATA)?;
+                writer.bytes(bytes)?;
+            }
+            Request::Reboot => writer.u8(kind::REBOOT)?,
+            Request::StreamFrame { size, rect, packed, len } => {
+                writer.u8(kind::STREAM_FRAME)?;
+                writer.u8(size.0)?;
+                writer.put(&rect.to_bytes())?;
+                writer.u8(packed as u8)?;
+                writer.u32(len)?;
+            }
+            Request::StreamData { bytes } => {
+                if bytes.len() > MAX_CHUNK_LEN {
+                    return Err(Error::TooLong);
+                }
+                writer.u8(kind::STREAM_DATA)?;
+                writer.bytes(bytes)?;
+            }
+            Request::StreamEnd => writer.u8(kind::STREAM_END)?,
+        }
+        Ok(writer.len)
+    }
+
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes);
+        let request = match reader.u8()? {
+            kind::VERSION => Request::Version,
+            kind::BATTERY => Request::Battery,
+            kind::LIST_FILES => Request::ListFiles,
+            kind::PLAY => Request::Play { name: reader.name()? },
+            kind::SET_BRIGHTNESS => Request::SetBrightness { percent: reader.u8()? },
+            kind::UPLOAD_START => Request::UploadStart { name: reader.name()?, len: reader.u32()? },
+            kind::UPLOAD_DATA => Request::UploadData { bytes: reader.bytes()? },
+            kind::REBOOT => Request::Reboot,
+            kind::STREAM_FRAME => Request::StreamFrame {
+                size: Size(reader.u8()?),
+                rect: DirtyRect::from_bytes(reader.take(DIRTY_RECT_SIZE)?.try_into().unwrap()),
+                packed: reader.u8()? != 0,
+                len: reader.u32()?,
+            },
+            kind::STREAM_DATA => Request::StreamData { bytes: reader.bytes()? },
+            kind::STREAM_END => Request::StreamEnd,
+            _ => return Err(Error::Malformed),
+        };
+        reader.finish(request)
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Encode the response into `out`, returning how many bytes it takes.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut writer = Writer::new(out);
+        match *self {
+            Response::Ok => writer.u8(kind::OK)?,
+            Response::Failed(failure) => {
+                writer.u8(kind::FAILED)?;
+                writer.u8(failure as u8)?;
+            }
+            Response::Version { version } => {
+                writer.u8(kind::VERSION_IS)?;
+                writer.bytes(version.as_bytes())?;
+            }
+            Response::Battery { millivolts, charging } => {
+                writer.u8(kind::BATTERY_IS)?;
+                // there's never no voltage at all while it's running
+                writer.u16(millivolts.unwrap_or(0))?;
+                writer.u8(charging as u8)?;
+            }
+            Response::Files { names } => {
+                writer.u8(kind::FILES)?;
+                writer.bytes(names.as_bytes())?;
+            }
+        }
+        Ok(writer.len)
+    }
+
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes);
+        let response = match reader.u8()? {
+            kind::OK => Response::Ok,
+            kind::FAILED => Response::Failed(match reader.u8()? {
+                0 => Failure::BadRequest,
+                1 => Failure::NotFound,
+                2 => Failure::BadName,
+                3 => Failure::CardError,
+                4 => Failure::Busy,
+                _ => return Err(Error::Malformed),
+            }),
+            kind::VERSION_IS => Response::Version { version: reader.str()? },
+            kind::BATTERY_IS => Response::Battery {
+                millivolts: Some(reader.u16()?).filter(|&millivolts| millivolts != 0),
+                charging: reader.u8()? != 0,
+            },
+            kind::FILES => Response::Files { names: reader.str()? },
+            _ => return Err(Error::Malformed),
+        };
+        reader.finish(response)
+    }
+}
+
+/// Bytes to and from the other end.
+pub trait Link {
+    type Error;
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Read whatever's arrived into `buf`, returning how many bytes that is. Zero if nothing's
+    /// arrived for too long.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum ClientError<E> {
+    Link(E),
+    Protocol(Error),
+    /// No answer came.
+    TimedOut,
+}
+
+impl<E: fmt::Display> fmt::Display for ClientError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Link(error) => write!(f, "{}", error),
+            ClientError::Protocol(error) => write!(f, "{}", error),
+            ClientError::TimedOut => write!(f, "no answer from the device"),
+        }
+    }
+}
+
+/// The computer's end.
+pub struct Client<L> {
+    link: L,
+    reader: FrameReader,
+    frame: [u8; frame::MAX_FRAME_LEN],
+}
+
+impl<L: Link> Client<L> {
+    pub fn new(link: L) -> Self {
+        Self { link, reader: FrameReader::new(), frame: [0; frame::MAX_FRAME_LEN] }
+    }
+
+    pub fn into_link(self) -> L {
+        self.link
+    }
+
+    /// Send `request` and wait for the answer.
+    pub fn request(&mut self, request: &Request<'_>) -> Result<Response<'_>, ClientError<L::Error>> {
+        self.send(request)?;
+        self.receive()
+    }
+
+    /// Send `request` without waiting for an answer, for the ones that aren't answered straight
+    /// away, or at all.
+    pub fn send(&mut self, request: &Request<'_>) -> Result<(), ClientError<L::Error>> {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let len = request.encode(&mut message).map_err(ClientError::Protocol)?;
+        let len = frame::encode(&message[..len], &mut self.frame).map_err(ClientError::Protocol)?;
+        self.link.write_all(&self.frame[..len]).map_err(ClientError::Link)?;
+        // anything half read is from before, and isn't an answer to this
+        self.reader.reset();
+        Ok(())
+    }
+
+    /// Wait for the answer to the last request sent.
+    pub fn receive(&mut self) -> Result<Response<'_>, ClientError<L::Error>> {
+        let mut buf = [0; 64];
+        'read: loop {
+            let n = self.link.read(&mut buf).map_err(ClientError::Link)?;
+            if n == 0 {
+                return Err(ClientError::TimedOut);
+            }
+            for &byte in &buf[..n] {
+                match self.reader.push(byte) {
+                    Some(Ok(())) => break 'read,
+                    Some(Err(error)) => return Err(ClientError::Protocol(error)),
+                    None => (),
+                }
+            }
+        }
+        Response::decode(self.reader.message()).map_err(ClientError::Protocol)
+    }
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        Self { out, len: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        if end > self.out.len().min(MAX_MESSAGE_LEN) {
+            return Err(Error::TooLong);
+        }
+        self.out[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.put(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), Error> {
+        self.put(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), Error> {
+        self.put(&value.to_le_bytes())
+    }
+
+    /// Up to 64KiB of bytes, after their length.
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let len = u16::try_from(bytes.len()).map_err(|_| Error::TooLong)?;
+        self.u16(len)?;
+        self.put(bytes)
+    }
+
+    fn name(&mut self, name: &str) -> Result<(), Error> {
+        match name.len() {
+            0 => Err(Error::Malformed),
+            1..=MAX_NAME_LEN => self.bytes(name.as_bytes()),
+            _ => Err(Error::TooLong),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bytes.len() {
+            return Err(Error::Malformed);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        core::str::from_utf8(self.bytes()?).map_err(|_| Error::Malformed)
+    }
+
+    fn name(&mut self) -> Result<&'a str, Error> {
+        let name = self.str()?;
+        match name.len() {
+            1..=MAX_NAME_LEN => Ok(name),
+            _ => Err(Error::Malformed),
+        }
+    }
+
+    /// `message`, if that was all of the bytes.
+    fn finish<T>(self, message: T) -> Result<T, Error> {
+        match self.bytes.is_empty() {
+            true => Ok(message),
+            false => Err(Error::Malformed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    use super::*;
+
+    const FRAME: Request<'static> = Request::StreamFrame {
+        size: Size(120),
+        rect: DirtyRect { x: 10, y: 20, width: 30, height: 40 },
+        packed: true,
+        len: 2400,
+    };
+
+    fn requests() -> [Request<'static>; 11] {
+        [
+            Request::Version,
+            Request::Battery,
+            Request::ListFiles,
+            Request::Play { name: "FIREWORK.LU" },
+            Request::SetBrightness { percent: 55 },
+            Request::UploadStart { name: "HEART.LU", len: 172_812 },
+            Request::UploadData { bytes: &[0, 1, 2, 0, 0, 0xff] },
+            Request::Reboot,
+            FRAME,
+            Request::StreamData { bytes: &[0; MAX_CHUNK_LEN] },
+            Request::StreamEnd,
+        ]
+    }
+
+    fn responses() -> [Response<'static>; 11] {
+        [
+            Response::Ok,
+            Response::Failed(Failure::BadRequest),
+            Response::Failed(Failure::NotFound),
+            Response::Failed(Failure::BadName),
+            Response::Failed(Failure::CardError),
+            Response::Failed(Failure::Busy),
+            Response::Version { version: "0.1.0" },
+            Response::Battery { millivolts: Some(3912), charging: true },
+            Response::Battery { millivolts: None, charging: false },
+            Response::Files { names: "FIREWORK.LU\nHEART.LU\n" },
+            Response::Files { names: "" },
+        ]
+    }
+
+    /// Encode and frame a message, as it goes on the wire.
+    fn framed(encode: impl FnOnce(&mut [u8]) -> Result<usize, Error>) -> Vec<u8> {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let len = encode(&mut message).unwrap();
+        let mut frame = [0; frame::MAX_FRAME_LEN];
+        let len = frame::encode(&message[..len], &mut frame).unwrap();
+        frame[..len].to_vec()
+    }
+
+    /// Read the one frame in `bytes`, returning the message in it.
+    fn unframed(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut reader = FrameReader::new();
+        let (&end, rest) = bytes.split_last().unwrap();
+        for &byte in rest {
+            assert_eq!(reader.push(byte), None);
+        }
+        reader.push(end).unwrap()?;
+        Ok(reader.message().to_vec())
+    }
+
+    /// Stands in for the USB serial port, with the LuLuu on the other end answering each request
+    /// with `answer`. Each answer arrives a few bytes at a time, like it does over USB.
+    struct Loopback<F> {
+        reader: FrameReader,
+        answer: F,
+        to_read: VecDeque<u8>,
+        /// Flip a bit in the answers, like a noisy line would.
+        garble: bool,
+    }
+
+    impl<F: FnMut(Request<'_>) -> Option<Response<'static>>> Loopback<F> {
+        fn new(answer: F) -> Self {
+            Self { reader: FrameReader::new(), answer, to_read: VecDeque::new(), garble: false }
+        }
+    }
+
+    impl<F: FnMut(Request<'_>) -> Option<Response<'static>>> Link for Loopback<F> {
+        type Error = Error;
+
+        fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error> {
+            for &byte in bytes {
+                if self.reader.push(byte).transpose()?.is_none() {
+                    continue;
+                }
+                let request = Request::decode(self.reader.message())?;
+                if let Some(response) = (self.answer)(request) {
+                    let mut frame = framed(|out| response.encode(out));
+                    if self.garble {
+                        frame[1] ^= 0x01;
+                    }
+                    self.to_read.extend(frame);
+                }
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let n = self.to_read.len().min(buf.len()).min(5);
+            for (to, from) in buf.iter_mut().zip(self.to_read.drain(..n)) {
+                *to = from;
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            let message = unframed(&framed(|out| request.encode(out))).unwrap();
+            assert_eq!(Request::decode(&message), Ok(request));
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in responses() {
+            let message = unframed(&framed(|out| response.encode(out))).unwrap();
+            assert_eq!(Response::decode(&message), Ok(response));
+        }
+    }
+
+    #[test]
+    fn client_over_loopback() {
+        let mut streamed = 0;
+        let link = Loopback::new(|request| match request {
+            Request::Version => Some(Response::Version { version: "0.1.0" }),
+            Request::ListFiles => Some(Response::Files { names: "HEART.LU\n" }),
+            Request::Play { name: "HEART.LU" } => Some(Response::Ok),
+            Request::Play { .. } => Some(Response::Failed(Failure::NotFound)),
+            Request::StreamData { bytes } => {
+                streamed += bytes.len();
+                None
+            }
+            _ => Some(Response::Failed(Failure::BadRequest)),
+        });
+        let mut client = Client::new(link);
+
+        assert_eq!(client.request(&Request::Version).unwrap(), Response::Version { version: "0.1.0" });
+        assert_eq!(client.request(&Request::ListFiles).unwrap(), Response::Files { names: "HEART.LU\n" });
+        assert_eq!(client.request(&Request::Play { name: "HEART.LU" }).unwrap(), Response::Ok);
+        assert_eq!(client.request(&Request::Play { name: "NOPE.LU" }).unwrap(), Response::Failed(Failure::NotFound));
+        assert_eq!(client.request(&Request::Reboot).unwrap(), Response::Failed(Failure::BadRequest));
+
+        // stream data isn't answered
+        client.send(&Request::StreamData { bytes: &[7; 100] }).unwrap();
+        client.send(&Request::StreamData { bytes: &[7; 20] }).unwrap();
+        assert!(matches!(client.receive(), Err(ClientError::TimedOut)));
+        drop(client);
+        assert_eq!(streamed, 120);
+    }
+
+    #[test]
+    fn client_garbled_answer() {
+        let mut link = Loopback::new(|_| Some(Response::Files { names: "HEART.LU\n" }));
+        link.garble = true;
+        let mut client = Client::new(link);
+        assert!(matches!(client.request(&Request::ListFiles), Err(ClientError::Protocol(Error::BadChecksum))));
+    }
+
+    #[test]
+    fn client_rejects_oversized_requests() {
+        let mut client = Client::new(Loopback::new(|_| Some(Response::Ok)));
+        let too_much = [0; MAX_CHUNK_LEN + 1];
+        assert!(matches!(
+            client.request(&Request::UploadData { bytes: &too_much }),
+            Err(ClientError::Protocol(Error::TooLong))
+        ));
+        // and nothing was sent
+        assert!(matches!(client.receive(), Err(ClientError::TimedOut)));
+    }
+
+    #[test]
+    fn truncated_messages() {
+        for request in requests() {
+            let mut message = [0; MAX_MESSAGE_LEN];
+            let len = request.encode(&mut message).unwrap();
+            for cut in 0..len {
+                assert_eq!(Request::decode(&message[..cut]), Err(Error::Malformed), "{request:?} cut to {cut}");
+            }
+        }
+        for response in responses() {
+            let mut message = [0; MAX_MESSAGE_LEN];
+            let len = response.encode(&mut message).unwrap();
+            for cut in 0..len {
+                assert_eq!(Response::decode(&message[..cut]), Err(Error::Malformed), "{response:?} cut to {cut}");
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let len = FRAME.encode(&mut message).unwrap();
+        assert_eq!(Request::decode(&message[..len + 1]), Err(Error::Malformed));
+        let len = Response::Ok.encode(&mut message).unwrap();
+        assert_eq!(Response::decode(&message[..len + 1]), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn unknown_messages() {
+        assert_eq!(Request::decode(&[0x00]), Err(Error::Malformed));
+        assert_eq!(Request::decode(&[0x80]), Err(Error::Malformed));
+        assert_eq!(Response::decode(&[0x01]), Err(Error::Malformed));
+        assert_eq!(Response::decode(&[0x81, 5]), Err(Error::Malformed));
+        // a name that isn't UTF-8
+        assert_eq!(Request::decode(&[0x04, 2, 0, 0xff, 0xfe]), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn oversized_messages() {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let too_much = [0; MAX_CHUNK_LEN + 1];
+        assert_eq!(Request::UploadData { bytes: &too_much }.encode(&mut message), Err(Error::TooLong));
+        assert_eq!(Request::StreamData { bytes: &too_much }.encode(&mut message), Err(Error::TooLong));
+        assert_eq!(Request::Play { name: "MUCHTOOLONG.LU" }.encode(&mut message), Err(Error::TooLong));
+        assert_eq!(Request::Play { name: "" }.encode(&mut message), Err(Error::Malformed));
+        assert_eq!(FRAME.encode(&mut message[..8]), Err(Error::TooLong));
+
+        let names = [b'A'; MAX_MESSAGE_LEN];
+        let names = core::str::from_utf8(&names).unwrap();
+        assert_eq!(Response::Files { names }.encode(&mut message), Err(Error::TooLong));
+
+        // a name that's too long arriving is malformed, rather than too long for a buffer
+        let mut long_name = std::vec![0x04, 13, 0];
+        long_name.extend_from_slice(b"TOOLONGNAME.L");
+        assert_eq!(Request::decode(&long_name), Err(Error::Malformed));
+    }
+}
@@ -0,0 +1,316 @@
+//! Packing a streamed frame's pixels so there are fewer of them to send.
+//!
+//! Packed pixels are a series of runs, each starting with a byte `n`. If its top bit is set, the
+//! run is the one pixel after it repeated `(n & 0x7f) + 1` times. Otherwise it's the `n + 1` pixels
+//! after it as they are. Screens and animations tend to have big areas of one colour, which pack
+//! down to almost nothing, and the rest only grows by a byte every 128 pixels.
+
+use luluu_enc::{DirtyRect, Rgb565BE, Size};
+
+use crate::Error;
+
+/// The most pixels in a run.
+const MAX_RUN: usize = 128;
+
+const REPEAT: u8 = 0x80;
+
+/// The most bytes `n_pixels` can take once they're packed, when none of them repeat.
+pub const fn max_packed_len(n_pixels: usize) -> usize {
+    n_pixels * 2 + n_pixels.div_ceil(MAX_RUN)
+}
+
+/// Pack `pixels` into `out`, which needs to be [`max_packed_len`] long to be sure they fit. Returns
+/// how many bytes they take.
+pub fn pack(pixels: &[Rgb565BE], out: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    // where the pixels that are going as they are start
+    let mut literal_start = 0;
+    let mut idx = 0;
+    while idx < pixels.len() {
+        let pixel = pixels[idx].to_raw();
+        let repeats = pixels[idx..].iter().take(MAX_RUN).take_while(|other| other.to_raw() == pixel).count();
+        // a run of even two pixels is a byte smaller than them as they are
+        if repeats < 2 && idx - literal_start < MAX_RUN {
+            idx += 1;
+            continue;
+        }
+        put_literal(&pixels[literal_start..idx], out, &mut len)?;
+        if repeats >= 2 {
+            put(&[REPEAT | (repeats as u8 - 1), pixel[0], pixel[1]], out, &mut len)?;
+            idx += repeats;
+        }
+        literal_start = idx;
+    }
+    put_literal(&pixels[literal_start..], out, &mut len)?;
+    Ok(len)
+}
+
+fn put_literal(pixels: &[Rgb565BE], out: &mut [u8], len: &mut usize) -> Result<(), Error> {
+    if pixels.is_empty() {
+        return Ok(());
+    }
+    put(&[pixels.len() as u8 - 1], out, len)?;
+    put(Rgb565BE::slice_as_bytes(pixels), out, len)
+}
+
+fn put(bytes: &[u8], out: &mut [u8], len: &mut usize) -> Result<(), Error> {
+    let end = *len + bytes.len();
+    out.get_mut(*len..end).ok_or(Error::TooLong)?.copy_from_slice(bytes);
+    *len = end;
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Run {
+    /// The next byte starts a run.
+    Start,
+    /// This many pixels as they are.
+    Literal(u8),
+    /// The next pixel, this many times.
+    Repeat(u8),
+}
+
+/// Puts the pixels of a streamed frame where they go in the framebuffer, unpacking them if need
+/// be, as they arrive.
+pub struct Unpacker {
+    rect: DirtyRect,
+    /// Pixels in a row of the framebuffer.
+    stride: usize,
+    packed: bool,
+    run: Run,
+    /// The first byte of a pixel whose second hasn't arrived yet.
+    half: Option<u8>,
+    /// Pixels put in the framebuffer so far, row by row through the rect.
+    written: usize,
+}
+
+impl Unpacker {
+    /// For a frame of `size`, of which `rect` is being sent.
+    pub fn new(size: Size, rect: DirtyRect, packed: bool) -> Result<Self, Error> {
+        if !size.is_supported() || !rect.fits(size) {
+            return Err(Error::Malformed);
+        }
+        Ok(Self {
+            rect,
+            stride: size.0 as usize,
+            packed,
+            run: Run::Start,
+            half: None,
+            written: 0,
+        })
+    }
+
+    /// Whether every pixel in the rect has been put in the framebuffer.
+    pub fn is_done(&self) -> bool {
+        self.written == self.rect.width as usize * self.rect.height as usize
+    }
+
+    /// Take the next bytes of the frame, putting the pixels in them in `framebuffer`. Fails if
+    /// there are more of them than fit in the rect.
+    pub fn push(&mut self, bytes: &[u8], framebuffer: &mut [Rgb565BE]) -> Result<(), Error> {
+        for &byte in bytes {
+            if self.packed {
+                if let Run::Start = self.run {
+                    self.run = match byte & REPEAT {
+                        0 => Run::Literal(byte + 1),
+                        _ => Run::Repeat((byte & !REPEAT) + 1),
+                    };
+                    continue;
+                }
+            }
+            let Some(first) = self.half.take() else {
+                self.half = Some(byte);
+                continue;
+            };
+            let pixel = Rgb565BE::from_raw([first, byte]);
+
+            match self.run {
+                // not packed
+                Run::Start => self.put(pixel, framebuffer)?,
+                Run::Literal(n) => {
+                    self.put(pixel, framebuffer)?;
+                    self.run = match n {
+                        1 => Run::Start,
+                        _ => Run::Literal(n - 1),
+                    };
+                }
+                Run::Repeat(n) => {
+                    for _ in 0..n {
+                        self.put(pixel, framebuffer)?;
+                    }
+                    self.run = Run::Start;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, pixel: Rgb565BE, framebuffer: &mut [Rgb565BE]) -> Result<(), Error> {
+        if self.is_done() {
+            return Err(Error::TooLong);
+        }
+        let width = self.rect.width as usize;
+        let (x, y) = (self.written % width, self.written / width);
+        let idx = (self.rect.y as usize + y) * self.stride + self.rect.x as usize + x;
+        *framebuffer.get_mut(idx).ok_or(Error::TooLong)? = pixel;
+        self.written += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn pixel(raw: u16) -> Rgb565BE {
+        Rgb565BE::from_raw(raw.to_be_bytes())
+    }
+
+    fn raw(pixels: &[Rgb565BE]) -> Vec<u16> {
+        pixels.iter().map(|pixel| u16::from_be_bytes(pixel.to_raw())).collect()
+    }
+
+    fn packed(pixels: &[Rgb565BE]) -> Vec<u8> {
+        let mut out = vec![0; max_packed_len(pixels.len())];
+        let len = pack(pixels, &mut out).unwrap();
+        out.truncate(len);
+        out
+    }
+
+    /// Send `bytes` for `rect` of a `size` frame, `chunk` bytes at a time, onto a framebuffer of
+    /// `0xaaaa`s.
+    fn unpacked(size: Size, rect: DirtyRect, is_packed: bool, bytes: &[u8], chunk: usize) -> Vec<Rgb565BE> {
+        let mut framebuffer = vec![pixel(0xaaaa); size.0 as usize * size.0 as usize];
+        let mut unpacker = Unpacker::new(size, rect, is_packed).unwrap();
+        for chunk in bytes.chunks(chunk) {
+            assert!(!unpacker.is_done());
+            unpacker.push(chunk, &mut framebuffer).unwrap();
+        }
+        assert!(unpacker.is_done());
+        framebuffer
+    }
+
+    /// All sorts of runs: long and short repeats, and pixels that don't repeat at all.
+    fn mixed(n: usize) -> Vec<Rgb565BE> {
+        (0..n)
+            .map(|i| match i % 500 {
+                0..=199 => pixel(0x1234),
+                200..=209 => pixel(i as u16),
+                210..=212 => pixel(0xf800),
+                213..=349 => pixel(i as u16 * 3),
+                _ => pixel(0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pack_runs() {
+        let red = pixel(0xf800);
+        assert_eq!(packed(&[]), []);
+        assert_eq!(packed(&[red]), [0, 0xf8, 0]);
+        assert_eq!(packed(&[red, red]), [0x81, 0xf8, 0]);
+        assert_eq!(packed(&[red; 128]), [0xff, 0xf8, 0]);
+        assert_eq!(packed(&[red; 130]), [0xff, 0xf8, 0, 0x81, 0xf8, 0]);
+        assert_eq!(packed(&[pixel(1), pixel(2), red, red, red]), [1, 0, 1, 0, 2, 0x82, 0xf8, 0]);
+    }
+
+    #[test]
+    fn pack_never_grows_past_max_len() {
+        let different: Vec<_> = (0..1000).map(pixel).collect();
+        let bytes = packed(&different);
+        assert_eq!(bytes.len(), max_packed_len(1000));
+        // a byte for every 128 pixels
+        assert_eq!(bytes.len(), 2000 + 8);
+        assert!(packed(&mixed(3600)).len() <= max_packed_len(3600));
+    }
+
+    #[test]
+    fn pack_into_too_little() {
+        let pixels = mixed(1000);
+        let mut out = vec![0; packed(&pixels).len() - 1];
+        assert_eq!(pack(&pixels, &mut out), Err(Error::TooLong));
+    }
+
+    #[test]
+    fn unpack_whole_frame() {
+        let size = Size(60);
+        let pixels = mixed(60 * 60);
+        let framebuffer = unpacked(size, DirtyRect::full(size), true, &packed(&pixels), 256);
+        assert_eq!(raw(&framebuffer), raw(&pixels));
+
+        let framebuffer = unpacked(size, DirtyRect::full(size), false, Rgb565BE::slice_as_bytes(&pixels), 256);
+        assert_eq!(raw(&framebuffer), raw(&pixels));
+    }
+
+    #[test]
+    fn unpack_rect_leaves_the_rest() {
+        let size = Size(120);
+        let rect = DirtyRect { x: 100, y: 7, width: 20, height: 30 };
+        let pixels = mixed(20 * 30);
+        let framebuffer = unpacked(size, rect, true, &packed(&pixels), 256);
+        for (idx, &got) in raw(&framebuffer).iter().enumerate() {
+            let (x, y) = (idx % 120, idx / 120);
+            let expected = match (100..120).contains(&x) && (7..37).contains(&y) {
+                true => raw(&pixels)[(y - 7) * 20 + x - 100],
+                false => 0xaaaa,
+            };
+            assert_eq!(got, expected, "at {x}, {y}");
+        }
+    }
+
+    #[test]
+    fn reassembled_from_any_size_of_chunk() {
+        // chunks split pixels in half, and runs from the pixels in them
+        let size = Size(60);
+        let rect = DirtyRect { x: 5, y: 5, width: 50, height: 40 };
+        let pixels = mixed(50 * 40);
+        let bytes = packed(&pixels);
+        let whole = raw(&unpacked(size, rect, true, &bytes, bytes.len()));
+        for chunk in [1, 2, 3, 7, 128, 255, crate::MAX_CHUNK_LEN] {
+            assert_eq!(raw(&unpacked(size, rect, true, &bytes, chunk)), whole, "{chunk} byte chunks");
+            let bytes = Rgb565BE::slice_as_bytes(&pixels);
+            assert_eq!(raw(&unpacked(size, rect, false, bytes, chunk)), whole, "{chunk} byte chunks unpacked");
+        }
+    }
+
+    #[test]
+    fn empty_rect_is_done_already() {
+        let unpacker = Unpacker::new(Size(240), DirtyRect::EMPTY, true).unwrap();
+        assert!(unpacker.is_done());
+    }
+
+    #[test]
+    fn rect_outside_the_frame() {
+        assert!(Unpacker::new(Size(120), DirtyRect::full(Size(240)), true).is_err());
+        assert!(Unpacker::new(Size(240), DirtyRect { x: 0, y: 200, width: 240, height: 100 }, false).is_err());
+        assert!(Unpacker::new(Size(240), DirtyRect { x: 241, y: 0, width: 0, height: 0 }, false).is_err());
+        assert!(Unpacker::new(Size(100), DirtyRect::full(Size(100)), false).is_err());
+    }
+
+    #[test]
+    fn too_many_pixels() {
+        let size = Size(60);
+        let rect = DirtyRect { x: 0, y: 0, width: 2, height: 2 };
+        let mut framebuffer = vec![pixel(0); 60 * 60];
+
+        let mut unpacker = Unpacker::new(size, rect, true).unwrap();
+        assert_eq!(unpacker.push(&[0x84, 0xff, 0xff], &mut framebuffer), Err(Error::TooLong));
+
+        let mut unpacker = Unpacker::new(size, rect, false).unwrap();
+        unpacker.push(&[1; 8], &mut framebuffer).unwrap();
+        assert!(unpacker.is_done());
+        assert_eq!(unpacker.push(&[1, 1], &mut framebuffer), Err(Error::TooLong));
+    }
+
+    #[test]
+    fn framebuffer_too_small() {
+        let mut framebuffer = vec![pixel(0); 10];
+        let mut unpacker = Unpacker::new(Size(60), DirtyRect::full(Size(60)), true).unwrap();
+        assert_eq!(unpacker.push(&[0xff, 0, 0], &mut framebuffer), Err(Error::TooLong));
+    }
+}
@@ -0,0 +1,273 @@
+#![no_std]
+
+//! Firmware images for updating a LuLuu from its SD card, made by `luluu-cli firmware pack` and
+//! installed by the firmware when it finds one called [`FILE_NAME`] on the card at power on.
+//!
+//! An image is a [`Header`] followed by the bytes of the flash from its start, second stage
+//! bootloader and all. The header has the firmware's [`Version`], so only newer firmware is
+//! installed, and a CRC-32 of the bytes, so a file that's been cut short or garbled isn't.
+//!
+//! This crate has no hardware dependencies, so the same code makes images on the host as checks
+//! them on the device.
+
+use core::fmt;
+
+/// The firmware looks for an update in this file in the top folder of the card.
+pub const FILE_NAME: &str = "LULUU.FW";
+
+/// What [`FILE_NAME`] is renamed to once it's been installed, or if it isn't newer.
+pub const INSTALLED_FILE_NAME: &str = "LULUU.OLD";
+
+pub const HEADER_LEN: usize = 20;
+
+/// The most firmware there's room for, the 2MiB of flash before the storage.
+pub const MAX_IMAGE_LEN: u32 = 2 * 1024 * 1024;
+
+const MAGIC: [u8; 4] = *b"LUFW";
+
+/// A firmware version, like `0.1.0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Version {
+    /// Parse `major.minor.patch`, like in `Cargo.toml`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.trim().split('.').map(|part| part.parse().ok());
+        let version = Self { major: parts.next()??, minor: parts.next()??, patch: parts.next()?? };
+        parts.next().is_none().then_some(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// What's wrong with a firmware image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// It doesn't start with a header.
+    NotAnImage,
+    /// It's more than [`MAX_IMAGE_LEN`].
+    TooBig,
+    /// It's a different length than the header says, or its bytes don't match the checksum.
+    Corrupted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotAnImage => write!(f, "not a firmware image"),
+            Error::TooBig => write!(f, "firmware too big for the flash"),
+            Error::Corrupted => write!(f, "firmware image cut short or garbled"),
+        }
+    }
+}
+
+/// The start of an image, with the magic bytes `LUFW`, then the version and the length and CRC-32
+/// of the bytes after it, all little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Header {
+    pub version: Version,
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl Header {
+    /// The header for an image of `bytes`.
+    pub fn for_bytes(version: Version, bytes: &[u8]) -> Result<Self, Error> {
+        let len = u32::try_from(bytes.len()).map_err(|_| Error::TooBig)?;
+        if len > MAX_IMAGE_LEN {
+            return Err(Error::TooBig);
+        }
+        let mut crc = Crc32::new();
+        crc.update(bytes);
+        Ok(Self { version, len, crc: crc.finish() })
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.major.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.version.minor.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.version.patch.to_le_bytes());
+        // 10..12 is left for later
+        bytes[12..16].copy_from_slice(&self.len.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; HEADER_LEN]) -> Result<Self, Error> {
+        if bytes[..4] != MAGIC {
+            return Err(Error::NotAnImage);
+        }
+        let half = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let header = Self {
+            version: Version { major: half(4), minor: half(6), patch: half(8) },
+            len: word(12),
+            crc: word(16),
+        };
+        if header.len > MAX_IMAGE_LEN {
+            return Err(Error::TooBig);
+        }
+        Ok(header)
+    }
+
+    /// Check that `bytes`, everything after the header, are the ones it's for.
+    pub fn check(&self, bytes: &[u8]) -> Result<(), Error> {
+        let mut crc = Crc32::new();
+        crc.update(bytes);
+        match bytes.len() == self.len as usize && crc.finish() == self.crc {
+            true => Ok(()),
+            false => Err(Error::Corrupted),
+        }
+    }
+}
+
+/// CRC-32, the one zip files use, worked out a bit at a time as the bytes come in.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(0xffff_ffff)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                self.0 = match self.0 & 1 {
+                    0 => self.0 >> 1,
+                    _ => (self.0 >> 1) ^ 0xedb8_8320,
+                };
+            }
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const VERSION: Version = Version { major: 1, minor: 2, patch: 3 };
+
+    fn body() -> Vec<u8> {
+        (0..1000u32).map(|n| (n * 7) as u8).collect()
+    }
+
+    #[test]
+    fn crc_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn crc_in_pieces() {
+        let body = body();
+        let mut whole = Crc32::new();
+        whole.update(&body);
+        let mut pieces = Crc32::new();
+        for piece in body.chunks(33) {
+            pieces.update(piece);
+        }
+        assert_eq!(pieces.finish(), whole.finish());
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = Header::for_bytes(VERSION, &body()).unwrap();
+        assert_eq!(header.len, 1000);
+        let bytes = header.encode();
+        assert_eq!(&bytes[..4], b"LUFW");
+        assert_eq!(Header::decode(&bytes), Ok(header));
+    }
+
+    #[test]
+    fn check_accepts_its_bytes() {
+        let body = body();
+        assert_eq!(Header::for_bytes(VERSION, &body).unwrap().check(&body), Ok(()));
+    }
+
+    #[test]
+    fn check_rejects_other_bytes() {
+        let body = body();
+        let header = Header::for_bytes(VERSION, &body).unwrap();
+        assert_eq!(header.check(&body[..999]), Err(Error::Corrupted));
+
+        let mut long = body.clone();
+        long.push(0);
+        assert_eq!(header.check(&long), Err(Error::Corrupted));
+
+        let mut flipped = body;
+        flipped[500] ^= 0x10;
+        assert_eq!(header.check(&flipped), Err(Error::Corrupted));
+    }
+
+    #[test]
+    fn decode_rejects_bad_magic() {
+        let mut bytes = Header::for_bytes(VERSION, &body()).unwrap().encode();
+        bytes[0] = b'X';
+        assert_eq!(Header::decode(&bytes), Err(Error::NotAnImage));
+    }
+
+    #[test]
+    fn decode_rejects_too_big() {
+        let header = Header { version: VERSION, len: MAX_IMAGE_LEN, crc: 0 };
+        assert_eq!(Header::decode(&header.encode()), Ok(header));
+        let header = Header { len: MAX_IMAGE_LEN + 1, ..header };
+        assert_eq!(Header::decode(&header.encode()), Err(Error::TooBig));
+    }
+
+    #[test]
+    fn for_bytes_rejects_too_big() {
+        let bytes = std::vec![0xff; MAX_IMAGE_LEN as usize + 1];
+        assert_eq!(Header::for_bytes(VERSION, &bytes), Err(Error::TooBig));
+    }
+
+    #[test]
+    fn version_parse() {
+        assert_eq!(Version::parse("1.2.3"), Some(VERSION));
+        assert_eq!(Version::parse(" 1.2.3 "), Some(VERSION));
+        assert_eq!(Version::parse("1.2"), None);
+        assert_eq!(Version::parse("1.2.3.4"), None);
+        assert_eq!(Version::parse("1..3"), None);
+        assert_eq!(Version::parse("1.2.x"), None);
+        assert_eq!(Version::parse(""), None);
+    }
+
+    #[test]
+    fn version_order() {
+        let v = |text| Version::parse(text).unwrap();
+        assert!(v("0.1.0") < v("0.1.1"));
+        assert!(v("0.1.9") < v("0.2.0"));
+        assert!(v("0.9.9") < v("1.0.0"));
+        // numbers, not text
+        assert!(v("0.2.0") < v("0.10.0"));
+        assert_eq!(std::format!("{}", v("0.10.0")), "0.10.0");
+    }
+}
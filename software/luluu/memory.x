@@ -1,8 +1,11 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
     FLASH : ORIGIN = 0x10000100, LENGTH = 2M - 0x100
-    /* NVM   : ORIGIN = 0x10200000, LENGTH = 16M - 2M */
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    /* read and written through luluu_bsp::flash, not by the linker */
+    NVM   : ORIGIN = 0x10200000, LENGTH = 16M - 2M
+    RAM   : ORIGIN = 0x20000000, LENGTH = 256K - 1K
+    /* kept as it is through resets, for the fault record */
+    FAULT : ORIGIN = 0x2003FC00, LENGTH = 1K
     SRAM4 : ORIGIN = 0x20040000, LENGTH = 4K
     SRAM5 : ORIGIN = 0x20080000, LENGTH = 4K
 }
@@ -27,4 +30,10 @@ SECTIONS {
     {
         KEEP(*(.sram5));
     } > SRAM5
+
+    /* not loaded or zeroed at startup, so what was there before the reset is still there */
+    .fault ORIGIN(FAULT) (NOLOAD) :
+    {
+        KEEP(*(.fault));
+    } > FAULT
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+#[cfg(feature = "battery-sense")]
+use luluu_bsp as bsp;
+
+#[cfg(feature = "battery-sense")]
+use bsp::{pac, BattSense, ChargeStat};
+
+pub use luluu_power::battery::{BatteryConfig, BatteryLevel, BatteryPolicy};
+
+/// The battery's voltage and charge status, as far as they can be measured. That's only with the
+/// `battery-sense` feature, for boards with the sense divider and `~CHRG` wired up, which no
+/// revision has yet; see `luluu_bsp::battery`. An unconnected ADC pin can read anything, so
+/// without the feature the ADC and pins are left alone, there are no readings at all, and
+/// [`BatteryPolicy`] stays at [`BatteryLevel::Normal`] and never shuts LuLuu! down.
+#[cfg(feature = "battery-sense")]
+pub struct Sensor(bsp::battery::Battery);
+
+#[cfg(not(feature = "battery-sense"))]
+pub struct Sensor;
+
+#[cfg(feature = "battery-sense")]
+impl Sensor {
+    pub fn new(adc: pac::ADC, batt_sense: BattSense, charge_stat: ChargeStat, resets: &mut pac::RESETS) -> Self {
+        Self(bsp::battery::Battery::new(adc, batt_sense, charge_stat, resets))
+    }
+
+    /// Battery voltage in millivolts, if it can be measured.
+    pub fn millivolts(&mut self) -> Option<u16> {
+        self.0.millivolts()
+    }
+
+    pub fn is_charging(&mut self) -> bool {
+        self.0.charge_status() == bsp::battery::ChargeStatus::Charging
+    }
+}
+
+#[cfg(not(feature = "battery-sense"))]
+impl Sensor {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Battery voltage in millivolts, if it can be measured.
+    pub fn millivolts(&mut self) -> Option<u16> {
+        None
+    }
+
+    pub fn is_charging(&mut self) -> bool {
+        false
+    }
+}
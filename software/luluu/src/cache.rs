@@ -0,0 +1,258 @@
+//! Copies of animations from the card kept in flash, see [`bsp::flash`], picked with `[cache]
+//! files` in the settings. They play without a card, and faster than from it.
+//!
+//! The first sector of the storage is a directory of what's there, and each file follows in
+//! sectors of its own. The cache is brought up to date at power on, before core 1 starts, with
+//! [`update`]. The directory is written last, so a cache that's only partly written is empty.
+//!
+//! Without a card, or with nothing to play on it, the cached animations are played instead, or
+//! [`BUILT_IN`] if there aren't any.
+
+use core::fmt::Write;
+
+use embedded_sdmmc::{DirEntry, Error, Mode, SdCardError, Timestamp};
+use luluu_bsp as bsp;
+use luluu_config::FileNames;
+
+use bsp::flash::{self, SECTOR_SIZE};
+
+use crate::decoder::{RootDir, SdCard, MAX_FILES};
+use crate::fault;
+use crate::playlist::FileName;
+use crate::read_file::{read_all, ReadFile};
+use crate::update::STAGING_OFFSET;
+
+/// Played when there's nothing else to, so there's always something to show.
+pub static BUILT_IN: &[u8] = include_bytes!("../assets/LULUU.LU");
+
+pub const BUILT_IN_NAME: &str = "LULUU.LU";
+
+/// Marks the directory as having been written, erased flash is all `0xff`.
+const MAGIC: [u8; 4] = *b"LUCA";
+
+const DIRECTORY_LEN: usize = SECTOR_SIZE;
+
+/// The storage after this is kept for firmware updates.
+const CACHE_LEN: usize = STAGING_OFFSET;
+
+/// The magic and the number of files.
+const DIRECTORY_HEADER_LEN: usize = 8;
+
+/// The name, padded with zeroes, then the modification time, length and offset into the storage.
+const ENTRY_LEN: usize = 24;
+
+/// An animation in flash.
+#[derive(Clone)]
+pub struct CachedFile {
+    pub name: FileName,
+    pub bytes: &'static [u8],
+}
+
+pub type CachedFiles = heapless::Vec<CachedFile, MAX_FILES>;
+
+/// A file in the directory.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    name: [u8; 12],
+    mtime: u32,
+    len: u32,
+    offset: u32,
+}
+
+impl Entry {
+    fn for_dir_entry(dir_entry: &DirEntry, offset: usize) -> Self {
+        let mut name = FileName::new();
+        write!(&mut name, "{}", dir_entry.name).unwrap();
+        let mut bytes = [0; 12];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Self { name: bytes, mtime: pack(&dir_entry.mtime), len: dir_entry.size, offset: offset as u32 }
+    }
+
+    fn name(&self) -> &str {
+        let len = self.name.iter().position(|&byte| byte == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    fn bytes(&self) -> &'static [u8] {
+        &flash::storage()[self.offset as usize..][..self.len as usize]
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        Self { name: bytes[..12].try_into().unwrap(), mtime: word(12), len: word(16), offset: word(20) }
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes[..12].copy_from_slice(&self.name);
+        bytes[12..16].copy_from_slice(&self.mtime.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.len.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.offset.to_le_bytes());
+    }
+}
+
+/// A FAT modification time in one word, like it's kept on the card.
+fn pack(time: &Timestamp) -> u32 {
+    (time.year_since_1970 as u32) << 25
+        | (time.zero_indexed_month as u32) << 21
+        | (time.zero_indexed_day as u32) << 16
+        | (time.hours as u32) << 11
+        | (time.minutes as u32) << 5
+        | time.seconds as u32 / 2
+}
+
+/// What's in the directory. Anything that doesn't make sense, like garbage from before the cache
+/// was ever written, is an empty cache.
+fn directory() -> heapless::Vec<Entry, MAX_FILES> {
+    let storage = flash::storage();
+    let mut entries = heapless::Vec::new();
+    if storage[..4] != MAGIC {
+        return entries;
+    }
+    let n_files = u32::from_le_bytes(storage[4..8].try_into().unwrap()) as usize;
+    if n_files > MAX_FILES {
+        return entries;
+    }
+    for bytes in storage[DIRECTORY_HEADER_LEN..].chunks_exact(ENTRY_LEN).take(n_files) {
+        let entry = Entry::read(bytes);
+        let end = entry.offset as usize + entry.len as usize;
+        if (entry.offset as usize) < DIRECTORY_LEN || end > CACHE_LEN || entry.name().is_empty() {
+            return heapless::Vec::new();
+        }
+        entries.push(entry).ok().unwrap();
+    }
+    entries
+}
+
+/// The animations in flash, or the built-in one if there aren't any.
+pub fn files() -> CachedFiles {
+    let mut files: CachedFiles = directory()
+        .iter()
+        .map(|entry| {
+            let mut name = FileName::new();
+            name.push_str(entry.name()).unwrap();
+            CachedFile { name, bytes: entry.bytes() }
+        })
+        .collect();
+    if files.is_empty() {
+        let mut name = FileName::new();
+        name.push_str(BUILT_IN_NAME).unwrap();
+        files.push(CachedFile { name, bytes: BUILT_IN }).ok().unwrap();
+    }
+    files
+}
+
+/// The copy in flash of the file in `dir_entry`, if there is one and it's the same as the one on
+/// the card.
+pub fn find(dir_entry: &DirEntry) -> Option<FlashFile> {
+    let wanted = Entry::for_dir_entry(dir_entry, 0);
+    directory()
+        .iter()
+        .find(|entry| entry.name == wanted.name && entry.mtime == wanted.mtime && entry.len == wanted.len)
+        .map(|entry| FlashFile::new(entry.bytes()))
+}
+
+/// The files in `dir_entries` that `names` picks out, as many of them as fit.
+pub fn pick(dir_entries: &[DirEntry], names: &FileNames) -> heapless::Vec<DirEntry, MAX_FILES> {
+    let mut picked = heapless::Vec::new();
+    let mut used = DIRECTORY_LEN;
+    let mut name = FileName::new();
+    for dir_entry in dir_entries {
+        name.clear();
+        write!(&mut name, "{}", dir_entry.name).unwrap();
+        let len = (dir_entry.size as usize).next_multiple_of(SECTOR_SIZE);
+        if names.matches(&name) && used + len <= CACHE_LEN {
+            used += len;
+            picked.push(dir_entry.clone()).ok().unwrap();
+        }
+    }
+    picked
+}
+
+/// Whether the cache already has exactly the files in `picked`, as they are on the card.
+pub fn is_current(picked: &[DirEntry]) -> bool {
+    let directory = directory();
+    directory.len() == picked.len()
+        && picked.iter().zip(&directory).all(|(dir_entry, entry)| {
+            let wanted = Entry::for_dir_entry(dir_entry, 0);
+            entry.name == wanted.name && entry.mtime == wanted.mtime && entry.len == wanted.len
+        })
+}
+
+/// Replace the cache with copies of the files in `picked`, from `root_dir`, going through `buffer`
+/// which has to be in RAM. Core 1 can't have been started yet. A card error leaves the cache
+/// empty.
+pub fn update(root_dir: &mut RootDir<'_>, picked: &[DirEntry], buffer: &mut [u8]) -> Result<(), Error<SdCardError>> {
+    // SAFETY, for all the flash writes here: core 1 isn't running yet, and `buffer` is in RAM.
+    // without its directory the cache is empty until it's all written
+    unsafe { flash::erase(0, DIRECTORY_LEN) };
+
+    let chunk_len = buffer.len() / SECTOR_SIZE * SECTOR_SIZE;
+    let mut entries: heapless::Vec<Entry, MAX_FILES> = heapless::Vec::new();
+    let mut offset = DIRECTORY_LEN;
+    for dir_entry in picked {
+        let mut file = root_dir.open_file_in_dir(&dir_entry.name, Mode::ReadOnly)?;
+        let mut len = 0;
+        loop {
+            fault::feed();
+            let read = read_all(&mut file, &mut buffer[..chunk_len])?;
+            if read == 0 {
+                break;
+            }
+            unsafe { flash::write(offset + len, &buffer[..read]) };
+            len += read;
+            if read < chunk_len {
+                break;
+            }
+        }
+        file.close()?;
+
+        let mut entry = Entry::for_dir_entry(dir_entry, offset);
+        entry.len = len as u32;
+        entries.push(entry).ok().unwrap();
+        offset += len.next_multiple_of(SECTOR_SIZE);
+    }
+
+    let directory = &mut buffer[..DIRECTORY_HEADER_LEN + entries.len() * ENTRY_LEN];
+    directory[..4].copy_from_slice(&MAGIC);
+    directory[4..8].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (entry, bytes) in entries.iter().zip(directory[DIRECTORY_HEADER_LEN..].chunks_exact_mut(ENTRY_LEN)) {
+        entry.write(bytes);
+    }
+    unsafe { flash::write(0, directory) };
+    Ok(())
+}
+
+/// An animation in flash, read like one on the card.
+pub struct FlashFile {
+    bytes: &'static [u8],
+    offset: usize,
+}
+
+impl FlashFile {
+    pub fn new(bytes: &'static [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+}
+
+impl ReadFile<SdCard> for FlashFile {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error<SdCardError>> {
+        let rest = &self.bytes[self.offset..];
+        let len = buffer.len().min(rest.len());
+        buffer[..len].copy_from_slice(&rest[..len]);
+        self.offset += len;
+        Ok(len)
+    }
+
+    fn seek_from_start(&mut self, offset: u32) -> Result<(), Error<SdCardError>> {
+        if offset as usize > self.bytes.len() {
+            return Err(Error::InvalidOffset);
+        }
+        self.offset = offset as usize;
+        Ok(())
+    }
+
+    fn seek_from_current(&mut self, offset: i32) -> Result<(), Error<SdCardError>> {
+        let offset = self.offset.checked_add_signed(offset as isize).ok_or(Error::InvalidOffset)?;
+        self.seek_from_start(offset as u32)
+    }
+}
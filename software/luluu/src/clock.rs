@@ -0,0 +1,72 @@
+//! Setting and reading the real-time clock in [`luluu_config::time::DateTime`]s.
+
+use luluu_bsp as bsp;
+
+use bsp::rtc::{DayOfWeek, Rtc, RtcError};
+use embedded_sdmmc::Mode;
+use luluu_config::time::{self, DateTime};
+
+use crate::decoder::RootDir;
+
+/// Set the clock from the time file in `root_dir` if there is one, then delete it so the same time
+/// isn't set again next time. A file without a time in it is left where it is.
+pub fn set_from_file(root_dir: &mut RootDir<'_>, rtc: &mut Rtc) {
+    let Ok(mut file) = root_dir.open_file_in_dir(time::FILE_NAME, Mode::ReadOnly) else {
+        return;
+    };
+    let mut bytes = [0u8; time::MAX_FILE_SIZE];
+    let read = file.read(&mut bytes);
+    // it has to be closed to delete it
+    drop(file);
+
+    let text = read.ok().and_then(|len| core::str::from_utf8(&bytes[..len]).ok());
+    let Some(date_time) = text.and_then(DateTime::parse) else {
+        #[cfg(feature = "probe")]
+        defmt::warn!("{} doesn't have a time in it", time::FILE_NAME);
+        return;
+    };
+    if set(rtc, date_time).is_err() {
+        return;
+    }
+    #[cfg(feature = "probe")]
+    defmt::info!("clock set to {}", date_time);
+
+    if root_dir.delete_file_in_dir(time::FILE_NAME).is_err() {
+        #[cfg(feature = "probe")]
+        defmt::warn!("couldn't delete {}", time::FILE_NAME);
+    }
+}
+
+pub fn set(rtc: &mut Rtc, date_time: DateTime) -> Result<(), RtcError> {
+    let day_of_week = match date_time.day_of_week() {
+        0 => DayOfWeek::Sunday,
+        1 => DayOfWeek::Monday,
+        2 => DayOfWeek::Tuesday,
+        3 => DayOfWeek::Wednesday,
+        4 => DayOfWeek::Thursday,
+        5 => DayOfWeek::Friday,
+        _ => DayOfWeek::Saturday,
+    };
+    rtc.set(bsp::rtc::DateTime {
+        year: date_time.year,
+        month: date_time.month,
+        day: date_time.day,
+        day_of_week,
+        hour: date_time.hour,
+        minute: date_time.minute,
+        second: date_time.second,
+    })
+}
+
+/// The current time, if the clock has been set since power on.
+pub fn now(rtc: &Rtc) -> Option<DateTime> {
+    let now = rtc.now()?;
+    Some(DateTime {
+        year: now.year,
+        month: now.month,
+        day: now.day,
+        hour: now.hour,
+        minute: now.minute,
+        second: now.second,
+    })
+}
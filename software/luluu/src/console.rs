@@ -0,0 +1,166 @@
+//! A text console on the debug connector's UART, so anyone with a USB serial adapter can follow
+//! what the player's doing and control it, without a probe. It's at [`bsp::uart::BAUD_RATE`], 8N1.
+//!
+//! Lines are written to it with [`log!`], from anywhere on core 0. Commands typed into it are
+//! picked up by [`Shell::poll`], and are mostly the same requests as over USB serial, see
+//! [`control`](crate::control).
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use critical_section::Mutex;
+use luluu_bsp as bsp;
+
+use bsp::uart::{UartReader, UartWriter};
+use luluu_proto::{Request, Response, MAX_NAME_LEN};
+
+/// Longest line that can be typed, which is plenty for any command.
+const MAX_LINE_LEN: usize = 32;
+
+const HELP: &str = "commands: status, list, play NAME, brightness PERCENT, battery, version, reboot";
+
+/// Taken out while it's being written to, so nothing else waits on the UART.
+static WRITER: Mutex<RefCell<Option<UartWriter>>> = Mutex::new(RefCell::new(None));
+
+/// Write a line to the console, formatted like [`write!`]. Does nothing before [`init`].
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::console::write_line(format_args!($($arg)*))
+    };
+}
+pub(crate) use log;
+
+/// Start the console on `uart`, returning the shell for the commands typed into it.
+pub fn init(uart: bsp::uart::Uart) -> Shell {
+    let (reader, writer) = uart.split();
+    critical_section::with(|cs| *WRITER.borrow_ref_mut(cs) = Some(writer));
+    Shell { reader, line: [0; MAX_LINE_LEN], len: 0, complete: false }
+}
+
+/// See [`log!`].
+pub fn write_line(args: fmt::Arguments<'_>) {
+    with_writer(|writer| {
+        let _ = writer.write_fmt(args);
+        writer.write_full_blocking(b"\r\n");
+    });
+}
+
+fn with_writer(f: impl FnOnce(&mut UartWriter)) {
+    // anything written while it's taken, which is nothing on core 0, is dropped
+    let Some(mut writer) = critical_section::with(|cs| WRITER.borrow_ref_mut(cs).take()) else {
+        return;
+    };
+    f(&mut writer);
+    critical_section::with(|cs| *WRITER.borrow_ref_mut(cs) = Some(writer));
+}
+
+/// Something typed into the console for the player to do.
+pub enum Command<'a> {
+    /// The same as asking over USB serial, answered with [`show_response`].
+    Request(Request<'a>),
+    /// What the player's up to.
+    Status,
+}
+
+/// Collects the characters typed into the console into commands.
+pub struct Shell {
+    reader: UartReader,
+    line: [u8; MAX_LINE_LEN],
+    len: usize,
+    /// The line's been ended with enter, and given out as a command.
+    complete: bool,
+}
+
+impl Shell {
+    /// The next command, once one's been typed in and enter pressed. Typing is echoed back, and
+    /// mistakes and `help` are answered here.
+    pub fn poll(&mut self) -> Option<Command<'_>> {
+        if self.complete {
+            self.len = 0;
+            self.complete = false;
+        }
+        while let Some(byte) = bsp::uart::read_byte(&mut self.reader) {
+            match byte {
+                b'\r' | b'\n' => {
+                    // \r\n ends the line once
+                    if self.len > 0 || byte == b'\r' {
+                        with_writer(|writer| writer.write_full_blocking(b"\r\n"));
+                    }
+                    if self.len > 0 {
+                        self.complete = true;
+                        break;
+                    }
+                }
+                // backspace or delete, depending on the terminal
+                0x08 | 0x7f if self.len > 0 => {
+                    self.len -= 1;
+                    with_writer(|writer| writer.write_full_blocking(b"\x08 \x08"));
+                }
+                b' '..=b'~' if self.len < MAX_LINE_LEN => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    with_writer(|writer| writer.write_full_blocking(&[byte]));
+                }
+                _ => (),
+            }
+        }
+        if !self.complete {
+            return None;
+        }
+
+        // only printable ASCII is let in
+        let line = core::str::from_utf8(&self.line[..self.len]).unwrap();
+        match parse(line) {
+            Ok(command) => Some(command),
+            Err(answer) => {
+                log!("{}", answer);
+                None
+            }
+        }
+    }
+}
+
+/// The command in `line`, or what to say back if it isn't one for the player.
+fn parse(line: &str) -> Result<Command<'_>, &'static str> {
+    let mut words = line.split_ascii_whitespace();
+    let (Some(word), argument) = (words.next(), words.next()) else {
+        return Err(HELP);
+    };
+    if words.next().is_some() {
+        return Err(HELP);
+    }
+
+    let is = |command: &str| word.eq_ignore_ascii_case(command);
+    let command = match argument {
+        None if is("status") => Command::Status,
+        None if is("list") => Command::Request(Request::ListFiles),
+        None if is("battery") => Command::Request(Request::Battery),
+        None if is("version") => Command::Request(Request::Version),
+        None if is("reboot") => Command::Request(Request::Reboot),
+        Some(name) if is("play") && name.len() <= MAX_NAME_LEN => Command::Request(Request::Play { name }),
+        Some(percent) if is("brightness") => match percent.trim_end_matches('%').parse() {
+            Ok(percent @ 1..=100) => Command::Request(Request::SetBrightness { percent }),
+            _ => return Err("brightness is in percent, from 1 to 100"),
+        },
+        _ => return Err(HELP),
+    };
+    Ok(command)
+}
+
+/// Answer a [`Command::Request`].
+pub fn show_response(response: &Response<'_>) {
+    match *response {
+        Response::Ok => log!("ok"),
+        Response::Failed(failure) => log!("failed: {}", failure),
+        Response::Version { version } => log!("LuLuu! {}", version),
+        Response::Battery { millivolts, charging } => match millivolts {
+            Some(millivolts) => log!("battery: {}mV{}", millivolts, if charging { ", charging" } else { "" }),
+            None => log!("battery: unknown{}", if charging { ", charging" } else { "" }),
+        },
+        Response::Files { names } => {
+            for name in names.lines() {
+                log!("{}", name);
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Controlling the player from a computer, over a USB serial port, with `luluu-cli device`.
+//!
+//! The messages are [`luluu_proto`]'s. Requests are picked up with [`Control::poll`] and answered
+//! with [`Control::respond`] by the player, since it's the one that knows the answers.
+
+use luluu_bsp as bsp;
+
+use bsp::usb::UsbBus;
+use luluu_proto::frame::{self, FrameReader};
+use luluu_proto::{Failure, Request, Response, MAX_MESSAGE_LEN};
+use usb_device::class_prelude::*;
+use usbd_serial::SerialPort;
+
+pub struct Control {
+    serial: SerialPort<'static, UsbBus>,
+    reader: FrameReader,
+    /// Bytes read from the port that haven't been through the reader yet, from `input_start` to
+    /// `input_end`.
+    input: [u8; bsp::usb::MAX_PACKET_SIZE as usize],
+    input_start: usize,
+    input_end: usize,
+    /// The framed answer to the last request, written out as the port has room for it.
+    output: [u8; frame::MAX_FRAME_LEN],
+    output_start: usize,
+    output_end: usize,
+}
+
+impl Control {
+    pub fn new(bus: &'static UsbBusAllocator<UsbBus>) -> Self {
+        Self {
+            serial: SerialPort::new(bus),
+            reader: FrameReader::new(),
+            input: [0; bsp::usb::MAX_PACKET_SIZE as usize],
+            input_start: 0,
+            input_end: 0,
+            output: [0; frame::MAX_FRAME_LEN],
+            output_start: 0,
+            output_end: 0,
+        }
+    }
+
+    /// The serial port's USB class, for polling the device.
+    pub fn class(&mut self) -> &mut SerialPort<'static, UsbBus> {
+        &mut self.serial
+    }
+
+    /// The next request from the computer, if one's arrived. It has to be answered with
+    /// [`Control::respond`] before the next one's picked up, unless it's one that isn't answered,
+    /// like [`Request::StreamData`].
+    pub fn poll(&mut self) -> Option<Request<'_>> {
+        self.flush();
+        // the computer waits for the last answer before asking anything else
+        if self.output_start < self.output_end {
+            return None;
+        }
+
+        loop {
+            if self.input_start == self.input_end {
+                self.input_start = 0;
+                self.input_end = self.serial.read(&mut self.input).unwrap_or(0);
+                if self.input_end == 0 {
+                    return None;
+                }
+            }
+            let byte = self.input[self.input_start];
+            self.input_start += 1;
+            match self.reader.push(byte) {
+                Some(Ok(())) => break,
+                // it was meant to be a request, so it needs an answer
+                Some(Err(_)) => {
+                    self.respond(&Response::Failed(Failure::BadRequest));
+                    return None;
+                }
+                None => (),
+            }
+        }
+
+        if Request::decode(self.reader.message()).is_err() {
+            #[cfg(feature = "probe")]
+            defmt::warn!("unknown request from the computer");
+            self.respond(&Response::Failed(Failure::BadRequest));
+            return None;
+        }
+        Request::decode(self.reader.message()).ok()
+    }
+
+    /// Answer the last request.
+    pub fn respond(&mut self, response: &Response<'_>) {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        // the answers are all short enough
+        let len = response.encode(&mut message).unwrap();
+        self.output_end = frame::encode(&message[..len], &mut self.output).unwrap();
+        self.output_start = 0;
+        self.flush();
+    }
+
+    /// Answer any request that's arrived with `failure`, while the player's too busy for it.
+    pub fn refuse(&mut self, failure: Failure) {
+        match self.poll() {
+            // the frame it's part of was turned down already
+            Some(Request::StreamData { .. }) | None => (),
+            Some(_) => self.respond(&Response::Failed(failure)),
+        }
+    }
+
+    /// Write as much of the answer as there's room for.
+    fn flush(&mut self) {
+        while self.output_start < self.output_end {
+            match self.serial.write(&self.output[self.output_start..self.output_end]) {
+                Ok(written) if written > 0 => self.output_start += written,
+                // the rest goes once the computer's read some
+                _ => break,
+            }
+        }
+    }
+}
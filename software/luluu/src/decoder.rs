@@ -0,0 +1,393 @@
+//! Core 1: reads animations off the SD card and decodes their frames for core 0 to show, or lends
+//! the card out to core 0 for a computer to use over USB.
+
+use core::fmt::Write;
+
+use luluu_bsp as bsp;
+
+use bsp::buffers::FullFramebuffer;
+use bsp::hal::{self, pac, sio::Sio};
+use bsp::luluu_enc::{DirtyRect, Header, DIRTY_RECT_SIZE};
+use bsp::spi_dma::SharedSpiDevice;
+use embedded_sdmmc::sdcard::DummyCsPin;
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx, DirEntry, SdCardError, VolumeIdx};
+
+use crate::pipeline::{self, Animation, Failure, PlayerLink, Request, Response};
+use crate::cache::{self, CachedFiles, FlashFile};
+use crate::read_file::{read_frame, ReadFile};
+
+#[cfg(not(feature = "probe"))]
+use core as defmt;
+
+/// The most animations we look at on the card.
+pub const MAX_FILES: usize = 16;
+
+pub type SdSpi = SharedSpiDevice<'static, DummyCsPin, hal::Timer>;
+pub type SdCard = embedded_sdmmc::SdCard<SdSpi, bsp::CardCs, hal::Timer>;
+pub type VolumeManager = embedded_sdmmc::VolumeManager<SdCard, bsp::rtc::RtcTimeSource, 1, 1, 1>;
+pub type DirEntries = heapless::Vec<DirEntry, MAX_FILES>;
+pub type RootDir<'a> = embedded_sdmmc::Directory<'a, SdCard, bsp::rtc::RtcTimeSource, 1, 1, 1>;
+pub type AnimationFile<'a> = embedded_sdmmc::File<'a, SdCard, bsp::rtc::RtcTimeSource, 1, 1, 1>;
+
+/// Serve [`Request`]s from core 0 forever. The card has already been set up and listed by core 0.
+pub fn run(mut volume_mgr: VolumeManager, mut dir_entries: DirEntries) -> ! {
+    // SAFETY: core 1 only uses its own end of the FIFOs, core 0 keeps the rest of the SIO
+    let pac = unsafe { pac::Peripherals::steal() };
+    let mut link = PlayerLink::new(Sio::new(pac.SIO).fifo);
+
+    // the request that got the card back after lending it out, for answering once it's open again
+    let mut pending = None;
+    loop {
+        let result = match volume_mgr.open_volume(VolumeIdx(0)) {
+            Ok(mut volume0) => match volume0.open_root_dir() {
+                Ok(mut root_dir) => serve(&mut link, pending.take(), &mut root_dir, &mut dir_entries),
+                Err(_) => Err(Failure::CardError),
+            },
+            Err(_) => Err(Failure::CardError),
+        };
+        match result {
+            // the volume's closed again by now, so the computer can do what it likes with the card
+            Ok(()) => pending = Some(lend(&mut link, volume_mgr.device())),
+            Err(failure) => fail_forever(&mut link, pending.take(), failure),
+        }
+    }
+}
+
+/// Serve [`Request`]s from core 0 forever without a card, playing the animations in `files` from
+/// flash instead. There's nothing to do for anything else.
+pub fn run_from_flash(files: CachedFiles) -> ! {
+    // SAFETY: as for `run`
+    let pac = unsafe { pac::Peripherals::steal() };
+    let mut link = PlayerLink::new(Sio::new(pac.SIO).fifo);
+
+    let mut request = link.recv();
+    loop {
+        request = match request {
+            Request::Open { file_idx } => match play(&mut link, &mut FlashFile::new(files[file_idx].bytes)) {
+                Ok(next) => next,
+                Err(failure) => {
+                    let request = link.recv();
+                    fail_until_open(&mut link, request, failure)
+                }
+            },
+            other => fail_forever(&mut link, Some(other), Failure::CardError),
+        };
+    }
+}
+
+/// Serve requests, starting with `pending` if there is one, until asked to lend out the card or the
+/// card stops working.
+fn serve(
+    link: &mut PlayerLink,
+    pending: Option<Request>,
+    root_dir: &mut RootDir<'_>,
+    dir_entries: &mut DirEntries,
+) -> Result<(), Failure> {
+    let mut request = pending.unwrap_or_else(|| link.recv());
+    loop {
+        request = match request {
+            Request::Open { file_idx } => {
+                let dir_entry = &dir_entries[file_idx];
+                // the copy in flash is quicker to read, if there is one
+                let result = match cache::find(dir_entry) {
+                    Some(mut img_file) => play(link, &mut img_file),
+                    None => match open_animation(root_dir, dir_entry) {
+                        Ok(mut img_file) => play(link, &mut img_file),
+                        Err(failure) => {
+                            link.send(Response::Failed { slot: None, failure });
+                            Err(failure)
+                        }
+                    },
+                };
+
+                match result {
+                    Ok(next) => next,
+                    Err(Failure::BadFile) => {
+                        let request = link.recv();
+                        fail_until_open(link, request, Failure::BadFile)
+                    }
+                    Err(Failure::CardError) => return Err(Failure::CardError),
+                }
+            }
+            Request::List { mut slot } => {
+                match list_animations(root_dir) {
+                    Ok(listed) => *dir_entries = listed,
+                    Err(_) => {
+                        link.send(Response::Failed { slot: Some(slot), failure: Failure::CardError });
+                        return Err(Failure::CardError);
+                    }
+                }
+                let mut name: heapless::String<12> = heapless::String::new();
+                for (idx, dir_entry) in dir_entries.iter().enumerate() {
+                    name.clear();
+                    write!(&mut name, "{}", dir_entry.name).unwrap();
+                    pipeline::set_listed_name(&mut slot, idx, &name);
+                }
+                link.send(Response::Listed { slot, n_files: dir_entries.len() });
+                link.recv()
+            }
+            Request::WriteFile { mut slot, len, append } => {
+                match write_file(root_dir, &mut slot, len, append) {
+                    Ok(()) => link.send(Response::Written { slot }),
+                    Err(failure) => {
+                        #[cfg(feature = "probe")]
+                        defmt::warn!("couldn't write {}: {}", pipeline::file_name(&slot), failure);
+                        link.send(Response::Failed { slot: Some(slot), failure });
+                        if failure == Failure::CardError {
+                            return Err(failure);
+                        }
+                    }
+                }
+                link.recv()
+            }
+            Request::Lend => return Ok(()),
+            Request::Decode { .. } => defmt::panic!("asked to decode before opening an animation"),
+            Request::ReadBlocks { .. } | Request::WriteBlocks { .. } => {
+                defmt::panic!("asked for blocks before lending out the card")
+            }
+        };
+    }
+}
+
+/// List the animations in `root_dir`, up to [`MAX_FILES`] of them.
+pub fn list_animations(root_dir: &mut RootDir<'_>) -> Result<DirEntries, embedded_sdmmc::Error<SdCardError>> {
+    let mut dir_entries = DirEntries::new();
+    root_dir.iterate_dir(|dir_entry| {
+        if dir_entries.is_full() {
+            return;
+        }
+        if dir_entry.attributes.is_hidden() || dir_entry.attributes.is_system() {
+            return;
+        }
+        if dir_entry.name.extension() == b"LU" {
+            dir_entries.push(dir_entry.clone()).unwrap();
+        }
+    })?;
+    Ok(dir_entries)
+}
+
+/// Open the animation in `dir_entry`.
+fn open_animation<'a>(root_dir: &'a mut RootDir<'_>, dir_entry: &DirEntry) -> Result<AnimationFile<'a>, Failure> {
+    let img_file = root_dir
+        .open_file_in_dir(&dir_entry.name, embedded_sdmmc::Mode::ReadOnly)
+        .map_err(failure_for)?;
+
+    #[cfg(feature = "probe")]
+    defmt::info!("found {}, size: {}", defmt::Display2Format(&dir_entry.name), dir_entry.size);
+    Ok(img_file)
+}
+
+/// Read the header of `img_file`, tell core 0 about the animation and decode its frames until
+/// asked for something else, which is returned. An animation that can't be opened is answered for.
+fn play<F: ReadFile<SdCard>>(link: &mut PlayerLink, img_file: &mut F) -> Result<Request, Failure> {
+    let header = match read_header(img_file) {
+        Ok(header) => header,
+        Err(failure) => {
+            link.send(Response::Failed { slot: None, failure });
+            return Err(failure);
+        }
+    };
+    link.send(Response::Opened(Animation {
+        size: header.size.0,
+        frame_rate: header.frame_rate.0,
+        n_frames: header.n_frames.as_u16(),
+    }));
+    decode_frames(link, img_file, &header)
+}
+
+/// Read the header at the start of `img_file`, checking it's an animation we can play.
+fn read_header<F: ReadFile<SdCard>>(img_file: &mut F) -> Result<Header, Failure> {
+    let mut header_bytes = [0u8; bsp::luluu_enc::HEADER_SIZE];
+    let read = img_file.read(&mut header_bytes).map_err(failure_for)?;
+    if read < bsp::luluu_enc::HEADER_SIZE {
+        return Err(Failure::BadFile);
+    }
+
+    let header = Header::decode(&header_bytes).map_err(|_| Failure::BadFile)?;
+    if header.n_frames.as_u16() == 0 {
+        return Err(Failure::BadFile);
+    }
+    if header.encoding != bsp::luluu_enc::Encoding::RGB565BE {
+        #[cfg(feature = "probe")]
+        defmt::warn!("animation isn't RGB565BE");
+        return Err(Failure::BadFile);
+    }
+    Ok(header)
+}
+
+/// Write the bytes in `slot` to the file named in it, see [`Request::WriteFile`]. A name the card
+/// can't have is a bad file.
+fn write_file(root_dir: &mut RootDir<'_>, slot: &mut FullFramebuffer, len: usize, append: bool) -> Result<(), Failure> {
+    let mut name: heapless::String<12> = heapless::String::new();
+    name.push_str(pipeline::file_name(slot)).unwrap();
+    let mode = match append {
+        true => embedded_sdmmc::Mode::ReadWriteAppend,
+        false => embedded_sdmmc::Mode::ReadWriteCreateOrTruncate,
+    };
+    let mut file = root_dir.open_file_in_dir(name.as_str(), mode).map_err(|error| match error {
+        embedded_sdmmc::Error::FilenameError(_) => Failure::BadFile,
+        error => failure_for(error),
+    })?;
+    file.write(&pipeline::file_bytes(slot)[..len]).map_err(failure_for)?;
+    file.close().map_err(failure_for)
+}
+
+/// Decode frames of an opened animation until asked to open another, which is returned.
+fn decode_frames<F: ReadFile<SdCard>>(link: &mut PlayerLink, img_file: &mut F, header: &Header) -> Result<Request, Failure> {
+    let n_frames = header.n_frames.as_u16() as u32;
+
+    // index in the file of the last frame decoded
+    let mut file_frame: Option<u32> = None;
+    loop {
+        let (mut slot, frame_step) = match link.recv() {
+            Request::Decode { slot, frame_step } => (slot, frame_step),
+            open => return Ok(open),
+        };
+
+        let next_frame = match file_frame {
+            Some(file_frame) => (file_frame + frame_step) % n_frames,
+            None => 0,
+        };
+        // the dirty rect is only any use if the display has the frame before this one
+        let follows_last = file_frame.is_some() && frame_step == 1;
+        // wrapped around, or skipping frames
+        let seek = file_frame.is_some_and(|file_frame| next_frame != file_frame + 1);
+        file_frame = Some(next_frame);
+
+        let result = read_next_frame(img_file, header, seek.then_some(next_frame), follows_last, &mut slot);
+        match result {
+            Ok(dirty) => link.send(Response::Decoded { slot, dirty }),
+            Err(failure) => {
+                #[cfg(feature = "probe")]
+                defmt::warn!("failed to decode frame {}: {}", next_frame, failure);
+                link.send(Response::Failed { slot: Some(slot), failure });
+                return Err(failure);
+            }
+        }
+    }
+}
+
+/// Read the next frame in the file into `fb`, or `seek_to` that one first. Returns its dirty rect,
+/// or the whole frame unless it `follows_last`. A dirty rect that's outside the frame makes it a
+/// bad file.
+fn read_next_frame<F: ReadFile<SdCard>>(
+    img_file: &mut F,
+    header: &Header,
+    seek_to: Option<u32>,
+    follows_last: bool,
+    fb: &mut FullFramebuffer,
+) -> Result<DirtyRect, Failure> {
+    if let Some(frame) = seek_to {
+        img_file.seek_from_start(header.frame_offset(frame)).map_err(failure_for)?;
+    }
+
+    let mut dirty = DirtyRect::full(header.size);
+    if header.has_dirty_rects() {
+        let mut bytes = [0u8; DIRTY_RECT_SIZE];
+        let read = img_file.read(&mut bytes).map_err(failure_for)?;
+        if read < DIRTY_RECT_SIZE {
+            return Err(Failure::BadFile);
+        }
+        let rect = DirtyRect::from_bytes(bytes);
+        if !rect.fits(header.size) {
+            return Err(Failure::BadFile);
+        }
+        if follows_last {
+            dirty = rect;
+        }
+    }
+
+    read_frame(img_file, header, fb).map_err(failure_for)?;
+    Ok(dirty)
+}
+
+/// Answer `request` and everything after it with `failure` until asked for something other than a
+/// frame, which is returned.
+fn fail_until_open(link: &mut PlayerLink, mut request: Request, failure: Failure) -> Request {
+    loop {
+        match request {
+            Request::Decode { slot, .. } => link.send(Response::Failed { slot: Some(slot), failure }),
+            open => return open,
+        }
+        request = link.recv();
+    }
+}
+
+/// Answer every request from now on with `failure`, starting with `pending` if there is one. Core 0
+/// restarts everything once it hears about a card error, until then there's nothing we can do.
+fn fail_forever(link: &mut PlayerLink, pending: Option<Request>, failure: Failure) -> ! {
+    let mut request = pending.unwrap_or_else(|| link.recv());
+    loop {
+        let slot = match request {
+            Request::Decode { slot, .. }
+            | Request::ReadBlocks { slot, .. }
+            | Request::WriteBlocks { slot, .. }
+            | Request::List { slot }
+            | Request::WriteFile { slot, .. } => Some(slot),
+            Request::Open { .. } | Request::Lend => None,
+        };
+        link.send(Response::Failed { slot, failure });
+        request = link.recv();
+    }
+}
+
+/// Lend out the card: answer requests to read and write blocks of it until asked for anything else,
+/// which is returned.
+fn lend(link: &mut PlayerLink, card: &SdCard) -> Request {
+    match card.num_blocks() {
+        Ok(n_blocks) => link.send(Response::Lent { n_blocks: n_blocks.0 }),
+        // core 0 gives up on the card
+        Err(_) => link.send(Response::Failed { slot: None, failure: Failure::CardError }),
+    }
+
+    loop {
+        let (slot, result) = match link.recv() {
+            Request::ReadBlocks { mut slot, block_idx, n_blocks } => {
+                let result = read_blocks(card, block_idx, n_blocks, slot.as_bytes_mut());
+                (slot, result)
+            }
+            Request::WriteBlocks { slot, block_idx, n_blocks } => {
+                let result = write_blocks(card, block_idx, n_blocks, slot.as_bytes());
+                (slot, result)
+            }
+            other => return other,
+        };
+        match result {
+            Ok(()) => link.send(Response::Blocks { slot }),
+            Err(_) => {
+                #[cfg(feature = "probe")]
+                defmt::warn!("reading or writing the lent out card failed");
+                link.send(Response::Failed { slot: Some(slot), failure: Failure::CardError });
+            }
+        }
+    }
+}
+
+/// Read `n_blocks` blocks of `card` from `block_idx` on into the start of `bytes`.
+pub fn read_blocks(card: &SdCard, block_idx: u32, n_blocks: u32, bytes: &mut [u8]) -> Result<(), SdCardError> {
+    // a block at a time, since they can't be read straight into a byte buffer
+    let mut block = [Block::new()];
+    for (idx, bytes) in (block_idx..).zip(bytes.chunks_exact_mut(Block::LEN).take(n_blocks as usize)) {
+        card.read(&mut block, BlockIdx(idx), "usb")?;
+        bytes.copy_from_slice(&block[0].contents);
+    }
+    Ok(())
+}
+
+/// Write `n_blocks` blocks from the start of `bytes` to `card` from `block_idx` on.
+pub fn write_blocks(card: &SdCard, block_idx: u32, n_blocks: u32, bytes: &[u8]) -> Result<(), SdCardError> {
+    let mut block = [Block::new()];
+    for (idx, bytes) in (block_idx..).zip(bytes.chunks_exact(Block::LEN).take(n_blocks as usize)) {
+        block[0].contents.copy_from_slice(bytes);
+        card.write(&block, BlockIdx(idx))?;
+    }
+    Ok(())
+}
+
+/// Whether an error from the card is down to the file or the card itself.
+fn failure_for<E>(error: embedded_sdmmc::Error<E>) -> Failure {
+    match error {
+        embedded_sdmmc::Error::EndOfFile | embedded_sdmmc::Error::InvalidOffset => Failure::BadFile,
+        _ => Failure::CardError,
+    }
+}
@@ -0,0 +1,287 @@
+//! Finding out what went wrong out in the field, where there's no probe attached.
+//!
+//! A panic, on either core, is written down in a [`Record`] in the `FAULT` region of RAM from
+//! `memory.x`, which nothing touches at startup, and the watchdog then resets the chip. The
+//! watchdog is also what gets a player that's stopped responding going again, so core 0 keeps
+//! [`feed`]ing it and noting down what it's up to, see [`note`]. On the next boot [`take`] gets the
+//! record back out, and it's added to [`LOG_FILE_NAME`] on the card once that can be read.
+
+use core::cell::{RefCell, UnsafeCell};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use critical_section::Mutex;
+use embedded_sdmmc::Mode;
+use fugit::ExtU32;
+use luluu_bsp as bsp;
+use luluu_config::time::DateTime;
+
+use bsp::hal::{pac, watchdog::Watchdog};
+
+use crate::decoder::RootDir;
+
+/// Faults are added to the end of this file in the top folder of the card, a line each.
+pub const LOG_FILE_NAME: &str = "FAULTS.TXT";
+
+/// How long core 0 can go without feeding the watchdog before the chip's reset. The longest the
+/// watchdog can be set to is a bit over 8s.
+const WATCHDOG_MICROS: u32 = 8_000_000;
+
+/// How long after a panic the watchdog resets the chip, long enough for it to be logged.
+#[cfg(not(feature = "probe"))]
+const PANIC_RESET_MICROS: u32 = 10_000;
+
+/// Marks a [`Record`] as having been written by this firmware, since RAM is garbage after power on.
+const MAGIC: u32 = 0x4c55_4c55;
+
+const MAX_MESSAGE_LEN: usize = 160;
+
+/// Long enough for an 8.3 file name.
+const MAX_NAME_LEN: usize = 12;
+
+/// What the player's up to, for saying what it was doing when something went wrong.
+#[derive(Clone, Copy)]
+pub enum Activity<'a> {
+    Playing { file_name: &'a str },
+    Receiving { file_name: &'a str },
+    Streaming,
+    LendingCard,
+    /// About to reset on purpose, so a watchdog reset after this isn't a fault.
+    Restarting,
+}
+
+/// Text that's cut short if it doesn't fit, rather than failing to write.
+#[derive(Clone, Copy)]
+struct Text<const N: usize> {
+    bytes: [u8; N],
+    len: u16,
+}
+
+impl<const N: usize> Text<N> {
+    const EMPTY: Self = Self { bytes: [0; N], len: 0 };
+
+    fn set(&mut self, text: &str) {
+        self.len = 0;
+        let _ = self.write_str(text);
+    }
+
+    fn as_str(&self) -> &str {
+        // it could be garbage from before power on
+        let bytes = &self.bytes[..(self.len as usize).min(N)];
+        match core::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl<const N: usize> Write for Text<N> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let len = (self.len as usize).min(N);
+        let mut n = text.len().min(N - len);
+        while !text.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.bytes[len..len + n].copy_from_slice(&text.as_bytes()[..n]);
+        self.len = (len + n) as u16;
+        Ok(())
+    }
+}
+
+/// What the player was doing, and what went wrong if it panicked. Every bit pattern is a valid
+/// record, so it's fine to read it whatever RAM had in it at power on.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Record {
+    /// [`MAGIC`] once it's been written by this firmware.
+    magic: u32,
+    /// An [`Activity`], with the file name in `file_name`.
+    activity: u8,
+    file_name: Text<MAX_NAME_LEN>,
+    /// Frames shown of the animation being played.
+    frame: u32,
+    /// Seconds since boot, as of the panic or the last time the watchdog was fed.
+    uptime_secs: u32,
+    /// Non-zero if there's been a panic, described in `message`.
+    panicked: u8,
+    message: Text<MAX_MESSAGE_LEN>,
+}
+
+impl Record {
+    const EMPTY: Self = Self {
+        magic: 0,
+        activity: ACTIVITY_STARTING,
+        file_name: Text::EMPTY,
+        frame: 0,
+        uptime_secs: 0,
+        panicked: 0,
+        message: Text::EMPTY,
+    };
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.panicked {
+            0 => write!(f, "stopped responding")?,
+            _ => write!(f, "{}", self.message.as_str())?,
+        }
+        write!(f, ", {}s after starting, ", self.uptime_secs)?;
+        match self.activity {
+            ACTIVITY_PLAYING => write!(f, "playing {} at frame {}", self.file_name.as_str(), self.frame),
+            ACTIVITY_RECEIVING => write!(f, "receiving {} over USB", self.file_name.as_str()),
+            ACTIVITY_STREAMING => write!(f, "streaming over USB"),
+            ACTIVITY_LENDING_CARD => write!(f, "with the card lent to USB"),
+            _ => write!(f, "starting up"),
+        }
+    }
+}
+
+const ACTIVITY_STARTING: u8 = 0;
+const ACTIVITY_PLAYING: u8 = 1;
+const ACTIVITY_RECEIVING: u8 = 2;
+const ACTIVITY_STREAMING: u8 = 3;
+const ACTIVITY_LENDING_CARD: u8 = 4;
+const ACTIVITY_RESTARTING: u8 = 5;
+
+struct RecordCell(UnsafeCell<Record>);
+
+// SAFETY: core 0 is the only one that writes to it, until there's a panic, at which point the
+// panicking core takes over and the other one's only a problem if it panics at the same time
+unsafe impl Sync for RecordCell {}
+
+/// `.fault` isn't loaded, so this is whatever was there before the reset, not `EMPTY`.
+#[link_section = ".fault"]
+static RECORD: RecordCell = RecordCell(UnsafeCell::new(Record::EMPTY));
+
+/// Taken by the panic handler, so core 0 stops feeding the watchdog and lets it reset the chip.
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+static WATCHDOG: Mutex<RefCell<Option<Watchdog>>> = Mutex::new(RefCell::new(None));
+
+/// # Safety
+///
+/// Only for core 0 outside the panic handler, and the panic handler. The reference can't be held
+/// on to.
+unsafe fn record() -> &'static mut Record {
+    &mut *RECORD.0.get()
+}
+
+/// The fault from before the last reset, if there was one, and start a fresh record. Has to be
+/// called first thing, with whether it was the watchdog that reset the chip.
+pub fn take(watchdog_reset: bool) -> Option<Record> {
+    // SAFETY: core 1 isn't running yet
+    let record = unsafe { record() };
+    let last = *record;
+    *record = Record::EMPTY;
+    record.magic = MAGIC;
+
+    let faulted = last.panicked != 0 || (watchdog_reset && last.activity != ACTIVITY_RESTARTING);
+    (last.magic == MAGIC && faulted).then_some(last)
+}
+
+/// Note down what the player's doing now. Only for core 0.
+pub fn note(activity: Activity<'_>) {
+    // SAFETY: we're on core 0
+    let record = unsafe { record() };
+    let (activity, file_name) = match activity {
+        Activity::Playing { file_name } => (ACTIVITY_PLAYING, file_name),
+        Activity::Receiving { file_name } => (ACTIVITY_RECEIVING, file_name),
+        Activity::Streaming => (ACTIVITY_STREAMING, ""),
+        Activity::LendingCard => (ACTIVITY_LENDING_CARD, ""),
+        Activity::Restarting => (ACTIVITY_RESTARTING, ""),
+    };
+    record.activity = activity;
+    record.file_name.set(file_name);
+    record.frame = 0;
+}
+
+/// Note down how many frames of the animation being played have been shown. Only for core 0.
+pub fn note_frame(frame: u32) {
+    // SAFETY: we're on core 0
+    unsafe { record() }.frame = frame;
+}
+
+/// Start the watchdog, which resets the chip unless it's [`feed`] at least every
+/// [`WATCHDOG_MICROS`].
+pub fn start_watchdog(mut watchdog: Watchdog) {
+    watchdog.start(WATCHDOG_MICROS.micros());
+    critical_section::with(|cs| *WATCHDOG.borrow_ref_mut(cs) = Some(watchdog));
+}
+
+/// Put off the watchdog resetting the chip. Only for core 0, which has to keep calling this while
+/// it's waiting on anything.
+pub fn feed() {
+    if PANICKED.load(Ordering::Relaxed) {
+        return;
+    }
+    critical_section::with(|cs| {
+        if let Some(watchdog) = WATCHDOG.borrow_ref(cs).as_ref() {
+            watchdog.feed();
+        }
+    });
+    // SAFETY: we're on core 0
+    unsafe { record() }.uptime_secs = uptime_secs();
+}
+
+/// Add `record` to the end of [`LOG_FILE_NAME`], with the time it was found if the clock's set.
+pub fn save(
+    root_dir: &mut RootDir<'_>,
+    record: &Record,
+    now: Option<DateTime>,
+) -> Result<(), embedded_sdmmc::Error<embedded_sdmmc::SdCardError>> {
+    let mut line = Text::<{ MAX_MESSAGE_LEN + 96 }>::EMPTY;
+    if let Some(now) = now {
+        let _ = write!(line, "{} ", now);
+    }
+    let _ = write!(line, "{}", record);
+    // the line has to end, even if that means cutting some off
+    line.len = line.len.min(line.bytes.len() as u16 - 2);
+    let _ = line.write_str("\r\n");
+
+    let mut file = root_dir.open_file_in_dir(LOG_FILE_NAME, Mode::ReadWriteCreateOrAppend)?;
+    file.write(line.as_str().as_bytes())?;
+    file.close()
+}
+
+/// Seconds since boot, from the timer's raw count so it doesn't need the [`hal::Timer`](bsp::hal::Timer).
+fn uptime_secs() -> u32 {
+    // SAFETY: the raw count is only read, unlike the latched one which is the `Timer`'s
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    loop {
+        let high = timer.timerawh.read().bits();
+        let low = timer.timerawl.read().bits();
+        // the low half wrapped in between
+        if timer.timerawh.read().bits() == high {
+            return (((high as u64) << 32 | low as u64) / 1_000_000) as u32;
+        }
+    }
+}
+
+/// Write down the panic and have the watchdog reset the chip. With the `probe` feature,
+/// `panic-probe` is used instead, to show the panic on the computer.
+#[cfg(not(feature = "probe"))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    PANICKED.store(true, Ordering::Relaxed);
+
+    // SAFETY: this is the panic handler, and it never returns
+    let record = unsafe { record() };
+    record.magic = MAGIC;
+    record.panicked = 1;
+    record.uptime_secs = uptime_secs();
+    record.message.len = 0;
+    let _ = match info.location() {
+        Some(location) => write!(record.message, "panicked at {}: {}", location, info.message()),
+        None => write!(record.message, "panicked: {}", info.message()),
+    };
+    crate::console::log!("{}", record);
+
+    // the watchdog we were given might be on the other core, so have our own, on the same
+    // hardware. SAFETY: nothing's going to use the watchdog to do anything else from now on.
+    let mut watchdog = Watchdog::new(unsafe { pac::Peripherals::steal() }.WATCHDOG);
+    watchdog.start(PANIC_RESET_MICROS.micros());
+    loop {
+        cortex_m::asm::nop();
+    }
+}
@@ -0,0 +1,13 @@
+use luluu_gesture::Gesture;
+
+pub use luluu_config::{Action, GestureActions};
+
+/// The action `gesture` triggers, from the `[gestures]` settings.
+pub fn action_for(actions: &GestureActions, gesture: Gesture) -> Option<Action> {
+    match gesture {
+        Gesture::Tap => actions.tap,
+        Gesture::DoubleTap => actions.double_tap,
+        Gesture::Shake => actions.shake,
+        Gesture::WristFlip => actions.wrist_flip,
+    }
+}
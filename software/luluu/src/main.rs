@@ -2,28 +2,32 @@
 #![no_main]
 
 use core::cell::RefCell;
-use core::ops::{DerefMut, Deref};
 use core::fmt::Write;
 
 use bsp::hal::Clock;
+use bsp::buffers::{BufferCell, BufferStorage, FramebufferPool, PlacedBufferCell};
+use bsp::hal::multicore::{Multicore, Stack};
 use bsp::hal::rosc::RingOscillator;
-use display_interface_spi::SPIInterface;
-use embedded_hal_bus::spi::RefCellDevice;
-use embedded_sdmmc::{VolumeIdx, DirEntry};
+use bsp::usb::usb_device::bus::UsbBusAllocator;
+use bsp::spi_dma::{DmaSpiBus, SharedSpi, SharedSpiDevice};
+use cortex_m::peripheral::SCB;
+use critical_section::Mutex;
+use embedded_sdmmc::VolumeIdx;
 use embedded_sdmmc::sdcard::{DummyCsPin, AcquireOpts};
 use luluu_bsp as bsp;
 
-use bsp::{hal as hal, DispReset, Rgb565BE};
+use bsp::{hal as hal, DispReset};
+use bsp::luluu_enc::{DirtyRect, Size};
 use bsp::{entry, hal::Spi, SpiPinLayout};
+use bsp::hal::dma::DMAExt;
 use embedded_hal::digital::{OutputPin, InputPin};
+use bsp::accel::Accelerometer;
 
 #[cfg(feature = "probe")]
 use defmt_rtt as _;
 #[cfg(feature = "probe")]
 use panic_probe as _;
 #[cfg(not(feature = "probe"))]
-use panic_halt as _;
-#[cfg(not(feature = "probe"))]
 use core as defmt;
 
 use bsp::hal::{
@@ -33,37 +37,117 @@ use bsp::hal::{
     watchdog::Watchdog,
 };
 
-use fugit::{RateExtU32, HertzU32};
+use fugit::RateExtU32;
+use luluu_config::{ClockFace, Config};
+use luluu_proto as proto;
 
-use crate::read_file::{read_60px_frame_into_main_fb, read_240px_frame_into_main_fb};
-use crate::read_file::read_120px_frame_into_main_fb;
+use crate::pipeline::{DecoderLink, Failure, FrameSlot, Request, Response};
 
+mod battery;
+mod cache;
+mod clock;
+mod console;
+mod control;
+mod decoder;
+mod fault;
+mod font;
+mod input;
+mod orientation;
+mod overlay;
+mod pipeline;
+mod playlist;
 mod read_file;
+mod render;
+mod settings;
+mod status;
+mod update;
+mod usb;
+mod usb_storage;
+mod watch;
 
-/// The `.sram4` section spans SRAM bank 4, which is 4KiB
-pub const FILE_BUFFER_SIZE: usize = 1024 * 4;
+/// Front and back buffers, passed back and forth with core 1 as [`pipeline::FrameSlot`]s. No room
+/// for scratch buffers.
+static FRAMEBUFFERS: FramebufferPool<0> = FramebufferPool::new();
 
-bsp::singleton! {
-    FileReadBuffer {
-        #[link_section = ".sram4"]
-        #[used]
-        static mut FILE_BUFFER: [u8; FILE_BUFFER_SIZE] = [0u8; FILE_BUFFER_SIZE];
-    }
-}
+/// Core 1's stack gets SRAM bank 5 to itself, since the framebuffers take up nearly all of the
+/// main RAM. That's 4KiB, so it's exactly 1024 words, and the flag for handing it out goes in
+/// [`CORE1_STACK`] in the main RAM.
+#[link_section = ".sram5"]
+static CORE1_STACK_MEM: BufferStorage<Stack<1024>> = BufferStorage::new(Stack::new());
 
-bsp::singleton! {
-    MainFramebuffer {
-        static mut MAIN_FRAMEBUFFER: bsp::Framebuffer<Rgb565BE> = bsp::Framebuffer::const_new(Rgb565BE::ZERO);
-    }
-}
+static CORE1_STACK: PlacedBufferCell<Stack<1024>> = PlacedBufferCell::new(&CORE1_STACK_MEM);
+
+/// The SPI bus the display and card share. It needs the clocks and DMA channels to build, so it's
+/// filled in at startup.
+static SHARED_SPI: BufferCell<Option<SharedSpi>> = BufferCell::new(None);
+
+/// How often to read the accelerometer, ~25Hz.
+const ACCEL_POLL_MICROS: u32 = 40_000;
+
+const GESTURE_CONFIG: luluu_gesture::Config = luluu_gesture::Config {
+    // we only sample at ~25Hz which is too slow to reliably catch taps, so we rely on the
+    // accelerometer's own click detection for those instead.
+    tap_jerk_threshold_mg: 0,
+    ..luluu_gesture::Config::DEFAULT
+};
+
+const BATTERY_CONFIG: battery::BatteryConfig = battery::BatteryConfig::DEFAULT;
+
+/// How often to measure the battery voltage.
+const BATTERY_POLL_MICROS: u32 = 5_000_000;
+
+/// How often to check whether another rule in the schedule applies.
+const SCHEDULE_POLL_MICROS: u32 = 1_000_000;
+
+/// How long most backlight fades take, like turning on for a new animation or going to sleep.
+const FADE_MILLIS: u32 = 300;
+
+/// Dimming after a while without activity is slower, so it's less of a surprise.
+const DIM_FADE_MILLIS: u32 = 1_500;
+
+/// How long to wait between looking for a card, or one with animations on it.
+const CARD_POLL_MILLIS: u32 = 1_000;
+
+/// SPI clock for setting up the SD card, which has to be at most 400kHz.
+const CARD_INIT_KHZ: u32 = 200;
 
-const SD_BAUDRATE: HertzU32 = HertzU32::kHz(31_250);
-const DISP_BAUDRATE: HertzU32 = HertzU32::kHz(62_500);
+/// How long a problem with a file is shown before moving on.
+const FAILURE_MILLIS: u32 = 3_000;
 
+/// How long to give up on an upload over USB serial after, if nothing more arrives.
+const UPLOAD_TIMEOUT_MILLIS: u32 = 5_000;
+
+/// How long to keep USB going after a last answer, so it gets to the computer before restarting.
+const LAST_ANSWER_MILLIS: u32 = 100;
+
+/// How long to go back to the card's animations after, once streamed frames stop arriving.
+const STREAM_TIMEOUT_MILLIS: u32 = 3_000;
+
+/// The names of the animations on the card, which is all core 0 needs of them.
+type FileNames = heapless::Vec<playlist::FileName, { decoder::MAX_FILES }>;
+
+/// Something asked for over USB serial that playback has to stop for.
+enum Interruption {
+    Play { file_idx: usize },
+    Upload { name: playlist::FileName, len: u32 },
+    Stream(StreamedFrame),
+    Reboot,
+}
+
+/// A frame the computer's started streaming, see [`proto::Request::StreamFrame`].
+#[derive(Clone, Copy)]
+struct StreamedFrame {
+    size: Size,
+    rect: DirtyRect,
+    packed: bool,
+    len: u32,
+}
 
 #[entry]
 fn main() -> ! {
     let mut peripherals = pac::Peripherals::take().unwrap();
+    // before it's written over by this run
+    let mut last_fault = fault::take(peripherals.WATCHDOG.reason.read().timer().bit_is_set());
     let mut watchdog = Watchdog::new(peripherals.WATCHDOG);
 
     let clocks = init_clocks_and_plls(
@@ -79,8 +163,9 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
-    let mut fb = unsafe { MainFramebuffer::acquire() };
-    let mut file_read_buffer = unsafe { FileReadBuffer::acquire() };
+    // both framebuffers, while neither is being decoded into or waiting to be shown
+    let framebuffers = FRAMEBUFFERS.take().unwrap();
+    let mut idle_slots: heapless::Vec<FrameSlot, 2> = heapless::Vec::from_iter([framebuffers.front, framebuffers.back]);
 
     let core = pac::CorePeripherals::take().unwrap();
 
@@ -88,7 +173,14 @@ fn main() -> ! {
 
     let mut timer = hal::Timer::new(peripherals.TIMER, &mut peripherals.RESETS, &clocks);
 
-    let sio = Sio::new(peripherals.SIO);
+    let mut rtc = bsp::rtc::Rtc::new(peripherals.RTC, clocks.rtc_clock, &mut peripherals.RESETS);
+
+    let usb_bus: &'static UsbBusAllocator<bsp::usb::UsbBus> = cortex_m::singleton!(: UsbBusAllocator<bsp::usb::UsbBus> =
+        bsp::usb::init_bus(peripherals.USBCTRL_REGS, peripherals.USBCTRL_DPRAM, clocks.usb_clock, &mut peripherals.RESETS)
+    ).unwrap();
+    let mut usb = usb::Usb::new(usb_bus);
+
+    let mut sio = Sio::new(peripherals.SIO);
 
     let mut pins = bsp::Pins::new(
         peripherals.IO_BANK0,
@@ -97,8 +189,45 @@ fn main() -> ! {
         &mut peripherals.RESETS,
     );
 
-    let mut disp_backlight = pins.disp_backlight;
-    disp_backlight.set_low().unwrap();
+    // first, so anything that goes wrong from here on can be followed on it
+    let uart = bsp::uart::init(
+        peripherals.UART0,
+        pins.uart_tx,
+        pins.uart_rx,
+        &mut peripherals.RESETS,
+        clocks.peripheral_clock.freq(),
+    );
+    let mut shell = console::init(uart);
+    console::log!("LuLuu! {}", env!("CARGO_PKG_VERSION"));
+    if let Some(record) = &last_fault {
+        console::log!("before the last reset: {}", record);
+    }
+    // from here on core 0 has to keep feeding it
+    fault::start_watchdog(watchdog);
+
+    let pwm_slices = hal::pwm::Slices::new(peripherals.PWM, &mut peripherals.RESETS);
+    let mut backlight = bsp::backlight::Backlight::new(pwm_slices.pwm3, pins.disp_backlight);
+
+    #[cfg(feature = "battery-sense")]
+    let mut battery = battery::Sensor::new(
+        peripherals.ADC,
+        pins.batt_sense,
+        pins.charge_stat,
+        &mut peripherals.RESETS,
+    );
+    #[cfg(not(feature = "battery-sense"))]
+    let mut battery = battery::Sensor::new();
+    let mut battery_policy = battery::BatteryPolicy::new(BATTERY_CONFIG);
+    battery_policy.update(battery.millivolts(), battery.is_charging());
+    #[cfg(feature = "probe")]
+    defmt::info!("battery: {}mV, {}", battery.millivolts(), battery_policy.level());
+    // only ever with the battery measured, which is the only way of knowing it's flat
+    #[cfg(feature = "battery-sense")]
+    if battery_policy.level() == battery::BatteryLevel::Shutdown {
+        // don't even bother starting up, the display hasn't been turned on yet
+        fault::note(fault::Activity::Restarting);
+        bsp::battery::shutdown_until_charging();
+    }
 
     pins.spi_mosi.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
     pins.spi_miso.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
@@ -108,12 +237,60 @@ fn main() -> ! {
     let spi = Spi::new(peripherals.SPI1, spi_pin_layout);
 
     // start at low baud rate for initializing card
-    let init = spi.init(&mut peripherals.RESETS, clocks.peripheral_clock.freq(), 200.kHz(), embedded_hal::spi::MODE_0);
-    let spi = init;
+    let spi = spi.init(&mut peripherals.RESETS, clocks.peripheral_clock.freq(), 200.kHz(), embedded_hal::spi::MODE_0);
+
+    let dma = peripherals.DMA.split(&mut peripherals.RESETS);
+    let shared_spi: &'static SharedSpi = SHARED_SPI.take().unwrap().leak().insert(Mutex::new(RefCell::new(
+        DmaSpiBus::new(spi, dma.ch0, dma.ch1, clocks.peripheral_clock.freq(), 200.kHz())
+    )));
+
+    // the display is set up first, so it can tell the wearer if there's a problem with the card
+    let mut disp_reset = pins.disp_reset;
+    disp_reset.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+
+    disp_reset.set_low().unwrap();
+    delay.delay_us(120);
+    disp_reset.set_high().unwrap();
+    delay.delay_ms(100);
+    // disp_reset.set_low().unwrap();
+    // delay.delay_us(10);
+    // disp_reset.set_high().unwrap();
+    // delay.delay_ms(100);
 
-    let shared_spi = RefCell::new(spi);
+    pins.disp_cs_main.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+    pins.disp_data_cmd.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+    let disp_cs_main = pins.disp_cs_main;
+    let disp_data_cmd = pins.disp_data_cmd;
+    let disp_vsync = pins.disp_vsync;
 
-    let sdcard_spi: RefCellDevice<'_, Spi<hal::spi::Enabled, pac::SPI1, (hal::gpio::Pin<hal::gpio::bank0::Gpio15, hal::gpio::FunctionSpi, hal::gpio::PullNone>, hal::gpio::Pin<hal::gpio::bank0::Gpio8, hal::gpio::FunctionSpi, hal::gpio::PullDown>, hal::gpio::Pin<hal::gpio::bank0::Gpio14, hal::gpio::FunctionSpi, hal::gpio::PullNone>), 8>, DummyCsPin, hal::Timer> = RefCellDevice::new(&shared_spi, DummyCsPin, timer.clone());
+    #[cfg(not(feature = "pio-display"))]
+    let display_bus = render::DisplayBus::new(shared_spi, disp_cs_main, disp_data_cmd, 400.kHz());
+    #[cfg(feature = "pio-display")]
+    let display_bus = {
+        use bsp::hal::pio::PIOExt;
+        let (mut pio, sm0, _, _, _) = peripherals.PIO0.split(&mut peripherals.RESETS);
+        render::DisplayBus::new(
+            shared_spi,
+            &mut pio,
+            sm0,
+            dma.ch2,
+            disp_cs_main,
+            disp_data_cmd,
+            clocks.system_clock.freq(),
+            400.kHz(),
+        )
+    };
+    let mut options = mipidsi::ModelOptions::with_sizes((240, 240), (240, 240));
+    // until the settings have been read, anything shown is shown the default way
+    options.set_invert_colors(mipidsi::ColorInversion::Inverted);
+    let mut display = mipidsi::Builder::new(display_bus.interface(), mipidsi::models::ST7789, options)
+        .init(&mut timer, None::<DispReset>)
+        .unwrap();
+    display.set_tearing_effect(mipidsi::TearingEffect::Vertical).unwrap();
+    display_bus.set_orientation(settings::orientation_config(&Config::DEFAULT).orientation).unwrap();
+    display_bus.set_baudrate(settings::display_baudrate(&Config::DEFAULT));
+
+    let sdcard_spi: decoder::SdSpi = SharedSpiDevice::new(shared_spi, DummyCsPin, timer.clone(), CARD_INIT_KHZ.kHz());
 
     pins.card_cs.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
     let card_cs = pins.card_cs;
@@ -128,186 +305,1160 @@ fn main() -> ! {
         }
     );
 
-    let mut volume_mgr = embedded_sdmmc::VolumeManager::<_, _, 1, 1, 1>::new_with_limits(sdcard, bsp::DummyTimesource, 0);
-    let mut volume0 = volume_mgr.open_volume(VolumeIdx(0)).unwrap();
-    let mut root_dir = volume0.open_root_dir().unwrap();
-    let mut dir_entries: heapless::Vec<DirEntry, 16> = heapless::Vec::new();
-    root_dir.iterate_dir(|dir_entry| {
-        if dir_entries.is_full() {
-            return;
-        }
-        if dir_entry.attributes.is_hidden() || dir_entry.attributes.is_system() {
-            return;
+    let mut volume_mgr: decoder::VolumeManager = embedded_sdmmc::VolumeManager::new_with_limits(sdcard, bsp::rtc::RtcTimeSource, 0);
+    // without anything to play on the card, what's in flash is played instead, after saying why
+    let (config, dir_entries, has_card) = match read_card(&mut volume_mgr, &mut rtc, &mut last_fault) {
+        Ok((config, dir_entries)) if !dir_entries.is_empty() => (config, dir_entries, true),
+        result => {
+            let (problem, config, has_card) = match result {
+                Ok((config, _)) => (status::Problem::NoAnimations, config, true),
+                Err(_) => (status::Problem::NoCard, Config::DEFAULT, false),
+            };
+            #[cfg(feature = "probe")]
+            defmt::warn!("nothing to play on the card: {}", problem);
+            let brightness = config.brightness_percent.min(battery_policy.level().max_brightness_percent());
+            show_problem(&display_bus, &mut idle_slots[0], &mut backlight, &timer, problem, brightness);
+            wait_millis_with_usb(&mut usb, &mut backlight, FAILURE_MILLIS, &timer);
+            (config, decoder::DirEntries::new(), has_card)
         }
-        if dir_entry.name.extension() == b"LU" {
-            dir_entries.push(dir_entry.clone()).unwrap();
+    };
+    let from_card = !dir_entries.is_empty();
+    // an empty drive is no use for putting animations on
+    usb.set_drive(config.usb_drive && has_card);
+    let brightness = config.brightness_percent.min(battery_policy.level().max_brightness_percent());
+
+    // newer firmware on the card is installed before anything else, restarting into it
+    if has_card {
+        // the card is initialized by now, so it can go full speed
+        volume_mgr.device().spi(|spi| spi.set_baudrate(settings::sd_baudrate(&config)));
+        update_firmware(&mut volume_mgr, &display_bus, &mut idle_slots, &mut backlight, &timer, brightness);
+    }
+
+    // core 0 only needs the names, for saying which file it couldn't play
+    let cached_files = cache::files();
+    let mut file_names: FileNames = match from_card {
+        true => dir_entries
+            .iter()
+            .map(|dir_entry| {
+                let mut name = heapless::String::new();
+                write!(&mut name, "{}", dir_entry.name).unwrap();
+                name
+            })
+            .collect(),
+        false => cached_files.iter().map(|file| file.name.clone()).collect(),
+    };
+
+    if from_card {
+        let picked = cache::pick(&dir_entries, &config.cache_files);
+        if !cache::is_current(&picked) {
+            // it takes a while, and the display's dark otherwise
+            show_problem(&display_bus, &mut idle_slots[0], &mut backlight, &timer, status::Problem::Caching, brightness);
+            wait_millis(&mut backlight, FADE_MILLIS, &timer);
+            update_cache(&mut volume_mgr, &picked, &mut idle_slots[1]);
         }
-    }).unwrap();
+    }
+    // the first animation fades in as usual
+    fade_and_wait(&mut backlight, 0, FADE_MILLIS, &timer);
+    // the card's set up from scratch again when it's looked at for animations, which has to be slow
+    if !from_card {
+        volume_mgr.device().spi(|spi| spi.set_baudrate(CARD_INIT_KHZ.kHz()));
+    }
 
-    defmt::assert!(dir_entries.len() > 0);
+    // from here on the card belongs to core 1, which decodes frames for us to show. without
+    // anything to play on it, core 1 plays from flash and core 0 keeps the card, to look out for
+    // animations being put on it
+    let mut own_card = None;
+    {
+        let mut mc = Multicore::new(&mut peripherals.PSM, &mut peripherals.PPB, &mut sio.fifo);
+        let cores = mc.cores();
+        let core1 = &mut cores[1];
+        let stack = CORE1_STACK.take().unwrap().leak();
+        if from_card {
+            core1.spawn(&mut stack.mem, move || decoder::run(volume_mgr, dir_entries)).unwrap();
+        } else {
+            own_card = Some(volume_mgr);
+            core1.spawn(&mut stack.mem, move || decoder::run_from_flash(cached_files)).unwrap();
+        }
+    }
+    let mut decoder = DecoderLink::new(sio.fifo);
 
     let mut rosc = RingOscillator::new(peripherals.ROSC).initialize();
-    let file_idx = (bsp::gen_rand_u32(&mut rosc) % dir_entries.len() as u32) as usize;
+    let mut playlist = playlist::Playlist::new(&config.schedule, &file_names, clock::now(&rtc));
+    let mut file_idx = playlist.first(config.order, &mut rosc);
+    // what moving on to the next animation on our own does
+    let change_action = match config.order {
+        luluu_config::PlayOrder::Shuffle => input::Action::Shuffle,
+        luluu_config::PlayOrder::Sequential => input::Action::Next,
+    };
+    let change_every_millis = settings::change_every_millis(&config);
 
-    let dir_entry = &dir_entries[file_idx];
+    display_bus.set_invert_colors(config.invert_colors).unwrap();
+    let mut auto_rotate = orientation::AutoRotate::new(settings::orientation_config(&config));
+    display_bus.set_orientation(auto_rotate.orientation()).unwrap();
+    display_bus.set_baudrate(settings::display_baudrate(&config));
 
-    let mut img_file = root_dir.open_file_in_dir(&dir_entry.name, embedded_sdmmc::Mode::ReadOnly).unwrap();
+    let power_config = settings::power_config(&config);
+    let i2c = bsp::accel::init_i2c(
+        peripherals.I2C1,
+        pins.i2c_data,
+        pins.i2c_clock,
+        &mut peripherals.RESETS,
+        clocks.system_clock.freq(),
+    );
+    let mut accel = match bsp::accel::Lis3dh::new(i2c, bsp::accel::ADDR_JUMPER, Default::default()) {
+        Ok(mut accel) => {
+            // double taps are told apart by the gesture recogniser
+            accel.configure_click(Some(bsp::accel::ClickConfig {
+                double: false,
+                ..Default::default()
+            })).unwrap();
+            accel.configure_activity(Some(bsp::accel::ActivityConfig {
+                threshold_mg: power_config.motion_threshold_mg,
+                ..Default::default()
+            })).unwrap();
+            Some(accel)
+        }
+        Err(_) => {
+            #[cfg(feature = "probe")]
+            defmt::warn!("no accelerometer found, motion-based sleep disabled");
+            None
+        }
+    };
+    let mut last_accel_poll = timer.get_counter_low();
+    let mut last_battery_poll = timer.get_counter_low();
+    let mut last_schedule_poll = timer.get_counter_low();
+    let mut power = luluu_power::PowerPolicy::new(power_config, millis(&timer));
+    let mut gestures = luluu_gesture::Recognizer::new(GESTURE_CONFIG);
+    let mut paused = false;
+    let mut brightness = playlist.brightness_percent().unwrap_or(config.brightness_percent);
+    let mut display_asleep = false;
+    let mut watch = watch::WatchFace::new(&config);
+    // the whole frame has to go again, like after reorienting since the display only turns pixels
+    // as they're written, or to cover up the watch face
+    let mut redraw = false;
+    // which way to go through the files when one can't be played
+    let mut last_action = change_action;
+    let mut interruption = None;
 
-    let mut dir_name: heapless::String<16> = heapless::String::new();
-    write!(&mut dir_name, "{}", &dir_entry.name).unwrap();
-    #[cfg(feature = "probe")]
-    defmt::info!("found {}, size: {}", dir_name, dir_entry.size);
+    loop {
+        decoder.send(Request::Open { file_idx });
+        let animation = match decoder.recv() {
+            Response::Opened(animation) => animation,
+            Response::Failed { failure, .. } => {
+                #[cfg(feature = "probe")]
+                defmt::warn!("can't open {}: {}", file_names[file_idx].as_str(), failure);
+                let target = target_brightness(brightness, &power, &battery_policy);
+                report_failure(&display_bus, &mut idle_slots[0], &mut backlight, &timer, failure, &file_names[file_idx], target);
+                file_idx = playlist.next(file_idx, last_action, &mut rosc);
+                continue;
+            }
+            _ => defmt::unreachable!(),
+        };
+        #[cfg(feature = "probe")]
+        defmt::info!("playing: {}", animation);
+        console::log!("playing {}", file_names[file_idx]);
+        fault::note(fault::Activity::Playing { file_name: &file_names[file_idx] });
 
-    let _baud = shared_spi.borrow_mut().set_baudrate(125.MHz(), SD_BAUDRATE);
-    #[cfg(feature = "probe")]
-    defmt::info!("set spi baud: {}", _baud);
+        // start decoding the first two frames
+        while let Some(slot) = idle_slots.pop() {
+            decoder.send(Request::Decode { slot, frame_step: 1 });
+        }
+        // the next frame to show and what's changed in it, once it's been decoded
+        let mut ready: Option<(FrameSlot, DirtyRect)> = None;
+        // the battery warning drawn over the last frame sent, and whether it was the critical one
+        let mut warning_on_display: Option<bool> = None;
+        // for moving on after the time set in the settings, restarted when unpaused
+        let mut playing_since = millis(&timer);
+        // sent again with the new animation, so there's something to fade in to if the face is
+        // shown instead of it
+        watch.invalidate();
 
-    let read = img_file.read(&mut file_read_buffer[0..bsp::luluu_enc::HEADER_SIZE]).unwrap();
+        #[cfg(feature = "probe")]
+        defmt::info!("frame rate: {}", animation.frame_rate);
 
-    defmt::assert_eq!(read, bsp::luluu_enc::HEADER_SIZE);
+        let display_frame_rate = match animation.size {
+            60 | 120 => {
+                match animation.frame_rate {
+                    1..=2 => mipidsi::FrameRate::Hz40,
+                    3 => mipidsi::FrameRate::Hz42,
+                    4 => mipidsi::FrameRate::Hz40,
+                    5 => mipidsi::FrameRate::Hz40,
+                    6 => mipidsi::FrameRate::Hz60,
+                    8 => mipidsi::FrameRate::Hz72,
+                    10 => mipidsi::FrameRate::Hz90,
+                    12 => mipidsi::FrameRate::Hz72,
+                    15 => mipidsi::FrameRate::Hz90,
+                    20 => mipidsi::FrameRate::Hz99,
+                    24 => mipidsi::FrameRate::Hz72,
+                    30 => mipidsi::FrameRate::Hz60,
+                    _ => defmt::unreachable!(),
+                }
+            }
+            240 => {
+                match animation.frame_rate {
+                    1..=2 => mipidsi::FrameRate::Hz40,
+                    3 => mipidsi::FrameRate::Hz60,
+                    4 => mipidsi::FrameRate::Hz90,
+                    5 => mipidsi::FrameRate::Hz40,
+                    6 => mipidsi::FrameRate::Hz60,
+                    8 => mipidsi::FrameRate::Hz72,
+                    10 => mipidsi::FrameRate::Hz90,
+                    12 => mipidsi::FrameRate::Hz72,
+                    _ => defmt::unreachable!(),
+                }
+            }
+            _ => defmt::unreachable!()
+        };
 
-    let header = bsp::luluu_enc::Header::decode((&file_read_buffer[..bsp::luluu_enc::HEADER_SIZE]).try_into().unwrap()).unwrap();
+        display.set_frame_rate(display_frame_rate, Default::default()).unwrap();
 
-    defmt::assert_eq!(header.encoding, bsp::luluu_enc::Encoding::RGB565BE);
+        let mut frame: u32 = 0; // number of frames shown
+        let result = 'playback: loop {
+            let start_time = timer.get_counter_low();
 
-    match header.size.0 {
-        60 => read_60px_frame_into_main_fb(&mut img_file, &mut *file_read_buffer, &mut *fb),
-        120 => read_120px_frame_into_main_fb(&mut img_file, &mut *file_read_buffer, &mut *fb),
-        240 => read_240px_frame_into_main_fb(&mut img_file, &mut *file_read_buffer, &mut *fb),
-        _ => defmt::unreachable!(),
-    }
+            // on a low battery we skip frames, showing each one for longer
+            let frame_step = battery_policy.level().frame_step();
+            let frame_budget_micros: u32 = (frame_step * 1_000_000 / animation.frame_rate as u32) - 200;
 
-    #[cfg(feature = "probe")]
-    defmt::info!("frame rate: {}", header.frame_rate);
-
-    let display_frame_rate = match header.size.0 {
-        60 | 120 => {
-            match header.frame_rate.0 {
-                1..=2 => mipidsi::FrameRate::Hz40,
-                3 => mipidsi::FrameRate::Hz42,
-                4 => mipidsi::FrameRate::Hz40,
-                5 => mipidsi::FrameRate::Hz40,
-                6 => mipidsi::FrameRate::Hz60,
-                8 => mipidsi::FrameRate::Hz72,
-                10 => mipidsi::FrameRate::Hz90,
-                12 => mipidsi::FrameRate::Hz72,
-                15 => mipidsi::FrameRate::Hz90,
-                20 => mipidsi::FrameRate::Hz99,
-                24 => mipidsi::FrameRate::Hz72,
-                _ => defmt::unreachable!(),
+            if !paused && !power.is_asleep() && watch.face() != ClockFace::Instead {
+                // core 1 is normally done with the next frame by now, if not we have to wait for it
+                let (slot, mut dirty) = match ready.take() {
+                    Some(ready) => ready,
+                    None => match decoder.recv() {
+                        Response::Decoded { slot, dirty } => (slot, dirty),
+                        Response::Failed { slot, failure } => {
+                            idle_slots.extend(slot);
+                            break 'playback Err(failure);
+                        }
+                        _ => defmt::unreachable!(),
+                    },
+                };
+
+                let size = Size(animation.size);
+                let warning = battery_policy.level().show_warning()
+                    .then(|| battery_policy.level() >= battery::BatteryLevel::Critical);
+                // the overlay isn't part of the dirty rects, so the whole frame goes when it changes
+                let warning_changed = warning != warning_on_display;
+                if warning_changed || redraw {
+                    dirty = DirtyRect::full(size);
+                    warning_on_display = warning;
+                    redraw = false;
+                }
+                // it's drawn over the frame, so it needs sending again if the frame covers it up
+                let send_warning = warning.is_some()
+                    && (warning_changed || overlay::overlaps_low_battery(render::display_rect(dirty, size)));
+                let send_watch = watch.face() == ClockFace::Over && watch.overlaps_over(render::display_rect(dirty, size));
+
+                #[cfg(feature = "probe")]
+                let draw_start = timer.get_counter_low();
+
+                // nothing to send if the frame's the same as the last one
+                if !dirty.is_empty() || send_warning {
+                    // we want to write starting *during* the time the controller driver is updating the lcd
+                    // from its internal memory, but *behind* the current place it's reading from its internal
+                    // memory. in this way we basically get two display-frames to update the display's memory.
+                    while !disp_vsync.is_high().unwrap() {}
+                    while disp_vsync.is_high().unwrap() {};
+                    delay.delay_us(300);
+
+                    // core 1 can carry on reading from the card in between the commands, but the bus is
+                    // ours for the pixels
+                    render::send_frame(&display_bus, &slot, size, dirty).unwrap();
+                    if let (true, Some(critical)) = (send_warning, warning) {
+                        overlay::send_low_battery(&display_bus, critical).unwrap();
+                    }
+                    if send_watch {
+                        watch.invalidate();
+                        watch.send(&display_bus, clock::now(&rtc)).unwrap();
+                    }
+                }
+
+                // the display has its own copy now, so core 1 can reuse the framebuffer right away
+                decoder.send(Request::Decode { slot, frame_step });
+
+                if frame == 1 {
+                    let target = target_brightness(brightness, &power, &battery_policy);
+                    backlight.fade_to(target, FADE_MILLIS, millis(&timer));
+                }
+
+                #[cfg(feature = "probe")]
+                if frame % 32 == 0 {
+                    let draw_end = timer.get_counter_low();
+                    defmt::info!("vsync and draw took: {}us", draw_end - draw_start);
+                }
+
+                frame += 1;
+                fault::note_frame(frame);
             }
-        }
-        240 => {
-            match header.frame_rate.0 {
-                1..=2 => mipidsi::FrameRate::Hz40,
-                3 => mipidsi::FrameRate::Hz60,
-                4 => mipidsi::FrameRate::Hz90,
-                _ => defmt::unreachable!(),
+
+            let frame_time = timer.get_counter_low().wrapping_sub(start_time);
+            #[cfg(feature = "probe")]
+            if (frame + 4) % 32 == 0 {
+                match frame_budget_micros.checked_sub(frame_time) {
+                    Some(micros_left) => defmt::info!("waiting for frame: {}us", micros_left),
+                    None => defmt::info!("frame overbudget, had: {} took: {}us", frame_budget_micros, frame_time),
+                }
             }
-        }
-        _ => defmt::unreachable!()
-    };
 
-    let mut disp_reset = pins.disp_reset;
-    disp_reset.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+            // spend whatever is left of the frame budget polling the accelerometer and USB. we
+            // poll at least once per frame even if we're over budget so that input still works on
+            // slow animations.
+            let mut frame_time = frame_time;
+            loop {
+                let now = timer.get_counter_low();
+                let mut transition = None;
+                let mut action = None;
 
-    disp_reset.set_low().unwrap();
-    delay.delay_us(120);
-    disp_reset.set_high().unwrap();
-    delay.delay_ms(100);
-    // disp_reset.set_low().unwrap();
-    // delay.delay_us(10);
-    // disp_reset.set_high().unwrap();
-    // delay.delay_ms(100);
+                backlight.update(millis(&timer));
+                fault::feed();
 
-    shared_spi.borrow_mut().set_baudrate(clocks.peripheral_clock.freq(), 400.kHz());
+                // the computer gets the card as soon as it's ready for it
+                if usb.poll() {
+                    break 'playback Ok(last_action);
+                }
 
-    pins.disp_cs_main.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
-    pins.disp_data_cmd.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
-    let disp_cs_main = pins.disp_cs_main;
-    let disp_data_cmd = pins.disp_data_cmd;
-    let disp_vsync = pins.disp_vsync;
+                let mut request = usb.control().poll();
+                // the console asks for the same things, and is told the answers instead
+                let mut from_console = false;
+                if request.is_none() {
+                    match shell.poll() {
+                        Some(console::Command::Request(console_request)) => {
+                            request = Some(console_request);
+                            from_console = true;
+                        }
+                        Some(console::Command::Status) => {
+                            console::log!(
+                                "playing {}, {} of {}, frame {}{}{}",
+                                file_names[file_idx],
+                                file_idx + 1,
+                                file_names.len(),
+                                frame,
+                                if paused { ", paused" } else { "" },
+                                if power.is_asleep() { ", asleep" } else { "" },
+                            );
+                            console::log!(
+                                "brightness {}%, battery {:?}, {}mV{}",
+                                target_brightness(brightness, &power, &battery_policy),
+                                battery_policy.level(),
+                                battery.millivolts().unwrap_or(0),
+                                if battery.is_charging() { ", charging" } else { "" },
+                            );
+                            if let Some(rule) = playlist.rule() {
+                                console::log!("schedule: {}", rule);
+                            }
+                            console::log!("up {}s", millis(&timer) / 1_000);
+                        }
+                        None => (),
+                    }
+                }
 
-    let display_spi = RefCellDevice::new(&shared_spi, disp_cs_main, timer.clone());
-    let display_interface = SPIInterface::new(display_spi, disp_data_cmd);
-    let mut options = mipidsi::ModelOptions::with_sizes((240, 240), (240, 240));
-    options.set_invert_colors(mipidsi::ColorInversion::Inverted);
-    let mut display = mipidsi::Builder::new(display_interface, mipidsi::models::ST7789, options)
-        .init(&mut timer, None::<DispReset>)
-        .unwrap();
-    display.set_tearing_effect(mipidsi::TearingEffect::Vertical).unwrap();
-    display.set_frame_rate(display_frame_rate, Default::default()).unwrap();
+                if let Some(request) = request {
+                    #[cfg(feature = "probe")]
+                    defmt::info!("request: {}", request);
+                    let mut listing: heapless::String<{ decoder::MAX_FILES * 13 }> = heapless::String::new();
+                    let response = match request {
+                        // answered once it's been shown, out of the way of playback
+                        proto::Request::StreamFrame { size, rect, packed, len } => {
+                            interruption = Some(Interruption::Stream(StreamedFrame { size, rect, packed, len }));
+                            break 'playback Ok(last_action);
+                        }
+                        // the rest of a frame that was turned down
+                        proto::Request::StreamData { .. } => continue,
+                        proto::Request::StreamEnd => proto::Response::Ok,
+                        proto::Request::Version => proto::Response::Version { version: env!("CARGO_PKG_VERSION") },
+                        proto::Request::Battery => proto::Response::Battery {
+                            millivolts: battery.millivolts(),
+                            charging: battery.is_charging(),
+                        },
+                        proto::Request::ListFiles => {
+                            for name in &file_names {
+                                writeln!(&mut listing, "{}", name).unwrap();
+                            }
+                            proto::Response::Files { names: listing.trim_end() }
+                        }
+                        proto::Request::Play { name } => {
+                            match file_names.iter().position(|file_name| file_name.eq_ignore_ascii_case(name)) {
+                                Some(file_idx) => {
+                                    interruption = Some(Interruption::Play { file_idx });
+                                    proto::Response::Ok
+                                }
+                                None => proto::Response::Failed(proto::Failure::NotFound),
+                            }
+                        }
+                        proto::Request::SetBrightness { percent } => {
+                            brightness = percent.clamp(1, 100);
+                            if !power.is_asleep() && frame > 1 {
+                                let target = target_brightness(brightness, &power, &battery_policy);
+                                backlight.fade_to(target, FADE_MILLIS, millis(&timer));
+                            }
+                            proto::Response::Ok
+                        }
+                        // core 1 hasn't got the card
+                        proto::Request::UploadStart { .. } if own_card.is_some() => {
+                            proto::Response::Failed(proto::Failure::CardError)
+                        }
+                        proto::Request::UploadStart { name, len } => {
+                            let mut file_name = playlist::FileName::new();
+                            // it's no longer than an 8.3 name, or it wouldn't have been decoded
+                            file_name.push_str(name).unwrap();
+                            interruption = Some(Interruption::Upload { name: file_name, len });
+                            proto::Response::Ok
+                        }
+                        proto::Request::UploadData { .. } => proto::Response::Failed(proto::Failure::BadRequest),
+                        proto::Request::Reboot => {
+                            interruption = Some(Interruption::Reboot);
+                            proto::Response::Ok
+                        }
+                    };
+                    match from_console {
+                        true => console::show_response(&response),
+                        false => usb.control().respond(&response),
+                    }
+                    if interruption.is_some() {
+                        break 'playback Ok(last_action);
+                    }
+                }
 
-    let _baud = shared_spi.borrow_mut().set_baudrate(clocks.peripheral_clock.freq(), DISP_BAUDRATE);
-    #[cfg(feature = "probe")]
-    defmt::info!("set spi baud: {}", _baud);
+                // the time changes whether or not frames are being shown
+                if watch.face() != ClockFace::Off && !power.is_asleep() {
+                    let sent = watch.send(&display_bus, clock::now(&rtc)).unwrap();
+                    // there are no frames to fade in with when it's shown instead of them
+                    if sent && watch.face() == ClockFace::Instead && frame == 0 {
+                        let target = target_brightness(brightness, &power, &battery_policy);
+                        backlight.fade_to(target, FADE_MILLIS, millis(&timer));
+                    }
+                }
 
-    let frame_budget_micros: u32 = (1_000_000 / header.frame_rate.0 as u32) - 200;
+                if ready.is_none() {
+                    match decoder.try_recv() {
+                        Some(Response::Decoded { slot, dirty }) => ready = Some((slot, dirty)),
+                        Some(Response::Failed { slot, failure }) => {
+                            idle_slots.extend(slot);
+                            break 'playback Err(failure);
+                        }
+                        Some(_) => defmt::unreachable!(),
+                        None => (),
+                    }
+                }
 
-    let mut frame: u32 = 1; // because we already loaded the first frame.
-    loop {
-        let start_time = timer.get_counter_low();
+                // sleep is driven by motion, so without an accelerometer we just never go to sleep
+                if let Some(accel) = accel.as_mut() {
+                    if now.wrapping_sub(last_accel_poll) >= ACCEL_POLL_MICROS {
+                        last_accel_poll = now;
+                        let now_millis = millis(&timer);
+                        let sample = accel.sample().ok();
+                        let events = accel.events().ok();
+                        let was_asleep = power.is_asleep();
+                        transition = transition.or(power.update(now_millis, sample, events));
 
-        // we want to write starting *during* the time the controller driver is updating the lcd
-        // from its internal memory, but *behind* the current place it's reading from its internal
-        // memory. in this way we basically get two display-frames to update the display's memory.
-        while !disp_vsync.is_high().unwrap() {}
-        while disp_vsync.is_high().unwrap() {};
-        delay.delay_us(300);
+                        if let Some(orientation) = sample.and_then(|s| auto_rotate.update(now_millis, s)) {
+                            #[cfg(feature = "probe")]
+                            defmt::info!("orientation: {}", orientation);
+                            display_bus.set_orientation(orientation).unwrap();
+                            redraw = true;
+                            watch.invalidate();
+                        }
 
-        #[cfg(feature = "probe")]
-        let draw_start = timer.get_counter_low();
+                        // taps while asleep are for waking up, not for controlling the player
+                        if !was_asleep && !power.is_asleep() {
+                            let mut gesture = None;
+                            if let Some(s) = sample {
+                                gesture = gestures.feed(now_millis, s);
+                            }
+                            if events.is_some_and(|e| e.click.is_some()) {
+                                gesture = gesture.or(gestures.tap(now_millis));
+                            }
+                            if let Some(gesture) = gesture {
+                                #[cfg(feature = "probe")]
+                                defmt::info!("gesture: {}", gesture);
+                                action = action.or(input::action_for(&config.gestures, gesture));
+                            }
+                        } else {
+                            gestures.reset();
+                        }
+                    }
+                }
+
+                if let Some(change_every_millis) = change_every_millis {
+                    let playing = !paused && !power.is_asleep() && watch.face() != ClockFace::Instead;
+                    if playing && millis(&timer).wrapping_sub(playing_since) >= change_every_millis {
+                        action = action.or(Some(change_action));
+                    }
+                }
+
+                if now.wrapping_sub(last_schedule_poll) >= SCHEDULE_POLL_MICROS {
+                    last_schedule_poll = now;
+                    if playlist.update(clock::now(&rtc)) {
+                        #[cfg(feature = "probe")]
+                        defmt::info!("schedule: {}", playlist.rule());
+                        if let Some(rule) = playlist.rule() {
+                            console::log!("schedule: {}", rule);
+                        }
+                        // until the next change, the wearer can still pick another brightness
+                        brightness = playlist.brightness_percent().unwrap_or(config.brightness_percent);
+                        if !power.is_asleep() && frame > 1 {
+                            let target = target_brightness(brightness, &power, &battery_policy);
+                            backlight.fade_to(target, FADE_MILLIS, millis(&timer));
+                        }
+                        if !playlist.contains(file_idx) {
+                            action = action.or(Some(change_action));
+                        }
+                    }
+                }
+
+                if now.wrapping_sub(last_battery_poll) >= BATTERY_POLL_MICROS {
+                    last_battery_poll = now;
+                    let millivolts = battery.millivolts();
+                    let charging = battery.is_charging();
+                    #[cfg(feature = "probe")]
+                    defmt::info!("battery: {}mV, charging: {}", millivolts, charging);
+
+                    match battery_policy.update(millivolts, charging) {
+                        // without the battery measured we never get here
+                        #[cfg(feature = "battery-sense")]
+                        Some(battery::BatteryLevel::Shutdown) => {
+                            #[cfg(feature = "probe")]
+                            defmt::warn!("battery flat, shutting down");
+                            console::log!("battery flat, shutting down");
+                            backlight.set_brightness(0);
+                            display.sleep(&mut timer).unwrap();
+                            if let Some(accel) = accel.as_mut() {
+                                let _ = accel.power_down();
+                            }
+                            fault::note(fault::Activity::Restarting);
+                            bsp::battery::shutdown_until_charging();
+                        }
+                        Some(level) => {
+                            #[cfg(feature = "probe")]
+                            defmt::info!("battery level: {}", level);
+                            console::log!("battery level: {:?}", level);
+                            // before the animation has faded in, leave it to do so at the new brightness
+                            if !power.is_asleep() && frame > 1 {
+                                let target = target_brightness(brightness, &power, &battery_policy);
+                                backlight.fade_to(target, FADE_MILLIS, millis(&timer));
+                            }
+                        }
+                        None => (),
+                    }
+                }
+
+                if let Some(action) = action {
+                    #[cfg(feature = "probe")]
+                    defmt::info!("action: {}", action);
+                    console::log!("action: {:?}", action);
+
+                    match action {
+                        input::Action::TogglePause => {
+                            paused = !paused;
+                            if !paused {
+                                playing_since = millis(&timer);
+                            }
+                        }
+                        input::Action::Next | input::Action::Previous | input::Action::Shuffle => {
+                            break 'playback Ok(action)
+                        }
+                    }
+                }
+
+                match transition {
+                    Some(luluu_power::PowerTransition::Dim) => {
+                        #[cfg(feature = "probe")]
+                        defmt::info!("dimming");
+                        let target = target_brightness(brightness, &power, &battery_policy);
+                        backlight.fade_to(target, DIM_FADE_MILLIS, millis(&timer));
+                    }
+                    Some(luluu_power::PowerTransition::Sleep) => {
+                        #[cfg(feature = "probe")]
+                        defmt::info!("going to sleep");
+                        console::log!("going to sleep");
+                        fade_and_wait(&mut backlight, 0, FADE_MILLIS, &timer);
+                        display.sleep(&mut timer).unwrap();
+                        display_asleep = true;
+                    }
+                    Some(luluu_power::PowerTransition::Wake) => {
+                        #[cfg(feature = "probe")]
+                        defmt::info!("waking up");
+                        console::log!("waking up");
+                        if display_asleep {
+                            display.wake(&mut timer).unwrap();
+                            display_asleep = false;
+                        }
+                        let target = target_brightness(brightness, &power, &battery_policy);
+                        backlight.fade_to(target, FADE_MILLIS, millis(&timer));
+                    }
+                    None => (),
+                }
 
-        display.set_pixels_565be(0, 0, 240, 240, fb.as_bytes()).unwrap();
-        if frame == 2 {
-            disp_backlight.set_high().unwrap();
+                if frame_time >= frame_budget_micros {
+                    break;
+                }
+                frame_time = timer.get_counter_low().wrapping_sub(start_time);
+            }
+        };
+
+        // fade out so the switch to the next animation isn't seen, it fades back in once the new
+        // animation's first frames are on the display
+        fade_and_wait(&mut backlight, 0, FADE_MILLIS, &timer);
+
+        // take back both framebuffers before switching, core 1 answers in order so these are all
+        // for the animation we're leaving
+        idle_slots.extend(ready.take().map(|(slot, _)| slot));
+        while !idle_slots.is_full() {
+            match decoder.recv() {
+                Response::Decoded { slot, .. } | Response::Failed { slot: Some(slot), .. } => {
+                    idle_slots.push(slot).ok().unwrap()
+                }
+                _ => defmt::unreachable!(),
+            }
         }
 
-        #[cfg(feature = "probe")]
-        if frame % 32 == 0 {
-            let draw_end = timer.get_counter_low();
-            defmt::info!("draw took: {}us", draw_end - draw_start);
+        paused = false;
+        match result {
+            Ok(action) => last_action = action,
+            Err(failure) => {
+                #[cfg(feature = "probe")]
+                defmt::warn!("can't play {}: {}", file_names[file_idx].as_str(), failure);
+                let target = target_brightness(brightness, &power, &battery_policy);
+                report_failure(&display_bus, &mut idle_slots[0], &mut backlight, &timer, failure, &file_names[file_idx], target);
+            }
         }
 
-        shared_spi.borrow_mut().set_baudrate(clocks.peripheral_clock.freq(), SD_BAUDRATE);
+        if usb.wants_card() {
+            #[cfg(feature = "probe")]
+            defmt::info!("lending the card to USB");
+            // being plugged in counts as activity, so the wearer can see what's going on
+            if display_asleep {
+                display.wake(&mut timer).unwrap();
+                display_asleep = false;
+            }
+            power.note_activity(millis(&timer));
+            let target = target_brightness(brightness, &power, &battery_policy);
+            if let Some(volume_mgr) = own_card.as_mut() {
+                // start again with whatever the computer puts on the card
+                show_problem(&display_bus, &mut idle_slots[0], &mut backlight, &timer, status::Problem::UsbDrive, target);
+                if let Ok(mut card) = usb_storage::OwnCard::new(volume_mgr.device(), &mut idle_slots[1]) {
+                    fault::note(fault::Activity::LendingCard);
+                    usb.serve(&mut card);
+                }
+                fault::note(fault::Activity::Restarting);
+                SCB::sys_reset();
+            }
+            file_names = lend_card(&mut decoder, &mut usb, &mut idle_slots, &display_bus, &mut backlight, &timer, target);
+            power.note_activity(millis(&timer));
+            // whatever's on the card now, it starts again from the beginning
+            playlist = playlist::Playlist::new(&config.schedule, &file_names, clock::now(&rtc));
+            file_idx = playlist.first(config.order, &mut rosc);
+            // anything asked for over serial beforehand is moot
+            interruption = None;
+            continue;
+        }
 
-        #[cfg(feature = "probe")]
-        let read_start = timer.get_counter_low();
+        match interruption.take() {
+            Some(Interruption::Play { file_idx: idx }) => {
+                file_idx = idx;
+                continue;
+            }
+            Some(Interruption::Upload { name, len }) => {
+                let playing = file_names[file_idx].clone();
+                let target = target_brightness(brightness, &power, &battery_policy);
+                match receive_upload(&mut decoder, &mut usb, &mut idle_slots, &mut backlight, &timer, &name, len) {
+                    Err(Failure::CardError) => {
+                        report_failure(&display_bus, &mut idle_slots[0], &mut backlight, &timer, Failure::CardError, &name, target);
+                    }
+                    // the computer's been told, and there's nothing to show for it
+                    _ => (),
+                }
+                file_names = list_files(&mut decoder, &mut idle_slots, &display_bus, &mut backlight, &timer, target);
+                if file_names.is_empty() {
+                    file_names = lend_card(&mut decoder, &mut usb, &mut idle_slots, &display_bus, &mut backlight, &timer, target);
+                }
+                power.note_activity(millis(&timer));
+                playlist = playlist::Playlist::new(&config.schedule, &file_names, clock::now(&rtc));
+                // show off the new animation if that's what it was, otherwise carry on where it was
+                let position = |wanted: &str| file_names.iter().position(|file_name| file_name.eq_ignore_ascii_case(wanted));
+                file_idx = match position(&name).or_else(|| position(&playing)) {
+                    Some(idx) => idx,
+                    None => playlist.first(config.order, &mut rosc),
+                };
+                continue;
+            }
+            Some(Interruption::Stream(first)) => {
+                let target = target_brightness(brightness, &power, &battery_policy);
+                show_stream(&mut usb, &mut idle_slots[0], &display_bus, &mut backlight, &timer, target, first);
+                power.note_activity(millis(&timer));
+                // the animation it interrupted starts again
+                continue;
+            }
+            Some(Interruption::Reboot) => {
+                wait_millis_with_usb(&mut usb, &mut backlight, LAST_ANSWER_MILLIS, &timer);
+                fault::note(fault::Activity::Restarting);
+                SCB::sys_reset();
+            }
+            None => (),
+        }
 
-        if frame % header.n_frames.as_u16() as u32 == 0 {
-            img_file.seek_from_start(bsp::luluu_enc::HEADER_SIZE as u32).unwrap();
+        // a card with animations on it takes over from flash, starting again with it
+        if let Some(volume_mgr) = own_card.as_mut() {
+            volume_mgr.device().mark_card_uninit();
+            if read_card(volume_mgr, &mut rtc, &mut last_fault).is_ok_and(|(_, dir_entries)| !dir_entries.is_empty()) {
+                #[cfg(feature = "probe")]
+                defmt::info!("found animations on the card");
+                console::log!("found animations on the card, restarting");
+                fault::note(fault::Activity::Restarting);
+                SCB::sys_reset();
+            }
         }
+        file_idx = playlist.next(file_idx, last_action, &mut rosc);
+    }
+}
 
-        match header.size.0 {
-            60 => read_60px_frame_into_main_fb(&mut img_file, &mut *file_read_buffer, &mut *fb),
-            120 => read_120px_frame_into_main_fb(&mut img_file, &mut *file_read_buffer, &mut *fb),
-            240 => read_240px_frame_into_main_fb(&mut img_file, &mut *file_read_buffer, &mut *fb),
-            _ => defmt::unreachable!(),
+/// Read the settings and list the animations on the card, setting the card up first if it needs it.
+/// Sets the clock too, if there's a time file, and logs `last_fault` if there is one.
+fn read_card(
+    volume_mgr: &mut decoder::VolumeManager,
+    rtc: &mut bsp::rtc::Rtc,
+    last_fault: &mut Option<fault::Record>,
+) -> Result<(Config, decoder::DirEntries), embedded_sdmmc::Error<embedded_sdmmc::SdCardError>> {
+    let mut volume0 = volume_mgr.open_volume(VolumeIdx(0))?;
+    let mut root_dir = volume0.open_root_dir()?;
+    let config = settings::load(&mut root_dir);
+    clock::set_from_file(&mut root_dir, rtc);
+    if let Some(record) = last_fault {
+        // a card that can be read but not written to still gets played
+        match fault::save(&mut root_dir, record, clock::now(rtc)) {
+            Ok(()) => *last_fault = None,
+            Err(_) => {
+                #[cfg(feature = "probe")]
+                defmt::warn!("couldn't add the last fault to {}", fault::LOG_FILE_NAME);
+            }
+        }
+    }
+    let dir_entries = decoder::list_animations(&mut root_dir)?;
+    Ok((config, dir_entries))
+}
+
+/// Install the firmware update on the card if it's newer than this, which restarts into it, or
+/// put it away if it's been installed. A bad one is shown, and left for the wearer to replace. Both
+/// framebuffers have to be in `idle_slots`, and core 1 can't have been started yet.
+fn update_firmware(
+    volume_mgr: &mut decoder::VolumeManager,
+    display_bus: &render::DisplayBus<'_>,
+    idle_slots: &mut heapless::Vec<FrameSlot, 2>,
+    backlight: &mut bsp::backlight::Backlight,
+    timer: &hal::Timer,
+    brightness: u8,
+) {
+    let Ok(mut volume0) = volume_mgr.open_volume(VolumeIdx(0)) else {
+        return;
+    };
+    let Ok(mut root_dir) = volume0.open_root_dir() else {
+        return;
+    };
+    let result = match update::find(&mut root_dir) {
+        Ok(update::Found::Nothing) => return,
+        Ok(update::Found::NotNewer(version)) => {
+            console::log!("firmware {} on the card is installed or older, putting it away", version);
+            update::put_away(&mut root_dir, idle_slots[1].as_bytes_mut())
+        }
+        Ok(update::Found::Newer(header)) => {
+            console::log!("installing firmware {}", header.version);
+            show_problem(display_bus, &mut idle_slots[0], backlight, timer, status::Problem::Updating, brightness);
+            wait_millis(backlight, FADE_MILLIS, timer);
+            match update::stage(&mut root_dir, &header, idle_slots[1].as_bytes_mut()) {
+                Ok(()) => update::install(&header),
+                Err(failure) => Err(failure),
+            }
+        }
+        Err(failure) => Err(failure),
+    };
+    match result {
+        Ok(()) => (),
+        Err(update::Failure::Image(error)) => {
+            #[cfg(feature = "probe")]
+            defmt::warn!("{}: {}", luluu_update::FILE_NAME, error);
+            console::log!("{}: {}", luluu_update::FILE_NAME, error);
+            show_problem(display_bus, &mut idle_slots[0], backlight, timer, status::Problem::BadUpdate, brightness);
+            wait_millis(backlight, FAILURE_MILLIS, timer);
+            fade_and_wait(backlight, 0, FADE_MILLIS, timer);
+        }
+        // the card not working is found out again when playing from it
+        Err(update::Failure::Card(_)) => console::log!("couldn't read the firmware update on the card"),
+    }
+}
+
+/// Replace the animations in flash with the `picked` ones from the card, going through `buffer`.
+/// Core 1 can't have been started yet, see [`cache::update`]. The cache is only a copy, so if the
+/// card fails it's left for playing to find out.
+fn update_cache(volume_mgr: &mut decoder::VolumeManager, picked: &[embedded_sdmmc::DirEntry], buffer: &mut FrameSlot) {
+    let result = volume_mgr.open_volume(VolumeIdx(0)).and_then(|mut volume0| {
+        let mut root_dir = volume0.open_root_dir()?;
+        cache::update(&mut root_dir, picked, buffer.as_bytes_mut())
+    });
+    match result {
+        Ok(()) => console::log!("cached {} animations", picked.len()),
+        Err(_) => {
+            #[cfg(feature = "probe")]
+            defmt::warn!("couldn't copy animations into flash");
+            console::log!("couldn't copy animations into flash");
+        }
+    }
+}
+
+/// Lend the card to a computer over USB until it's done with it, then list the animations on it
+/// again, waiting for some to be put there if there aren't any. Both framebuffers have to be in
+/// `idle_slots`. The settings and the time file aren't read again, only at power on.
+fn lend_card(
+    decoder: &mut DecoderLink,
+    usb: &mut usb::Usb,
+    idle_slots: &mut heapless::Vec<FrameSlot, 2>,
+    display_bus: &render::DisplayBus<'_>,
+    backlight: &mut bsp::backlight::Backlight,
+    timer: &hal::Timer,
+    brightness: u8,
+) -> FileNames {
+    loop {
+        if usb.wants_card() {
+            fault::note(fault::Activity::LendingCard);
+            show_problem(display_bus, &mut idle_slots[0], backlight, timer, status::Problem::UsbDrive, brightness);
+            // the display has its own copy of the message, so either framebuffer will do
+            let slot = idle_slots.pop().unwrap();
+            let slot = match usb_storage::LentCard::lend(decoder, slot) {
+                Ok(mut card) => {
+                    usb.serve(&mut card);
+                    card.into_slot()
+                }
+                // listing fails too, and that's reported below
+                Err(slot) => slot,
+            };
+            idle_slots.push(slot).ok().unwrap();
+        }
+
+        let file_names = list_files(decoder, idle_slots, display_bus, backlight, timer, brightness);
+        if !file_names.is_empty() {
+            fade_and_wait(backlight, 0, FADE_MILLIS, timer);
+            return file_names;
         }
 
         #[cfg(feature = "probe")]
-        if (frame + 2) % 32 == 0 {
-            let read_end = timer.get_counter_low();
-            defmt::info!("modify took: {}us", read_end - read_start);
+        defmt::warn!("nothing to play after lending out the card");
+        show_problem(display_bus, &mut idle_slots[0], backlight, timer, status::Problem::NoAnimations, brightness);
+        wait_millis_with_usb(usb, backlight, CARD_POLL_MILLIS, timer);
+    }
+}
+
+/// List the animations on the card again, now it's changed. Both framebuffers have to be in
+/// `idle_slots`. The card not working restarts everything, like when playing, and any other failure
+/// is shown and then there's nothing listed.
+fn list_files(
+    decoder: &mut DecoderLink,
+    idle_slots: &mut heapless::Vec<FrameSlot, 2>,
+    display_bus: &render::DisplayBus<'_>,
+    backlight: &mut bsp::backlight::Backlight,
+    timer: &hal::Timer,
+    brightness: u8,
+) -> FileNames {
+    let slot = idle_slots.pop().unwrap();
+    decoder.send(Request::List { slot });
+    match decoder.recv() {
+        Response::Listed { slot, n_files } => {
+            let file_names = (0..n_files)
+                .map(|idx| {
+                    let mut name = heapless::String::new();
+                    name.push_str(pipeline::listed_name(&slot, idx)).unwrap();
+                    name
+                })
+                .collect();
+            idle_slots.push(slot).ok().unwrap();
+            file_names
+        }
+        Response::Failed { slot, failure } => {
+            idle_slots.extend(slot);
+            // only returns if it wasn't the card
+            report_failure(display_bus, &mut idle_slots[0], backlight, timer, failure, "", brightness);
+            FileNames::new()
         }
+        _ => defmt::unreachable!(),
+    }
+}
 
-        let _= shared_spi.borrow_mut().set_baudrate(clocks.peripheral_clock.freq(), DISP_BAUDRATE);
+/// Receive the `len` byte file called `name` that the computer's started uploading over USB serial,
+/// and write it to the card a framebuffer-full at a time. Each part's answered once it's safely on
+/// the card. It gives up if the computer goes quiet, leaving the file cut short. Both framebuffers
+/// have to be in `idle_slots`.
+fn receive_upload(
+    decoder: &mut DecoderLink,
+    usb: &mut usb::Usb,
+    idle_slots: &mut heapless::Vec<FrameSlot, 2>,
+    backlight: &mut bsp::backlight::Backlight,
+    timer: &hal::Timer,
+    name: &str,
+    len: u32,
+) -> Result<(), Failure> {
+    #[cfg(feature = "probe")]
+    defmt::info!("receiving {}, {} bytes", name, len);
+    console::log!("receiving {} over USB, {} bytes", name, len);
+    fault::note(fault::Activity::Receiving { file_name: name });
+    let mut slot = idle_slots.pop().unwrap();
+    pipeline::set_file_name(&mut slot, name);
+    // in whole blocks, so they don't have to be read back to be written
+    let capacity = pipeline::file_bytes(&mut slot).len() / 512 * 512;
+    let len = len as usize;
+    let mut received = 0;
+    // bytes in the slot that aren't in the file yet
+    let mut buffered = 0;
+    let mut append = false;
+    let mut result = Ok(());
 
-        let current_time = timer.get_counter_low();
-        let frame_time = current_time - start_time;
-        if let Some(micros_left) = frame_budget_micros.checked_sub(frame_time) {
+    // there's nothing to wait for
+    if len == 0 {
+        (slot, result) = write_file(decoder, slot, 0, false);
+    }
+    let mut last_heard = millis(timer);
+    while result.is_ok() && received < len {
+        fault::feed();
+        usb.poll();
+        if millis(timer).wrapping_sub(last_heard) >= UPLOAD_TIMEOUT_MILLIS {
             #[cfg(feature = "probe")]
-            if (frame + 4) % 32 == 0 {
-                defmt::info!("waiting for frame: {}us", micros_left);
+            defmt::warn!("upload stopped after {} bytes", received);
+            console::log!("upload stopped after {} bytes", received);
+            break;
+        }
+        let Some(request) = usb.control().poll() else {
+            continue;
+        };
+        last_heard = millis(timer);
+        let proto::Request::UploadData { bytes } = request else {
+            usb.control().respond(&proto::Response::Failed(proto::Failure::Busy));
+            continue;
+        };
+        if received + bytes.len() > len {
+            usb.control().respond(&proto::Response::Failed(proto::Failure::BadRequest));
+            continue;
+        }
+
+        if buffered + bytes.len() > capacity {
+            (slot, result) = write_file(decoder, slot, buffered, append);
+            append = true;
+            buffered = 0;
+        }
+        pipeline::file_bytes(&mut slot)[buffered..][..bytes.len()].copy_from_slice(bytes);
+        buffered += bytes.len();
+        received += bytes.len();
+        if result.is_ok() && received == len {
+            (slot, result) = write_file(decoder, slot, buffered, append);
+        }
+
+        usb.control().respond(&match result {
+            Ok(()) => proto::Response::Ok,
+            Err(Failure::BadFile) => proto::Response::Failed(proto::Failure::BadName),
+            Err(Failure::CardError) => proto::Response::Failed(proto::Failure::CardError),
+        });
+    }
+    idle_slots.push(slot).ok().unwrap();
+
+    if result == Err(Failure::CardError) {
+        // the card's about to be reported, which restarts everything
+        wait_millis_with_usb(usb, backlight, LAST_ANSWER_MILLIS, timer);
+    }
+    result
+}
+
+/// Show frames streamed from the computer over USB serial, starting with `first`, until it says
+/// it's done, stops sending them for a while, or wants the card. They're received straight into
+/// `fb`, which keeps the last one so only what's changed needs sending.
+fn show_stream(
+    usb: &mut usb::Usb,
+    fb: &mut FrameSlot,
+    display_bus: &render::DisplayBus<'_>,
+    backlight: &mut bsp::backlight::Backlight,
+    timer: &hal::Timer,
+    brightness: u8,
+    first: StreamedFrame,
+) {
+    #[cfg(feature = "probe")]
+    defmt::info!("streaming from USB");
+    console::log!("streaming from USB");
+    fault::note(fault::Activity::Streaming);
+    // whatever's left in it isn't part of the stream
+    fb.pixels_mut().fill(bsp::Rgb565BE::ZERO);
+    let mut next = Some(first);
+    let mut n_shown: u32 = 0;
+    let mut last_heard = millis(timer);
+    loop {
+        if let Some(frame) = next.take() {
+            let result = receive_frame(usb, fb, timer, frame);
+            if result.is_ok() {
+                render::send_frame(display_bus, fb, frame.size, frame.rect).unwrap();
+                if n_shown == 0 {
+                    backlight.fade_to(brightness, FADE_MILLIS, millis(timer));
+                }
+                n_shown += 1;
             }
-            delay.delay_us(micros_left);
-        } else {
-            #[cfg(feature = "probe")]
-            if (frame + 4) % 32 == 0 {
-                defmt::info!("frame overbudget, had: {} took: {}us", frame_budget_micros, frame_time);
+            usb.control().respond(&match result {
+                Ok(()) => proto::Response::Ok,
+                Err(failure) => proto::Response::Failed(failure),
+            });
+            last_heard = millis(timer);
+        }
+
+        backlight.update(millis(timer));
+        fault::feed();
+        if usb.poll() || millis(timer).wrapping_sub(last_heard) >= STREAM_TIMEOUT_MILLIS {
+            break;
+        }
+        let Some(request) = usb.control().poll() else {
+            continue;
+        };
+        last_heard = millis(timer);
+        match request {
+            proto::Request::StreamFrame { size, rect, packed, len } => {
+                next = Some(StreamedFrame { size, rect, packed, len });
+            }
+            proto::Request::StreamEnd => {
+                usb.control().respond(&proto::Response::Ok);
+                break;
             }
+            proto::Request::StreamData { .. } => (),
+            _ => usb.control().respond(&proto::Response::Failed(proto::Failure::Busy)),
         }
+    }
+    #[cfg(feature = "probe")]
+    defmt::info!("streamed {} frames", n_shown);
+    console::log!("streamed {} frames", n_shown);
+    fade_and_wait(backlight, 0, FADE_MILLIS, timer);
+}
+
+/// Receive the pixels of `frame` into `fb`. A frame that doesn't fit is still received, so the
+/// next one starts in the right place, and then turned down.
+fn receive_frame(usb: &mut usb::Usb, fb: &mut FrameSlot, timer: &hal::Timer, frame: StreamedFrame) -> Result<(), proto::Failure> {
+    let mut unpacker = proto::stream::Unpacker::new(frame.size, frame.rect, frame.packed).ok();
+    let len = frame.len as usize;
+    let mut received = 0;
+    let mut last_heard = millis(timer);
+    while received < len {
+        fault::feed();
+        usb.poll();
+        if millis(timer).wrapping_sub(last_heard) >= STREAM_TIMEOUT_MILLIS {
+            return Err(proto::Failure::BadRequest);
+        }
+        let Some(request) = usb.control().poll() else {
+            continue;
+        };
+        last_heard = millis(timer);
+        let proto::Request::StreamData { bytes } = request else {
+            usb.control().respond(&proto::Response::Failed(proto::Failure::Busy));
+            continue;
+        };
+        received += bytes.len();
+        if unpacker.as_mut().is_some_and(|unpacker| unpacker.push(bytes, fb.pixels_mut()).is_err()) {
+            unpacker = None;
+        }
+    }
+    match unpacker {
+        Some(unpacker) if unpacker.is_done() => Ok(()),
+        _ => Err(proto::Failure::BadRequest),
+    }
+}
+
+/// Have core 1 write the first `len` bytes in `slot` to the file named in it, then hand it back.
+fn write_file(decoder: &mut DecoderLink, slot: FrameSlot, len: usize, append: bool) -> (FrameSlot, Result<(), Failure>) {
+    decoder.send(Request::WriteFile { slot, len, append });
+    match decoder.recv() {
+        Response::Written { slot } => (slot, Ok(())),
+        Response::Failed { slot: Some(slot), failure } => (slot, Err(failure)),
+        _ => defmt::unreachable!(),
+    }
+}
+
+/// Put `problem` on the display, drawn in `fb`, and bring the backlight up to `brightness` so it can
+/// be read.
+fn show_problem(
+    display_bus: &render::DisplayBus<'_>,
+    fb: &mut FrameSlot,
+    backlight: &mut bsp::backlight::Backlight,
+    timer: &hal::Timer,
+    problem: status::Problem<'_>,
+    brightness: u8,
+) {
+    console::log!("{}", problem);
+    status::show(display_bus, fb, problem).unwrap();
+    backlight.fade_to(brightness, FADE_MILLIS, millis(timer));
+}
+
+/// Tell the wearer that the animation in `file_name` couldn't be played, and give them time to read
+/// it. The card not working restarts everything, which waits for one that does.
+fn report_failure(
+    display_bus: &render::DisplayBus<'_>,
+    fb: &mut FrameSlot,
+    backlight: &mut bsp::backlight::Backlight,
+    timer: &hal::Timer,
+    failure: Failure,
+    file_name: &str,
+    brightness: u8,
+) {
+    let problem = match failure {
+        Failure::BadFile => status::Problem::BadFile { name: file_name },
+        Failure::CardError => status::Problem::CardError,
+    };
+    show_problem(display_bus, fb, backlight, timer, problem, brightness);
+    wait_millis(backlight, FAILURE_MILLIS, timer);
+    if failure == Failure::CardError {
+        fault::note(fault::Activity::Restarting);
+        SCB::sys_reset();
+    }
+    fade_and_wait(backlight, 0, FADE_MILLIS, timer);
+}
+
+/// Brightness the backlight should be at: the wearer's chosen `level` in percent, limited by the
+/// power and battery policies.
+fn target_brightness(level: u8, power: &luluu_power::PowerPolicy, battery: &battery::BatteryPolicy) -> u8 {
+    if power.is_asleep() {
+        return 0;
+    }
+    let mut brightness = level.min(battery.level().max_brightness_percent());
+    if power.is_dimmed() {
+        brightness = brightness.min(power.config().dim_brightness_percent);
+    }
+    brightness
+}
 
-        frame += 1;
+/// Fade the backlight to `percent` and wait for the fade to finish.
+fn fade_and_wait(backlight: &mut bsp::backlight::Backlight, percent: u8, duration_millis: u32, timer: &hal::Timer) {
+    backlight.fade_to(percent, duration_millis, millis(timer));
+    while backlight.is_fading() {
+        backlight.update(millis(timer));
+        fault::feed();
     }
 }
+
+/// Wait for `duration_millis`, carrying on with any backlight fade meanwhile.
+fn wait_millis(backlight: &mut bsp::backlight::Backlight, duration_millis: u32, timer: &hal::Timer) {
+    let start = millis(timer);
+    while millis(timer).wrapping_sub(start) < duration_millis {
+        backlight.update(millis(timer));
+        fault::feed();
+    }
+}
+
+/// Wait for `duration_millis` like [`wait_millis`], keeping the USB connection going meanwhile and
+/// turning down requests over serial. Returns whether a computer wants the card by then.
+fn wait_millis_with_usb(
+    usb: &mut usb::Usb,
+    backlight: &mut bsp::backlight::Backlight,
+    duration_millis: u32,
+    timer: &hal::Timer,
+) -> bool {
+    let start = millis(timer);
+    while millis(timer).wrapping_sub(start) < duration_millis {
+        backlight.update(millis(timer));
+        fault::feed();
+        usb.poll();
+        usb.control().refuse(proto::Failure::Busy);
+    }
+    usb.wants_card()
+}
+
+/// Milliseconds since boot. Wraps after ~49 days.
+fn millis(timer: &hal::Timer) -> u32 {
+    (timer.get_counter().ticks() / 1_000) as u32
+}
@@ -0,0 +1,120 @@
+use luluu_bsp as bsp;
+
+use bsp::accel::Sample;
+use bsp::display::{Orientation, Rotation};
+
+/// Which ways up [`AutoRotate`] will turn the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "probe", derive(defmt::Format))]
+pub enum AutoRotateMode {
+    /// Always use the configured orientation.
+    Off,
+    /// Turn it upside down when the display is, like when the sleeve is moved to the other arm.
+    HalfTurns,
+    /// Keep the top of the image whichever edge of the display is highest.
+    QuarterTurns,
+}
+
+/// Tunables for [`AutoRotate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrientationConfig {
+    /// How the image is shown with the display upright, or all the time if `auto_rotate` is off.
+    ///
+    /// Upright is the accelerometer's +Y pointing up. If it's mounted some other way round from
+    /// the panel, rotating this makes up for it.
+    pub orientation: Orientation,
+    pub auto_rotate: AutoRotateMode,
+    /// Gravity along the display's X or Y axis needed to count it as being held that way up, so
+    /// nothing changes while it's lying flat.
+    pub auto_rotate_min_mg: i16,
+    /// How long the display has to be held a new way up before the image turns.
+    pub auto_rotate_hold_millis: u32,
+}
+
+impl OrientationConfig {
+    pub const DEFAULT: Self = Self {
+        orientation: Orientation::DEFAULT,
+        auto_rotate: AutoRotateMode::Off,
+        auto_rotate_min_mg: 600,
+        auto_rotate_hold_millis: 1_000,
+    };
+}
+
+impl Default for OrientationConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Picks the display's orientation, turning the configured one to follow gravity if auto rotation
+/// is on.
+pub struct AutoRotate {
+    config: OrientationConfig,
+    rotation: Rotation,
+    /// A different way up the display has been held since the given time.
+    candidate: Option<(Rotation, u32)>,
+}
+
+impl AutoRotate {
+    pub fn new(config: OrientationConfig) -> Self {
+        Self {
+            config,
+            rotation: Rotation::Deg0,
+            candidate: None,
+        }
+    }
+
+    /// The orientation the display should be in.
+    pub fn orientation(&self) -> Orientation {
+        self.config.orientation.rotated(self.rotation)
+    }
+
+    /// Feed the latest accelerometer sample. Returns the new orientation if it's changed.
+    pub fn update(&mut self, now_millis: u32, sample: Sample) -> Option<Orientation> {
+        let Some(rotation) = self.rotation_for(sample) else {
+            self.candidate = None;
+            return None;
+        };
+        if rotation == self.rotation {
+            self.candidate = None;
+            return None;
+        }
+
+        match self.candidate {
+            Some((candidate, since)) if candidate == rotation => {
+                if now_millis.wrapping_sub(since) < self.config.auto_rotate_hold_millis {
+                    return None;
+                }
+                self.rotation = rotation;
+                self.candidate = None;
+                Some(self.orientation())
+            }
+            _ => {
+                self.candidate = Some((rotation, now_millis));
+                None
+            }
+        }
+    }
+
+    /// How far to turn the image for the display to be right way up, if it's clear which way up
+    /// it is.
+    fn rotation_for(&self, sample: Sample) -> Option<Rotation> {
+        let min = self.config.auto_rotate_min_mg.unsigned_abs();
+        let (x, y) = (sample.x, sample.y);
+        // at rest the accelerometer reads 1g upwards, so the axis with the most is pointing up
+        let y_up = y.unsigned_abs() >= x.unsigned_abs() && y.unsigned_abs() >= min;
+        let x_up = x.unsigned_abs() > y.unsigned_abs() && x.unsigned_abs() >= min;
+
+        match self.config.auto_rotate {
+            AutoRotateMode::Off => None,
+            AutoRotateMode::HalfTurns | AutoRotateMode::QuarterTurns if y_up => {
+                Some(if y > 0 { Rotation::Deg0 } else { Rotation::Deg180 })
+            }
+            // the right hand edge is up, so the top of the image goes there
+            AutoRotateMode::QuarterTurns if x_up => {
+                Some(if x > 0 { Rotation::Deg90 } else { Rotation::Deg270 })
+            }
+            _ => None,
+        }
+    }
+}
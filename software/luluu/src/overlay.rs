@@ -0,0 +1,66 @@
+use luluu_bsp as bsp;
+
+use bsp::luluu_enc::{DirtyRect, Rgb565NE};
+use bsp::Rgb565BE;
+use display_interface::DisplayError;
+
+use crate::render::DisplayBus;
+
+const WHITE: Rgb565BE = Rgb565NE::pack_565(31, 63, 31).to_be();
+const BLACK: Rgb565BE = Rgb565NE::pack_565(0, 0, 0).to_be();
+const ORANGE: Rgb565BE = Rgb565NE::pack_565(31, 40, 0).to_be();
+const RED: Rgb565BE = Rgb565NE::pack_565(31, 0, 0).to_be();
+
+/// Battery icon body, top left corner and size. Kept well inside the corner so it isn't hidden
+/// behind the bezel of round displays.
+const ICON_X: usize = 180;
+const ICON_Y: usize = 36;
+const ICON_W: usize = 24;
+const ICON_H: usize = 12;
+
+/// The whole icon including its border and terminal nub, which is what's sent to the display.
+const BOX_X: usize = ICON_X - 1;
+const BOX_Y: usize = ICON_Y - 1;
+const BOX_W: usize = ICON_W + 5;
+const BOX_H: usize = ICON_H + 2;
+
+/// Draw a nearly empty battery icon over the top right of the display, orange if `critical` is
+/// false and red if it's true.
+pub fn send_low_battery(display_bus: &DisplayBus<'_>, critical: bool) -> Result<(), DisplayError> {
+    let fill = if critical { RED } else { ORANGE };
+
+    // dark border so the icon stands out on light frames
+    let mut pixels = [BLACK; BOX_W * BOX_H];
+    // outline and the terminal nub
+    fill_rect(&mut pixels, 1, 1, ICON_W, ICON_H, WHITE);
+    fill_rect(&mut pixels, 1 + ICON_W, 1 + 3, 3, ICON_H - 6, WHITE);
+    // hollow it out
+    fill_rect(&mut pixels, 3, 3, ICON_W - 4, ICON_H - 4, BLACK);
+    // the little charge that's left
+    fill_rect(&mut pixels, 4, 4, (ICON_W - 6) / 4, ICON_H - 6, fill);
+
+    display_bus.write_pixels_with(
+        BOX_X as u16,
+        BOX_Y as u16,
+        (BOX_X + BOX_W - 1) as u16,
+        (BOX_Y + BOX_H - 1) as u16,
+        Rgb565BE::slice_as_bytes(&pixels),
+        || (),
+    )
+}
+
+/// Whether `rect` on the display covers any of the battery icon, so it needs sending again.
+pub fn overlaps_low_battery(rect: DirtyRect) -> bool {
+    let (x, y) = (rect.x as usize, rect.y as usize);
+    let (w, h) = (rect.width as usize, rect.height as usize);
+    !rect.is_empty() && x < BOX_X + BOX_W && BOX_X < x + w && y < BOX_Y + BOX_H && BOX_Y < y + h
+}
+
+/// Fill part of the icon's `BOX_W` wide pixels.
+fn fill_rect(pixels: &mut [Rgb565BE], x: usize, y: usize, w: usize, h: usize, color: Rgb565BE) {
+    for row in y..(y + h).min(BOX_H) {
+        let start = row * BOX_W + x.min(BOX_W);
+        let end = row * BOX_W + (x + w).min(BOX_W);
+        pixels[start..end].fill(color);
+    }
+}
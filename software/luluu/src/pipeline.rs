@@ -0,0 +1,312 @@
+//! Handing frames back and forth between the cores.
+//!
+//! Core 1 reads frames off the SD card and decodes them into one of two framebuffers while core 0
+//! sends the other one to the display. Each framebuffer belongs to whichever core holds its
+//! [`FrameSlot`], and slots move between the cores inside the messages sent over the SIO FIFOs by
+//! [`DecoderLink`] and [`PlayerLink`], which are the only things that send or receive on them.
+//!
+//! While a computer has the card over USB, core 1 lends it out instead: it closes the volume and
+//! reads and writes blocks of the card through a slot for core 0, then lists the animations again
+//! once it gets the card back. Files sent over USB serial are written through a slot too.
+
+use luluu_bsp as bsp;
+
+use bsp::buffers::{Buffer, FullFramebuffer};
+use bsp::hal::sio::SioFifo;
+use bsp::luluu_enc::DirtyRect;
+
+#[cfg(not(feature = "probe"))]
+use core as defmt;
+
+/// Ownership of one of the two framebuffers.
+pub type FrameSlot = Buffer<FullFramebuffer>;
+
+/// The parts of an animation's header that core 0 needs to play it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "probe", derive(defmt::Format))]
+pub struct Animation {
+    pub size: u8,
+    pub frame_rate: u8,
+    pub n_frames: u16,
+}
+
+/// Why core 1 couldn't answer a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "probe", derive(defmt::Format))]
+pub enum Failure {
+    /// The file isn't an animation that can be played, or stops part way through. Other files
+    /// might still be fine.
+    BadFile,
+    /// Reading from the card failed, so nothing else on it is going to work either.
+    CardError,
+}
+
+/// Sent from core 0 to core 1.
+pub enum Request {
+    /// Open the `file_idx`th animation on the card. Answered with [`Response::Opened`].
+    Open { file_idx: usize },
+    /// Decode the frame `frame_step` frames after the last one decoded into `slot`, wrapping
+    /// around at the end. The first one after opening an animation is always its first frame.
+    /// Answered with [`Response::Decoded`].
+    Decode { slot: FrameSlot, frame_step: u32 },
+    /// Stop playing and lend out the card. Answered with [`Response::Lent`].
+    Lend,
+    /// Read `n_blocks` blocks of the lent out card from `block_idx` on into the start of `slot`.
+    /// Answered with [`Response::Blocks`].
+    ReadBlocks { slot: FrameSlot, block_idx: u32, n_blocks: u32 },
+    /// Write `n_blocks` blocks from the start of `slot` to the lent out card from `block_idx` on.
+    /// Answered with [`Response::Blocks`].
+    WriteBlocks { slot: FrameSlot, block_idx: u32, n_blocks: u32 },
+    /// Take back the card if it was lent out, and list the animations on it into `slot`. Answered
+    /// with [`Response::Listed`].
+    List { slot: FrameSlot },
+    /// Write `len` bytes from `slot` to the file named in it, see [`set_file_name`], replacing what
+    /// was in it unless it's to `append` them. Answered with [`Response::Written`].
+    WriteFile { slot: FrameSlot, len: usize, append: bool },
+}
+
+/// Sent from core 1 to core 0, in the same order as the requests they answer.
+pub enum Response {
+    Opened(Animation),
+    /// `dirty` is the part of the frame that's changed since the frame decoded before it, in the
+    /// animation's pixels. It's the whole frame if that wasn't the frame before it in the file.
+    Decoded { slot: FrameSlot, dirty: DirtyRect },
+    /// Answers any kind of request, handing back the slot if it came with one. After a failure,
+    /// core 1 keeps failing decodes until it's asked to open another animation, and after a card
+    /// error it fails everything.
+    Failed { slot: Option<FrameSlot>, failure: Failure },
+    /// The card's volume is closed and it's ready for reading and writing blocks.
+    Lent { n_blocks: u32 },
+    /// The blocks asked for are in `slot`, or have been written from it.
+    Blocks { slot: FrameSlot },
+    /// The names of the `n_files` animations on the card are in `slot`, read them with
+    /// [`listed_name`]. From now on they're the ones [`Request::Open`] picks from.
+    Listed { slot: FrameSlot, n_files: usize },
+    /// The bytes in `slot` are in the file.
+    Written { slot: FrameSlot },
+}
+
+/// Names in slots take up this many bytes each, padded with zeros.
+const NAME_LEN: usize = 12;
+
+/// Put `name` in `slot` as the `idx`th for [`Response::Listed`], cut short if it's too long.
+pub fn set_listed_name(slot: &mut FullFramebuffer, idx: usize, name: &str) {
+    let entry = &mut slot.as_bytes_mut()[idx * NAME_LEN..][..NAME_LEN];
+    let len = name.len().min(NAME_LEN);
+    entry.fill(0);
+    entry[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+/// The `idx`th name in a slot from [`Response::Listed`].
+pub fn listed_name(slot: &FullFramebuffer, idx: usize) -> &str {
+    let entry = &slot.as_bytes()[idx * NAME_LEN..][..NAME_LEN];
+    let len = entry.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+    // 8.3 names are ASCII
+    core::str::from_utf8(&entry[..len]).unwrap_or_default()
+}
+
+/// Put the name of the file for [`Request::WriteFile`] in `slot`. It's at the start, so the bytes
+/// for the file are in [`file_bytes`].
+pub fn set_file_name(slot: &mut FullFramebuffer, name: &str) {
+    set_listed_name(slot, 0, name)
+}
+
+/// The name of the file in a slot for [`Request::WriteFile`].
+pub fn file_name(slot: &FullFramebuffer) -> &str {
+    listed_name(slot, 0)
+}
+
+/// Where the bytes for [`Request::WriteFile`] go in its slot, after the name.
+pub fn file_bytes(slot: &mut FullFramebuffer) -> &mut [u8] {
+    &mut slot.as_bytes_mut()[NAME_LEN..]
+}
+
+// every message is three words. the first has the kind in its top byte, the second is a slot's
+// address for messages that carry one
+const OPEN: u32 = 1;
+const DECODE: u32 = 2;
+const OPENED: u32 = 3;
+const DECODED: u32 = 4;
+const FAILED: u32 = 5;
+const LEND: u32 = 6;
+const READ_BLOCKS: u32 = 7;
+const WRITE_BLOCKS: u32 = 8;
+const LIST: u32 = 9;
+const LENT: u32 = 10;
+const BLOCKS: u32 = 11;
+const LISTED: u32 = 12;
+const WRITE_FILE: u32 = 13;
+const WRITTEN: u32 = 14;
+
+#[inline(always)]
+fn pack(kind: u32, arg: u32) -> u32 {
+    defmt::debug_assert!(arg <= 0xff_ffff);
+    kind << 24 | arg
+}
+
+#[inline(always)]
+fn unpack(word: u32) -> (u32, u32) {
+    (word >> 24, word & 0xff_ffff)
+}
+
+/// Core 0's end of the link, for asking the decoder on core 1 for frames.
+pub struct DecoderLink {
+    fifo: SioFifo,
+}
+
+impl DecoderLink {
+    pub fn new(fifo: SioFifo) -> Self {
+        Self { fifo }
+    }
+
+    pub fn send(&mut self, request: Request) {
+        let words = match request {
+            Request::Open { file_idx } => [pack(OPEN, file_idx as u32), 0, 0],
+            Request::Decode { slot, frame_step } => [pack(DECODE, frame_step), slot.into_raw() as u32, 0],
+            Request::Lend => [pack(LEND, 0), 0, 0],
+            Request::ReadBlocks { slot, block_idx, n_blocks } => {
+                [pack(READ_BLOCKS, n_blocks), slot.into_raw() as u32, block_idx]
+            }
+            Request::WriteBlocks { slot, block_idx, n_blocks } => {
+                [pack(WRITE_BLOCKS, n_blocks), slot.into_raw() as u32, block_idx]
+            }
+            Request::List { slot } => [pack(LIST, 0), slot.into_raw() as u32, 0],
+            Request::WriteFile { slot, len, append } => {
+                [pack(WRITE_FILE, append as u32), slot.into_raw() as u32, len as u32]
+            }
+        };
+        for word in words {
+            self.fifo.write_blocking(word);
+        }
+    }
+
+    /// Wait for the next response.
+    pub fn recv(&mut self) -> Response {
+        let first = self.fifo.read_blocking();
+        self.finish_recv(first)
+    }
+
+    /// The next response, if there is one yet.
+    pub fn try_recv(&mut self) -> Option<Response> {
+        let first = self.fifo.read()?;
+        Some(self.finish_recv(first))
+    }
+
+    fn finish_recv(&mut self, first: u32) -> Response {
+        // the words are written back to back, the rest won't be far behind
+        let second = self.fifo.read_blocking();
+        let third = self.fifo.read_blocking();
+        match unpack(first) {
+            (OPENED, size) => Response::Opened(Animation {
+                size: size as u8,
+                frame_rate: (size >> 8) as u8,
+                n_frames: second as u16,
+            }),
+            (DECODED, _) => Response::Decoded {
+                // SAFETY: core 1 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+                dirty: DirtyRect::from_bytes(third.to_le_bytes()),
+            },
+            (FAILED, failure) => Response::Failed {
+                // SAFETY: core 1 gave up the slot to send it, if there was one
+                slot: (second != 0).then(|| unsafe { FrameSlot::from_raw(second as *mut _) }),
+                failure: match failure {
+                    0 => Failure::BadFile,
+                    _ => Failure::CardError,
+                },
+            },
+            (LENT, _) => Response::Lent { n_blocks: second },
+            (BLOCKS, _) => Response::Blocks {
+                // SAFETY: core 1 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+            },
+            (LISTED, n_files) => Response::Listed {
+                // SAFETY: core 1 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+                n_files: n_files as usize,
+            },
+            (WRITTEN, _) => Response::Written {
+                // SAFETY: core 1 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+            },
+            _ => defmt::panic!("bad message from core 1: {:x}", first),
+        }
+    }
+}
+
+/// Core 1's end of the link, for taking requests from the player on core 0.
+pub struct PlayerLink {
+    fifo: SioFifo,
+}
+
+impl PlayerLink {
+    pub fn new(fifo: SioFifo) -> Self {
+        Self { fifo }
+    }
+
+    pub fn send(&mut self, response: Response) {
+        let words = match response {
+            Response::Opened(animation) => [
+                pack(OPENED, animation.size as u32 | (animation.frame_rate as u32) << 8),
+                animation.n_frames as u32,
+                0,
+            ],
+            Response::Decoded { slot, dirty } => [
+                pack(DECODED, 0),
+                slot.into_raw() as u32,
+                u32::from_le_bytes(dirty.to_bytes()),
+            ],
+            Response::Failed { slot, failure } => [
+                pack(FAILED, failure as u32),
+                slot.map_or(0, |slot| slot.into_raw() as u32),
+                0,
+            ],
+            Response::Lent { n_blocks } => [pack(LENT, 0), n_blocks, 0],
+            Response::Blocks { slot } => [pack(BLOCKS, 0), slot.into_raw() as u32, 0],
+            Response::Listed { slot, n_files } => [pack(LISTED, n_files as u32), slot.into_raw() as u32, 0],
+            Response::Written { slot } => [pack(WRITTEN, 0), slot.into_raw() as u32, 0],
+        };
+        for word in words {
+            self.fifo.write_blocking(word);
+        }
+    }
+
+    /// Wait for the next request.
+    pub fn recv(&mut self) -> Request {
+        let first = self.fifo.read_blocking();
+        let second = self.fifo.read_blocking();
+        let third = self.fifo.read_blocking();
+        match unpack(first) {
+            (OPEN, file_idx) => Request::Open { file_idx: file_idx as usize },
+            (DECODE, frame_step) => Request::Decode {
+                // SAFETY: core 0 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+                frame_step,
+            },
+            (LEND, _) => Request::Lend,
+            (READ_BLOCKS, n_blocks) => Request::ReadBlocks {
+                // SAFETY: core 0 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+                block_idx: third,
+                n_blocks,
+            },
+            (WRITE_BLOCKS, n_blocks) => Request::WriteBlocks {
+                // SAFETY: core 0 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+                block_idx: third,
+                n_blocks,
+            },
+            (LIST, _) => Request::List {
+                // SAFETY: core 0 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+            },
+            (WRITE_FILE, append) => Request::WriteFile {
+                // SAFETY: core 0 gave up the slot to send it
+                slot: unsafe { FrameSlot::from_raw(second as *mut _) },
+                len: third as usize,
+                append: append != 0,
+            },
+            _ => defmt::panic!("bad message from core 0: {:x}", first),
+        }
+    }
+}
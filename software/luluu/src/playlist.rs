@@ -0,0 +1,103 @@
+//! Which of the animations on the card to play, following the `[schedule]` in the settings as the
+//! time of day changes.
+
+use luluu_bsp as bsp;
+
+use bsp::hal::rosc::{Enabled, RingOscillator};
+use luluu_config::schedule::{Rule, Schedule};
+use luluu_config::time::DateTime;
+use luluu_config::PlayOrder;
+
+use crate::input::Action;
+
+pub type FileName = heapless::String<12>;
+
+pub struct Playlist<'a> {
+    schedule: &'a Schedule,
+    file_names: &'a [FileName],
+    /// Index into the schedule's rules of the one that applies now.
+    active: Option<usize>,
+    /// Whether any of the files are ones the active rule plays. If not, they all are, rather than
+    /// there being nothing to play.
+    any_match: bool,
+}
+
+impl<'a> Playlist<'a> {
+    pub fn new(schedule: &'a Schedule, file_names: &'a [FileName], now: Option<DateTime>) -> Self {
+        let mut playlist = Self { schedule, file_names, active: None, any_match: false };
+        playlist.update(now);
+        playlist
+    }
+
+    /// Check the schedule against `now`, which is `None` if the clock isn't set. True if a
+    /// different rule applies from now on, or none does any more.
+    pub fn update(&mut self, now: Option<DateTime>) -> bool {
+        let active = now.and_then(|now| self.schedule.active(&now));
+        if active == self.active {
+            return false;
+        }
+        self.active = active;
+        self.any_match = match self.rule() {
+            Some(rule) => self.file_names.iter().any(|name| rule.plays(name)),
+            None => true,
+        };
+        #[cfg(feature = "probe")]
+        if !self.any_match {
+            defmt::warn!("no files for the schedule's {}, playing them all", self.rule());
+        }
+        true
+    }
+
+    /// The rule that applies now, if any.
+    pub fn rule(&self) -> Option<&'a Rule> {
+        self.active.map(|idx| &self.schedule.rules()[idx])
+    }
+
+    /// The brightness the schedule asks for now, if it asks for one.
+    pub fn brightness_percent(&self) -> Option<u8> {
+        self.rule().and_then(Rule::brightness_percent)
+    }
+
+    pub fn contains(&self, file_idx: usize) -> bool {
+        match self.rule() {
+            Some(rule) if self.any_match => rule.plays(&self.file_names[file_idx]),
+            _ => true,
+        }
+    }
+
+    /// The file to start playing with.
+    pub fn first(&self, order: PlayOrder, rosc: &mut RingOscillator<Enabled>) -> usize {
+        match order {
+            PlayOrder::Shuffle => self.pick_random(None, rosc).unwrap_or(0),
+            PlayOrder::Sequential => self.next(self.file_names.len() - 1, Action::Next, rosc),
+        }
+    }
+
+    /// The file to play after `file_idx` for `action`. Shuffling picks any other file in the
+    /// playlist, if there is one.
+    pub fn next(&self, file_idx: usize, action: Action, rosc: &mut RingOscillator<Enabled>) -> usize {
+        let n_files = self.file_names.len();
+        let step = match action {
+            Action::Shuffle => return self.pick_random(Some(file_idx), rosc).unwrap_or(file_idx),
+            // a step back, the whole way round
+            Action::Previous => n_files - 1,
+            Action::Next | Action::TogglePause => 1,
+        };
+        // the file itself comes round again last, for when it's the only one
+        (1..=n_files)
+            .map(|n| (file_idx + n * step) % n_files)
+            .find(|&idx| self.contains(idx))
+            .unwrap_or(file_idx)
+    }
+
+    /// Any file in the playlist other than `except`.
+    fn pick_random(&self, except: Option<usize>, rosc: &mut RingOscillator<Enabled>) -> Option<usize> {
+        let candidates = (0..self.file_names.len()).filter(|&idx| Some(idx) != except && self.contains(idx));
+        let n_candidates = candidates.clone().count();
+        if n_candidates == 0 {
+            return None;
+        }
+        let pick = bsp::gen_rand_u32(rosc) as usize % n_candidates;
+        candidates.clone().nth(pick)
+    }
+}
@@ -0,0 +1,84 @@
+//! Sending decoded frames to the display.
+//!
+//! Frames are kept at their own size in the framebuffer. Smaller ones are upscaled a line at a
+//! time as they're sent, each line made while the one before it goes out over the bus, so
+//! upscaling takes no time of its own and never needs a full size copy of the frame.
+//!
+//! With the `pio-display` feature the display is driven by PIO instead, which repeats each pixel
+//! across on the wire, so smaller frames go straight out of the framebuffer without the CPU.
+
+use luluu_bsp as bsp;
+
+use bsp::buffers::FullFramebuffer;
+use bsp::luluu_enc::{DirtyRect, Size};
+#[cfg(not(feature = "pio-display"))]
+use bsp::Rgb565BE;
+use display_interface::DisplayError;
+
+#[cfg(not(feature = "pio-display"))]
+pub use bsp::display::DisplayBus;
+#[cfg(feature = "pio-display")]
+pub use bsp::pio_display::PioDisplayBus as DisplayBus;
+
+/// Width and height of the display, in pixels.
+pub const DISPLAY_SIZE: u8 = 240;
+
+/// The part of the display showing `rect` of a frame of `size`.
+#[inline]
+pub fn display_rect(rect: DirtyRect, size: Size) -> DirtyRect {
+    rect.scaled(DISPLAY_SIZE / size.0)
+}
+
+/// Send the `dirty` part of `fb`, which holds a frame of `size`, to the display.
+pub fn send_frame(display_bus: &DisplayBus<'_>, fb: &FullFramebuffer, size: Size, dirty: DirtyRect) -> Result<(), DisplayError> {
+    if dirty.is_empty() {
+        return Ok(());
+    }
+
+    let rect = display_rect(dirty, size);
+    let (sx, sy) = (rect.x as u16, rect.y as u16);
+    let (ex, ey) = (sx + rect.width as u16 - 1, sy + rect.height as u16 - 1);
+
+    let size = size.0 as usize;
+    let scale = DISPLAY_SIZE as usize / size;
+    if scale == 1 {
+        return display_bus.write_region(sx, sy, ex, ey, fb.as_bytes(), size);
+    }
+
+    send_scaled(display_bus, fb, size, scale, dirty, (sx, sy, ex, ey))
+}
+
+/// Send a frame `scale` times smaller than the display, upscaling it a line at a time with the CPU.
+#[cfg(not(feature = "pio-display"))]
+fn send_scaled(
+    display_bus: &DisplayBus<'_>,
+    fb: &FullFramebuffer,
+    size: usize,
+    scale: usize,
+    dirty: DirtyRect,
+    (sx, sy, ex, ey): (u16, u16, u16, u16),
+) -> Result<(), DisplayError> {
+    let src = &fb.pixels()[..size * size];
+    let (src_x, src_width) = (dirty.x as usize, dirty.width as usize);
+    display_bus.write_lines(sx, sy, ex, ey, |y, line| {
+        let src_row = &src[y as usize / scale * size..][src_x..src_x + src_width];
+        let line = Rgb565BE::cast_bytes_mut(line);
+        for (src_pixel, dst_pixels) in src_row.iter().zip(line.chunks_exact_mut(scale)) {
+            dst_pixels.fill(*src_pixel);
+        }
+    })
+}
+
+/// Send a frame `scale` times smaller than the display, letting the PIO repeat its pixels across.
+#[cfg(feature = "pio-display")]
+fn send_scaled(
+    display_bus: &DisplayBus<'_>,
+    fb: &FullFramebuffer,
+    size: usize,
+    scale: usize,
+    _dirty: DirtyRect,
+    (sx, sy, ex, ey): (u16, u16, u16, u16),
+) -> Result<(), DisplayError> {
+    let replicate = bsp::pio_display::Replicate::from_scale(scale).unwrap();
+    display_bus.write_region_scaled(sx, sy, ex, ey, fb.as_bytes(), size, replicate)
+}
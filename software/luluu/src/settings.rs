@@ -0,0 +1,92 @@
+//! Settings from `LULUU.INI` on the SD card, turned into the configs the rest of the firmware uses.
+
+use luluu_bsp as bsp;
+
+use bsp::display::{Orientation, Rotation};
+use embedded_sdmmc::Mode;
+use fugit::HertzU32;
+use luluu_config::{AutoRotate, Config};
+use luluu_power::PowerConfig;
+
+use crate::decoder::RootDir;
+use crate::orientation::{AutoRotateMode, OrientationConfig};
+
+/// Read the settings file from `root_dir`, or the defaults if there isn't one. Problems with it
+/// are logged and the settings they affect left at their defaults.
+pub fn load(root_dir: &mut RootDir<'_>) -> Config {
+    let Ok(mut file) = root_dir.open_file_in_dir(luluu_config::FILE_NAME, Mode::ReadOnly) else {
+        #[cfg(feature = "probe")]
+        defmt::info!("no {}, using default settings", luluu_config::FILE_NAME);
+        return Config::DEFAULT;
+    };
+
+    let mut bytes = [0u8; luluu_config::MAX_FILE_SIZE];
+    let len = match file.read(&mut bytes) {
+        Ok(len) => len,
+        Err(_) => {
+            #[cfg(feature = "probe")]
+            defmt::warn!("couldn't read {}, using default settings", luluu_config::FILE_NAME);
+            return Config::DEFAULT;
+        }
+    };
+
+    let config = Config::parse(&bytes[..len], |_error| {
+        #[cfg(feature = "probe")]
+        defmt::warn!("{}: {}", luluu_config::FILE_NAME, _error);
+    });
+    #[cfg(feature = "probe")]
+    defmt::info!("settings: {}", config);
+    config
+}
+
+pub fn sd_baudrate(config: &Config) -> HertzU32 {
+    HertzU32::kHz(config.sd_clock_khz)
+}
+
+pub fn display_baudrate(config: &Config) -> HertzU32 {
+    HertzU32::kHz(config.display_clock_khz)
+}
+
+pub fn orientation_config(config: &Config) -> OrientationConfig {
+    OrientationConfig {
+        orientation: Orientation {
+            // the parser only lets through whole quarter turns
+            rotation: Rotation::from_degrees(config.rotation_degrees).unwrap_or_default(),
+            mirror_x: config.mirror_x,
+            mirror_y: config.mirror_y,
+        },
+        auto_rotate: match config.auto_rotate {
+            AutoRotate::Off => AutoRotateMode::Off,
+            AutoRotate::Flip => AutoRotateMode::HalfTurns,
+            AutoRotate::Any => AutoRotateMode::QuarterTurns,
+        },
+        ..OrientationConfig::DEFAULT
+    }
+}
+
+pub fn power_config(config: &Config) -> PowerConfig {
+    PowerConfig {
+        dim_after_millis: seconds_or_never(config.dim_after_seconds),
+        sleep_after_millis: seconds_or_never(config.sleep_after_seconds),
+        motion_threshold_mg: config.motion_threshold_mg,
+        // the settings only go up to 1g, so these fit
+        raise_min_z_mg: config.raise_min_z_mg as i16,
+        raise_max_tilt_mg: config.raise_max_tilt_mg as i16,
+        raise_hold_millis: config.raise_hold_millis,
+        wake_on_tap: config.wake_on_tap,
+        ..PowerConfig::DEFAULT
+    }
+}
+
+/// How long before moving on to the next animation, if ever.
+pub fn change_every_millis(config: &Config) -> Option<u32> {
+    (config.change_every_seconds > 0).then(|| config.change_every_seconds * 1_000)
+}
+
+/// 0 seconds means never.
+fn seconds_or_never(seconds: u32) -> u32 {
+    match seconds {
+        0 => u32::MAX,
+        seconds => seconds * 1_000,
+    }
+}
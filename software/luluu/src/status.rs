@@ -0,0 +1,159 @@
+//! Full screen messages for when there's something wrong, so the wearer can see what it is instead
+//! of a dark display.
+
+use core::fmt;
+
+use luluu_bsp as bsp;
+
+use bsp::buffers::FullFramebuffer;
+use bsp::luluu_enc::{DirtyRect, Rgb565NE, Size};
+use bsp::Rgb565BE;
+use display_interface::DisplayError;
+
+use crate::font;
+use crate::render::{self, DisplayBus, DISPLAY_SIZE};
+
+const BLACK: Rgb565BE = Rgb565NE::pack_565(0, 0, 0).to_be();
+const WHITE: Rgb565BE = Rgb565NE::pack_565(31, 63, 31).to_be();
+const GREY: Rgb565BE = Rgb565NE::pack_565(20, 40, 20).to_be();
+const ORANGE: Rgb565BE = Rgb565NE::pack_565(31, 40, 0).to_be();
+const RED: Rgb565BE = Rgb565NE::pack_565(31, 0, 0).to_be();
+
+const TITLE_SCALE: usize = 3;
+const TEXT_SCALE: usize = 2;
+/// Kept clear around the edges, so nothing's hidden behind the bezel of round displays.
+const MARGIN: usize = 24;
+const LINE_GAP: usize = 6;
+/// Most lines of hint that fit below the title.
+const MAX_HINT_LINES: usize = 5;
+
+/// Something stopping animations from being played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "probe", derive(defmt::Format))]
+pub enum Problem<'a> {
+    /// There's no card in the slot, or it can't be read as a FAT volume.
+    NoCard,
+    /// The card's fine, but there's nothing on it to play.
+    NoAnimations,
+    /// The named file isn't an animation that can be played.
+    BadFile { name: &'a str },
+    /// Reading from the card stopped working part way through, usually because it was taken out.
+    CardError,
+    /// A computer has the card over USB.
+    UsbDrive,
+    /// Not a problem, but animations are being copied into flash, see [`cache`](crate::cache).
+    Caching,
+    /// New firmware from the card is being installed.
+    Updating,
+    /// The firmware update on the card is cut short or garbled, see [`update`](crate::update).
+    BadUpdate,
+}
+
+impl Problem<'_> {
+    fn title(&self) -> &'static str {
+        match self {
+            Problem::NoCard => "No card",
+            Problem::NoAnimations => "No files",
+            Problem::BadFile { .. } => "Bad file",
+            Problem::CardError => "Card error",
+            Problem::UsbDrive => "USB drive",
+            Problem::Caching => "Caching",
+            Problem::Updating => "Updating",
+            Problem::BadUpdate => "Bad update",
+        }
+    }
+
+    fn hint(&self) -> &'static str {
+        match self {
+            Problem::NoCard => "Insert a FAT formatted card with .LU files on it",
+            Problem::NoAnimations => "Put some .LU files in the top folder of the card",
+            Problem::BadFile { .. } => "Convert it again with luluu-cli. Skipping it for now",
+            Problem::CardError => "Check the card is pushed all the way in. Restarting",
+            Problem::UsbDrive => "Eject it on the computer to carry on playing",
+            Problem::Caching => "Copying animations into memory, to play without the card",
+            Problem::Updating => "Installing new firmware from the card. Keep LuLuu! turned on",
+            Problem::BadUpdate => "Make LULUU.FW again with luluu-cli. Starting as usual",
+        }
+    }
+
+    fn color(&self) -> Rgb565BE {
+        match self {
+            Problem::NoCard | Problem::NoAnimations | Problem::UsbDrive | Problem::Caching | Problem::Updating => WHITE,
+            Problem::BadFile { .. } | Problem::BadUpdate => ORANGE,
+            Problem::CardError => RED,
+        }
+    }
+}
+
+/// The same message as on the display, on one line.
+impl fmt::Display for Problem<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.title())?;
+        if let Problem::BadFile { name } = self {
+            write!(f, " {}", name)?;
+        }
+        write!(f, ": {}", self.hint())
+    }
+}
+
+/// Draw `problem` into `fb` and send it to the whole display. The backlight is left as it is.
+pub fn show(display_bus: &DisplayBus<'_>, fb: &mut FullFramebuffer, problem: Problem<'_>) -> Result<(), DisplayError> {
+    fb.pixels_mut().fill(BLACK);
+
+    let max_chars = (DISPLAY_SIZE as usize - 2 * MARGIN + 1) / (font::ADVANCE * TEXT_SCALE);
+    let hint_lines = wrap(problem.hint(), max_chars).take(MAX_HINT_LINES);
+    let name = match problem {
+        Problem::BadFile { name } => Some(name),
+        _ => None,
+    };
+
+    // the whole message is centred, so it's as far from the corners as it can be
+    let line_height = |scale: usize| font::GLYPH_HEIGHT * scale + LINE_GAP;
+    let height = line_height(TITLE_SCALE)
+        + name.map_or(0, |_| line_height(TEXT_SCALE))
+        + LINE_GAP
+        + hint_lines.clone().count() * line_height(TEXT_SCALE);
+    let mut y = (DISPLAY_SIZE as usize).saturating_sub(height) / 2;
+
+    draw_centered(fb, y, TITLE_SCALE, problem.color(), problem.title());
+    y += line_height(TITLE_SCALE);
+    if let Some(name) = name {
+        draw_centered(fb, y, TEXT_SCALE, WHITE, name);
+        y += line_height(TEXT_SCALE);
+    }
+    y += LINE_GAP;
+    for line in hint_lines {
+        draw_centered(fb, y, TEXT_SCALE, GREY, line);
+        y += line_height(TEXT_SCALE);
+    }
+
+    let size = Size(DISPLAY_SIZE);
+    render::send_frame(display_bus, fb, size, DirtyRect::full(size))
+}
+
+fn draw_centered(fb: &mut FullFramebuffer, y: usize, scale: usize, color: Rgb565BE, text: &str) {
+    let x = (DISPLAY_SIZE as usize).saturating_sub(font::text_width(text, scale)) / 2;
+    font::draw_text(fb, x, y, scale, color, text);
+}
+
+/// Split `text` into lines of at most `max_chars`, breaking between words where possible.
+fn wrap(text: &str, max_chars: usize) -> impl Iterator<Item = &str> + Clone {
+    let mut rest = text.trim();
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        // the messages are all ASCII, so bytes are characters
+        let end = if rest.len() <= max_chars {
+            rest.len()
+        } else {
+            match rest[..=max_chars].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => max_chars,
+            }
+        };
+        let (line, remainder) = rest.split_at(end);
+        rest = remainder.trim_start();
+        Some(line.trim_end())
+    })
+}
@@ -0,0 +1,132 @@
+//! Updating the firmware from the card, with an image made by `luluu-cli firmware pack`, see
+//! [`luluu_update`].
+//!
+//! At power on, before core 1 starts, [`find`] looks for [`FILE_NAME`] on the card. Newer firmware
+//! is [`stage`]d in the end of the storage and checked there, then [`install`]ed over this, which
+//! restarts into it. The new firmware then finds the same image, no newer than itself, and puts it
+//! away with [`put_away`] so it isn't looked at again.
+
+use embedded_sdmmc::{Mode, SdCardError};
+use luluu_bsp as bsp;
+use luluu_update::{Header, Version, FILE_NAME, HEADER_LEN, INSTALLED_FILE_NAME, MAX_IMAGE_LEN};
+
+use bsp::flash::{self, SECTOR_SIZE};
+
+use crate::decoder::RootDir;
+use crate::fault;
+use crate::read_file::read_all;
+
+/// Where in the storage new firmware goes before it's installed, the end of it.
+pub const STAGING_OFFSET: usize = flash::STORAGE_LEN - MAX_IMAGE_LEN as usize;
+
+/// What's on the card.
+pub enum Found {
+    Nothing,
+    /// Firmware newer than this.
+    Newer(Header),
+    /// This firmware, once it's been installed, or older.
+    NotNewer(Version),
+}
+
+/// Why an update didn't go ahead.
+#[derive(Debug)]
+pub enum Failure {
+    Card(embedded_sdmmc::Error<SdCardError>),
+    Image(luluu_update::Error),
+}
+
+impl From<embedded_sdmmc::Error<SdCardError>> for Failure {
+    fn from(error: embedded_sdmmc::Error<SdCardError>) -> Self {
+        Self::Card(error)
+    }
+}
+
+/// The version of this firmware.
+pub fn version() -> Version {
+    Version::parse(env!("CARGO_PKG_VERSION")).unwrap()
+}
+
+/// Look for an update in `root_dir`, and read its header.
+pub fn find(root_dir: &mut RootDir<'_>) -> Result<Found, Failure> {
+    let mut file = match root_dir.open_file_in_dir(FILE_NAME, Mode::ReadOnly) {
+        Ok(file) => file,
+        Err(embedded_sdmmc::Error::NotFound) => return Ok(Found::Nothing),
+        Err(error) => return Err(error.into()),
+    };
+    let mut bytes = [0; HEADER_LEN];
+    let read = read_all(&mut file, &mut bytes)?;
+    let file_len = file.length() as usize;
+    file.close()?;
+
+    if read < HEADER_LEN {
+        return Err(Failure::Image(luluu_update::Error::NotAnImage));
+    }
+    let header = Header::decode(&bytes).map_err(Failure::Image)?;
+    if file_len != HEADER_LEN + header.len as usize {
+        return Err(Failure::Image(luluu_update::Error::Corrupted));
+    }
+    match header.version > version() {
+        true => Ok(Found::Newer(header)),
+        false => Ok(Found::NotNewer(header.version)),
+    }
+}
+
+/// Copy the firmware in the update in `root_dir` to [`STAGING_OFFSET`], going through `buffer`,
+/// then check it against `header`. Core 1 can't have been started yet.
+pub fn stage(root_dir: &mut RootDir<'_>, header: &Header, buffer: &mut [u8]) -> Result<(), Failure> {
+    let mut file = root_dir.open_file_in_dir(FILE_NAME, Mode::ReadOnly)?;
+    file.seek_from_start(HEADER_LEN as u32)?;
+
+    let chunk_len = buffer.len() / SECTOR_SIZE * SECTOR_SIZE;
+    let mut len = 0;
+    while len < header.len as usize {
+        fault::feed();
+        let read = read_all(&mut file, &mut buffer[..chunk_len])?;
+        if read == 0 {
+            break;
+        }
+        // SAFETY: core 1 isn't running yet, and `buffer` is in RAM
+        unsafe { flash::write(STAGING_OFFSET + len, &buffer[..read]) };
+        len += read;
+    }
+    file.close()?;
+
+    // checked as it is in flash, so it's caught if it didn't go in right either
+    fault::feed();
+    header.check(&flash::storage()[STAGING_OFFSET..][..len]).map_err(Failure::Image)
+}
+
+/// Replace this firmware with the one that's been [`stage`]d for `header`, and start it.
+pub fn install(header: &Header) -> ! {
+    fault::note(fault::Activity::Restarting);
+    // SAFETY: core 1 isn't running yet, and `stage` checked it's firmware
+    unsafe { flash::install(STAGING_OFFSET, header.len as usize) }
+}
+
+/// Rename the update in `root_dir` to [`INSTALLED_FILE_NAME`], replacing one from before. The card
+/// can't rename files, so it's copied a `buffer`-full at a time and then deleted.
+pub fn put_away(root_dir: &mut RootDir<'_>, buffer: &mut [u8]) -> Result<(), Failure> {
+    match root_dir.delete_file_in_dir(INSTALLED_FILE_NAME) {
+        Ok(()) | Err(embedded_sdmmc::Error::NotFound) => (),
+        Err(error) => return Err(error.into()),
+    }
+
+    // only one file can be open at a time
+    let mut copied = 0;
+    loop {
+        fault::feed();
+        let mut file = root_dir.open_file_in_dir(FILE_NAME, Mode::ReadOnly)?;
+        file.seek_from_start(copied)?;
+        let read = read_all(&mut file, buffer)?;
+        file.close()?;
+        if read == 0 {
+            break;
+        }
+        let mut file = root_dir.open_file_in_dir(INSTALLED_FILE_NAME, Mode::ReadWriteCreateOrAppend)?;
+        file.write(&buffer[..read])?;
+        file.close()?;
+        copied += read as u32;
+    }
+    root_dir.delete_file_in_dir(FILE_NAME)?;
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! The USB port. A computer plugged into it sees a drive with the SD card in it, see
+//! [`usb_storage`](crate::usb_storage), and a serial port for controlling the player, see
+//! [`control`](crate::control).
+
+use luluu_bsp as bsp;
+
+use bsp::usb::UsbBus;
+use luluu_proto::Failure;
+use usb_device::class_prelude::*;
+use usb_device::prelude::*;
+
+use crate::control::Control;
+use crate::fault;
+use crate::usb_storage::{Card, MassStorage};
+
+pub struct Usb {
+    device: UsbDevice<'static, UsbBus>,
+    storage: MassStorage,
+    control: Control,
+    /// Whether the drive gets the card, from the settings.
+    drive: bool,
+}
+
+impl Usb {
+    pub fn new(bus: &'static UsbBusAllocator<UsbBus>) -> Self {
+        let storage = MassStorage::new(bus);
+        let control = Control::new(bus);
+        let device = UsbDeviceBuilder::new(bus, bsp::usb::VID_PID)
+            .manufacturer(bsp::usb::MANUFACTURER)
+            .product(bsp::usb::PRODUCT)
+            // the serial port is two interfaces, which have to be grouped together
+            .composite_with_iads()
+            .build();
+        Self { device, storage, control, drive: true }
+    }
+
+    /// Whether the drive gets the card when a computer's plugged in. If not, it's always empty.
+    pub fn set_drive(&mut self, drive: bool) {
+        self.drive = drive;
+    }
+
+    /// Keep the connection going while the drive's empty. Needs calling every few milliseconds.
+    /// True if a computer wants the card, see [`Usb::wants_card`].
+    pub fn poll(&mut self) -> bool {
+        self.device.poll(&mut [&mut self.storage, self.control.class()]);
+        self.storage.process(None);
+        self.wants_card()
+    }
+
+    /// Whether a computer's plugged in and has set up the drive, and hasn't ejected it since.
+    pub fn wants_card(&self) -> bool {
+        self.drive && self.device.state() == UsbDeviceState::Configured && !self.storage.ejected()
+    }
+
+    /// For answering requests from the computer, between polls.
+    pub fn control(&mut self) -> &mut Control {
+        &mut self.control
+    }
+
+    /// Put `card` in the drive until the computer ejects it or is unplugged. Requests over the
+    /// serial port are turned down meanwhile.
+    pub fn serve(&mut self, card: &mut dyn Card) {
+        #[cfg(feature = "probe")]
+        defmt::info!("card lent to USB, {} blocks", card.n_blocks());
+        while self.wants_card() {
+            fault::feed();
+            self.device.poll(&mut [&mut self.storage, self.control.class()]);
+            self.storage.process(Some(&mut *card));
+            self.control.refuse(Failure::Busy);
+        }
+        self.storage.abort();
+        #[cfg(feature = "probe")]
+        defmt::info!("card back from USB");
+    }
+}
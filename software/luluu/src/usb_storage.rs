@@ -0,0 +1,528 @@
+//! USB mass storage, so the SD card shows up as a drive on a computer plugged into the LuLuu and
+//! animations can be copied onto it without taking it out of the sleeve.
+//!
+//! It's the bulk-only transport with SCSI commands, like any USB stick. The drive's always there
+//! once a computer's plugged in, but with no card in it until the player hands one over with
+//! [`crate::usb::Usb::serve`], which it does as soon as the computer's set the drive up. Playback
+//! stops meanwhile, since the computer could change anything on the card, and carries on once the
+//! drive's ejected or the cable's pulled out.
+
+use luluu_bsp as bsp;
+
+use bsp::buffers::FullFramebuffer;
+use bsp::usb::UsbBus;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+use crate::decoder::{self, SdCard};
+use crate::pipeline::{DecoderLink, FrameSlot, Request, Response};
+
+#[cfg(not(feature = "probe"))]
+use core as defmt;
+
+const CLASS_MASS_STORAGE: u8 = 0x08;
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const REQUEST_GET_MAX_LUN: u8 = 0xfe;
+const REQUEST_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_LEN: usize = 13;
+
+const PACKET_SIZE: usize = bsp::usb::MAX_PACKET_SIZE as usize;
+
+const BLOCK_LEN: u32 = 512;
+
+/// Most blocks read or written in one go, so the bus isn't left waiting too long.
+const MAX_CHUNK_BLOCKS: u32 = 32;
+
+mod op {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const MODE_SENSE_6: u8 = 0x1a;
+    pub const START_STOP_UNIT: u8 = 0x1b;
+    pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+    pub const READ_FORMAT_CAPACITIES: u8 = 0x23;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2a;
+    pub const VERIFY_10: u8 = 0x2f;
+    pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+    pub const MODE_SENSE_10: u8 = 0x5a;
+}
+
+/// A removable disk, SPC-2, with our names in.
+const INQUIRY_DATA: [u8; 36] = *b"\x00\x80\x04\x02\x1f\x00\x00\x00LuLuu   SD card         1.0 ";
+
+/// The card, while it's in the drive.
+pub trait Card {
+    fn n_blocks(&self) -> u32;
+
+    /// Where blocks are read into and written from, a whole number of blocks long.
+    fn buffer(&mut self) -> &mut [u8];
+
+    /// Read `n_blocks` blocks from `block_idx` on into the start of the buffer.
+    fn read(&mut self, block_idx: u32, n_blocks: u32) -> Result<(), CardError>;
+
+    /// Write `n_blocks` blocks from the start of the buffer to the card from `block_idx` on.
+    fn write(&mut self, block_idx: u32, n_blocks: u32) -> Result<(), CardError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "probe", derive(defmt::Format))]
+pub struct CardError;
+
+/// The card while core 1 has lent it out, read and written by core 1 through a framebuffer.
+pub struct LentCard<'a> {
+    decoder: &'a mut DecoderLink,
+    /// Only ever missing while it's with core 1.
+    slot: Option<FrameSlot>,
+    n_blocks: u32,
+}
+
+impl<'a> LentCard<'a> {
+    /// Ask core 1 to stop playing and lend out the card. Both framebuffers have to be back from it
+    /// first. `slot` is handed back if it can't.
+    pub fn lend(decoder: &'a mut DecoderLink, slot: FrameSlot) -> Result<Self, FrameSlot> {
+        decoder.send(Request::Lend);
+        match decoder.recv() {
+            Response::Lent { n_blocks } => Ok(Self { decoder, slot: Some(slot), n_blocks }),
+            Response::Failed { .. } => Err(slot),
+            _ => defmt::unreachable!(),
+        }
+    }
+
+    /// Finished with the card, it goes back to core 1 with the next request.
+    pub fn into_slot(self) -> FrameSlot {
+        self.slot.unwrap()
+    }
+
+    fn blocks(&mut self, request: impl FnOnce(FrameSlot) -> Request) -> Result<(), CardError> {
+        self.decoder.send(request(self.slot.take().unwrap()));
+        let (slot, result) = match self.decoder.recv() {
+            Response::Blocks { slot } => (slot, Ok(())),
+            Response::Failed { slot: Some(slot), .. } => (slot, Err(CardError)),
+            _ => defmt::unreachable!(),
+        };
+        self.slot = Some(slot);
+        result
+    }
+}
+
+impl Card for LentCard<'_> {
+    fn n_blocks(&self) -> u32 {
+        self.n_blocks
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        self.slot.as_mut().unwrap().as_bytes_mut()
+    }
+
+    fn read(&mut self, block_idx: u32, n_blocks: u32) -> Result<(), CardError> {
+        self.blocks(|slot| Request::ReadBlocks { slot, block_idx, n_blocks })
+    }
+
+    fn write(&mut self, block_idx: u32, n_blocks: u32) -> Result<(), CardError> {
+        self.blocks(|slot| Request::WriteBlocks { slot, block_idx, n_blocks })
+    }
+}
+
+/// The card while core 0 still has it, before core 1's started because there's nothing on it to
+/// play.
+pub struct OwnCard<'a> {
+    card: &'a SdCard,
+    fb: &'a mut FullFramebuffer,
+    n_blocks: u32,
+}
+
+impl<'a> OwnCard<'a> {
+    /// The card's volume has to be closed.
+    pub fn new(card: &'a SdCard, fb: &'a mut FullFramebuffer) -> Result<Self, CardError> {
+        let n_blocks = embedded_sdmmc::BlockDevice::num_blocks(card).map_err(|_| CardError)?;
+        Ok(Self { card, fb, n_blocks: n_blocks.0 })
+    }
+}
+
+impl Card for OwnCard<'_> {
+    fn n_blocks(&self) -> u32 {
+        self.n_blocks
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        self.fb.as_bytes_mut()
+    }
+
+    fn read(&mut self, block_idx: u32, n_blocks: u32) -> Result<(), CardError> {
+        decoder::read_blocks(self.card, block_idx, n_blocks, self.fb.as_bytes_mut()).map_err(|_| CardError)
+    }
+
+    fn write(&mut self, block_idx: u32, n_blocks: u32) -> Result<(), CardError> {
+        decoder::write_blocks(self.card, block_idx, n_blocks, self.fb.as_bytes()).map_err(|_| CardError)
+    }
+}
+
+/// Why the last command failed, for the host to ask with `REQUEST SENSE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sense {
+    key: u8,
+    code: u8,
+    qualifier: u8,
+}
+
+impl Sense {
+    const NONE: Self = Self { key: 0x00, code: 0x00, qualifier: 0x00 };
+    const MEDIUM_NOT_PRESENT: Self = Self { key: 0x02, code: 0x3a, qualifier: 0x00 };
+    const READ_ERROR: Self = Self { key: 0x03, code: 0x11, qualifier: 0x00 };
+    const WRITE_ERROR: Self = Self { key: 0x03, code: 0x0c, qualifier: 0x00 };
+    const INVALID_COMMAND: Self = Self { key: 0x05, code: 0x20, qualifier: 0x00 };
+    const LBA_OUT_OF_RANGE: Self = Self { key: 0x05, code: 0x21, qualifier: 0x00 };
+    const INVALID_FIELD: Self = Self { key: 0x05, code: 0x24, qualifier: 0x00 };
+}
+
+/// The data for a command.
+enum Data {
+    None,
+    /// The first `len` bytes of the reply buffer.
+    Reply { len: u32 },
+    /// Blocks from the card, `buffered` bytes of which are in its buffer from `buffer_start` on.
+    Read { block_idx: u32, len: u32, buffer_start: u32, buffered: u32 },
+    /// Blocks for the card, collected in its buffer from `buffer_start` on.
+    Write { block_idx: u32, len: u32, buffer_start: u32 },
+}
+
+impl Data {
+    fn len(&self) -> u32 {
+        match *self {
+            Data::None => 0,
+            Data::Reply { len } | Data::Read { len, .. } | Data::Write { len, .. } => len,
+        }
+    }
+}
+
+/// A command being carried out.
+struct Transfer {
+    tag: u32,
+    /// Bytes of data the host's sending or expecting. Anything past the command's own data is
+    /// padding.
+    length: u32,
+    to_host: bool,
+    /// Bytes of data sent or received so far.
+    done: u32,
+    data: Data,
+    passed: bool,
+}
+
+enum Stage {
+    /// Waiting for the host's next command.
+    Command,
+    Data(Transfer),
+    /// Sending the status of a finished command.
+    Status([u8; CSW_LEN]),
+}
+
+pub struct MassStorage {
+    interface: InterfaceNumber,
+    ep_in: EndpointIn<'static, UsbBus>,
+    ep_out: EndpointOut<'static, UsbBus>,
+    stage: Stage,
+    sense: Sense,
+    reply: [u8; INQUIRY_DATA.len()],
+    /// The host ejected the drive. It stays empty until the next time it's plugged in.
+    ejected: bool,
+}
+
+impl MassStorage {
+    pub fn new(bus: &'static UsbBusAllocator<UsbBus>) -> Self {
+        Self {
+            interface: bus.interface(),
+            ep_in: bus.bulk(bsp::usb::MAX_PACKET_SIZE),
+            ep_out: bus.bulk(bsp::usb::MAX_PACKET_SIZE),
+            stage: Stage::Command,
+            sense: Sense::NONE,
+            reply: [0; INQUIRY_DATA.len()],
+            ejected: false,
+        }
+    }
+
+    /// Whether the host's ejected the drive since it was plugged in.
+    pub fn ejected(&self) -> bool {
+        self.ejected
+    }
+
+    /// Give up on whatever command was going on, since it's never going to finish now.
+    pub fn abort(&mut self) {
+        self.stage = Stage::Command;
+    }
+
+    /// Get on with the current command as far as the endpoints let us, with `card` in the drive.
+    pub fn process(&mut self, card: Option<&mut dyn Card>) {
+        let mut card = card;
+        loop {
+            let stage = core::mem::replace(&mut self.stage, Stage::Command);
+            let (stage, progressed) = match stage {
+                Stage::Command => match self.receive_command(&mut card) {
+                    Some(stage) => (stage, true),
+                    None => (Stage::Command, false),
+                },
+                Stage::Data(transfer) if transfer.done >= transfer.length => (Stage::Status(self.status(&transfer)), true),
+                Stage::Data(transfer) if transfer.to_host => self.send_data(transfer, &mut card),
+                Stage::Data(transfer) => self.receive_data(transfer, &mut card),
+                Stage::Status(csw) => match self.ep_in.write(&csw) {
+                    Ok(_) => (Stage::Command, true),
+                    Err(_) => (Stage::Status(csw), false),
+                },
+            };
+            self.stage = stage;
+            if !progressed {
+                return;
+            }
+        }
+    }
+
+    fn receive_command(&mut self, card: &mut Option<&mut dyn Card>) -> Option<Stage> {
+        let mut packet = [0u8; PACKET_SIZE];
+        let len = self.ep_out.read(&mut packet).ok()?;
+        let word = |idx: usize| u32::from_le_bytes(packet[idx..idx + 4].try_into().unwrap());
+        if len != CBW_LEN || word(0) != CBW_SIGNATURE {
+            // nothing sensible to answer, the host resets us if it's waiting on something
+            return Some(Stage::Command);
+        }
+
+        let mut transfer = Transfer {
+            tag: word(4),
+            length: word(8),
+            to_host: packet[12] & 0x80 != 0,
+            done: 0,
+            data: Data::None,
+            passed: true,
+        };
+        let block: [u8; 16] = packet[15..CBW_LEN].try_into().unwrap();
+        match self.execute(&block, &*card) {
+            // the data has to go the way the host expects it to
+            Ok(data) if data.len() > 0 && matches!(data, Data::Write { .. }) == transfer.to_host => {
+                self.sense = Sense::INVALID_FIELD;
+                transfer.passed = false;
+            }
+            Ok(data) => transfer.data = data,
+            Err(sense) => {
+                #[cfg(feature = "probe")]
+                defmt::debug!("SCSI command {:x} failed", block[0]);
+                self.sense = sense;
+                transfer.passed = false;
+            }
+        }
+        Some(Stage::Data(transfer))
+    }
+
+    /// Carry out the SCSI command in `block`, or say why not.
+    fn execute(&mut self, block: &[u8; 16], card: &Option<&mut dyn Card>) -> Result<Data, Sense> {
+        let n_blocks = card.as_ref().map(|card| card.n_blocks());
+        let be_u32 = |idx: usize| u32::from_be_bytes(block[idx..idx + 4].try_into().unwrap());
+
+        match block[0] {
+            op::TEST_UNIT_READY => {
+                n_blocks.ok_or(Sense::MEDIUM_NOT_PRESENT)?;
+                Ok(Data::None)
+            }
+            op::REQUEST_SENSE => {
+                let sense = core::mem::replace(&mut self.sense, Sense::NONE);
+                Ok(self.reply(&[
+                    0x70, 0x00, sense.key, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, sense.code,
+                    sense.qualifier, 0x00, 0x00, 0x00, 0x00,
+                ]))
+            }
+            op::INQUIRY => {
+                // no vital product data pages
+                if block[1] & 0x01 != 0 {
+                    return Err(Sense::INVALID_FIELD);
+                }
+                Ok(self.reply(&INQUIRY_DATA))
+            }
+            op::MODE_SENSE_6 => Ok(self.reply(&[0x03, 0x00, 0x00, 0x00])),
+            op::MODE_SENSE_10 => Ok(self.reply(&[0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])),
+            op::START_STOP_UNIT => {
+                let load_eject = block[4] & 0x02 != 0;
+                let start = block[4] & 0x01 != 0;
+                if load_eject && !start {
+                    self.ejected = true;
+                }
+                Ok(Data::None)
+            }
+            // the card's written before each command finishes, so nothing has to wait for these
+            op::PREVENT_ALLOW_MEDIUM_REMOVAL | op::SYNCHRONIZE_CACHE_10 | op::VERIFY_10 => Ok(Data::None),
+            op::READ_FORMAT_CAPACITIES => {
+                let n_blocks = n_blocks.ok_or(Sense::MEDIUM_NOT_PRESENT)?.to_be_bytes();
+                let block_len = BLOCK_LEN.to_be_bytes();
+                Ok(self.reply(&[
+                    0x00, 0x00, 0x00, 0x08, n_blocks[0], n_blocks[1], n_blocks[2], n_blocks[3],
+                    // formatted media
+                    0x02, block_len[1], block_len[2], block_len[3],
+                ]))
+            }
+            op::READ_CAPACITY_10 => {
+                let last_block = (n_blocks.ok_or(Sense::MEDIUM_NOT_PRESENT)? - 1).to_be_bytes();
+                let block_len = BLOCK_LEN.to_be_bytes();
+                Ok(self.reply(&[
+                    last_block[0], last_block[1], last_block[2], last_block[3], block_len[0], block_len[1],
+                    block_len[2], block_len[3],
+                ]))
+            }
+            op::READ_10 | op::WRITE_10 => {
+                let n_card_blocks = n_blocks.ok_or(Sense::MEDIUM_NOT_PRESENT)?;
+                let block_idx = be_u32(2);
+                let n_blocks = u16::from_be_bytes([block[7], block[8]]) as u32;
+                if block_idx as u64 + n_blocks as u64 > n_card_blocks as u64 {
+                    return Err(Sense::LBA_OUT_OF_RANGE);
+                }
+                let len = n_blocks * BLOCK_LEN;
+                Ok(match block[0] {
+                    op::READ_10 => Data::Read { block_idx, len, buffer_start: 0, buffered: 0 },
+                    _ => Data::Write { block_idx, len, buffer_start: 0 },
+                })
+            }
+            _ => Err(Sense::INVALID_COMMAND),
+        }
+    }
+
+    fn reply(&mut self, bytes: &[u8]) -> Data {
+        self.reply[..bytes.len()].copy_from_slice(bytes);
+        Data::Reply { len: bytes.len() as u32 }
+    }
+
+    /// Send the next packet of data to the host, reading more from the card first if it's needed.
+    fn send_data(&mut self, mut transfer: Transfer, card: &mut Option<&mut dyn Card>) -> (Stage, bool) {
+        let mut packet = [0u8; PACKET_SIZE];
+        let packet_len = (transfer.length - transfer.done).min(PACKET_SIZE as u32) as usize;
+        let done = transfer.done;
+
+        match (&mut transfer.data, card.as_mut()) {
+            (Data::Reply { len }, _) if done < *len => {
+                let n = (*len - done).min(packet_len as u32) as usize;
+                packet[..n].copy_from_slice(&self.reply[done as usize..][..n]);
+            }
+            (Data::Read { block_idx, len, buffer_start, buffered }, Some(card)) if done < *len => {
+                if done >= *buffer_start + *buffered {
+                    let chunk_blocks = (card.buffer().len() as u32 / BLOCK_LEN).min(MAX_CHUNK_BLOCKS);
+                    let n_blocks = ((*len - done) / BLOCK_LEN).min(chunk_blocks);
+                    match card.read(*block_idx + done / BLOCK_LEN, n_blocks) {
+                        Ok(()) => {
+                            *buffer_start = done;
+                            *buffered = n_blocks * BLOCK_LEN;
+                        }
+                        Err(CardError) => {
+                            // the rest is padding, and the host's told it failed once it's been sent
+                            self.sense = Sense::READ_ERROR;
+                            transfer.passed = false;
+                            transfer.data = Data::None;
+                        }
+                    }
+                }
+                if let Data::Read { buffer_start, buffered, .. } = transfer.data {
+                    let n = (buffer_start + buffered - done).min(packet_len as u32) as usize;
+                    packet[..n].copy_from_slice(&card.buffer()[(done - buffer_start) as usize..][..n]);
+                }
+            }
+            // padding
+            _ => (),
+        }
+
+        match self.ep_in.write(&packet[..packet_len]) {
+            Ok(_) => {
+                transfer.done += packet_len as u32;
+                (Stage::Data(transfer), true)
+            }
+            Err(_) => (Stage::Data(transfer), false),
+        }
+    }
+
+    /// Take the next packet of data from the host, writing it to the card once there's enough of
+    /// it.
+    fn receive_data(&mut self, mut transfer: Transfer, card: &mut Option<&mut dyn Card>) -> (Stage, bool) {
+        let mut packet = [0u8; PACKET_SIZE];
+        let Ok(packet_len) = self.ep_out.read(&mut packet) else {
+            return (Stage::Data(transfer), false);
+        };
+        let done = transfer.done;
+        transfer.done += packet_len as u32;
+
+        if let (Data::Write { block_idx, len, buffer_start }, Some(card)) = (&mut transfer.data, card.as_mut()) {
+            if done < *len {
+                let chunk_len = (card.buffer().len() as u32 / BLOCK_LEN).min(MAX_CHUNK_BLOCKS) * BLOCK_LEN;
+                let offset = (done - *buffer_start) as usize;
+                let n = packet_len.min((*len - done) as usize).min(chunk_len as usize - offset);
+                card.buffer()[offset..][..n].copy_from_slice(&packet[..n]);
+
+                let filled = offset as u32 + n as u32;
+                if filled == chunk_len || done + n as u32 >= *len {
+                    if card.write(*block_idx + *buffer_start / BLOCK_LEN, filled / BLOCK_LEN).is_err() {
+                        // the rest is ignored, and the host's told it failed once it's all arrived
+                        self.sense = Sense::WRITE_ERROR;
+                        transfer.passed = false;
+                        transfer.data = Data::None;
+                    } else {
+                        *buffer_start += filled;
+                    }
+                }
+            }
+        }
+
+        (Stage::Data(transfer), true)
+    }
+
+    /// The command status wrapper that finishes `transfer`.
+    fn status(&self, transfer: &Transfer) -> [u8; CSW_LEN] {
+        let residue = transfer.length - transfer.data.len().min(transfer.length);
+        let mut csw = [0u8; CSW_LEN];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&transfer.tag.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = match transfer.passed {
+            true => 0x00,
+            false => 0x01,
+        };
+        csw
+    }
+}
+
+impl UsbClass<UsbBus> for MassStorage {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.interface(self.interface, CLASS_MASS_STORAGE, SUBCLASS_SCSI, PROTOCOL_BULK_ONLY)?;
+        writer.endpoint(&self.ep_in)?;
+        writer.endpoint(&self.ep_out)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.stage = Stage::Command;
+        self.sense = Sense::NONE;
+        self.ejected = false;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<UsbBus>) {
+        let request = xfer.request();
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+            && request.request == REQUEST_GET_MAX_LUN
+        {
+            // just the one drive
+            xfer.accept_with(&[0]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<UsbBus>) {
+        let request = xfer.request();
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.interface) as u16
+            && request.request == REQUEST_RESET
+        {
+            self.stage = Stage::Command;
+            xfer.accept().ok();
+        }
+    }
+}
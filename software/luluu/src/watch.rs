@@ -0,0 +1,180 @@
+//! The watch face: the time, and the date if it's wanted, over the bottom of the animation or in
+//! place of it.
+//!
+//! It's sent straight to the display a row of pixels at a time, the same way whichever face it is,
+//! so it doesn't need a framebuffer of its own or to be drawn into the animation's.
+
+use core::fmt::Write;
+
+use luluu_bsp as bsp;
+
+use bsp::luluu_enc::{DirtyRect, Rgb565NE};
+use bsp::Rgb565BE;
+use display_interface::DisplayError;
+use luluu_config::time::DateTime;
+use luluu_config::{ClockFace, Config};
+
+use crate::font;
+use crate::render::{DisplayBus, DISPLAY_SIZE};
+
+const WHITE: Rgb565BE = Rgb565NE::pack_565(31, 63, 31).to_be();
+const GREY: Rgb565BE = Rgb565NE::pack_565(20, 40, 20).to_be();
+const BLACK: Rgb565BE = Rgb565NE::pack_565(0, 0, 0).to_be();
+
+/// Where a line of text goes: a band of rows across part of the display, with the text centred in
+/// it as big as it fits up to `max_scale`.
+struct Band {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    max_scale: usize,
+}
+
+/// Over the animation, low enough down to leave most of it showing but clear of the bezel of round
+/// displays.
+const OVER_TIME: Band = Band { x: 54, y: 164, width: 132, height: 27, max_scale: 3 };
+const OVER_DATE: Band = Band { x: 54, y: 191, width: 132, height: 20, max_scale: 2 };
+
+/// In place of the animation, the whole display is cleared and these are roughly in the middle.
+const INSTEAD_TIME: Band = Band { x: 0, y: 68, width: DISPLAY_SIZE as usize, height: 65, max_scale: 7 };
+const INSTEAD_DATE: Band = Band { x: 20, y: 137, width: DISPLAY_SIZE as usize - 40, height: 29, max_scale: 3 };
+
+/// Leaves a little room at the sides of each band.
+const SIDE_GAP: usize = 6;
+
+type TimeText = heapless::String<8>;
+type DateText = heapless::String<12>;
+
+pub struct WatchFace {
+    face: ClockFace,
+    show_date: bool,
+    twelve_hour: bool,
+    /// What's on the display now, or `None` if it needs sending again.
+    shown: Option<(TimeText, DateText)>,
+}
+
+impl WatchFace {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            face: config.clock_face,
+            show_date: config.clock_date,
+            twelve_hour: config.clock_twelve_hour,
+            shown: None,
+        }
+    }
+
+    pub fn face(&self) -> ClockFace {
+        self.face
+    }
+
+    /// Send the whole face again next time, like when something else has been drawn over it.
+    pub fn invalidate(&mut self) {
+        self.shown = None;
+    }
+
+    /// Whether `rect` on the display covers any of the face drawn over the animation.
+    pub fn overlaps_over(&self, rect: DirtyRect) -> bool {
+        let (x, y) = (rect.x as usize, rect.y as usize);
+        let (w, h) = (rect.width as usize, rect.height as usize);
+        let bottom = if self.show_date { OVER_DATE.y + OVER_DATE.height } else { OVER_TIME.y + OVER_TIME.height };
+        !rect.is_empty()
+            && x < OVER_TIME.x + OVER_TIME.width
+            && OVER_TIME.x < x + w
+            && y < bottom
+            && OVER_TIME.y < y + h
+    }
+
+    /// Send the face for `now` if it's changed since it was last sent, or needs sending again.
+    /// Returns whether it was sent.
+    pub fn send(&mut self, display_bus: &DisplayBus<'_>, now: Option<DateTime>) -> Result<bool, DisplayError> {
+        let text = (self.time_text(now), self.date_text(now));
+        if self.shown.as_ref() == Some(&text) {
+            return Ok(false);
+        }
+
+        let (time_band, date_band) = match self.face {
+            ClockFace::Off => return Ok(false),
+            ClockFace::Over => (&OVER_TIME, &OVER_DATE),
+            ClockFace::Instead => {
+                if self.shown.is_none() {
+                    clear(display_bus)?;
+                }
+                (&INSTEAD_TIME, &INSTEAD_DATE)
+            }
+        };
+        send_line(display_bus, time_band, WHITE, &text.0)?;
+        if self.show_date {
+            send_line(display_bus, date_band, GREY, &text.1)?;
+        }
+        self.shown = Some(text);
+        Ok(true)
+    }
+
+    fn time_text(&self, now: Option<DateTime>) -> TimeText {
+        let mut text = TimeText::new();
+        let Some(now) = now else {
+            // the clock hasn't been set
+            text.push_str("--:--").unwrap();
+            return text;
+        };
+        if self.twelve_hour {
+            let hour = match now.hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            let am_pm = if now.hour < 12 { "am" } else { "pm" };
+            write!(&mut text, "{}:{:02}{}", hour, now.minute, am_pm).unwrap();
+        } else {
+            write!(&mut text, "{:02}:{:02}", now.hour, now.minute).unwrap();
+        }
+        text
+    }
+
+    fn date_text(&self, now: Option<DateTime>) -> DateText {
+        let mut text = DateText::new();
+        if let (true, Some(now)) = (self.show_date, now) {
+            write!(&mut text, "{} {} {}", now.day_name(), now.day, now.month_name()).unwrap();
+        }
+        text
+    }
+}
+
+/// Send `band` with `text` in it over whatever was there.
+fn send_line(display_bus: &DisplayBus<'_>, band: &Band, color: Rgb565BE, text: &str) -> Result<(), DisplayError> {
+    let width_at_1 = font::text_width(text, 1).max(1);
+    let scale = ((band.width - 2 * SIDE_GAP) / width_at_1).clamp(1, band.max_scale);
+    let text_x = band.width.saturating_sub(font::text_width(text, scale)) / 2;
+    let text_y = band.height.saturating_sub(font::GLYPH_HEIGHT * scale) / 2;
+
+    let mut row = [BLACK; DISPLAY_SIZE as usize];
+    let row = &mut row[..band.width];
+    for dy in 0..band.height {
+        row.fill(BLACK);
+        let glyph_row = dy.checked_sub(text_y).map(|dy| dy / scale).filter(|&r| r < font::GLYPH_HEIGHT);
+        if let Some(glyph_row) = glyph_row {
+            for (i, c) in text.chars().enumerate() {
+                for (col, bits) in font::glyph(c).iter().enumerate() {
+                    if bits & (1 << glyph_row) != 0 {
+                        let x = text_x + (i * font::ADVANCE + col) * scale;
+                        row[x.min(band.width)..(x + scale).min(band.width)].fill(color);
+                    }
+                }
+            }
+        }
+
+        let (x, y) = (band.x as u16, (band.y + dy) as u16);
+        display_bus.write_pixels_with(x, y, x + band.width as u16 - 1, y, Rgb565BE::slice_as_bytes(row), || ())?;
+    }
+    Ok(())
+}
+
+/// Black out the whole display.
+fn clear(display_bus: &DisplayBus<'_>) -> Result<(), DisplayError> {
+    let row = [BLACK; DISPLAY_SIZE as usize];
+    let end = DISPLAY_SIZE as u16 - 1;
+    for y in 0..DISPLAY_SIZE as u16 {
+        display_bus.write_pixels_with(0, y, end, y, Rgb565BE::slice_as_bytes(&row), || ())?;
+    }
+    Ok(())
+}